@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest};
+use serde::Deserialize;
+use crate::error::ApiError;
+
+/// Query parameter every Azure OpenAI data-plane call must carry
+pub const API_VERSION_PARAM: &str = "api-version";
+
+/// Azure OpenAI compatible routes (`/openai/deployments/{deployment}/...`)
+#[derive(Deserialize, Clone)]
+pub struct AzureConfig {
+    /// Accepted `api-version` values; empty accepts any version
+    #[serde(default = "default_api_versions")]
+    pub api_versions: Vec<String>,
+    /// Deployment name to model; empty accepts any deployment and reports
+    /// the deployment name as the model
+    #[serde(default)]
+    pub deployments: HashMap<String, String>,
+    /// Provider profile for Azure routes when the request does not name one
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+impl Default for AzureConfig {
+    fn default() -> Self {
+        Self {
+            api_versions: default_api_versions(),
+            deployments: HashMap::new(),
+            profile: None,
+        }
+    }
+}
+
+fn default_api_versions() -> Vec<String> {
+    [
+        "2023-05-15",
+        "2024-02-01",
+        "2024-06-01",
+        "2024-10-21",
+        "2024-08-01-preview",
+        "2024-10-01-preview",
+        "2024-12-01-preview",
+        "2025-01-01-preview",
+    ]
+    .iter()
+    .map(|version| version.to_string())
+    .collect()
+}
+
+/// Azure's reply to a missing or unsupported `api-version`
+fn resource_not_found() -> ApiError {
+    ApiError::azure(StatusCode::NOT_FOUND, "404", "Resource not found")
+}
+
+fn deployment_not_found() -> ApiError {
+    ApiError::azure(
+        StatusCode::NOT_FOUND,
+        "DeploymentNotFound",
+        "The API deployment for this resource does not exist. If you created the deployment within the last 5 minutes, please wait a moment and try again.",
+    )
+}
+
+impl AzureConfig {
+    /// Validate `api-version` and resolve `deployment` to the model it serves
+    pub fn resolve(&self, req: &HttpRequest, deployment: &str) -> Result<String, ApiError> {
+        let version = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.get(API_VERSION_PARAM).cloned())
+            .ok_or_else(resource_not_found)?;
+        if !self.api_versions.is_empty() && !self.api_versions.contains(&version) {
+            return Err(resource_not_found());
+        }
+
+        if self.deployments.is_empty() {
+            return Ok(deployment.to_string());
+        }
+        self.deployments.get(deployment).cloned().ok_or_else(deployment_not_found)
+    }
+}
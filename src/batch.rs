@@ -0,0 +1,385 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use actix_web::http::StatusCode;
+use clickhouse::{Client, Row};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use crate::config_loader::DatabaseConfig;
+use crate::db::{self, DATABASE_NAME};
+use crate::error::ApiError;
+
+/// Endpoints a batch can run requests against, as OpenAI accepts them
+const ENDPOINTS: &[&str] = &["/v1/chat/completions", "/v1/responses", "/v1/embeddings", "/v1/completions"];
+
+/// How long a batch may take, the only window OpenAI offers
+const COMPLETION_WINDOW: &str = "24h";
+const COMPLETION_WINDOW_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Jobs of the simulated Batch API
+#[derive(Deserialize, Clone, Debug)]
+pub struct BatchesConfig {
+    /// Time a new batch spends `validating`
+    #[serde(default = "default_validating_ms")]
+    pub validating_ms: u64,
+    /// Time a batch spends `in_progress`, over which its requests complete
+    #[serde(default = "default_in_progress_ms")]
+    pub in_progress_ms: u64,
+    /// Time a batch spends `finalizing`, and a cancelled one `cancelling`
+    #[serde(default = "default_finalizing_ms")]
+    pub finalizing_ms: u64,
+    /// `request_counts.total` of every batch
+    #[serde(default = "default_requests")]
+    pub requests: u32,
+    /// Jobs kept; the oldest are forgotten first
+    #[serde(default = "default_max_batches")]
+    pub max_batches: usize,
+    /// Table in `midai_simulator` (on the `database` connection) the jobs
+    /// are written to and loaded from at startup, so they outlive a
+    /// restart; jobs are kept in memory only when unset
+    #[serde(default)]
+    pub table: Option<String>,
+}
+
+impl Default for BatchesConfig {
+    fn default() -> Self {
+        Self {
+            validating_ms: default_validating_ms(),
+            in_progress_ms: default_in_progress_ms(),
+            finalizing_ms: default_finalizing_ms(),
+            requests: default_requests(),
+            max_batches: default_max_batches(),
+            table: None,
+        }
+    }
+}
+
+fn default_validating_ms() -> u64 {
+    1000
+}
+
+fn default_in_progress_ms() -> u64 {
+    60000
+}
+
+fn default_finalizing_ms() -> u64 {
+    1000
+}
+
+fn default_requests() -> u32 {
+    100
+}
+
+fn default_max_batches() -> usize {
+    1000
+}
+
+/// Query of `GET /v1/batches`
+#[derive(Deserialize, Debug)]
+pub struct ListQuery {
+    /// Id of the batch the page starts after
+    pub after: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+/// A batch job as created; its status follows from the time since
+/// `created_at` (and `cancelled_at`), so this is all there is to persist
+#[derive(Row, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BatchJob {
+    pub id: String,
+    pub endpoint: String,
+    pub input_file_id: String,
+    pub completion_window: String,
+    /// The `metadata` object as JSON
+    pub metadata: String,
+    /// Unix milliseconds
+    pub created_at: i64,
+    /// Unix milliseconds; 0 unless cancelled
+    pub cancelled_at: i64,
+}
+
+/// Batch jobs, oldest first
+pub struct BatchStore {
+    config: BatchesConfig,
+    jobs: Mutex<VecDeque<BatchJob>>,
+}
+
+impl BatchStore {
+    pub fn new(config: BatchesConfig) -> Self {
+        Self {
+            config,
+            jobs: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Keep the newest jobs of `previous`, the store before a reload, up to
+    /// this store's `max_batches`
+    pub fn carry_over(&self, previous: &BatchStore) {
+        let previous = previous.jobs.lock().unwrap();
+        let skip = previous.len().saturating_sub(self.config.max_batches);
+        *self.jobs.lock().unwrap() = previous.iter().skip(skip).cloned().collect();
+    }
+
+    /// Create the table `batches.table` when missing and load the jobs it
+    /// holds. Does nothing without a table.
+    pub async fn load(&self, client: &Client, database: &DatabaseConfig) -> Result<(), String> {
+        let Some(table) = &self.config.table else {
+            return Ok(());
+        };
+        let failed = |e: clickhouse::error::Error| format!("cannot load batch jobs from {}.{}: {}", DATABASE_NAME, table, e);
+        let create = db::create_batches_table_query(table);
+        db::retry(&database.retry, "Creating the batch jobs table", || client.query(&create).execute())
+            .await
+            .map_err(failed)?;
+        let query = db::batches_query(table);
+        let loaded = db::retry(&database.retry, "Loading batch jobs", || client.query(&query).fetch_all::<BatchJob>())
+            .await
+            .map_err(failed)?;
+        info!("Batch jobs: {} loaded from {}.{}", loaded.len(), DATABASE_NAME, table);
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.extend(loaded);
+        let excess = jobs.len().saturating_sub(self.config.max_batches);
+        jobs.drain(..excess);
+        Ok(())
+    }
+
+    /// Write `job` to `batches.table`, if set, in the background so the
+    /// request does not wait on ClickHouse. A failed write is logged and
+    /// leaves the job in memory only. Writes may land out of order: the
+    /// table keeps the row with the latest `cancelled_at`.
+    pub fn persist(&self, client: Client, database: &DatabaseConfig, job: &BatchJob) {
+        let Some(table) = self.config.table.clone() else {
+            return;
+        };
+        let row = match serde_json::to_string(job) {
+            Ok(row) => row,
+            Err(e) => {
+                warn!("Failed to encode batch {}: {}", job.id, e);
+                return;
+            }
+        };
+        // `?` binds query arguments; `??` is a literal one
+        let insert = format!("INSERT INTO {} FORMAT JSONEachRow\n{}", table, row.replace('?', "??"));
+        let retry = database.retry.clone();
+        let id = job.id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = db::retry(&retry, "Saving a batch job", || client.query(&insert).execute()).await {
+                warn!("Failed to save batch {} to {}.{}: {}", id, DATABASE_NAME, table, e);
+            }
+        });
+    }
+
+    /// Validate a `POST /v1/batches` body and start a batch for it
+    pub fn create(&self, body: &Value, metadata: Map<String, Value>, now: i64) -> Result<BatchJob, ApiError> {
+        let input_file_id = body["input_file_id"]
+            .as_str()
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| ApiError::invalid_request("Missing required parameter: 'input_file_id'.").with_param("input_file_id"))?;
+        let endpoint = body["endpoint"].as_str().unwrap_or_default();
+        if !ENDPOINTS.contains(&endpoint) {
+            return Err(ApiError::invalid_request(format!(
+                "Invalid 'endpoint': expected one of {}, but got '{}' instead.",
+                ENDPOINTS.join(", "),
+                endpoint
+            ))
+            .with_param("endpoint"));
+        }
+        let completion_window = body["completion_window"].as_str().unwrap_or_default();
+        if completion_window != COMPLETION_WINDOW {
+            return Err(ApiError::invalid_request(format!(
+                "Invalid 'completion_window': expected '{}', but got '{}' instead.",
+                COMPLETION_WINDOW, completion_window
+            ))
+            .with_param("completion_window"));
+        }
+        let job = BatchJob {
+            id: format!("batch_{}", uuid::Uuid::new_v4().simple()),
+            endpoint: endpoint.to_string(),
+            input_file_id: input_file_id.to_string(),
+            completion_window: completion_window.to_string(),
+            metadata: Value::Object(metadata).to_string(),
+            created_at: now,
+            cancelled_at: 0,
+        };
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= self.config.max_batches.max(1) {
+            jobs.pop_front();
+        }
+        jobs.push_back(job.clone());
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Option<BatchJob> {
+        self.jobs.lock().unwrap().iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Cancel a batch that has not finished yet, returning it updated
+    pub fn cancel(&self, id: &str, now: i64) -> Result<BatchJob, ApiError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter_mut().find(|job| job.id == id).ok_or_else(|| not_found(id))?;
+        let status = self.status(job, now);
+        if !matches!(status, "validating" | "in_progress" | "finalizing") {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "invalid_request_error",
+                format!("Cannot cancel a batch with status '{}'.", status),
+            ));
+        }
+        job.cancelled_at = now;
+        Ok(job.clone())
+    }
+
+    /// A page of batches, newest first, as OpenAI lists them: `{"object":
+    /// "list", "data", "first_id", "last_id", "has_more"}`
+    pub fn list(&self, query: &ListQuery, now: i64) -> Value {
+        let jobs = self.jobs.lock().unwrap();
+        let newest_first: Vec<&BatchJob> = jobs.iter().rev().collect();
+        let start = query
+            .after
+            .as_ref()
+            .and_then(|after| newest_first.iter().position(|job| job.id == *after))
+            .map_or(0, |position| position + 1);
+        let limit = query.limit.clamp(1, 100);
+        let page: Vec<Value> = newest_first.iter().skip(start).take(limit).map(|job| self.object(job, now)).collect();
+        json!({
+            "object": "list",
+            "first_id": page.first().map(|batch| batch["id"].clone()),
+            "last_id": page.last().map(|batch| batch["id"].clone()),
+            "has_more": newest_first.len() > start + page.len(),
+            "data": page,
+        })
+    }
+
+    /// Where `job` is at `now`: `validating`, `in_progress`, `finalizing`
+    /// and `completed` one after the other, or `cancelling` for
+    /// `finalizing_ms` and then `cancelled`
+    fn status(&self, job: &BatchJob, now: i64) -> &'static str {
+        let phases = Phases::of(&self.config, job);
+        if job.cancelled_at > 0 {
+            return if now < phases.cancelled { "cancelling" } else { "cancelled" };
+        }
+        match now {
+            now if now < phases.validated => "validating",
+            now if now < phases.processed => "in_progress",
+            now if now < phases.finalized => "finalizing",
+            _ => "completed",
+        }
+    }
+
+    /// `job` as OpenAI's batch object at `now`. Its requests complete
+    /// evenly while `in_progress`; a cancelled batch stops where it was.
+    pub fn object(&self, job: &BatchJob, now: i64) -> Value {
+        let phases = Phases::of(&self.config, job);
+        let status = self.status(job, now);
+        let progressed_until = if job.cancelled_at > 0 { job.cancelled_at.min(now) } else { now };
+        let reached = |at: i64| (at <= progressed_until).then_some(at / 1000);
+        let in_progress_ms = self.config.in_progress_ms.max(1) as f64;
+        let progress = ((progressed_until - phases.validated) as f64 / in_progress_ms).clamp(0.0, 1.0);
+        let completed = (f64::from(self.config.requests) * progress).floor() as u32;
+        let metadata: Value = serde_json::from_str(&job.metadata).unwrap_or_else(|_| json!({}));
+        json!({
+            "id": job.id,
+            "object": "batch",
+            "endpoint": job.endpoint,
+            "errors": null,
+            "input_file_id": job.input_file_id,
+            "completion_window": job.completion_window,
+            "status": status,
+            "output_file_id": (status == "completed").then(|| format!("file-{}", job.id.trim_start_matches("batch_"))),
+            "error_file_id": null,
+            "created_at": job.created_at / 1000,
+            "in_progress_at": reached(phases.validated),
+            "expires_at": (job.created_at + COMPLETION_WINDOW_MS) / 1000,
+            "finalizing_at": reached(phases.processed),
+            "completed_at": (status == "completed").then_some(phases.finalized / 1000),
+            "failed_at": null,
+            "expired_at": null,
+            "cancelling_at": (job.cancelled_at > 0).then_some(job.cancelled_at / 1000),
+            "cancelled_at": (status == "cancelled").then_some(phases.cancelled / 1000),
+            "request_counts": {
+                "total": self.config.requests,
+                "completed": completed,
+                "failed": 0,
+            },
+            "metadata": metadata,
+        })
+    }
+}
+
+/// When `job` leaves each phase, in Unix milliseconds
+struct Phases {
+    validated: i64,
+    processed: i64,
+    finalized: i64,
+    /// Meaningless unless the job was cancelled
+    cancelled: i64,
+}
+
+impl Phases {
+    fn of(config: &BatchesConfig, job: &BatchJob) -> Self {
+        let validated = job.created_at + config.validating_ms as i64;
+        let processed = validated + config.in_progress_ms as i64;
+        Self {
+            validated,
+            processed,
+            finalized: processed + config.finalizing_ms as i64,
+            cancelled: job.cancelled_at + config.finalizing_ms as i64,
+        }
+    }
+}
+
+pub fn not_found(id: &str) -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("Batch {} not found", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> BatchStore {
+        BatchStore::new(BatchesConfig {
+            validating_ms: 1000,
+            in_progress_ms: 10000,
+            finalizing_ms: 1000,
+            requests: 10,
+            ..BatchesConfig::default()
+        })
+    }
+
+    fn create(store: &BatchStore) -> BatchJob {
+        let body = json!({"input_file_id": "file-abc", "endpoint": "/v1/chat/completions", "completion_window": "24h"});
+        store.create(&body, Map::new(), 1_000_000).unwrap()
+    }
+
+    #[test]
+    fn status_follows_the_configured_phases() {
+        let store = store();
+        let job = create(&store);
+        let at = |offset: i64| store.object(&job, job.created_at + offset);
+        assert_eq!(at(500)["status"], "validating");
+        assert_eq!(at(6000)["status"], "in_progress");
+        assert_eq!(at(6000)["request_counts"]["completed"], 5);
+        assert_eq!(at(11500)["status"], "finalizing");
+        assert_eq!(at(12000)["status"], "completed");
+        assert_eq!(at(12000)["request_counts"]["completed"], 10);
+        assert!(at(12000)["output_file_id"].is_string());
+    }
+
+    #[test]
+    fn cancelled_batch_stops_where_it_was() {
+        let store = store();
+        let job = create(&store);
+        let cancelled = store.cancel(&job.id, job.created_at + 3000).unwrap();
+        assert_eq!(store.object(&cancelled, job.created_at + 3500)["status"], "cancelling");
+        let later = store.object(&cancelled, job.created_at + 20000);
+        assert_eq!(later["status"], "cancelled");
+        assert_eq!(later["request_counts"]["completed"], 2);
+        assert!(later["completed_at"].is_null());
+        assert!(store.cancel(&job.id, job.created_at + 20000).is_err());
+    }
+}
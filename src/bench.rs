@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use actix_web::web::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::TokioExecutor;
+use rand::distributions::{Distribution, WeightedIndex};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::cli::Args;
+use crate::error::CustomError;
+use crate::stream::estimate_tokens;
+
+type Client = HyperClient<HttpConnector, Full<Bytes>>;
+
+/// One kind of request of the mix, sent in proportion to its `weight`
+#[derive(Deserialize, Debug)]
+pub struct MixEntry {
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Chat completion request body, sent as is
+    pub body: Value,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// What `bench` sends, where, and for how long
+#[derive(Debug)]
+pub struct BenchOptions {
+    pub url: http::Uri,
+    pub api_key: Option<String>,
+    /// Requests kept in flight
+    pub concurrency: usize,
+    pub duration: Duration,
+    /// Stop after this many requests, even before `duration`
+    pub requests: Option<usize>,
+    pub timeout: Duration,
+    pub mix: Vec<MixEntry>,
+    /// Print the report as JSON
+    pub json: bool,
+}
+
+impl BenchOptions {
+    /// `--url`, `--api-key`, `--concurrency` (10), `--duration` in seconds
+    /// (30), `--requests`, `--timeout` in seconds (120), `--json` and the
+    /// request mix: a YAML or JSON list of `{weight, body}` given with
+    /// `--mix`, else one request to `--model` (gpt-4o-mini), streamed
+    /// unless `--no-stream`
+    pub fn from_args(args: &Args) -> Result<Self, CustomError> {
+        let url = args.value("url").unwrap_or("http://127.0.0.1:4545/v1/chat/completions");
+        let url: http::Uri =
+            url.parse().map_err(|e| CustomError::ConfigError(format!("invalid --url '{}': {}", url, e)))?;
+        if url.scheme_str() != Some("http") {
+            return Err(CustomError::ConfigError(format!("--url must be a plain http:// endpoint, got '{}'", url)));
+        }
+        let mix = match args.value("mix") {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| CustomError::ConfigError(format!("cannot read --mix '{}': {}", path, e)))?;
+                let mix: Vec<MixEntry> = serde_yaml::from_str(&content)
+                    .map_err(|e| CustomError::ConfigError(format!("invalid --mix '{}': {}", path, e)))?;
+                if mix.iter().all(|entry| entry.weight == 0) {
+                    return Err(CustomError::ConfigError(format!("--mix '{}' has no entry with a weight", path)));
+                }
+                mix
+            }
+            None => vec![MixEntry {
+                weight: 1,
+                body: json!({
+                    "model": args.value("model").unwrap_or("gpt-4o-mini"),
+                    "stream": !args.flag("no-stream"),
+                    "stream_options": { "include_usage": true },
+                    "messages": [{ "role": "user", "content": "Hello!" }],
+                }),
+            }],
+        };
+        Ok(Self {
+            url,
+            api_key: args.value("api-key").map(str::to_string),
+            concurrency: args.parse_or("concurrency", 10)?.max(1),
+            duration: Duration::from_secs_f64(args.parse_or("duration", 30.0)?),
+            requests: args.value("requests").map(|_| args.parse_or("requests", 0)).transpose()?,
+            timeout: Duration::from_secs_f64(args.parse_or("timeout", 120.0)?),
+            mix,
+            json: args.flag("json"),
+        })
+    }
+}
+
+/// Outcome of one request
+struct Sample {
+    /// `None` when no response came back
+    status: Option<u16>,
+    ttft: Option<Duration>,
+    latency: Duration,
+    completion_tokens: u32,
+}
+
+/// p50, p90, p99 and max of one measure
+#[derive(Serialize, Debug, Default)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    pub fn of(mut values: Vec<f64>) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_by(f64::total_cmp);
+        let rank = |p: f64| values[((p * values.len() as f64).ceil() as usize).clamp(1, values.len()) - 1];
+        Self {
+            p50: rank(0.50),
+            p90: rank(0.90),
+            p99: rank(0.99),
+            max: values[values.len() - 1],
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct BenchReport {
+    pub requests: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub elapsed_seconds: f64,
+    pub requests_per_second: f64,
+    pub completion_tokens_per_second: f64,
+    /// Responses by status code, `error` for requests that got none
+    pub statuses: BTreeMap<String, usize>,
+    pub ttft_ms: Percentiles,
+    pub latency_ms: Percentiles,
+    /// Completion tokens per second of each request, after its first token
+    pub tokens_per_second: Percentiles,
+}
+
+/// Run the load generator with the options given on the command line and
+/// print its report
+pub async fn run(args: &Args) -> Result<(), CustomError> {
+    let options = BenchOptions::from_args(args)?;
+    let json = options.json;
+    if !json {
+        println!(
+            "Benchmarking {} with {} concurrent requests for {:?}",
+            options.url, options.concurrency, options.duration
+        );
+    }
+    let report = bench(options.into()).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
+
+/// Keep `concurrency` requests in flight until the duration or request
+/// count is reached, then summarize them
+pub async fn bench(options: Arc<BenchOptions>) -> Result<BenchReport, CustomError> {
+    let weights = WeightedIndex::new(options.mix.iter().map(|entry| entry.weight))
+        .map_err(|e| CustomError::ConfigError(format!("invalid request mix: {}", e)))?;
+    let client: Client = HyperClient::builder(TokioExecutor::new()).build(HttpConnector::new());
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let sent = Arc::new(AtomicUsize::new(0));
+
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|_| {
+            let (options, client, weights, sent) = (options.clone(), client.clone(), weights.clone(), sent.clone());
+            tokio::spawn(async move {
+                let mut samples = Vec::new();
+                while Instant::now() < deadline {
+                    if options.requests.is_some_and(|limit| sent.fetch_add(1, Ordering::Relaxed) >= limit) {
+                        break;
+                    }
+                    let entry = &options.mix[weights.sample(&mut rand::thread_rng())];
+                    samples.push(send(&client, &options, &entry.body).await);
+                }
+                samples
+            })
+        })
+        .collect();
+    let mut samples = Vec::new();
+    for worker in workers {
+        samples.extend(worker.await.map_err(|_| CustomError::FetchError)?);
+    }
+    Ok(summarize(&samples, started.elapsed()))
+}
+
+async fn send(client: &Client, options: &BenchOptions, body: &Value) -> Sample {
+    let started = Instant::now();
+    let failed = |started: Instant| Sample {
+        status: None,
+        ttft: None,
+        latency: started.elapsed(),
+        completion_tokens: 0,
+    };
+    let mut request = http::Request::post(options.url.clone()).header(http::header::CONTENT_TYPE, "application/json");
+    if let Some(key) = &options.api_key {
+        request = request.header(http::header::AUTHORIZATION, format!("Bearer {}", key));
+    }
+    let Ok(request) = request.body(Full::new(Bytes::from(body.to_string()))) else {
+        return failed(started);
+    };
+    let exchange = async {
+        let response = client.request(request).await.ok()?;
+        let status = response.status().as_u16();
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        let mut ttft = None;
+        while let Some(frame) = body.frame().await {
+            let Ok(data) = frame.ok()?.into_data() else {
+                continue;
+            };
+            if ttft.is_none() && !data.is_empty() {
+                ttft = Some(started.elapsed());
+            }
+            received.extend_from_slice(&data);
+        }
+        Some((status, ttft, received))
+    };
+    match tokio::time::timeout(options.timeout, exchange).await {
+        Ok(Some((status, ttft, received))) => Sample {
+            status: Some(status),
+            ttft,
+            latency: started.elapsed(),
+            completion_tokens: completion_tokens(&received),
+        },
+        _ => failed(started),
+    }
+}
+
+/// Completion tokens of a response body, streamed or not: the reported
+/// usage, else an estimate from the content
+fn completion_tokens(body: &[u8]) -> u32 {
+    let text = String::from_utf8_lossy(body);
+    let documents: Vec<Value> = if text.trim_start().starts_with('{') {
+        serde_json::from_str(&text).into_iter().collect()
+    } else {
+        text.lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .filter_map(|data| serde_json::from_str(data.trim()).ok())
+            .collect()
+    };
+    if let Some(tokens) = documents.iter().rev().find_map(|document| document["usage"]["completion_tokens"].as_u64()) {
+        return tokens as u32;
+    }
+    let content: String = documents
+        .iter()
+        .flat_map(|document| document["choices"].as_array().cloned().unwrap_or_default())
+        .filter_map(|choice| {
+            choice["delta"]["content"].as_str().or_else(|| choice["message"]["content"].as_str()).map(str::to_string)
+        })
+        .collect();
+    estimate_tokens(&content)
+}
+
+fn summarize(samples: &[Sample], elapsed: Duration) -> BenchReport {
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let succeeded: Vec<&Sample> = samples.iter().filter(|sample| sample.status.is_some_and(|status| status < 400)).collect();
+    let mut statuses = BTreeMap::new();
+    for sample in samples {
+        let status = sample.status.map_or_else(|| "error".to_string(), |status| status.to_string());
+        *statuses.entry(status).or_insert(0) += 1;
+    }
+    let completion_tokens: u64 = succeeded.iter().map(|sample| u64::from(sample.completion_tokens)).sum();
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    BenchReport {
+        requests: samples.len(),
+        succeeded: succeeded.len(),
+        failed: samples.len() - succeeded.len(),
+        elapsed_seconds: elapsed.as_secs_f64(),
+        requests_per_second: samples.len() as f64 / seconds,
+        completion_tokens_per_second: completion_tokens as f64 / seconds,
+        statuses,
+        ttft_ms: Percentiles::of(succeeded.iter().filter_map(|sample| sample.ttft).map(millis).collect()),
+        latency_ms: Percentiles::of(succeeded.iter().map(|sample| millis(sample.latency)).collect()),
+        tokens_per_second: Percentiles::of(
+            succeeded
+                .iter()
+                .filter_map(|sample| {
+                    let generating = sample.latency.saturating_sub(sample.ttft?).as_secs_f64();
+                    (generating > 0.0).then(|| f64::from(sample.completion_tokens) / generating)
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn print_report(report: &BenchReport) {
+    println!(
+        "\n{} requests in {:.1}s ({} succeeded, {} failed): {:.1} req/s, {:.1} completion tokens/s",
+        report.requests,
+        report.elapsed_seconds,
+        report.succeeded,
+        report.failed,
+        report.requests_per_second,
+        report.completion_tokens_per_second
+    );
+    let statuses: Vec<String> = report.statuses.iter().map(|(status, count)| format!("{} x{}", status, count)).collect();
+    println!("Statuses: {}\n", statuses.join(", "));
+    println!("{:<16}{:>10}{:>10}{:>10}{:>10}", "", "p50", "p90", "p99", "max");
+    for (name, percentiles) in [
+        ("TTFT (ms)", &report.ttft_ms),
+        ("Latency (ms)", &report.latency_ms),
+        ("Tokens/s", &report.tokens_per_second),
+    ] {
+        println!(
+            "{:<16}{:>10.1}{:>10.1}{:>10.1}{:>10.1}",
+            name, percentiles.p50, percentiles.p90, percentiles.p99, percentiles.max
+        );
+    }
+}
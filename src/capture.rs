@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use actix_web::web::Bytes;
+use serde::Serialize;
+use crate::request::SamplingParams;
+use crate::stream::{ChunkTiming, StreamTrace};
+
+/// One request served by the simulator, as written to the JSONL snapshot
+#[derive(Serialize, Clone, Debug)]
+pub struct CaptureRecord {
+    pub id: String,
+    /// `x-request-id` echoed to the client
+    pub request_id: String,
+    pub received_at: String,
+    pub endpoint: String,
+    pub model: String,
+    /// Masked API key, as in `/admin/stats`
+    pub api_key: String,
+    pub status: u16,
+    pub fixture: Option<String>,
+    /// Fault injected into the response, e.g. `stream_error`
+    pub fault: Option<String>,
+    /// Request body as JSON, or as a string when it was not valid JSON
+    pub request: serde_json::Value,
+    /// `logit_bias` and penalties the request was sent with
+    pub sampling: SamplingParams,
+    /// Content streamed back, or the error body for failed requests
+    pub response: String,
+    pub prompt_tokens: u32,
+    /// Prompt tokens reported as read from the prompt cache
+    pub cached_tokens: u32,
+    /// Prompt tokens written to the cache by `cache_control` breakpoints
+    pub cache_creation_tokens: u32,
+    /// Zero for failed requests
+    pub completion_tokens: u32,
+    /// Of the first choice
+    pub finish_reason: Option<String>,
+    /// Upstream provider an OpenRouter request was routed to
+    pub provider: Option<String>,
+    pub chunks: Vec<ChunkTiming>,
+    /// Content of each of `chunks`, kept for `/admin/replay`
+    #[serde(skip)]
+    pub frames: Vec<Bytes>,
+    pub completed: bool,
+    pub duration_ms: f64,
+}
+
+impl CaptureRecord {
+    pub fn new(id: String, endpoint: &str, body: &[u8]) -> Self {
+        let request = if body.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice(body)
+                .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(body).into_owned()))
+        };
+        Self {
+            id,
+            request_id: String::new(),
+            received_at: chrono::Utc::now().to_rfc3339(),
+            endpoint: endpoint.to_string(),
+            model: String::new(),
+            api_key: String::new(),
+            status: 200,
+            fixture: None,
+            fault: None,
+            request,
+            sampling: SamplingParams::default(),
+            response: String::new(),
+            prompt_tokens: 0,
+            cached_tokens: 0,
+            cache_creation_tokens: 0,
+            completion_tokens: 0,
+            finish_reason: None,
+            provider: None,
+            chunks: Vec::new(),
+            frames: Vec::new(),
+            completed: false,
+            duration_ms: 0.0,
+        }
+    }
+
+    /// Fill in the stream timings once the response body is finished
+    pub fn with_trace(mut self, trace: StreamTrace) -> Self {
+        self.chunks = trace.chunks;
+        self.frames = trace.frames;
+        self.completed = trace.completed;
+        self.duration_ms = trace.duration.as_secs_f64() * 1000.0;
+        self
+    }
+}
+
+/// Bounded in-memory log of everything served since startup. Once full,
+/// the oldest records are evicted.
+pub struct CaptureStore {
+    records: Mutex<VecDeque<CaptureRecord>>,
+    capacity: usize,
+}
+
+impl CaptureStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Keep the newest records of `previous`, the store before a reload,
+    /// up to this store's capacity
+    pub fn carry_over(&self, previous: &CaptureStore) {
+        let previous = previous.records.lock().unwrap();
+        let skip = previous.len().saturating_sub(self.capacity);
+        *self.records.lock().unwrap() = previous.iter().skip(skip).cloned().collect();
+    }
+
+    pub fn push(&self, record: CaptureRecord) {
+        if !self.enabled() {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Run `f` over the records, oldest first
+    pub fn with_records<R>(&self, f: impl FnOnce(&VecDeque<CaptureRecord>) -> R) -> R {
+        f(&self.records.lock().unwrap())
+    }
+
+    /// All records, oldest first, one JSON document per line
+    pub fn to_jsonl(&self) -> String {
+        let records = self.records.lock().unwrap();
+        records
+            .iter()
+            .filter_map(|record| serde_json::to_string(record).ok())
+            .map(|line| line + "\n")
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carry_over_keeps_the_newest_records_that_fit() {
+        let previous = CaptureStore::new(10);
+        for id in ["a", "b", "c"] {
+            previous.push(CaptureRecord::new(id.to_string(), "/v1/chat/completions", b"{}"));
+        }
+        let next = CaptureStore::new(2);
+        next.carry_over(&previous);
+        let ids: Vec<String> = next.with_records(|records| records.iter().map(|record| record.id.clone()).collect());
+        assert_eq!(ids, ["b", "c"]);
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use crate::metrics::{self, Metrics};
+use crate::webhook::{Event, Webhooks};
+
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// One trial request is probing whether the backend has healed
+    HalfOpen,
+}
+
+/// Stops sending queries to a failing backend for a while once it has
+/// failed `threshold` times in a row, then lets a single trial through
+pub struct CircuitBreaker {
+    name: &'static str,
+    threshold: u32,
+    open_for: Duration,
+    state: Mutex<BreakerState>,
+    metrics: Arc<Metrics>,
+    webhooks: Arc<Webhooks>,
+}
+
+impl CircuitBreaker {
+    /// A `threshold` of 0 disables the breaker
+    pub fn new(
+        name: &'static str,
+        threshold: u32,
+        open_for: Duration,
+        metrics: Arc<Metrics>,
+        webhooks: Arc<Webhooks>,
+    ) -> Self {
+        metrics.set_gauge(&metrics::DB_CIRCUIT_OPEN, 0.0);
+        Self {
+            name,
+            threshold,
+            open_for,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+            metrics,
+            webhooks,
+        }
+    }
+
+    /// Whether a query may be sent now
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                info!("event=circuit name={} state=half_open", self.name);
+                *state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen => false,
+        }
+    }
+
+    /// True while queries are being short-circuited
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.lock().unwrap(), BreakerState::Closed { .. })
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, BreakerState::Closed { .. }) {
+            info!("event=circuit name={} state=closed", self.name);
+            self.metrics.set_gauge(&metrics::DB_CIRCUIT_OPEN, 0.0);
+            self.webhooks.send(Event::CircuitClosed, serde_json::json!({ "name": self.name }));
+        }
+        *state = BreakerState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            BreakerState::HalfOpen => self.threshold,
+            BreakerState::Open { .. } => return,
+        };
+        if failures >= self.threshold {
+            warn!(
+                "event=circuit name={} state=open failures={} open_for={:?}",
+                self.name, failures, self.open_for
+            );
+            self.metrics.set_gauge(&metrics::DB_CIRCUIT_OPEN, 1.0);
+            self.webhooks.send(
+                Event::CircuitOpened,
+                serde_json::json!({
+                    "name": self.name,
+                    "failures": failures,
+                    "open_ms": self.open_for.as_millis() as u64,
+                }),
+            );
+            *state = BreakerState::Open { until: Instant::now() + self.open_for };
+        } else {
+            *state = BreakerState::Closed { failures };
+        }
+    }
+}
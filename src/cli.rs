@@ -0,0 +1,70 @@
+use std::str::FromStr;
+use crate::error::CustomError;
+
+/// A command line: an optional subcommand, then `--name value`,
+/// `--name=value` and bare `--flag` options. An option takes the next
+/// argument as its value unless that starts with `--`.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub command: Option<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    pub fn from_env() -> Self {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Self {
+        let mut args = args.into_iter().peekable();
+        let command = args.next_if(|arg| !arg.starts_with("--"));
+        let mut options = Vec::new();
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix("--") else {
+                continue;
+            };
+            match option.split_once('=') {
+                Some((name, value)) => options.push((name.to_string(), Some(value.to_string()))),
+                None => {
+                    let value = args.next_if(|next| !next.starts_with("--"));
+                    options.push((option.to_string(), value));
+                }
+            }
+        }
+        Self { command, options }
+    }
+
+    /// Value of the last `--name`
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options.iter().rev().find(|(option, _)| option == name).and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Values of every `--name`, in order
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        self.options.iter().filter(|(option, _)| option == name).filter_map(|(_, value)| value.as_deref()).collect()
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| option == name)
+    }
+
+    /// `--name` parsed as `T`, or `default` when it is not given
+    pub fn parse_or<T: FromStr>(&self, name: &str, default: T) -> Result<T, CustomError> {
+        match self.value(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| CustomError::ConfigError(format!("invalid value '{}' for --{}", value, name))),
+            None => Ok(default),
+        }
+    }
+
+    /// `--name`, which must be given
+    pub fn required(&self, name: &str) -> Result<&str, CustomError> {
+        self.value(name).ok_or_else(|| CustomError::ConfigError(format!("missing --{}", name)))
+    }
+
+    /// Config profile passed as `--profile <name>` or `--profile=<name>`
+    pub fn profile(&self) -> Option<&str> {
+        self.value("profile")
+    }
+}
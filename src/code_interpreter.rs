@@ -0,0 +1,79 @@
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::reply::BuiltinToolCall;
+use crate::request::ChatCompletionRequest;
+
+/// Tool type answered with a simulated code execution
+pub const TOOL_TYPE: &str = "code_interpreter";
+
+/// Simulated runs of the `code_interpreter` tool
+#[derive(Deserialize, Clone, Debug)]
+pub struct CodeInterpreterConfig {
+    /// Words in the last user message that make the run also plot a chart,
+    /// adding an image file to its outputs
+    #[serde(default = "default_image_keywords")]
+    pub image_keywords: Vec<String>,
+}
+
+impl Default for CodeInterpreterConfig {
+    fn default() -> Self {
+        Self {
+            image_keywords: default_image_keywords(),
+        }
+    }
+}
+
+fn default_image_keywords() -> Vec<String> {
+    ["plot", "chart", "graph", "grafik", "diagram", "gambar"].map(String::from).to_vec()
+}
+
+/// The `code_interpreter` call answering `request`: a short Python script
+/// averaging a few numbers, its `logs` and, when the user asked for a
+/// chart, an `image` file. `None` when the request declares no such tool.
+pub fn call(config: &CodeInterpreterConfig, request: &ChatCompletionRequest) -> Option<BuiltinToolCall> {
+    if !request.declares_tool(&[TOOL_TYPE]) {
+        return None;
+    }
+    let question = request.last_user_message().unwrap_or_default();
+    let lowercase = question.to_lowercase();
+    let plot = config.image_keywords.iter().any(|keyword| lowercase.contains(&keyword.to_lowercase()));
+
+    let mut rng = rand::thread_rng();
+    let values: Vec<u32> = (0..rng.gen_range(5..=8)).map(|_| rng.gen_range(1..=100)).collect();
+    let mean = values.iter().sum::<u32>() as f64 / values.len() as f64;
+    let listed: Vec<String> = values.iter().map(u32::to_string).collect();
+
+    let mut input = String::new();
+    if let Some(line) = question.lines().map(str::trim).find(|line| !line.is_empty()) {
+        input.push_str(&format!("# {}\n", line.chars().take(80).collect::<String>()));
+    }
+    input.push_str(&format!("values = [{}]\n", listed.join(", ")));
+    input.push_str("mean = sum(values) / len(values)\n");
+    input.push_str("print(f\"n = {len(values)}, mean = {mean:.2f}\")\n");
+    if plot {
+        input.push_str("\nimport matplotlib.pyplot as plt\n");
+        input.push_str("plt.bar(range(len(values)), values)\n");
+        input.push_str("plt.axhline(mean, color=\"red\")\n");
+        input.push_str("plt.show()\n");
+    }
+
+    let mut outputs = vec![json!({
+        "type": "logs",
+        "logs": format!("n = {}, mean = {:.2}\n", values.len(), mean),
+    })];
+    if plot {
+        outputs.push(json!({ "type": "image", "image": { "file_id": file_id() } }));
+    }
+    Some(BuiltinToolCall::new(TOOL_TYPE, json!({ "input": input, "outputs": Value::from(outputs) })))
+}
+
+/// `file-` and 22 random alphanumerics, the id of a generated file
+fn file_id() -> String {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(22)
+        .map(char::from)
+        .collect();
+    format!("file-{}", suffix)
+}
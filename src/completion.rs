@@ -0,0 +1,181 @@
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use futures_util::{Stream, StreamExt};
+use serde_json::{json, Map, Value};
+use crate::error::{ApiError, CustomError};
+
+/// One choice of the completion being put together from its deltas
+#[derive(Default)]
+struct ChoiceBuilder {
+    content: Option<String>,
+    refusal: Option<String>,
+    tool_calls: Vec<Map<String, Value>>,
+    annotations: Vec<Value>,
+    finish_reason: Option<String>,
+}
+
+impl ChoiceBuilder {
+    fn apply(&mut self, delta: &Value) {
+        if let Some(content) = delta.get("content").and_then(Value::as_str) {
+            self.content.get_or_insert_with(String::new).push_str(content);
+        }
+        if let Some(refusal) = delta.get("refusal").and_then(Value::as_str) {
+            self.refusal.get_or_insert_with(String::new).push_str(refusal);
+        }
+        if let Some(annotations) = delta.get("annotations").and_then(Value::as_array) {
+            self.annotations.extend(annotations.iter().cloned());
+        }
+        for call in delta.get("tool_calls").and_then(Value::as_array).into_iter().flatten() {
+            let index = call.get("index").and_then(Value::as_u64).unwrap_or(0) as usize;
+            if self.tool_calls.len() <= index {
+                self.tool_calls.resize_with(index + 1, Map::new);
+            }
+            let entry = &mut self.tool_calls[index];
+            // `id`, `type` and the output of a built-in tool
+            for (key, value) in call.as_object().into_iter().flatten() {
+                if key != "index" && key != "function" {
+                    match entry.get_mut(key) {
+                        Some(existing) => merge(existing, value),
+                        None => {
+                            entry.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+            }
+            if call.get("function").is_none() {
+                continue;
+            }
+            let function = entry
+                .entry("function")
+                .or_insert_with(|| json!({"name": "", "arguments": ""}));
+            if let Some(name) = call.pointer("/function/name").and_then(Value::as_str) {
+                function["name"] = Value::from(name);
+            }
+            if let Some(arguments) = call.pointer("/function/arguments").and_then(Value::as_str) {
+                let accumulated = function["arguments"].as_str().unwrap_or_default().to_string() + arguments;
+                function["arguments"] = Value::from(accumulated);
+            }
+        }
+    }
+
+    fn build(self, index: usize) -> Value {
+        let mut message = json!({
+            "role": "assistant",
+            "content": self.content,
+            "refusal": self.refusal,
+        });
+        if !self.tool_calls.is_empty() {
+            message["tool_calls"] = Value::from(self.tool_calls);
+        }
+        if !self.annotations.is_empty() {
+            message["annotations"] = Value::from(self.annotations);
+        }
+        json!({
+            "index": index,
+            "message": message,
+            "logprobs": null,
+            "finish_reason": self.finish_reason.unwrap_or_else(|| "stop".to_string()),
+        })
+    }
+}
+
+/// Fold a delta of a built-in tool call into what was streamed before:
+/// objects merge, arrays and strings are appended to, anything else is
+/// replaced
+fn merge(target: &mut Value, delta: &Value) {
+    match (target, delta) {
+        (Value::Object(target), Value::Object(delta)) => {
+            for (key, value) in delta {
+                match target.get_mut(key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        target.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (Value::Array(target), Value::Array(delta)) => target.extend(delta.iter().cloned()),
+        (Value::String(target), Value::String(delta)) => target.push_str(delta),
+        (target, delta) => *target = delta.clone(),
+    }
+}
+
+/// The `chat.completion` object a non-streaming request gets, put together
+/// from the SSE frames of its stream
+pub struct CompletionCollector {
+    completion: Value,
+    choices: Vec<ChoiceBuilder>,
+}
+
+impl Default for CompletionCollector {
+    fn default() -> Self {
+        Self {
+            completion: json!({"object": "chat.completion"}),
+            choices: Vec::new(),
+        }
+    }
+}
+
+impl CompletionCollector {
+    /// Fold the deltas of `frame` into the completion. A mid-stream `error`
+    /// event is handed back as `Err`.
+    pub fn push(&mut self, frame: &[u8]) -> Result<(), Value> {
+        let frame = String::from_utf8_lossy(frame);
+        for data in frame.lines().filter_map(|line| line.strip_prefix("data: ")) {
+            let Ok(chunk) = serde_json::from_str::<Value>(data) else {
+                continue;
+            };
+            if chunk.get("error").is_some() {
+                return Err(chunk);
+            }
+            for key in ["id", "created", "model", "system_fingerprint", "provider", "citations", "sim_parameters"] {
+                if let (Some(value), None) = (chunk.get(key), self.completion.get(key)) {
+                    self.completion[key] = value.clone();
+                }
+            }
+            if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+                self.completion["usage"] = usage.clone();
+            }
+            for choice in chunk.get("choices").and_then(Value::as_array).into_iter().flatten() {
+                let index = choice.get("index").and_then(Value::as_u64).unwrap_or(0) as usize;
+                if self.choices.len() <= index {
+                    self.choices.resize_with(index + 1, ChoiceBuilder::default);
+                }
+                if let Some(delta) = choice.get("delta") {
+                    self.choices[index].apply(delta);
+                }
+                if let Some(finish_reason) = choice.get("finish_reason").and_then(Value::as_str) {
+                    self.choices[index].finish_reason = Some(finish_reason.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Value {
+        let mut completion = self.completion;
+        completion["choices"] = self.choices.into_iter().enumerate().map(|(index, choice)| choice.build(index)).collect();
+        completion
+    }
+}
+
+/// Read a completion's SSE frames to the end and answer with the
+/// `chat.completion` object a non-streaming request gets, folding the
+/// deltas of every choice back together. A mid-stream `error` event
+/// becomes a `500` with that error as body.
+pub async fn collect<S>(stream: S) -> Result<HttpResponse, CustomError>
+where
+    S: Stream<Item = Result<Bytes, actix_web::Error>>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut collector = CompletionCollector::default();
+    while let Some(frame) = stream.next().await {
+        // Only the hang fault aborts a stream before its end
+        let frame = frame.map_err(|_| ApiError::new(StatusCode::GATEWAY_TIMEOUT, "server_error", "Gateway timeout"))?;
+        if let Err(error) = collector.push(&frame) {
+            return Ok(HttpResponse::InternalServerError().json(error));
+        }
+    }
+    Ok(HttpResponse::Ok().json(collector.finish()))
+}
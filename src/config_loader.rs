@@ -1,19 +1,460 @@
-use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use crate::azure::AzureConfig;
+use crate::batch::BatchesConfig;
+use crate::code_interpreter::CodeInterpreterConfig;
+use crate::content_filter::ContentFilterConfig;
+use crate::error::CustomError;
+use crate::error_reporting::ErrorReportingConfig;
+use crate::fault::FaultsConfig;
+use crate::file_search::FileSearchConfig;
+use crate::folder::FilesConfig;
+use crate::headers::CustomHeader;
+use crate::hooks::HooksConfig;
+use crate::load::AdaptiveLatencyConfig;
+use crate::logging::LoggingConfig;
+use crate::mcp::McpConfig;
+use crate::models::ModelInfo;
+use crate::openrouter::OpenRouterConfig;
+use crate::profile::ProviderProfile;
+use crate::prompt_cache::PromptCacheConfig;
+use crate::quota::QuotaConfig;
+use crate::reply::ToolCallsConfig;
+use crate::script::ScriptsConfig;
+use crate::selection::SelectionConfig;
+use crate::session::DedupConfig;
+use crate::stored::StoredCompletionsConfig;
+use crate::tiers::TierConfig;
+use crate::warmup::WarmupConfig;
+use crate::wasm::WasmConfig;
+use crate::webhook::WebhooksConfig;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct DatabaseConfig {
     pub username: String,
     pub password: String,
     pub url: String,
+    /// Replicas queried, in order, while `url` fails its health checks
+    #[serde(default)]
+    pub failover_urls: Vec<String>,
+    /// How often every URL is health checked when `failover_urls` are set
+    #[serde(default = "default_health_check_interval_ms")]
+    pub health_check_interval_ms: u64,
+    /// Certificate checks of `https://` URLs
+    #[serde(default)]
+    pub tls: DatabaseTlsConfig,
+    /// Refuse to start while ClickHouse is unreachable instead of serving
+    /// degraded and connecting in the background
+    #[serde(default)]
+    pub fail_fast: bool,
+    /// How often the background connection is retried after a failed
+    /// startup
+    #[serde(default = "default_connect_retry_ms")]
+    pub connect_retry_ms: u64,
+    /// Idle keep-alive connections kept open to ClickHouse for reuse
+    #[serde(default = "default_pool_size")]
+    pub pool_size: usize,
+    /// Close pooled connections idle for longer than this; keep it below the
+    /// server's keep-alive timeout (3s before ClickHouse 23.11, 10s after)
+    #[serde(default = "default_pool_idle_timeout_ms")]
+    pub pool_idle_timeout_ms: u64,
+    /// Longest a request waits for its corpus query, retries included,
+    /// before the query is cancelled and a 503 returned; 0 waits forever
+    #[serde(default = "default_query_timeout_ms")]
+    pub query_timeout_ms: u64,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Table holding the response corpus
+    #[serde(default = "default_table")]
+    pub table: String,
+    #[serde(default)]
+    pub columns: ColumnMapping,
+    /// Optional `WHERE` condition appended to the generated query,
+    /// e.g. `lang = 'id' AND active`
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// Fully custom query used verbatim instead of `table`/`columns`/`filter`.
+    /// It must return id, question, answer, reference, category, language,
+    /// persona and weight, in that order and under those names.
+    #[serde(default)]
+    pub query: Option<String>,
+    #[serde(default)]
+    pub selection: SelectionStrategy,
+    /// How `random` selection reads the corpus
+    #[serde(default)]
+    pub sampling: SamplingMode,
+    #[serde(default)]
+    pub best_match: BestMatchConfig,
+    #[serde(default)]
+    pub semantic: SemanticConfig,
+    /// Create the database and `table` at startup when they are missing
+    #[serde(default)]
+    pub create_schema: bool,
+    /// Refuse to start when the table layout or recorded schema version
+    /// does not match what this binary reads
+    #[serde(default = "default_verify_schema")]
+    pub verify_schema: bool,
+    /// Further corpus tables, by name, chosen per request instead of
+    /// `table`
+    #[serde(default)]
+    pub datasets: BTreeMap<String, DatasetConfig>,
 }
 
-#[derive(Deserialize)]
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            username: "default".to_string(),
+            password: String::new(),
+            url: "http://127.0.0.1:8123".to_string(),
+            failover_urls: Vec::new(),
+            health_check_interval_ms: default_health_check_interval_ms(),
+            tls: DatabaseTlsConfig::default(),
+            fail_fast: false,
+            connect_retry_ms: default_connect_retry_ms(),
+            pool_size: default_pool_size(),
+            pool_idle_timeout_ms: default_pool_idle_timeout_ms(),
+            query_timeout_ms: default_query_timeout_ms(),
+            retry: RetryConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            table: default_table(),
+            columns: ColumnMapping::default(),
+            filter: None,
+            query: None,
+            selection: SelectionStrategy::default(),
+            sampling: SamplingMode::default(),
+            best_match: BestMatchConfig::default(),
+            semantic: SemanticConfig::default(),
+            create_schema: false,
+            verify_schema: default_verify_schema(),
+            datasets: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_verify_schema() -> bool {
+    true
+}
+
+/// A corpus table served, in place of `database.table`, to requests that
+/// name it with `x-sim-dataset`, ask for one of its models or come from one
+/// of its keys
+#[derive(Deserialize, Clone, Debug)]
+pub struct DatasetConfig {
+    pub table: String,
+    /// `WHERE` condition for this table; `database.filter` does not apply
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// Models (without a `@category` suffix) served from this table
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Masked key ids served from this table, e.g. `sk-...a1b2`
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+fn default_health_check_interval_ms() -> u64 {
+    5000
+}
+
+fn default_connect_retry_ms() -> u64 {
+    5000
+}
+
+/// How the ClickHouse server certificate is checked
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct DatabaseTlsConfig {
+    /// PEM bundle of the CAs trusted instead of the built-in web roots,
+    /// e.g. a company CA
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// Accept any certificate, e.g. a lab server's self-signed one. Never
+    /// use it against a server reachable by others.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+fn default_table() -> String {
+    "response_simulator".to_string()
+}
+
+/// How a database row is chosen for a request
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    /// Weighted random choice among the rows matching the request's metadata
+    #[default]
+    Random,
+    /// The row whose question is most similar to the last user message,
+    /// found with a ClickHouse ngram search
+    BestMatch,
+    /// The row whose question embedding is nearest to the last user
+    /// message's embedding
+    Semantic,
+}
+
+/// How a random row is drawn from the corpus table
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplingMode {
+    /// Fetch every row (or Redis's copy) and choose among them in memory
+    #[default]
+    Buffer,
+    /// Stream the rows per request, keeping a single weighted sample, so
+    /// no request holds the whole table in memory
+    Reservoir,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct BestMatchConfig {
+    /// Similarity (0–1, from ngram distance) the top hit needs to be served
+    #[serde(default = "default_min_similarity")]
+    pub min_similarity: f64,
+    /// Served when no question is similar enough; when unset a random row
+    /// is served instead
+    #[serde(default)]
+    pub fallback_answer: Option<String>,
+}
+
+impl Default for BestMatchConfig {
+    fn default() -> Self {
+        Self {
+            min_similarity: default_min_similarity(),
+            fallback_answer: None,
+        }
+    }
+}
+
+fn default_min_similarity() -> f64 {
+    0.3
+}
+
+/// Where `semantic` selection gets its embeddings from
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbedderKind {
+    /// Built-in hashed bag of words and character trigrams; needs no model
+    /// but only captures lexical overlap
+    #[default]
+    Hashed,
+    /// An OpenAI-compatible `/v1/embeddings` endpoint over plain HTTP, such
+    /// as a local Ollama or text-embeddings-inference server
+    Api,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SemanticConfig {
+    #[serde(default)]
+    pub embedder: EmbedderKind,
+    /// Vector size of the `hashed` embedder
+    #[serde(default = "default_semantic_dimensions")]
+    pub dimensions: usize,
+    /// Embeddings endpoint of the `api` embedder
+    #[serde(default = "default_semantic_url")]
+    pub url: String,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Cosine similarity the nearest question needs to be served
+    #[serde(default = "default_semantic_min_similarity")]
+    pub min_similarity: f64,
+    /// Served when no question is similar enough; when unset a random row
+    /// is served instead
+    #[serde(default)]
+    pub fallback_answer: Option<String>,
+}
+
+impl Default for SemanticConfig {
+    fn default() -> Self {
+        Self {
+            embedder: EmbedderKind::default(),
+            dimensions: default_semantic_dimensions(),
+            url: default_semantic_url(),
+            model: String::new(),
+            api_key: None,
+            min_similarity: default_semantic_min_similarity(),
+            fallback_answer: None,
+        }
+    }
+}
+
+fn default_semantic_dimensions() -> usize {
+    512
+}
+
+fn default_semantic_url() -> String {
+    "http://127.0.0.1:11434/v1/embeddings".to_string()
+}
+
+fn default_semantic_min_similarity() -> f64 {
+    0.5
+}
+
+/// Column (or expression) names in `database.table` for each response field
+#[derive(Deserialize, Clone, Debug)]
+pub struct ColumnMapping {
+    /// UUID identifying the row; empty when the table has no such column
+    #[serde(default = "default_column_id")]
+    pub id: String,
+    #[serde(default = "default_column_question")]
+    pub question: String,
+    #[serde(default = "default_column_answer")]
+    pub answer: String,
+    #[serde(default = "default_column_reference")]
+    pub reference: String,
+    /// Optional metadata columns; empty when the table has no such column
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    pub persona: String,
+    /// Numeric column weighting random selection; empty weighs rows equally
+    #[serde(default)]
+    pub weight: String,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self {
+            id: default_column_id(),
+            question: default_column_question(),
+            answer: default_column_answer(),
+            reference: default_column_reference(),
+            category: String::new(),
+            language: String::new(),
+            persona: String::new(),
+            weight: String::new(),
+        }
+    }
+}
+
+fn default_column_id() -> String {
+    "qa_id".to_string()
+}
+
+fn default_column_question() -> String {
+    "pertanyaan".to_string()
+}
+
+fn default_column_answer() -> String {
+    "jawaban".to_string()
+}
+
+fn default_column_reference() -> String {
+    "referensi".to_string()
+}
+
+fn default_pool_size() -> usize {
+    16
+}
+
+fn default_pool_idle_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_query_timeout_ms() -> u64 {
+    10000
+}
+
+/// Retry policy for ClickHouse queries that fail with transient errors
+#[derive(Deserialize, Clone, Debug)]
+pub struct RetryConfig {
+    /// Attempts including the first one; 1 disables retries
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every further attempt
+    #[serde(default = "default_retry_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// Randomise each delay by up to this fraction in either direction
+    #[serde(default = "default_retry_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            initial_backoff_ms: default_retry_initial_backoff_ms(),
+            max_backoff_ms: default_retry_max_backoff_ms(),
+            jitter: default_retry_jitter(),
+        }
+    }
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_backoff_ms() -> u64 {
+    100
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    2000
+}
+
+fn default_retry_jitter() -> f64 {
+    0.2
+}
+
+/// Fall back to the file corpus while ClickHouse keeps failing
+#[derive(Deserialize, Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed fetches that open the circuit; 0 disables it
+    #[serde(default = "default_circuit_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial query is let through
+    #[serde(default = "default_circuit_open_ms")]
+    pub open_ms: u64,
+    /// Serve responses from the markdown folder when the database fails
+    #[serde(default = "default_true")]
+    pub fallback_to_file: bool,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: default_circuit_failure_threshold(),
+            open_ms: default_circuit_open_ms(),
+            fallback_to_file: true,
+        }
+    }
+}
+
+fn default_circuit_failure_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_open_ms() -> u64 {
+    30000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone)]
 pub struct BindingConfig {
     pub port: u16,
     pub host: String,
 }
 
-#[derive(Deserialize)]
+impl Default for BindingConfig {
+    fn default() -> Self {
+        Self {
+            port: 4545,
+            host: "0.0.0.0".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
 pub struct RedisConfig {
     pub url: String,
     #[serde(default = "default_redis_prefix")]
@@ -24,33 +465,587 @@ fn default_redis_prefix() -> String {
     "rai_simulator".to_string()
 }
 
-#[derive(Deserialize)]
+/// Where chat completion content is drawn from
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// Markdown files in the response folder
+    #[default]
+    File,
+    /// The ClickHouse table configured under `database`
+    Database,
+}
+
+#[derive(Deserialize, Clone)]
 pub struct Config {
-    pub source: String,
+    pub source: SourceKind,
     pub database: DatabaseConfig,
     pub binding: BindingConfig,
     pub tracking: TrackingConfig,
     pub log_level: String,
+    #[serde(default)]
+    pub logging: LoggingConfig,
     pub channel_capacity: usize,
     pub semaphore_limit: usize,
     pub workers: usize,
     #[serde(default = "default_cache_ttl")]
     pub cache_ttl: u64,
-    pub redis: RedisConfig,
+    /// Redis cache; when omitted every request goes straight to the source
+    #[serde(default)]
+    pub redis: Option<RedisConfig>,
+    #[serde(default)]
+    pub latency: LatencyConfig,
+    #[serde(default)]
+    pub adaptive_latency: AdaptiveLatencyConfig,
+    #[serde(default)]
+    pub warmup: WarmupConfig,
+    #[serde(default)]
+    pub fixtures: FixturesConfig,
+    #[serde(default)]
+    pub scripts: ScriptsConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub stored_completions: StoredCompletionsConfig,
+    #[serde(default)]
+    pub batches: BatchesConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+    /// Named provider profiles, selected per request with `x-sim-profile`
+    #[serde(default)]
+    pub profiles: HashMap<String, ProviderProfile>,
+    /// Profile used when a request does not name one
+    #[serde(default)]
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub content_filter: ContentFilterConfig,
+    #[serde(default)]
+    pub azure: AzureConfig,
+    #[serde(default)]
+    pub response_headers: ResponseHeadersConfig,
+    #[serde(default)]
+    pub endpoints: EndpointsConfig,
+    #[serde(default)]
+    pub selection: SelectionConfig,
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    #[serde(default)]
+    pub files: FilesConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    /// Key classes with their own concurrency pool and rate limit, keyed
+    /// by tier name; `default` takes the keys no tier lists
+    #[serde(default)]
+    pub tiers: HashMap<String, TierConfig>,
+    #[serde(default)]
+    pub faults: FaultsConfig,
+    #[serde(default)]
+    pub wasm: WasmConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub tool_calls: ToolCallsConfig,
+    #[serde(default)]
+    pub prompt_cache: PromptCacheConfig,
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+    #[serde(default)]
+    pub error_reporting: ErrorReportingConfig,
+    /// Models served by `/v1/models`, keyed by id
+    #[serde(default)]
+    pub models: BTreeMap<String, ModelInfo>,
+    #[serde(default)]
+    pub openrouter: OpenRouterConfig,
+    #[serde(default)]
+    pub file_search: FileSearchConfig,
+    #[serde(default)]
+    pub code_interpreter: CodeInterpreterConfig,
+    #[serde(default)]
+    pub mcp: McpConfig,
+    /// Config profile applied from `config_profiles`, if any
+    #[serde(skip)]
+    pub config_profile: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            source: SourceKind::default(),
+            database: DatabaseConfig::default(),
+            binding: BindingConfig::default(),
+            tracking: TrackingConfig::default(),
+            log_level: "info".to_string(),
+            logging: LoggingConfig::default(),
+            channel_capacity: 1000,
+            semaphore_limit: 10000,
+            workers: 8,
+            cache_ttl: default_cache_ttl(),
+            redis: None,
+            latency: LatencyConfig::default(),
+            adaptive_latency: AdaptiveLatencyConfig::default(),
+            warmup: WarmupConfig::default(),
+            fixtures: FixturesConfig::default(),
+            scripts: ScriptsConfig::default(),
+            capture: CaptureConfig::default(),
+            stored_completions: StoredCompletionsConfig::default(),
+            batches: BatchesConfig::default(),
+            limits: LimitsConfig::default(),
+            streaming: StreamingConfig::default(),
+            profiles: HashMap::new(),
+            default_profile: None,
+            content_filter: ContentFilterConfig::default(),
+            azure: AzureConfig::default(),
+            response_headers: ResponseHeadersConfig::default(),
+            endpoints: EndpointsConfig::default(),
+            selection: SelectionConfig::default(),
+            dedup: DedupConfig::default(),
+            files: FilesConfig::default(),
+            quota: QuotaConfig::default(),
+            tiers: HashMap::new(),
+            faults: FaultsConfig::default(),
+            wasm: WasmConfig::default(),
+            hooks: HooksConfig::default(),
+            tool_calls: ToolCallsConfig::default(),
+            prompt_cache: PromptCacheConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            error_reporting: ErrorReportingConfig::default(),
+            models: BTreeMap::new(),
+            openrouter: OpenRouterConfig::default(),
+            file_search: FileSearchConfig::default(),
+            code_interpreter: CodeInterpreterConfig::default(),
+            mcp: McpConfig::default(),
+            config_profile: None,
+        }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Default)]
 pub struct TrackingConfig {
     pub enabled: bool,
 }
 
+/// Stream pacing; all delays default to zero (stream as fast as possible)
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub struct LatencyConfig {
+    /// Delay before the first chunk (time to first token)
+    #[serde(default)]
+    pub ttft_ms: u64,
+    /// Delay between consecutive chunks
+    #[serde(default)]
+    pub chunk_delay_ms: u64,
+}
+
+impl LatencyConfig {
+    /// Both delays multiplied by `factor`
+    pub fn scaled(self, factor: f64) -> Self {
+        if factor == 1.0 {
+            return self;
+        }
+        Self {
+            ttft_ms: (self.ttft_ms as f64 * factor).round() as u64,
+            chunk_delay_ms: (self.chunk_delay_ms as f64 * factor).round() as u64,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct FixturesConfig {
+    /// Directory of YAML fixture bundles, selectable per request
+    #[serde(default)]
+    pub dir: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CaptureConfig {
+    /// Requests kept for `/admin/snapshot`; oldest are evicted first, 0 disables capture
+    #[serde(default = "default_capture_max_records")]
+    pub max_records: usize,
+    /// Echo the recorded `logit_bias` and penalties back in the usage chunk
+    /// (or the completion) as `sim_parameters`
+    #[serde(default)]
+    pub echo_parameters: bool,
+    /// Keep the frames of every captured stream so `/admin/replay` can send
+    /// them again
+    #[serde(default = "default_enabled")]
+    pub keep_frames: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            max_records: default_capture_max_records(),
+            echo_parameters: false,
+            keep_frames: true,
+        }
+    }
+}
+
+fn default_capture_max_records() -> usize {
+    10000
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LimitsConfig {
+    /// Log a saturation warning once this fraction of `semaphore_limit` is in use
+    #[serde(default = "default_saturation_warn_ratio")]
+    pub saturation_warn_ratio: f64,
+    /// Answer 503 right away, instead of queuing on the semaphore, while
+    /// this many requests are already in flight (held or waiting)
+    #[serde(default)]
+    pub shed_above: Option<usize>,
+    /// Hand freed permits to waiting requests round-robin per API key
+    /// instead of first come first served, so one busy client cannot
+    /// starve the others
+    #[serde(default)]
+    pub fair_queuing: bool,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            saturation_warn_ratio: default_saturation_warn_ratio(),
+            shed_above: None,
+            fair_queuing: false,
+        }
+    }
+}
+
+fn default_saturation_warn_ratio() -> f64 {
+    0.8
+}
+
+/// Provider metadata headers set on every response
+#[derive(Deserialize, Clone)]
+pub struct ResponseHeadersConfig {
+    /// Value of `openai-organization`
+    #[serde(default = "default_organization")]
+    pub organization: String,
+    /// Value of `x-sim-region`, e.g. to mimic a multi-region deployment
+    #[serde(default = "default_region")]
+    pub region: String,
+    /// Further headers, static or templated, optionally per endpoint
+    #[serde(default)]
+    pub custom: Vec<CustomHeader>,
+}
+
+impl Default for ResponseHeadersConfig {
+    fn default() -> Self {
+        Self {
+            organization: default_organization(),
+            region: default_region(),
+            custom: Vec::new(),
+        }
+    }
+}
+
+fn default_organization() -> String {
+    "user-simulator".to_string()
+}
+
+fn default_region() -> String {
+    "local".to_string()
+}
+
+/// Route groups served by this instance; disabled routes are not
+/// registered and answer 404 like any unknown URL
+#[derive(Deserialize, Clone)]
+pub struct EndpointsConfig {
+    /// `/v1/chat/completions` (and its WebSocket variant)
+    #[serde(default = "default_enabled")]
+    pub chat_completions: bool,
+    /// `/openai/deployments/{deployment}/chat/completions`
+    #[serde(default = "default_enabled")]
+    pub azure: bool,
+    /// `/test_completion`
+    #[serde(default = "default_enabled")]
+    pub test_completion: bool,
+    /// `/v1/models` and `/v1/models/{model}`
+    #[serde(default = "default_enabled")]
+    pub models: bool,
+    /// `/api/v1/chat/completions` and `/api/v1/generation`
+    #[serde(default = "default_enabled")]
+    pub openrouter: bool,
+    /// `/v1/batches`
+    #[serde(default = "default_enabled")]
+    pub batches: bool,
+    /// `/health` and `/readyz`
+    #[serde(default = "default_enabled")]
+    pub health: bool,
+    /// Everything under `/admin`
+    #[serde(default = "default_enabled")]
+    pub admin: bool,
+    /// `/metrics`
+    #[serde(default = "default_enabled")]
+    pub metrics: bool,
+}
+
+impl Default for EndpointsConfig {
+    fn default() -> Self {
+        Self {
+            chat_completions: true,
+            azure: true,
+            test_completion: true,
+            models: true,
+            openrouter: true,
+            batches: true,
+            health: true,
+            admin: true,
+            metrics: true,
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// What the generator does when a client reads slower than frames are produced
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowConsumerPolicy {
+    /// Generate ahead into a `channel_capacity`-sized buffer
+    #[default]
+    Buffer,
+    /// Generate a frame only once the previous one was taken by the client
+    Pace,
+}
+
+/// Where the content of a stream is cut into chunks, outside fidelity mode
+/// (which always streams token-sized deltas)
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Chunking {
+    /// Every 10 characters, also in the middle of a word
+    #[default]
+    Chars,
+    /// One word per chunk, with the whitespace following it
+    Words,
+    /// One sentence per chunk, ending punctuation and whitespace included
+    Sentences,
+}
+
+/// Bigger, fewer chunks for streams started while the server is near
+/// saturation, like providers batching tokens under load
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct CoalesceConfig {
+    /// Fraction of `semaphore_limit` in use from which streams coalesce
+    #[serde(default = "default_coalesce_above_occupancy")]
+    pub above_occupancy: f64,
+    /// Consecutive deltas merged into one chunk
+    #[serde(default = "default_coalesce_deltas")]
+    pub deltas: usize,
+}
+
+fn default_coalesce_above_occupancy() -> f64 {
+    0.8
+}
+
+fn default_coalesce_deltas() -> usize {
+    4
+}
+
+/// One step of the pipeline reshaping the content chunks of a stream,
+/// to exercise how clients diff and render streamed text
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChunkTransform {
+    /// Re-cut the content into chunks of `min` to `max` characters
+    Resize {
+        #[serde(default = "default_resize_min")]
+        min: usize,
+        #[serde(default = "default_resize_max")]
+        max: usize,
+    },
+    /// Swap two adjacent letters of a chunk, then follow it with a chunk
+    /// erasing them with `backspace` and sending the right ones
+    Typo {
+        /// Share of chunks carrying a typo, from 0.0 to 1.0
+        #[serde(default = "default_typo_probability")]
+        probability: f64,
+        #[serde(default = "default_backspace")]
+        backspace: String,
+    },
+    /// Send `text` as a chunk of its own after every `every` chunks
+    Marker {
+        text: String,
+        #[serde(default = "default_marker_every")]
+        every: usize,
+    },
+}
+
+fn default_resize_min() -> usize {
+    1
+}
+
+fn default_resize_max() -> usize {
+    12
+}
+
+fn default_typo_probability() -> f64 {
+    0.1
+}
+
+fn default_backspace() -> String {
+    "\u{8}".to_string()
+}
+
+fn default_marker_every() -> usize {
+    5
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StreamingConfig {
+    #[serde(default)]
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Abort a stream whose client has not read a frame for this long; 0 waits forever
+    #[serde(default)]
+    pub stall_timeout_ms: u64,
+    /// Send an SSE comment this often while waiting out a long delay; 0 disables it
+    #[serde(default = "default_keepalive_interval_ms")]
+    pub keepalive_interval_ms: u64,
+    /// Tag frames with SSE `id:` lines and resume streams on `Last-Event-ID`
+    #[serde(default)]
+    pub event_ids: bool,
+    /// Reconnection delay advertised with an SSE `retry:` line; 0 omits it
+    #[serde(default)]
+    pub retry_ms: u64,
+    /// How many recent streams can be resumed
+    #[serde(default = "default_resume_max_streams")]
+    pub resume_max_streams: usize,
+    /// Also serve chat completions over WebSocket at `/v1/chat/completions/ws`
+    #[serde(default)]
+    pub websocket: bool,
+    #[serde(default)]
+    pub chunking: Chunking,
+    /// Merge deltas into fewer chunks near saturation; off when unset
+    #[serde(default)]
+    pub coalesce: Option<CoalesceConfig>,
+    /// Applied in order to the content chunks of every stream
+    #[serde(default)]
+    pub transforms: Vec<ChunkTransform>,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            slow_consumer: SlowConsumerPolicy::default(),
+            stall_timeout_ms: 0,
+            keepalive_interval_ms: default_keepalive_interval_ms(),
+            event_ids: false,
+            retry_ms: 0,
+            resume_max_streams: default_resume_max_streams(),
+            websocket: false,
+            chunking: Chunking::default(),
+            coalesce: None,
+            transforms: Vec::new(),
+        }
+    }
+}
+
+fn default_keepalive_interval_ms() -> u64 {
+    15000
+}
+
+fn default_resume_max_streams() -> usize {
+    1000
+}
+
 fn default_cache_ttl() -> u64 {
     60 // Default cache TTL: 60 seconds
 }
 
+/// Config file read from the working directory
+pub const CONFIG_FILE: &str = "config.yml";
+/// Environment variable naming the config profile when none is passed
+pub const PROFILE_ENV: &str = "SIM_PROFILE";
+/// Top-level `config.yml` key holding named config profiles
+const CONFIG_PROFILES_KEY: &str = "config_profiles";
+
 impl Config {
+    /// Read `config.yml`, applying the config profile named by `SIM_PROFILE`
     pub fn load() -> Self {
-        let config_str = std::fs::read_to_string("config.yml").expect("Failed to read config file");
-        serde_yaml::from_str(&config_str).expect("Failed to parse config file")
+        let config_str = std::fs::read_to_string(CONFIG_FILE).expect("Failed to read config file");
+        Self::parse(&config_str, Self::profile_name(None).as_deref()).expect("Failed to parse config file")
+    }
+
+    /// Read `config.yml`, applying config profile `profile`, or else the
+    /// one named by `SIM_PROFILE`
+    pub fn read(profile: Option<&str>) -> Result<Self, CustomError> {
+        let config_str = std::fs::read_to_string(CONFIG_FILE)
+            .map_err(|e| CustomError::ConfigError(format!("cannot read {}: {}", CONFIG_FILE, e)))?;
+        Self::parse(&config_str, Self::profile_name(profile).as_deref())
+    }
+
+    /// Config profile to apply: `profile`, else the one named by
+    /// `SIM_PROFILE`
+    pub fn profile_name(profile: Option<&str>) -> Option<String> {
+        let env_profile = std::env::var(PROFILE_ENV).ok().filter(|name| !name.is_empty());
+        profile.map(str::to_string).or(env_profile)
+    }
+
+    /// Parse a `config.yml` document. The shared settings are the defaults;
+    /// the entries of `config_profiles.<profile>` are merged over them,
+    /// section by section, so a profile only lists what it changes.
+    pub fn parse(content: &str, profile: Option<&str>) -> Result<Self, CustomError> {
+        let document = Self::effective_document(content, profile)?;
+        let mut config: Config =
+            serde_yaml::from_value(document).map_err(|e| CustomError::ConfigError(e.to_string()))?;
+        config.config_profile = profile.map(str::to_string);
+        Ok(config)
+    }
+
+    /// The `config.yml` document with `config_profiles.<profile>` merged
+    /// in, as `parse` reads it
+    pub fn effective_document(content: &str, profile: Option<&str>) -> Result<Value, CustomError> {
+        let invalid = |e: serde_yaml::Error| CustomError::ConfigError(e.to_string());
+        let mut document: Value = serde_yaml::from_str(content).map_err(invalid)?;
+        let profiles = match &mut document {
+            Value::Mapping(mapping) => mapping.remove(CONFIG_PROFILES_KEY),
+            _ => None,
+        };
+        if let Some(name) = profile {
+            let overlay = profiles
+                .as_ref()
+                .and_then(|profiles| profiles.get(name))
+                .cloned()
+                .ok_or_else(|| {
+                    let known: Vec<&str> = profiles
+                        .as_ref()
+                        .and_then(Value::as_mapping)
+                        .map(|profiles| profiles.keys().filter_map(Value::as_str).collect())
+                        .unwrap_or_default();
+                    CustomError::ConfigError(format!(
+                        "unknown config profile '{}', {} declares: {}",
+                        name,
+                        CONFIG_PROFILES_KEY,
+                        if known.is_empty() { "none".to_string() } else { known.join(", ") }
+                    ))
+                })?;
+            // A profile declared with no entries changes nothing
+            if !overlay.is_null() {
+                merge(&mut document, overlay);
+            }
+        }
+        Ok(document)
+    }
+}
+
+/// Merge `overlay` into `base`: mappings are merged key by key, anything
+/// else in `overlay` replaces the value in `base`
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
     }
 }
@@ -0,0 +1,75 @@
+use actix_web::http::header::HeaderMap;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use crate::request::ChatCompletionRequest;
+
+/// Header forcing a content-filter stop; its value may name the category
+pub const CONTENT_FILTER_HEADER: &str = "x-sim-content-filter";
+
+/// Harm categories reported by Azure OpenAI's content filter
+pub const CATEGORIES: [&str; 4] = ["hate", "self_harm", "sexual", "violence"];
+
+/// When to end a stream early with `finish_reason: "content_filter"`
+#[derive(Deserialize, Clone)]
+pub struct ContentFilterConfig {
+    /// Case-insensitive substrings of the last user message that trigger the filter
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Content chunks delivered before the stream is cut off
+    #[serde(default = "default_after_chunks")]
+    pub after_chunks: usize,
+    /// Category reported as filtered unless the header names another
+    #[serde(default = "default_category")]
+    pub category: String,
+}
+
+impl Default for ContentFilterConfig {
+    fn default() -> Self {
+        Self {
+            keywords: Vec::new(),
+            after_chunks: default_after_chunks(),
+            category: default_category(),
+        }
+    }
+}
+
+fn default_after_chunks() -> usize {
+    3
+}
+
+fn default_category() -> String {
+    "violence".to_string()
+}
+
+impl ContentFilterConfig {
+    /// Category to report as filtered when this request should trip the
+    /// filter, either through the header or a configured keyword
+    pub fn triggered(&self, headers: &HeaderMap, request: &ChatCompletionRequest) -> Option<String> {
+        if let Some(value) = headers.get(CONTENT_FILTER_HEADER).and_then(|v| v.to_str().ok()) {
+            let value = value.trim().to_lowercase();
+            return Some(if CATEGORIES.contains(&value.as_str()) { value } else { self.category.clone() });
+        }
+        let message = request.last_user_message()?.to_lowercase();
+        self.keywords
+            .iter()
+            .any(|keyword| message.contains(&keyword.to_lowercase()))
+            .then(|| self.category.clone())
+    }
+}
+
+/// Azure-style `content_filter_results`, with every category safe except
+/// `filtered`, which is reported as blocked
+pub fn annotations(filtered: Option<&str>) -> Value {
+    let results: Map<String, Value> = CATEGORIES
+        .iter()
+        .map(|category| {
+            let result = if filtered == Some(*category) {
+                json!({ "filtered": true, "severity": "high" })
+            } else {
+                json!({ "filtered": false, "severity": "safe" })
+            };
+            (category.to_string(), result)
+        })
+        .collect();
+    Value::Object(results)
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use log::debug;
+use rand::seq::SliceRandom;
+use crate::request::ChatCompletionRequest;
+use crate::response::ResponseSimulator;
+
+/// Header restricting database rows to one category
+pub const CATEGORY_HEADER: &str = "x-sim-category";
+/// Query parameter restricting database rows to one category
+pub const CATEGORY_QUERY_PARAM: &str = "category";
+/// Header restricting database rows to one persona
+pub const PERSONA_HEADER: &str = "x-sim-persona";
+/// Query parameter restricting database rows to one persona
+pub const PERSONA_QUERY_PARAM: &str = "persona";
+/// Separates a category from the model name, e.g. `gpt-4o-mini@billing`
+pub const MODEL_CATEGORY_SEPARATOR: char = '@';
+
+/// Metadata a database row must carry to be served for a request
+#[derive(Debug, Default, Clone)]
+pub struct CorpusFilter {
+    pub category: Option<String>,
+    pub persona: Option<String>,
+    /// Preferred rather than required: rows in other languages are served
+    /// when none match
+    pub language: Option<String>,
+    /// Last user message, matched against questions by `best_match`
+    pub text: Option<String>,
+    /// Session whose already-served entries are skipped when de-duplicating
+    pub session: Option<String>,
+    /// Requested model, choosing the per-model markdown subfolder
+    pub model: Option<String>,
+    /// Database dataset rows come from; `None` is `database.table`
+    pub dataset: Option<String>,
+}
+
+impl CorpusFilter {
+    /// Build the filter from explicit selectors, taking the category from a
+    /// `model@category` suffix when no selector names one
+    pub fn new(
+        request: &ChatCompletionRequest,
+        category: Option<String>,
+        persona: Option<String>,
+        language: Option<String>,
+    ) -> Self {
+        let category = category.or_else(|| {
+            request
+                .model
+                .as_deref()
+                .and_then(|model| model.rsplit_once(MODEL_CATEGORY_SEPARATOR))
+                .map(|(_, category)| category.to_string())
+        });
+        Self {
+            category: category.filter(|c| !c.trim().is_empty()),
+            persona: persona.filter(|p| !p.trim().is_empty()),
+            language: language.filter(|l| !l.trim().is_empty()),
+            text: request.last_user_message().filter(|t| !t.trim().is_empty()),
+            session: None,
+            model: request.model.clone(),
+            dataset: None,
+        }
+    }
+
+    /// Whether the filter has no hard constraints
+    pub fn is_empty(&self) -> bool {
+        self.category.is_none() && self.persona.is_none()
+    }
+
+    pub fn matches(&self, response: &ResponseSimulator) -> bool {
+        self.matches_fields(&response.category, &response.persona)
+    }
+
+    /// Whether an entry with this category and persona may be served
+    pub fn matches_fields(&self, category: &str, persona: &str) -> bool {
+        matches_field(&self.category, category) && matches_field(&self.persona, persona)
+    }
+
+    /// Whether an entry in `language` is in the preferred language
+    pub fn prefers_language(&self, language: &str) -> bool {
+        self.language.is_some() && matches_field(&self.language, language)
+    }
+}
+
+impl fmt::Display for CorpusFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(category) = &self.category {
+            parts.push(format!("category '{}'", category));
+        }
+        if let Some(persona) = &self.persona {
+            parts.push(format!("persona '{}'", persona));
+        }
+        write!(f, "{}", parts.join(" and "))
+    }
+}
+
+fn matches_field(wanted: &Option<String>, value: &str) -> bool {
+    wanted.as_deref().is_none_or(|wanted| wanted.trim().eq_ignore_ascii_case(value.trim()))
+}
+
+/// Rows that may be served for `filter`: those matching its metadata with
+/// a positive weight, narrowed to the requested language when any are in it
+pub fn candidates<'a>(responses: &'a [ResponseSimulator], filter: &CorpusFilter) -> Vec<&'a ResponseSimulator> {
+    let candidates: Vec<&ResponseSimulator> = responses
+        .iter()
+        .filter(|response| filter.matches(response) && response.weight > 0.0)
+        .collect();
+    if filter.language.is_none() {
+        return candidates;
+    }
+    let same_language: Vec<&ResponseSimulator> = candidates
+        .iter()
+        .copied()
+        .filter(|response| filter.prefers_language(&response.language))
+        .collect();
+    if same_language.is_empty() {
+        debug!("No responses in language {:?}, serving any language", filter.language);
+        candidates
+    } else {
+        same_language
+    }
+}
+
+/// Pick one of `candidates` at random, in proportion to its `weight`
+pub fn choose<'a>(candidates: &[&'a ResponseSimulator]) -> Option<&'a ResponseSimulator> {
+    candidates
+        .choose_weighted(&mut rand::thread_rng(), |response| response.weight)
+        .ok()
+        .copied()
+}
+
+/// Key identifying a row in per-session history
+pub fn key(response: &ResponseSimulator) -> String {
+    match response.qa_id {
+        Some(id) => id.to_string(),
+        None => response.pertanyaan.clone(),
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::config_loader::DatabaseConfig;
+use crate::corpus::MODEL_CATEGORY_SEPARATOR;
+use crate::db;
+use crate::error::{ApiError, CustomError};
+
+/// Header naming the dataset to serve from
+pub const DATASET_HEADER: &str = "x-sim-dataset";
+/// Query parameter naming the dataset to serve from
+pub const DATASET_QUERY_PARAM: &str = "dataset";
+/// Name of `database.table` itself, which a request may ask for explicitly
+pub const DEFAULT_DATASET: &str = "default";
+
+/// The `database.datasets`, each as the `database` section it is queried
+/// with. Tables come from the config only, checked to be identifiers at
+/// startup; a request merely picks one by name.
+#[derive(Default)]
+pub struct Datasets {
+    configs: BTreeMap<String, DatabaseConfig>,
+    by_model: HashMap<String, String>,
+    by_key: HashMap<String, String>,
+}
+
+impl Datasets {
+    pub fn new(config: &DatabaseConfig) -> Result<Self, CustomError> {
+        let invalid = |name: &str, e: String| CustomError::ConfigError(format!("database.datasets.{}: {}", name, e));
+        let mut datasets = Self::default();
+        for (name, dataset) in &config.datasets {
+            if name == DEFAULT_DATASET {
+                return Err(invalid(name, format!("'{}' names database.table itself", DEFAULT_DATASET)));
+            }
+            if config.query.is_some() {
+                return Err(invalid(name, "datasets cannot be used with database.query".to_string()));
+            }
+            if !db::is_identifier(dataset.table.trim()) {
+                return Err(invalid(name, format!("table '{}' is not a table name", dataset.table)));
+            }
+            for (selector, values, index) in
+                [("model", &dataset.models, &mut datasets.by_model), ("key", &dataset.keys, &mut datasets.by_key)]
+            {
+                for value in values {
+                    if let Some(other) = index.insert(value.clone(), name.clone()) {
+                        return Err(invalid(name, format!("{} '{}' is already served from {}", selector, value, other)));
+                    }
+                }
+            }
+            let mut database = config.clone();
+            database.table = dataset.table.trim().to_string();
+            database.filter = dataset.filter.clone();
+            database.datasets.clear();
+            datasets.configs.insert(name.clone(), database);
+        }
+        Ok(datasets)
+    }
+
+    /// Dataset serving a request: the one it names, else the one listing
+    /// its model, else the one listing its API key. `None` is
+    /// `database.table`.
+    pub fn select(
+        &self,
+        requested: Option<&str>,
+        model: Option<&str>,
+        api_key: &str,
+    ) -> Result<Option<String>, ApiError> {
+        if let Some(name) = requested.map(str::trim).filter(|name| !name.is_empty()) {
+            if name == DEFAULT_DATASET {
+                return Ok(None);
+            }
+            if !self.configs.contains_key(name) {
+                let known: Vec<&str> =
+                    std::iter::once(DEFAULT_DATASET).chain(self.configs.keys().map(String::as_str)).collect();
+                return Err(ApiError::invalid_request(format!(
+                    "Unknown dataset '{}', expected one of: {}",
+                    name,
+                    known.join(", ")
+                )));
+            }
+            return Ok(Some(name.to_string()));
+        }
+        let model = model.map(|model| model.split(MODEL_CATEGORY_SEPARATOR).next().unwrap_or(model));
+        Ok(model.and_then(|model| self.by_model.get(model)).or_else(|| self.by_key.get(api_key)).cloned())
+    }
+
+    /// The `database` section to query `dataset` with
+    pub fn config<'a>(&'a self, database: &'a DatabaseConfig, dataset: Option<&str>) -> &'a DatabaseConfig {
+        dataset.and_then(|name| self.configs.get(name)).unwrap_or(database)
+    }
+
+    /// Every dataset's `database` section, by name
+    pub fn configs(&self) -> &BTreeMap<String, DatabaseConfig> {
+        &self.configs
+    }
+}
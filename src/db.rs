@@ -0,0 +1,398 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use clickhouse::error::Error;
+use clickhouse::Client;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::TokioExecutor;
+use log::warn;
+use rand::Rng;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use crate::config_loader::{DatabaseConfig, DatabaseTlsConfig, RetryConfig};
+use crate::error::{ApiError, CustomError, ErrorShape};
+
+/// Database holding the response corpus
+pub const DATABASE_NAME: &str = "midai_simulator";
+
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// ClickHouse client backed by a keep-alive connection pool sized from the
+/// `database` section. The client is cheap to clone and every clone (one
+/// per request) shares the same pool, so queries reuse warm connections
+/// instead of opening a socket each time. Fails when `database.tls` cannot
+/// be loaded.
+pub fn build_client(config: &DatabaseConfig) -> Result<Client, CustomError> {
+    build_endpoint_client(config, &config.url)
+}
+
+/// [`build_client`] for another endpoint of the same database, e.g. one of
+/// `database.failover_urls`
+pub fn build_endpoint_client(config: &DatabaseConfig, url: &str) -> Result<Client, CustomError> {
+    let mut connector = HttpConnector::new();
+    connector.set_keepalive(Some(TCP_KEEPALIVE));
+    connector.set_nodelay(true);
+    let connector = https_connector(connector, &config.tls)?;
+
+    let http_client = HyperClient::builder(TokioExecutor::new())
+        .pool_max_idle_per_host(config.pool_size)
+        .pool_idle_timeout(Duration::from_millis(config.pool_idle_timeout_ms))
+        .build(connector);
+
+    Ok(Client::with_http_client(http_client)
+        .with_url(url)
+        .with_database(DATABASE_NAME)
+        .with_user(config.username.clone())
+        .with_password(config.password.clone())
+        // A query abandoned by `with_query_timeout` closes its connection;
+        // have ClickHouse stop it rather than run it to completion
+        .with_option("cancel_http_readonly_queries_on_client_close", "1"))
+}
+
+/// `connector` also speaking TLS to `https://` URLs, verified as `tls`
+/// says. Shared with the other outbound HTTP clients (webhooks, error
+/// reports), which verify with the default web roots.
+pub fn https_connector(mut connector: HttpConnector, tls: &DatabaseTlsConfig) -> Result<HttpsConnector<HttpConnector>, CustomError> {
+    connector.enforce_http(false);
+    Ok(HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config(tls)?)
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(connector))
+}
+
+/// Roots and verifier for `https://` URLs: the built-in web roots, the CAs
+/// of `tls.ca_file` instead, or no verification at all
+fn tls_config(config: &DatabaseTlsConfig) -> Result<ClientConfig, CustomError> {
+    let invalid = |e: String| CustomError::ConfigError(format!("database.tls: {}", e));
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?;
+    if config.insecure_skip_verify {
+        warn!("database.tls.insecure_skip_verify is set: ClickHouse server certificates are not checked");
+        let verifier = Arc::new(SkipVerification(provider));
+        return Ok(builder.dangerous().with_custom_certificate_verifier(verifier).with_no_client_auth());
+    }
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(path) => {
+            let unreadable = |e: String| invalid(format!("cannot load ca_file '{}': {}", path, e));
+            for cert in CertificateDer::pem_file_iter(path).map_err(|e| unreadable(e.to_string()))? {
+                roots.add(cert.map_err(|e| unreadable(e.to_string()))?).map_err(|e| unreadable(e.to_string()))?;
+            }
+            if roots.is_empty() {
+                return Err(unreadable("no certificates found".to_string()));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+/// Accepts any server certificate (`database.tls.insecure_skip_verify`),
+/// still checking the handshake signatures
+#[derive(Debug)]
+struct SkipVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Query returning the response corpus: `database.query` verbatim, or a
+/// `SELECT` built from the table, column mapping and optional filter
+pub fn select_query(config: &DatabaseConfig) -> String {
+    if let Some(query) = &config.query {
+        return query.trim().trim_end_matches(';').to_string();
+    }
+    let columns = &config.columns;
+    let weight = match columns.weight.trim() {
+        "" => "toFloat64(1)".to_string(),
+        weight => format!("toFloat64({})", weight),
+    };
+    let select = [
+        column(&columns.id, "CAST(NULL AS Nullable(UUID))", "id"),
+        column(&columns.question, "''", "question"),
+        column(&columns.answer, "''", "answer"),
+        column(&columns.reference, "''", "reference"),
+        column(&columns.category, "''", "category"),
+        column(&columns.language, "''", "language"),
+        column(&columns.persona, "''", "persona"),
+        column(&weight, "", "weight"),
+    ];
+    let mut query = format!("SELECT {} FROM {}", select.join(", "), config.table);
+    if let Some(filter) = config.filter.as_deref().filter(|f| !f.trim().is_empty()) {
+        query.push_str(&format!(" WHERE {}", filter));
+    }
+    query
+}
+
+/// Mapped column (or `fallback` when the table has no such column) under
+/// the field name `alias`
+fn column(mapped: &str, fallback: &str, alias: &str) -> String {
+    match mapped.trim() {
+        "" => format!("{} AS {}", fallback, alias),
+        mapped if mapped == alias => mapped.to_string(),
+        mapped => format!("{} AS {}", mapped, alias),
+    }
+}
+
+/// Mapped column of each response field the table has, in table order,
+/// with its ClickHouse type. Fails when a column is mapped to an
+/// expression, which cannot be created or inserted into.
+pub fn table_columns(config: &DatabaseConfig) -> Result<Vec<(&'static str, String, &'static str)>, String> {
+    let columns = &config.columns;
+    let mapped = [
+        ("qa_id", &columns.id, "UUID"),
+        ("pertanyaan", &columns.question, "String"),
+        ("jawaban", &columns.answer, "String"),
+        ("referensi", &columns.reference, "String"),
+        ("category", &columns.category, "String"),
+        ("language", &columns.language, "String"),
+        ("persona", &columns.persona, "String"),
+        ("weight", &columns.weight, "Float64"),
+    ];
+    let mut table = Vec::new();
+    for (field, column, kind) in mapped {
+        let column = column.trim();
+        if column.is_empty() {
+            continue;
+        }
+        if !is_identifier(column) {
+            return Err(format!("{} is mapped to the expression '{}', not a column", field, column));
+        }
+        table.push((field, column.to_string(), kind));
+    }
+    if !table.iter().any(|(field, _, _)| *field == "jawaban") {
+        return Err("database.columns.answer is empty".to_string());
+    }
+    Ok(table)
+}
+
+/// A plain or backquoted ClickHouse identifier
+pub fn is_identifier(column: &str) -> bool {
+    let quoted = column.len() > 2 && column.starts_with('`') && column.ends_with('`');
+    quoted
+        || (column.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && column.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// `CREATE TABLE IF NOT EXISTS` for `database.table` with the mapped
+/// columns, ordered by the id column when there is one
+pub fn create_table_query(config: &DatabaseConfig) -> Result<String, String> {
+    let columns = table_columns(config)?;
+    let definitions: Vec<String> = columns.iter().map(|(_, column, kind)| format!("{} {}", column, kind)).collect();
+    let order_by = columns.iter().find(|(field, _, _)| *field == "qa_id").map_or("tuple()", |(_, column, _)| column);
+    Ok(format!(
+        "CREATE TABLE IF NOT EXISTS {} ({}) ENGINE = MergeTree ORDER BY {}",
+        config.table,
+        definitions.join(", "),
+        order_by
+    ))
+}
+
+/// Number of rows [`select_query`] returns
+pub fn count_query(config: &DatabaseConfig) -> String {
+    format!("SELECT count() FROM ({})", select_query(config))
+}
+
+/// [`select_query`] with invalid UTF-8 in its text columns replaced, and
+/// a last column telling whether the question, answer and reference were
+/// valid to begin with
+pub fn checked_query(config: &DatabaseConfig) -> String {
+    format!(
+        "SELECT id, toValidUTF8(question), toValidUTF8(answer), toValidUTF8(reference), toValidUTF8(category), \
+         toValidUTF8(language), toValidUTF8(persona), weight, \
+         isValidUTF8(question) AND isValidUTF8(answer) AND isValidUTF8(reference) FROM ({})",
+        select_query(config)
+    )
+}
+
+/// The row of [`select_query`] whose question is closest to a bound text,
+/// if its similarity reaches a bound threshold. Binds, in order: text,
+/// threshold, then category and persona when `category`/`persona` are set.
+pub fn best_match_query(config: &DatabaseConfig, category: bool, persona: bool) -> String {
+    let mut query = format!(
+        "SELECT * FROM ({}) WHERE 1 - ngramDistanceCaseInsensitiveUTF8(question, ?) AS similarity >= ?",
+        select_query(config)
+    );
+    if category {
+        query.push_str(" AND lower(category) = lower(?)");
+    }
+    if persona {
+        query.push_str(" AND lower(persona) = lower(?)");
+    }
+    query.push_str(" ORDER BY similarity DESC, weight DESC LIMIT 1");
+    query
+}
+
+/// `CREATE TABLE IF NOT EXISTS` for the batch jobs table `table`: one row
+/// per job, a cancelled job's row replacing the one it was created with
+pub fn create_batches_table_query(table: &str) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} (id String, endpoint String, input_file_id String, completion_window String, \
+         metadata String, created_at Int64, cancelled_at Int64) ENGINE = ReplacingMergeTree(cancelled_at) ORDER BY id",
+        table
+    )
+}
+
+/// The latest row of every job in the batch jobs table `table`, oldest job
+/// first
+pub fn batches_query(table: &str) -> String {
+    format!(
+        "SELECT id, endpoint, input_file_id, completion_window, metadata, created_at, cancelled_at \
+         FROM {} FINAL ORDER BY created_at",
+        table
+    )
+}
+
+/// ClickHouse exception codes of a server that is busy, restarting or lost
+/// a replica, so the same query may pass when tried again
+const TRANSIENT_EXCEPTION_CODES: &[u32] = &[
+    159, // TIMEOUT_EXCEEDED
+    202, // TOO_MANY_SIMULTANEOUS_QUERIES
+    203, // NO_FREE_CONNECTION
+    209, // SOCKET_TIMEOUT
+    210, // NETWORK_ERROR
+    242, // TABLE_IS_READ_ONLY
+    252, // TOO_MANY_PARTS
+    279, // ALL_CONNECTION_TRIES_FAILED
+    285, // TOO_FEW_LIVE_REPLICAS
+    319, // UNKNOWN_STATUS_OF_INSERT
+    425, // SYSTEM_ERROR
+    999, // KEEPER_EXCEPTION
+];
+
+/// Errors worth retrying: the connection or the server failed, not the
+/// query. A bad response is one only when it is a 5xx status without an
+/// exception, or an exception listed in `TRANSIENT_EXCEPTION_CODES`; 4xx,
+/// authentication and SQL errors fail the same way every time.
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Network(_) | Error::TimedOut => true,
+        Error::BadResponse(reason) => match exception_code(reason) {
+            Some(code) => TRANSIENT_EXCEPTION_CODES.contains(&code),
+            None => status_code(reason).is_some_and(|status| (500..600).contains(&status)),
+        },
+        _ => false,
+    }
+}
+
+/// The status of a response the client could not read the body of, which
+/// it reports as e.g. `503 Service Unavailable`
+fn status_code(reason: &str) -> Option<u16> {
+    reason.split_whitespace().next()?.parse().ok()
+}
+
+/// The code of a `Code: <code>. DB::Exception: ...` response
+fn exception_code(reason: &str) -> Option<u32> {
+    let (_, rest) = reason.rsplit_once("Code: ")?;
+    rest.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+}
+
+/// Delay before retry number `retry` (1-based): exponential, capped, jittered
+fn backoff(policy: &RetryConfig, retry: u32) -> Duration {
+    let exponential = policy
+        .initial_backoff_ms
+        .saturating_mul(1u64 << (retry - 1).min(20))
+        .min(policy.max_backoff_ms) as f64;
+    let jitter = policy.jitter.clamp(0.0, 1.0);
+    let factor = if jitter > 0.0 { rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter) } else { 1.0 };
+    Duration::from_millis((exponential * factor) as u64)
+}
+
+/// Run the corpus query of a request within `database.query_timeout_ms`.
+/// Past it the query is dropped, cancelling it on the server, and the
+/// request is answered with a 503 instead of holding its permit.
+pub async fn with_query_timeout<T, Fut>(config: &DatabaseConfig, what: &str, query: Fut) -> Result<T, CustomError>
+where
+    Fut: Future<Output = Result<T, CustomError>>,
+{
+    if config.query_timeout_ms == 0 {
+        return query.await;
+    }
+    let timeout = Duration::from_millis(config.query_timeout_ms);
+    tokio::time::timeout(timeout, query).await.unwrap_or_else(|_| {
+        warn!("{} timed out after {:?}, cancelled", what, timeout);
+        Err(ApiError::shed(ErrorShape::OpenAi).into())
+    })
+}
+
+/// Run `query`, retrying transient failures according to `policy`
+pub async fn retry<T, F, Fut>(policy: &RetryConfig, what: &str, mut query: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match query().await {
+            Err(e) if attempt < max_attempts && is_transient(&e) => {
+                let delay = backoff(policy, attempt);
+                warn!("{} failed (attempt {}/{}): {}; retrying in {:?}", what, attempt, max_attempts, e, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_transient;
+    use clickhouse::error::Error;
+
+    #[test]
+    fn only_server_side_failures_are_retried() {
+        let bad = |reason: &str| Error::BadResponse(reason.to_string());
+        assert!(is_transient(&Error::TimedOut));
+        assert!(is_transient(&bad("503 Service Unavailable")));
+        assert!(is_transient(&bad(
+            "Code: 202. DB::Exception: Too many simultaneous queries. Maximum: 100. (TOO_MANY_SIMULTANEOUS_QUERIES) (version 24.3.1.1)"
+        )));
+        assert!(!is_transient(&bad("Code: 62. DB::Exception: Syntax error: failed at position 1. (SYNTAX_ERROR) (version 24.3.1.1)")));
+        assert!(!is_transient(&bad(
+            "Code: 516. DB::Exception: default: Authentication failed. (AUTHENTICATION_FAILED) (version 24.3.1.1)"
+        )));
+        assert!(!is_transient(&bad("Code: 60. DB::Exception: Table midai_simulator.x does not exist. (UNKNOWN_TABLE)")));
+        assert!(!is_transient(&bad("404 Not Found")));
+    }
+}
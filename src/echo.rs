@@ -0,0 +1,60 @@
+use actix_web::HttpRequest;
+use serde_json::{json, Map, Value};
+use crate::request::{api_key_id, ChatCompletionRequest};
+
+/// Model name answering with the request itself instead of a completion
+pub const ECHO_MODEL: &str = "sim/echo-request";
+/// Header switching echo mode on for any model
+pub const ECHO_HEADER: &str = "x-sim-echo";
+/// Query parameter switching echo mode on for any model
+pub const ECHO_QUERY_PARAM: &str = "echo";
+
+/// Headers carrying credentials, shown only by their length
+const SECRET_HEADERS: [&str; 5] = ["authorization", "api-key", "x-api-key", "cookie", "proxy-authorization"];
+
+/// Whether the request asked for echo mode, by model name or by an
+/// `x-sim-echo` selector other than `false` or `0`
+pub fn requested(request: &ChatCompletionRequest, selector: Option<&str>) -> bool {
+    request.model.as_deref() == Some(ECHO_MODEL)
+        || selector.is_some_and(|value| !matches!(value.trim(), "" | "false" | "0"))
+}
+
+/// What the simulator received, as pretty-printed JSON: method, path,
+/// query, headers (credentials redacted), the body and the masked API key
+/// it was sent with
+pub fn dump(req: &HttpRequest, body: &[u8]) -> String {
+    let mut headers = Map::new();
+    for (name, value) in req.headers() {
+        let value = if SECRET_HEADERS.contains(&name.as_str()) {
+            format!("<redacted {} bytes>", value.len())
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        match headers.get_mut(name.as_str()) {
+            Some(Value::String(joined)) => {
+                joined.push_str(", ");
+                joined.push_str(&value);
+            }
+            _ => {
+                headers.insert(name.to_string(), Value::String(value));
+            }
+        }
+    }
+    let body = match serde_json::from_slice::<Value>(body) {
+        Ok(body) => body,
+        Err(_) if body.is_empty() => Value::Null,
+        Err(_) => Value::String(String::from_utf8_lossy(body).into_owned()),
+    };
+    let dump = json!({
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "query": req.query_string(),
+        "headers": headers,
+        "body": body,
+        "auth": {
+            "api_key": api_key_id(req.headers()),
+        },
+        "peer_addr": req.peer_addr().map(|addr| addr.to_string()),
+    });
+    serde_json::to_string_pretty(&dump).unwrap_or_default()
+}
@@ -0,0 +1,216 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use actix_web::web::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::TokioExecutor;
+use log::{error, info};
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+use crate::config_loader::{EmbedderKind, SemanticConfig};
+use crate::corpus::CorpusFilter;
+use crate::error::CustomError;
+use crate::response::ResponseSimulator;
+
+/// Texts sent to the embeddings endpoint per request
+const API_BATCH_SIZE: usize = 64;
+
+/// Turns text into unit-length vectors whose dot product is their cosine
+/// similarity
+pub enum Embedder {
+    Hashed {
+        dimensions: usize,
+    },
+    Api {
+        client: Box<HyperClient<HttpConnector, Full<Bytes>>>,
+        url: http::Uri,
+        model: String,
+        api_key: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl Embedder {
+    pub fn new(config: &SemanticConfig) -> Result<Self, CustomError> {
+        match config.embedder {
+            EmbedderKind::Hashed => Ok(Self::Hashed {
+                dimensions: config.dimensions.max(1),
+            }),
+            EmbedderKind::Api => {
+                let url: http::Uri = config.url.parse().map_err(|e| {
+                    CustomError::ConfigError(format!("Invalid database.semantic.url '{}': {}", config.url, e))
+                })?;
+                if url.scheme_str() != Some("http") {
+                    return Err(CustomError::ConfigError(format!(
+                        "database.semantic.url must be a plain http:// endpoint, got '{}'",
+                        config.url
+                    )));
+                }
+                Ok(Self::Api {
+                    client: Box::new(HyperClient::builder(TokioExecutor::new()).build(HttpConnector::new())),
+                    url,
+                    model: config.model.clone(),
+                    api_key: config.api_key.clone(),
+                })
+            }
+        }
+    }
+
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, CustomError> {
+        match self {
+            Self::Hashed { dimensions } => Ok(texts.iter().map(|text| hashed(text, *dimensions)).collect()),
+            Self::Api { client, url, model, api_key } => {
+                let mut vectors = Vec::with_capacity(texts.len());
+                for batch in texts.chunks(API_BATCH_SIZE) {
+                    vectors.extend(embed_remote(client, url, model, api_key.as_deref(), batch).await?);
+                }
+                Ok(vectors)
+            }
+        }
+    }
+}
+
+async fn embed_remote(
+    client: &HyperClient<HttpConnector, Full<Bytes>>,
+    url: &http::Uri,
+    model: &str,
+    api_key: Option<&str>,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>, CustomError> {
+    let body = serde_json::json!({ "model": model, "input": texts }).to_string();
+    let mut request = http::Request::post(url.clone()).header(http::header::CONTENT_TYPE, "application/json");
+    if let Some(key) = api_key {
+        request = request.header(http::header::AUTHORIZATION, format!("Bearer {}", key));
+    }
+    let request = request.body(Full::new(Bytes::from(body))).map_err(|e| {
+        error!("Failed to build embeddings request: {}", e);
+        CustomError::FetchError
+    })?;
+
+    let response = client.request(request).await.map_err(|e| {
+        error!("Embeddings request to {} failed: {}", url, e);
+        CustomError::FetchError
+    })?;
+    let status = response.status();
+    let body = response.into_body().collect().await.map_err(|e| {
+        error!("Failed to read embeddings response: {}", e);
+        CustomError::FetchError
+    })?;
+    if !status.is_success() {
+        error!("Embeddings endpoint returned {}: {}", status, String::from_utf8_lossy(&body.to_bytes()));
+        return Err(CustomError::FetchError);
+    }
+    let mut parsed: EmbeddingsResponse = serde_json::from_slice(&body.to_bytes()).map_err(|e| {
+        error!("Unexpected embeddings response: {}", e);
+        CustomError::FetchError
+    })?;
+    if parsed.data.len() != texts.len() {
+        error!("Embeddings endpoint returned {} vectors for {} inputs", parsed.data.len(), texts.len());
+        return Err(CustomError::FetchError);
+    }
+    parsed.data.sort_by_key(|data| data.index);
+    Ok(parsed.data.into_iter().map(|data| normalized(data.embedding)).collect())
+}
+
+/// Words and character trigrams hashed into `dimensions` signed buckets
+fn hashed(text: &str, dimensions: usize) -> Vec<f32> {
+    let mut vector = vec![0.0; dimensions];
+    let mut add = |feature: &str| {
+        let mut hasher = DefaultHasher::new();
+        feature.hash(&mut hasher);
+        let hash = hasher.finish();
+        let sign = if hash & 1 == 0 { 1.0 } else { -1.0 };
+        vector[((hash >> 1) % dimensions as u64) as usize] += sign;
+    };
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+    {
+        add(&word);
+        let chars: Vec<char> = format!(" {} ", word).chars().collect();
+        for trigram in chars.windows(3) {
+            add(&trigram.iter().collect::<String>());
+        }
+    }
+    normalized(vector)
+}
+
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Corpus rows with their question embeddings, computed once
+pub struct SemanticIndex {
+    rows: Vec<ResponseSimulator>,
+    vectors: Vec<Vec<f32>>,
+}
+
+impl SemanticIndex {
+    pub async fn build(embedder: &Embedder, rows: Vec<ResponseSimulator>) -> Result<Self, CustomError> {
+        let questions: Vec<String> = rows.iter().map(|row| row.pertanyaan.clone()).collect();
+        let vectors = embedder.embed(&questions).await?;
+        info!("Embedded {} corpus questions for semantic selection", vectors.len());
+        Ok(Self { rows, vectors })
+    }
+
+    /// The row matching `filter` whose question is nearest to `query`, with
+    /// its cosine similarity
+    pub fn nearest(&self, query: &[f32], filter: &CorpusFilter) -> Option<(&ResponseSimulator, f32)> {
+        self.rows
+            .iter()
+            .zip(&self.vectors)
+            .filter(|(row, _)| filter.matches(row) && row.weight > 0.0)
+            .map(|(row, vector)| (row, similarity(query, vector)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+}
+
+/// State of `database.selection: semantic`: the embedder and the index of
+/// each dataset, built on the first request that needs it
+pub struct SemanticSearch {
+    pub embedder: Embedder,
+    indexes: Mutex<HashMap<Option<String>, Arc<OnceCell<SemanticIndex>>>>,
+}
+
+impl SemanticSearch {
+    pub fn new(embedder: Embedder) -> Self {
+        Self {
+            embedder,
+            indexes: Mutex::default(),
+        }
+    }
+
+    /// Index of `dataset` (`None` is `database.table`), possibly not built yet
+    pub fn index(&self, dataset: Option<&str>) -> Arc<OnceCell<SemanticIndex>> {
+        let mut indexes = self.indexes.lock().unwrap();
+        indexes.entry(dataset.map(str::to_string)).or_default().clone()
+    }
+
+    pub async fn embed_one(&self, text: &str) -> Result<Vec<f32>, CustomError> {
+        let mut vectors = self.embedder.embed(&[text.to_string()]).await?;
+        vectors.pop().ok_or(CustomError::FetchError)
+    }
+}
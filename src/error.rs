@@ -0,0 +1,229 @@
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use derive_more::Display;
+use serde::Deserialize;
+
+#[derive(Debug, Display)]
+pub enum CustomError {
+    #[display(fmt = "Failed to fetch responses")]
+    FetchError,
+    #[display(fmt = "Failed to bind server: {}", _0)]
+    BindError(String),
+    #[display(fmt = "Redis error: {}", _0)]
+    RedisError(String),
+    #[display(fmt = "Invalid configuration: {}", _0)]
+    ConfigError(String),
+    #[display(fmt = "{}", _0)]
+    Api(ApiError),
+}
+
+/// Which provider's error body to reproduce
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorShape {
+    /// `{"error": {"message", "type", "param", "code"}}`
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// `{"error": {"code", "message"}}`
+    Azure,
+    /// `{"type": "error", "error": {"type", "message"}}`
+    Anthropic,
+}
+
+/// An error returned to the client in OpenAI's (or Azure's) `{"error": {...}}` shape
+#[derive(Debug, Display)]
+#[display(fmt = "{}", message)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+    pub error_type: &'static str,
+    pub param: Option<String>,
+    pub code: Option<String>,
+    pub shape: ErrorShape,
+    /// Extra response headers, e.g. retry hints
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error_type: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            error_type,
+            param: None,
+            code: None,
+            shape: ErrorShape::OpenAi,
+            headers: Vec::new(),
+        }
+    }
+
+    /// An error in Azure OpenAI's shape, which carries only a code and message
+    pub fn azure(status: StatusCode, code: &str, message: impl Into<String>) -> Self {
+        let mut error = Self::new(status, "invalid_request_error", message).with_code(code);
+        error.shape = ErrorShape::Azure;
+        error
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+    }
+
+    /// The 404 OpenAI returns for a model the caller cannot use
+    pub fn model_not_found(model: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            format!("The model `{}` does not exist or you do not have access to it.", model),
+        )
+        .with_code("model_not_found")
+    }
+
+    /// The 429 OpenAI returns once the account's quota or budget is used up
+    pub fn insufficient_quota() -> Self {
+        Self::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "insufficient_quota",
+            "You exceeded your current quota, please check your plan and billing details. For more information on this error, read the docs: https://platform.openai.com/docs/guides/error-codes/api-errors.",
+        )
+        .with_code("insufficient_quota")
+    }
+
+    /// The 529 Anthropic returns while its API is temporarily overloaded
+    pub fn overloaded() -> Self {
+        let status = StatusCode::from_u16(529).expect("529 is a valid status code");
+        Self::new(status, "overloaded_error", "Overloaded")
+    }
+
+    /// The 503 a provider sheds load with, in the wording of `shape`
+    pub fn shed(shape: ErrorShape) -> Self {
+        let error = match shape {
+            ErrorShape::Anthropic => Self::new(StatusCode::SERVICE_UNAVAILABLE, "overloaded_error", "Overloaded"),
+            ErrorShape::Azure => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_error",
+                "The service is temporarily unable to process your request. Please try again later.",
+            )
+            .with_code("ServiceUnavailable"),
+            ErrorShape::OpenAi => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_error",
+                "The server is overloaded or not ready yet.",
+            ),
+        };
+        error.with_shape(shape)
+    }
+
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+
+    /// Name the request field at fault
+    pub fn with_param(mut self, param: impl Into<String>) -> Self {
+        self.param = Some(param.into());
+        self
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Render the body as `shape` unless the error already has a
+    /// provider-specific shape
+    pub fn with_shape(mut self, shape: ErrorShape) -> Self {
+        if self.shape == ErrorShape::OpenAi {
+            self.shape = shape;
+        }
+        self
+    }
+
+    pub fn body(&self) -> serde_json::Value {
+        match self.shape {
+            ErrorShape::Azure => serde_json::json!({
+                "error": {
+                    "code": self.code,
+                    "message": self.message,
+                }
+            }),
+            ErrorShape::Anthropic => serde_json::json!({
+                "type": "error",
+                "error": {
+                    "type": self.error_type,
+                    "message": self.message,
+                }
+            }),
+            ErrorShape::OpenAi => serde_json::json!({
+                "error": {
+                    "message": self.message,
+                    "type": self.error_type,
+                    "param": self.param,
+                    "code": self.code,
+                }
+            }),
+        }
+    }
+}
+
+impl From<ApiError> for CustomError {
+    fn from(error: ApiError) -> Self {
+        CustomError::Api(error)
+    }
+}
+
+impl CustomError {
+    /// `type` of the error body, or a name for errors answered without one
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            CustomError::Api(error) => error.error_type,
+            CustomError::FetchError => "fetch_error",
+            CustomError::BindError(_) => "bind_error",
+            CustomError::RedisError(_) => "redis_error",
+            CustomError::ConfigError(_) => "config_error",
+        }
+    }
+}
+
+impl ResponseError for CustomError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CustomError::Api(error) => error.status,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            CustomError::Api(error) => {
+                let mut response = HttpResponse::build(error.status);
+                for header in &error.headers {
+                    response.insert_header(header.clone());
+                }
+                response.json(error.body())
+            }
+            _ => HttpResponse::build(self.status_code())
+                .insert_header(ContentType::plaintext())
+                .body(self.to_string()),
+        }
+    }
+}
+
+impl From<clickhouse::error::Error> for CustomError {
+    fn from(_error: clickhouse::error::Error) -> Self {
+        CustomError::FetchError
+    }
+}
+
+impl From<std::io::Error> for CustomError {
+    fn from(error: std::io::Error) -> Self {
+        CustomError::BindError(error.to_string())
+    }
+}
+
+impl From<redis::RedisError> for CustomError {
+    fn from(error: redis::RedisError) -> Self {
+        CustomError::RedisError(error.to_string())
+    }
+}
@@ -0,0 +1,236 @@
+use std::panic;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, Once, PoisonError};
+use std::time::Duration;
+use actix_web::ResponseError;
+use log::{debug, warn};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use crate::capture::CaptureRecord;
+use crate::error::CustomError;
+use crate::health::SinkHealth;
+use crate::outbound;
+
+/// Reports of panics and unexpected errors sent to a Sentry-compatible
+/// server
+#[derive(Deserialize, Clone, Debug)]
+pub struct ErrorReportingConfig {
+    /// `https://<public_key>@<host>[:<port>]/<project_id>` (or `http://`);
+    /// nothing is reported when unset
+    #[serde(default, deserialize_with = "dsn")]
+    pub dsn: Option<Dsn>,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    /// Give up on a report after this long
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for ErrorReportingConfig {
+    fn default() -> Self {
+        Self {
+            dsn: None,
+            environment: default_environment(),
+            timeout_ms: default_timeout_ms(),
+        }
+    }
+}
+
+fn default_environment() -> String {
+    "simulator".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+/// Where reports go and the key they are sent under
+#[derive(Clone, Debug)]
+pub struct Dsn {
+    store_url: http::Uri,
+    public_key: String,
+}
+
+fn dsn<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Dsn>, D::Error> {
+    let Some(dsn) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let invalid = |reason: &str| serde::de::Error::custom(format!("invalid error_reporting.dsn '{}': {}", dsn, reason));
+    let (scheme, rest) = dsn
+        .split_once("://")
+        .filter(|(scheme, _)| matches!(*scheme, "http" | "https"))
+        .ok_or_else(|| invalid("must be an http:// or https:// DSN"))?;
+    let (public_key, location) = rest.split_once('@').ok_or_else(|| invalid("missing public key"))?;
+    let public_key = public_key.split(':').next().unwrap_or_default();
+    let (prefix, project_id) = location.rsplit_once('/').ok_or_else(|| invalid("missing project id"))?;
+    if public_key.is_empty() || project_id.is_empty() {
+        return Err(invalid("missing public key or project id"));
+    }
+    let store_url = format!("{}://{}/api/{}/store/", scheme, prefix, project_id)
+        .parse()
+        .map_err(|_| invalid("not a valid URL"))?;
+    Ok(Some(Dsn {
+        store_url,
+        public_key: public_key.to_string(),
+    }))
+}
+
+/// Reporter the panic hook sends to, the one of the latest state
+static PANIC_REPORTER: Mutex<Option<Arc<ErrorReporter>>> = Mutex::new(None);
+
+/// Sends events from a thread of its own, so panicking workers and
+/// runtimes shutting down still get their report out
+pub struct ErrorReporter {
+    sender: Option<Sender<Value>>,
+    environment: String,
+    health: Arc<SinkHealth>,
+}
+
+impl Default for ErrorReporter {
+    fn default() -> Self {
+        Self::new(&ErrorReportingConfig::default())
+    }
+}
+
+impl ErrorReporter {
+    pub fn new(config: &ErrorReportingConfig) -> Self {
+        let health = Arc::new(SinkHealth::default());
+        let sender = config.dsn.clone().map(|dsn| {
+            let health = health.clone();
+            let (sender, receiver) = mpsc::channel::<Value>();
+            let timeout = Duration::from_millis(config.timeout_ms);
+            std::thread::spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        warn!("event=error_report error=\"cannot start reporter: {}\"", e);
+                        return;
+                    }
+                };
+                let client = outbound::client();
+                while let Ok(event) = receiver.recv() {
+                    match runtime.block_on(deliver(&client, &dsn, event, timeout)) {
+                        Ok(()) => health.record_success(),
+                        Err(e) => health.record_failure(e),
+                    }
+                }
+            });
+            sender
+        });
+        Self {
+            sender,
+            environment: config.environment.clone(),
+            health,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    pub fn health(&self) -> &SinkHealth {
+        &self.health
+    }
+
+    /// Report `error`, which failed the request `record` describes, unless
+    /// it is an injected fault or the client's own doing
+    pub fn report_error(&self, error: &CustomError, record: &CaptureRecord) {
+        if self.sender.is_none() || record.fault.is_some() || !error.status_code().is_server_error() {
+            return;
+        }
+        let mut event = self.event("error", kind(error), &error.to_string());
+        event["transaction"] = json!(record.endpoint);
+        event["request"] = json!({ "url": record.endpoint, "method": "POST", "data": record.request });
+        event["tags"] = json!({
+            "request_id": record.request_id,
+            "model": record.model,
+            "status": record.status.to_string(),
+        });
+        event["user"] = json!({ "id": record.api_key });
+        event["extra"] = json!({ "completion_id": record.id, "received_at": record.received_at });
+        self.send(event);
+    }
+
+    /// Report every panic from now on to this reporter, before handing it
+    /// to the previous hook. A later call (after a reload) takes over, and
+    /// a reporter without a DSN stops the reporting.
+    pub fn capture_panics(self: &Arc<Self>) {
+        static INSTALLED: Once = Once::new();
+        let reporter = self.sender.is_some().then(|| self.clone());
+        *PANIC_REPORTER.lock().unwrap_or_else(PoisonError::into_inner) = reporter;
+        if self.sender.is_none() {
+            return;
+        }
+        INSTALLED.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                let reporter = PANIC_REPORTER.lock().unwrap_or_else(PoisonError::into_inner).clone();
+                if let Some(reporter) = reporter {
+                    let message = info
+                        .payload()
+                        .downcast_ref::<&str>()
+                        .map(|message| message.to_string())
+                        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+                    let mut event = reporter.event("fatal", "panic", &message);
+                    event["culprit"] = json!(info.location().map(|location| location.to_string()));
+                    event["extra"] = json!({
+                        "thread": std::thread::current().name(),
+                        "backtrace": std::backtrace::Backtrace::force_capture().to_string(),
+                    });
+                    reporter.send(event);
+                }
+                previous(info);
+            }));
+        });
+    }
+
+    fn event(&self, level: &str, kind: &str, message: &str) -> Value {
+        json!({
+            "event_id": uuid::Uuid::new_v4().simple().to_string(),
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "platform": "other",
+            "level": level,
+            "logger": env!("CARGO_PKG_NAME"),
+            "release": concat!(env!("CARGO_PKG_NAME"), "@", env!("CARGO_PKG_VERSION")),
+            "environment": self.environment,
+            "message": { "formatted": message },
+            "exception": { "values": [{ "type": kind, "value": message }] },
+        })
+    }
+
+    fn send(&self, event: Value) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+fn kind(error: &CustomError) -> &'static str {
+    match error {
+        CustomError::FetchError => "FetchError",
+        CustomError::BindError(_) => "BindError",
+        CustomError::RedisError(_) => "RedisError",
+        CustomError::ConfigError(_) => "ConfigError",
+        CustomError::Api(_) => "ApiError",
+    }
+}
+
+async fn deliver(client: &outbound::Client, dsn: &Dsn, event: Value, timeout: Duration) -> Result<(), String> {
+    let auth = format!(
+        "Sentry sentry_version=7, sentry_key={}, sentry_client={}/{}",
+        dsn.public_key,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    match outbound::post_json(client, &dsn.store_url, vec![("x-sentry-auth", auth)], event.to_string(), timeout).await {
+        Ok(status) => {
+            debug!("event=error_report url={} status={}", dsn.store_url, status);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("event=error_report url={} error=\"{}\"", dsn.store_url, e);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use crate::cli::Args;
+use crate::config_loader::Config;
+use crate::error::CustomError;
+use crate::response::load_corpus;
+
+/// Dump the corpus the config serves from to `--out` (stdout when absent
+/// or `-`) in `--format` (only `jsonl`, the default): one
+/// `response_simulator` row per line, whatever the source, so fixture data
+/// moves between ClickHouse and file environments. Entries with a problem
+/// are exported as read and listed on stderr.
+pub async fn run(args: &Args) -> Result<(), CustomError> {
+    let format = args.value("format").unwrap_or("jsonl");
+    if format != "jsonl" {
+        return Err(CustomError::ConfigError(format!("unsupported --format '{}', expected jsonl", format)));
+    }
+    let config = Config::read(args.profile())?;
+    let entries = load_corpus(&config).await?;
+
+    let out = args.value("out").filter(|out| *out != "-");
+    let mut writer: Box<dyn Write> = match out {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|e| CustomError::ConfigError(format!("cannot create --out '{}': {}", path, e)))?,
+        )),
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    let failed = |e: std::io::Error| CustomError::ConfigError(format!("cannot write {}: {}", out.unwrap_or("stdout"), e));
+    for entry in &entries {
+        if let Some(problem) = &entry.problem {
+            eprintln!("{}: {}", entry.origin, problem);
+        }
+        let line = serde_json::to_string(&entry.response)
+            .map_err(|e| CustomError::ConfigError(format!("cannot serialize {}: {}", entry.origin, e)))?;
+        writeln!(writer, "{}", line).map_err(failed)?;
+    }
+    writer.flush().map_err(failed)?;
+    eprintln!(
+        "Exported {} entries from the {:?} source to {}",
+        entries.len(),
+        config.source,
+        out.unwrap_or("stdout")
+    );
+    Ok(())
+}
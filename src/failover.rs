@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use clickhouse::Client;
+use log::{info, warn};
+use serde::Serialize;
+use crate::config_loader::DatabaseConfig;
+use crate::db;
+use crate::error::CustomError;
+use crate::metrics;
+use crate::server::AppState;
+
+/// How long an endpoint gets to answer a health check
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Endpoint {
+    url: String,
+    client: Client,
+    healthy: AtomicBool,
+}
+
+/// Health of one endpoint, for `/admin/stats`
+#[derive(Serialize)]
+pub struct EndpointStatus {
+    pub url: String,
+    pub healthy: bool,
+    pub active: bool,
+}
+
+/// The ClickHouse endpoints, `database.url` then `database.failover_urls`,
+/// queried through the first one that passed its last health check, so
+/// the primary is served from again as soon as it recovers
+pub struct DbEndpoints {
+    endpoints: Vec<Endpoint>,
+    active: AtomicUsize,
+    /// Whether the startup connection (schema, first queries) succeeded
+    connected: AtomicBool,
+    /// Why the last connection attempt failed
+    connect_error: Mutex<Option<String>>,
+    /// Rows of `database.table` when last counted or fetched
+    corpus_rows: Mutex<Option<u64>>,
+}
+
+impl DbEndpoints {
+    pub fn new(config: &DatabaseConfig) -> Result<Self, CustomError> {
+        let endpoints = std::iter::once(&config.url)
+            .chain(&config.failover_urls)
+            .map(|url| {
+                Ok(Endpoint {
+                    url: url.clone(),
+                    client: db::build_endpoint_client(config, url)?,
+                    healthy: AtomicBool::new(true),
+                })
+            })
+            .collect::<Result<_, CustomError>>()?;
+        Ok(Self {
+            endpoints,
+            active: AtomicUsize::new(0),
+            connected: AtomicBool::new(false),
+            connect_error: Mutex::new(None),
+            corpus_rows: Mutex::new(None),
+        })
+    }
+
+    /// Client of the endpoint queries go to; cheap to clone, sharing its pool
+    pub fn client(&self) -> Client {
+        self.endpoints[self.active.load(Ordering::Relaxed)].client.clone()
+    }
+
+    pub fn active_url(&self) -> &str {
+        &self.endpoints[self.active.load(Ordering::Relaxed)].url
+    }
+
+    pub fn has_replicas(&self) -> bool {
+        self.endpoints.len() > 1
+    }
+
+    /// Whether the database is ready to serve from; until then requests
+    /// are answered as when the circuit breaker is open
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn connect_error(&self) -> Option<String> {
+        self.connect_error.lock().unwrap().clone()
+    }
+
+    /// Record the outcome of a connection attempt
+    pub fn set_connected(&self, result: Result<(), String>) {
+        self.connected.store(result.is_ok(), Ordering::Relaxed);
+        *self.connect_error.lock().unwrap() = result.err();
+    }
+
+    pub fn corpus_rows(&self) -> Option<u64> {
+        *self.corpus_rows.lock().unwrap()
+    }
+
+    pub fn set_corpus_rows(&self, rows: u64) {
+        *self.corpus_rows.lock().unwrap() = Some(rows);
+    }
+
+    /// Ping every endpoint and move queries to the first healthy one,
+    /// returning its URL when that is a change. When none answers, queries
+    /// stay where they are.
+    pub async fn check(&self) -> Option<String> {
+        let pings = self.endpoints.iter().map(|endpoint| async move {
+            let ping = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, endpoint.client.query("SELECT 1").execute()).await;
+            matches!(ping, Ok(Ok(())))
+        });
+        let results = futures::future::join_all(pings).await;
+        for (endpoint, &healthy) in self.endpoints.iter().zip(&results) {
+            if endpoint.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                match healthy {
+                    true => info!("ClickHouse endpoint {} is healthy again", endpoint.url),
+                    false => warn!("ClickHouse endpoint {} failed its health check", endpoint.url),
+                }
+            }
+        }
+        let first_healthy = results.iter().position(|&healthy| healthy)?;
+        let previous = self.active.swap(first_healthy, Ordering::Relaxed);
+        if previous == first_healthy {
+            return None;
+        }
+        let url = &self.endpoints[first_healthy].url;
+        warn!("Switching ClickHouse queries from {} to {}", self.endpoints[previous].url, url);
+        Some(url.clone())
+    }
+
+    pub fn status(&self) -> Vec<EndpointStatus> {
+        let active = self.active.load(Ordering::Relaxed);
+        self.endpoints
+            .iter()
+            .enumerate()
+            .map(|(index, endpoint)| EndpointStatus {
+                url: endpoint.url.clone(),
+                healthy: endpoint.healthy.load(Ordering::Relaxed),
+                active: index == active,
+            })
+            .collect()
+    }
+}
+
+/// Health check the endpoints every `interval`, counting failovers
+pub async fn watch(state: Arc<AppState>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Some(url) = state.db.check().await {
+            state.metrics.inc_counter(&metrics::DB_FAILOVERS_TOTAL, &vec![("url", url)], 1.0);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use actix_web::http::StatusCode;
+use futures_util::Stream;
+use rand::Rng;
+use serde::Deserialize;
+use crate::error::ApiError;
+
+/// Header injecting a fault into the request by name
+pub const FAULT_HEADER: &str = "x-sim-fault";
+/// Query parameter injecting a fault into the request by name
+pub const FAULT_QUERY_PARAM: &str = "fault";
+
+/// Failures injected into otherwise successful requests, either on demand
+/// through `x-sim-fault` or at random with the configured probability
+#[derive(Deserialize, Clone, Default)]
+pub struct FaultsConfig {
+    #[serde(default)]
+    pub stream_error: StreamErrorConfig,
+    #[serde(default)]
+    pub hang: HangConfig,
+    #[serde(default)]
+    pub overloaded: OverloadedConfig,
+    #[serde(default)]
+    pub schema_violation: SchemaViolationConfig,
+}
+
+/// An `error` event sent partway through the stream in place of the
+/// remaining chunks, as OpenAI does when generation fails mid-stream
+#[derive(Deserialize, Clone, Debug)]
+pub struct StreamErrorConfig {
+    /// Share of streams failing without being asked to, from 0.0 to 1.0
+    #[serde(default)]
+    pub probability: f64,
+    /// Chunks delivered before the error event
+    #[serde(default = "default_after_chunks")]
+    pub after_chunks: usize,
+    #[serde(default = "default_message")]
+    pub message: String,
+    #[serde(rename = "type", default = "default_error_type")]
+    pub error_type: String,
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+impl Default for StreamErrorConfig {
+    fn default() -> Self {
+        Self {
+            probability: 0.0,
+            after_chunks: default_after_chunks(),
+            message: default_message(),
+            error_type: default_error_type(),
+            code: None,
+        }
+    }
+}
+
+fn default_after_chunks() -> usize {
+    3
+}
+
+fn default_message() -> String {
+    "The server had an error while processing your request. Sorry about that!".to_string()
+}
+
+fn default_error_type() -> String {
+    "server_error".to_string()
+}
+
+impl StreamErrorConfig {
+    /// Body of the `data:` event ending the stream
+    pub fn event(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "message": self.message,
+                "type": self.error_type,
+                "param": null,
+                "code": self.code,
+            }
+        })
+    }
+}
+
+/// Accept the request and then leave the connection idle, to exercise
+/// client read timeouts and gateway timeout budgets
+#[derive(Deserialize, Clone, Debug)]
+pub struct HangConfig {
+    /// Share of requests hanging without being asked to, from 0.0 to 1.0
+    #[serde(default)]
+    pub probability: f64,
+    /// How long the connection stays idle
+    #[serde(default = "default_hang_duration_ms")]
+    pub duration_ms: u64,
+    /// Send the `200` response headers right away, then hang in the body
+    /// and close the connection. Otherwise nothing is sent until the hang
+    /// ends with a `504 Gateway Timeout`.
+    #[serde(default)]
+    pub send_headers: bool,
+}
+
+impl Default for HangConfig {
+    fn default() -> Self {
+        Self {
+            probability: 0.0,
+            duration_ms: default_hang_duration_ms(),
+            send_headers: false,
+        }
+    }
+}
+
+fn default_hang_duration_ms() -> u64 {
+    30000
+}
+
+impl HangConfig {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// What a gateway answers once its upstream timed out
+    pub fn gateway_timeout(&self) -> ApiError {
+        ApiError::new(StatusCode::GATEWAY_TIMEOUT, "server_error", "Gateway timeout")
+    }
+}
+
+/// Anthropic's `529 overloaded_error`, with the retry hints its SDKs
+/// honour
+#[derive(Deserialize, Clone, Debug)]
+pub struct OverloadedConfig {
+    /// Share of requests rejected without being asked to, from 0.0 to 1.0
+    #[serde(default)]
+    pub probability: f64,
+    /// Advertised with `retry-after` (rounded up to seconds) and
+    /// `retry-after-ms`
+    #[serde(default = "default_retry_after_ms")]
+    pub retry_after_ms: u64,
+}
+
+impl Default for OverloadedConfig {
+    fn default() -> Self {
+        Self {
+            probability: 0.0,
+            retry_after_ms: default_retry_after_ms(),
+        }
+    }
+}
+
+fn default_retry_after_ms() -> u64 {
+    1000
+}
+
+impl OverloadedConfig {
+    pub fn error(&self) -> ApiError {
+        ApiError::overloaded()
+            .with_header("retry-after", self.retry_after_ms.div_ceil(1000).to_string())
+            .with_header("retry-after-ms", self.retry_after_ms.to_string())
+            .with_header("x-should-retry", "true")
+    }
+}
+
+/// Structured output that does not match the requested `json_schema`: a
+/// required property is missing or a value has the wrong type
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SchemaViolationConfig {
+    /// Share of structured output requests answered off-schema without
+    /// being asked to, from 0.0 to 1.0
+    #[serde(default)]
+    pub probability: f64,
+}
+
+/// Response body that stays idle for `duration` and then fails, which makes
+/// actix drop the connection instead of ending the response
+pub fn hang(duration: Duration) -> impl Stream<Item = io::Result<String>> {
+    futures_util::stream::once(async move {
+        tokio::time::sleep(duration).await;
+        Err(io::Error::new(io::ErrorKind::TimedOut, "simulated hang, closing the connection"))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// See `StreamErrorConfig`
+    StreamError,
+    /// See `HangConfig`
+    Hang,
+    /// See `OverloadedConfig`
+    Overloaded,
+    /// See `SchemaViolationConfig`
+    SchemaViolation,
+}
+
+impl Fault {
+    const ALL: [Fault; 4] = [Fault::StreamError, Fault::Hang, Fault::Overloaded, Fault::SchemaViolation];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fault::StreamError => "stream_error",
+            Fault::Hang => "hang",
+            Fault::Overloaded => "overloaded",
+            Fault::SchemaViolation => "schema_violation",
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FaultsConfig {
+    fn probability(&self, fault: Fault) -> f64 {
+        match fault {
+            Fault::StreamError => self.stream_error.probability,
+            Fault::Hang => self.hang.probability,
+            Fault::Overloaded => self.overloaded.probability,
+            Fault::SchemaViolation => self.schema_violation.probability,
+        }
+    }
+
+    /// Fault to inject: the one `requested` by name, else one rolled from
+    /// the configured probabilities
+    pub fn select(&self, requested: Option<&str>) -> Result<Option<Fault>, ApiError> {
+        if let Some(name) = requested {
+            let name = name.trim().to_lowercase();
+            return Fault::ALL
+                .into_iter()
+                .find(|fault| fault.name() == name)
+                .map(Some)
+                .ok_or_else(|| {
+                    let known: Vec<_> = Fault::ALL.iter().map(|fault| fault.name()).collect();
+                    ApiError::invalid_request(format!("Unknown fault '{}', expected one of: {}", name, known.join(", ")))
+                });
+        }
+        let mut rng = rand::thread_rng();
+        Ok(Fault::ALL
+            .into_iter()
+            .find(|fault| rng.gen_bool(self.probability(*fault).clamp(0.0, 1.0))))
+    }
+}
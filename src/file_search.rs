@@ -0,0 +1,156 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::reply::BuiltinToolCall;
+use crate::request::ChatCompletionRequest;
+
+/// Tool types answered with file search results; `retrieval` is the
+/// Assistants v1 name
+pub const TOOL_TYPES: [&str; 2] = ["file_search", "retrieval"];
+/// Ranker reported in `ranking_options`
+const RANKER: &str = "default_2024_08_21";
+
+/// Results of the `file_search` tool, drawn from the corpus
+#[derive(Deserialize, Clone, Debug)]
+pub struct FileSearchConfig {
+    /// Results returned unless the tool sets `max_num_results`
+    #[serde(default = "default_max_num_results")]
+    pub max_num_results: usize,
+    /// Size of the chunks corpus entries are split into
+    #[serde(default = "default_chunk_tokens")]
+    pub chunk_tokens: u32,
+}
+
+impl Default for FileSearchConfig {
+    fn default() -> Self {
+        Self {
+            max_num_results: default_max_num_results(),
+            chunk_tokens: default_chunk_tokens(),
+        }
+    }
+}
+
+fn default_max_num_results() -> usize {
+    20
+}
+
+fn default_chunk_tokens() -> u32 {
+    800
+}
+
+/// A corpus entry searched as if it were an uploaded file
+pub struct Document {
+    pub file_name: String,
+    pub text: String,
+}
+
+/// The `file_search` (or `retrieval`) call answering `request`: the chunks
+/// of `documents` sharing the most words with the last user message, best
+/// first, with scores from 0.0 to 1.0. `None` when the request declares no
+/// such tool.
+pub fn call(config: &FileSearchConfig, request: &ChatCompletionRequest, documents: &[Document]) -> Option<BuiltinToolCall> {
+    let tool = request
+        .tools
+        .iter()
+        .flatten()
+        .find(|tool| tool.get("type").and_then(Value::as_str).is_some_and(|kind| TOOL_TYPES.contains(&kind)))?;
+    let kind = tool["type"].as_str().unwrap_or(TOOL_TYPES[0]);
+    // Responses API tools set options at the top level, Assistants tools
+    // under the tool type
+    let option = |name: &str| tool.get(name).or_else(|| tool.get(kind).and_then(|options| options.get(name)));
+    let max_num_results = option("max_num_results")
+        .and_then(Value::as_u64)
+        .map_or(config.max_num_results, |max| max as usize);
+    let score_threshold = option("ranking_options")
+        .and_then(|ranking| ranking.get("score_threshold"))
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0);
+
+    let query = words(&request.last_user_message().unwrap_or_default());
+    let mut rng = rand::thread_rng();
+    let mut results: Vec<(f64, &Document, String)> = documents
+        .iter()
+        .flat_map(|document| chunks(&document.text, config.chunk_tokens).into_iter().map(move |chunk| (document, chunk)))
+        .map(|(document, chunk)| {
+            let overlap = if query.is_empty() {
+                rng.gen_range(0.0..0.5)
+            } else {
+                let chunk_words = words(&chunk);
+                query.intersection(&chunk_words).count() as f64 / query.len() as f64
+            };
+            let score = (0.3 + 0.65 * overlap + rng.gen_range(0.0..0.05)).min(1.0);
+            (score, document, chunk)
+        })
+        .filter(|(score, _, _)| *score >= score_threshold)
+        .collect();
+    results.sort_by(|a, b| b.0.total_cmp(&a.0));
+    results.truncate(max_num_results);
+
+    let results: Vec<Value> = results
+        .into_iter()
+        .map(|(score, document, chunk)| {
+            json!({
+                "file_id": file_id(&document.file_name),
+                "file_name": document.file_name,
+                "score": (score * 10000.0).round() / 10000.0,
+                "content": [{ "type": "text", "text": chunk }],
+            })
+        })
+        .collect();
+    let output = json!({
+        "ranking_options": { "ranker": RANKER, "score_threshold": score_threshold },
+        "results": results,
+    });
+    Some(BuiltinToolCall::new(kind, output))
+}
+
+/// Lowercase words of at least three letters or digits
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 3)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// `text` cut into pieces of about `tokens` tokens, at whitespace
+fn chunks(text: &str, tokens: u32) -> Vec<String> {
+    let limit = tokens.max(1) as usize * 4;
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        if !current.is_empty() && current.len() + word.len() >= limit {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// `file-` and 22 characters derived from the name, so a document keeps
+/// its id across requests
+fn file_id(file_name: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut id = String::from("file-");
+    let mut seed = 0u64;
+    while id.len() < 27 {
+        let mut hasher = DefaultHasher::new();
+        (file_name, seed).hash(&mut hasher);
+        let mut hash = hasher.finish();
+        for _ in 0..10 {
+            id.push(ALPHABET[(hash % ALPHABET.len() as u64) as usize] as char);
+            hash /= ALPHABET.len() as u64;
+        }
+        seed += 1;
+    }
+    id.truncate(27);
+    id
+}
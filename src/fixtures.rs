@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::path::Path;
+use log::{info, debug};
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use crate::config_loader::LatencyConfig;
+use crate::error::{ApiError, CustomError};
+use crate::request::ChatCompletionRequest;
+use crate::selection::Selector;
+
+/// Header selecting a fixture bundle by name
+pub const FIXTURE_HEADER: &str = "x-sim-fixture";
+/// Query parameter selecting a fixture bundle by name
+pub const FIXTURE_QUERY_PARAM: &str = "fixture";
+
+/// A self-contained set of responses shipped by one test suite
+#[derive(Deserialize, Debug, Clone)]
+pub struct FixtureBundle {
+    /// Defaults to the file stem of the bundle
+    #[serde(default)]
+    pub name: String,
+    /// Models this bundle expects; requests for other models get a 404.
+    /// Empty means any model is accepted.
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Overrides the global `latency` section for this bundle
+    #[serde(default)]
+    pub latency: Option<LatencyConfig>,
+    pub responses: Vec<FixtureResponse>,
+    #[serde(default)]
+    pub rules: Vec<FixtureRule>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FixtureResponse {
+    pub id: String,
+    pub content: String,
+}
+
+/// Serves `response` when every condition given matches the request
+#[derive(Deserialize, Debug, Clone)]
+pub struct FixtureRule {
+    /// Case-insensitive substring of the last user message
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// Exact model name
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Id of the response to serve
+    pub response: String,
+}
+
+impl FixtureRule {
+    fn matches(&self, request: &ChatCompletionRequest) -> bool {
+        if let Some(model) = &self.model {
+            if request.model.as_deref() != Some(model.as_str()) {
+                return false;
+            }
+        }
+        if let Some(needle) = &self.contains {
+            let message = request.last_user_message().unwrap_or_default();
+            if !message.to_lowercase().contains(&needle.to_lowercase()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl FixtureBundle {
+    /// Reject requests for models the bundle does not declare
+    pub fn check_model(&self, model: Option<&str>) -> Result<(), ApiError> {
+        if self.models.is_empty() {
+            return Ok(());
+        }
+        match model {
+            Some(model) if self.models.iter().any(|m| m == model) => Ok(()),
+            model => Err(ApiError::model_not_found(model.unwrap_or_default())),
+        }
+    }
+
+    /// The first matching rule's response, otherwise one picked by
+    /// `selector`, or a random response
+    pub fn select_response(
+        &self,
+        request: &ChatCompletionRequest,
+        selector: &Selector,
+        session: Option<&str>,
+    ) -> &FixtureResponse {
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(request)) {
+            debug!("Fixture {}: rule matched, serving {}", self.name, rule.response);
+            return self.response(&rule.response).expect("rule targets validated on load");
+        }
+        let responses: Vec<&FixtureResponse> = self.responses.iter().collect();
+        let prompt = request.last_user_message();
+        if let Some(response) = selector.pick(&responses, |response| response.id.clone(), session, prompt.as_deref()) {
+            return response;
+        }
+        let mut rng = rand::thread_rng();
+        self.responses.choose(&mut rng).expect("bundle responses validated on load")
+    }
+
+    fn response(&self, id: &str) -> Option<&FixtureResponse> {
+        self.responses.iter().find(|response| response.id == id)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.responses.is_empty() {
+            return Err("declares no responses".to_string());
+        }
+        for rule in &self.rules {
+            if self.response(&rule.response).is_none() {
+                return Err(format!("rule targets unknown response '{}'", rule.response));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// All bundles loaded from the fixtures directory, keyed by name
+#[derive(Default)]
+pub struct FixtureSet {
+    bundles: HashMap<String, FixtureBundle>,
+}
+
+impl FixtureSet {
+    /// Load every `.yml`/`.yaml` file in `dir` as a bundle. Any unreadable
+    /// or invalid bundle fails the whole load so mistakes surface at startup.
+    pub fn load_dir(dir: &str) -> Result<Self, CustomError> {
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| CustomError::ConfigError(format!("cannot read fixtures dir {}: {}", dir, e)))?;
+
+        let mut bundles = HashMap::new();
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if !path.extension().is_some_and(|ext| ext == "yml" || ext == "yaml") {
+                continue;
+            }
+            let bundle = load_bundle(&path)?;
+            if bundles.contains_key(&bundle.name) {
+                return Err(CustomError::ConfigError(format!(
+                    "duplicate fixture bundle name '{}' in {}", bundle.name, path.display()
+                )));
+            }
+            bundles.insert(bundle.name.clone(), bundle);
+        }
+
+        info!("Loaded {} fixture bundle(s) from {}", bundles.len(), dir);
+        Ok(Self { bundles })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FixtureBundle> {
+        self.bundles.get(name)
+    }
+}
+
+fn load_bundle(path: &Path) -> Result<FixtureBundle, CustomError> {
+    let invalid = |reason: String| CustomError::ConfigError(format!("fixture {}: {}", path.display(), reason));
+
+    let content = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let mut bundle: FixtureBundle = serde_yaml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+    if bundle.name.is_empty() {
+        bundle.name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
+    bundle.validate().map_err(invalid)?;
+    debug!("Loaded fixture bundle {} with {} responses", bundle.name, bundle.responses.len());
+    Ok(bundle)
+}
@@ -0,0 +1,444 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use crate::config_loader::LatencyConfig;
+use crate::corpus::{CorpusFilter, MODEL_CATEGORY_SEPARATOR};
+use crate::error::CustomError;
+use crate::reply::{Reply, ReplyChoice};
+use crate::server::AppState;
+
+/// Folder scanned for responses when no other folder is configured
+pub const DEFAULT_RESPONSE_DIR: &str = "zresponse";
+
+#[derive(Deserialize, Clone)]
+pub struct FilesConfig {
+    /// Folder holding the response files
+    #[serde(default = "default_dir")]
+    pub dir: String,
+    /// Glob patterns, relative to `dir`, of the files served; `**` matches
+    /// any number of folders
+    #[serde(default = "default_patterns")]
+    pub patterns: Vec<String>,
+    /// How often the response folder is checked for changes; 0 disables
+    /// hot reload
+    #[serde(default = "default_watch_interval_ms")]
+    pub watch_interval_ms: u64,
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            patterns: default_patterns(),
+            watch_interval_ms: default_watch_interval_ms(),
+        }
+    }
+}
+
+fn default_dir() -> String {
+    DEFAULT_RESPONSE_DIR.to_string()
+}
+
+fn default_patterns() -> Vec<String> {
+    ["*.md", "*.txt", "*.json", "*/*.md", "*/*.txt", "*/*.json"].map(String::from).to_vec()
+}
+
+fn default_watch_interval_ms() -> u64 {
+    2000
+}
+
+/// Optional YAML front-matter at the top of a markdown or text response
+/// file, between `---` lines. JSON response files carry the same fields at
+/// their top level.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FrontMatter {
+    /// Relative chance of being picked; 0 or less never picks the file
+    #[serde(default)]
+    pub weight: Option<f64>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub persona: Option<String>,
+    /// Stream pacing for this response instead of `latency`
+    #[serde(default)]
+    pub latency: Option<LatencyConfig>,
+    /// `finish_reason` of the final chunk, e.g. `length`; in a JSON file,
+    /// the default for choices that don't set one
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    /// Models this response may be served for; empty serves every model
+    #[serde(default)]
+    pub models: Vec<String>,
+}
+
+impl FrontMatter {
+    pub fn serves_model(&self, model: Option<&str>) -> bool {
+        if self.models.is_empty() {
+            return true;
+        }
+        let Some(model) = model else {
+            return false;
+        };
+        let base = model.split(MODEL_CATEGORY_SEPARATOR).next().unwrap_or(model);
+        self.models
+            .iter()
+            .any(|tag| tag.eq_ignore_ascii_case(model) || tag.eq_ignore_ascii_case(base))
+    }
+}
+
+/// Split a markdown file into its front-matter (if any) and body. Invalid
+/// front-matter is logged and ignored.
+pub fn split_front_matter<'a>(file: &str, content: &'a str) -> (Option<FrontMatter>, &'a str) {
+    let Some(rest) = content.strip_prefix("---\n").or_else(|| content.strip_prefix("---\r\n")) else {
+        return (None, content);
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            let yaml = &rest[..offset];
+            let body = rest[offset + line.len()..].trim_start_matches(['\r', '\n']);
+            return match serde_yaml::from_str::<FrontMatter>(yaml) {
+                Ok(front_matter) => (Some(front_matter), body),
+                Err(e) => {
+                    warn!("Ignoring invalid front-matter in {}: {}", file, e);
+                    (None, body)
+                }
+            };
+        }
+        offset += line.len();
+    }
+    (None, content)
+}
+
+/// Extensions of the files the folder serves
+const EXTENSIONS: [&str; 3] = ["md", "txt", "json"];
+
+/// A response file: its metadata and what it answers
+#[derive(Debug, Clone, Default)]
+pub struct ResponseFile {
+    pub front_matter: FrontMatter,
+    pub reply: Reply,
+}
+
+/// A JSON response file: front-matter fields and the `choices` of a
+/// non-streaming chat completion, so a real OpenAI response can be used as is
+#[derive(Deserialize)]
+struct JsonResponseFile {
+    #[serde(flatten)]
+    front_matter: FrontMatter,
+    choices: Vec<ReplyChoice>,
+}
+
+impl ResponseFile {
+    /// Parse `content` by the extension of `file`: markdown and text with
+    /// optional front-matter, or a JSON response
+    pub fn parse(file: &str, content: &str) -> Result<Self, String> {
+        if !file.ends_with(".json") {
+            let (front_matter, body) = split_front_matter(file, content);
+            let front_matter = front_matter.unwrap_or_default();
+            let reply = Reply::text(body).with_finish_reason(front_matter.finish_reason.clone());
+            return Ok(Self { front_matter, reply });
+        }
+        let parsed: JsonResponseFile = serde_json::from_str(content).map_err(|e| e.to_string())?;
+        if parsed.choices.is_empty() {
+            return Err("declares no choices".to_string());
+        }
+        let reply = Reply { choices: parsed.choices, citations: Vec::new() }.with_finish_reason(parsed.front_matter.finish_reason.clone());
+        Ok(Self { front_matter: parsed.front_matter, reply })
+    }
+}
+
+/// The response files matched by `files.patterns`, their per-model
+/// subfolders and parsed contents
+pub struct ResponseFolder {
+    root: String,
+    patterns: Vec<String>,
+    index: RwLock<FolderIndex>,
+}
+
+#[derive(Default)]
+struct FolderIndex {
+    /// Matched files, relative to the root
+    files: Vec<String>,
+    /// First-level folders holding matched files
+    subfolders: Vec<String>,
+    contents: HashMap<String, CachedFile>,
+}
+
+/// File as read from disk, valid while the file keeps its size and
+/// modification time
+struct CachedFile {
+    stamp: Stamp,
+    file: Arc<ResponseFile>,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl From<&Metadata> for Stamp {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+impl ResponseFolder {
+    /// Index the files under `config.dir` matching `config.patterns`
+    pub fn new(config: &FilesConfig) -> Result<Self, CustomError> {
+        for pattern in &config.patterns {
+            glob::Pattern::new(pattern)
+                .map_err(|e| CustomError::ConfigError(format!("Invalid files.patterns entry '{}': {}", pattern, e)))?;
+        }
+        let folder = Self {
+            root: config.dir.trim_end_matches('/').to_string(),
+            patterns: config.patterns.clone(),
+            index: RwLock::new(FolderIndex::default()),
+        };
+        folder.rescan();
+        Ok(folder)
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// Number of files currently matched
+    pub fn len(&self) -> usize {
+        self.index.read().unwrap().files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Matched files relative to the root, as of the last scan
+    pub fn files(&self) -> Vec<String> {
+        self.index.read().unwrap().files.clone()
+    }
+
+    /// Matched `.md`, `.txt` and `.json` files relative to the root, sorted,
+    /// including those that cannot be read or parsed
+    pub fn matched_files(&self) -> Vec<String> {
+        let mut files: Vec<String> = self
+            .patterns
+            .iter()
+            .filter_map(|pattern| glob::glob(&format!("{}/{}", self.root, pattern)).ok())
+            .flatten()
+            .filter_map(Result::ok)
+            .filter(|path| path.is_file())
+            .filter(|path| path.extension().is_some_and(|ext| EXTENSIONS.iter().any(|supported| ext == *supported)))
+            .filter_map(|path| {
+                path.strip_prefix(&self.root)
+                    .ok()
+                    .and_then(|relative| relative.to_str())
+                    .map(|relative| relative.replace('\\', "/"))
+            })
+            .collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// Re-match the files and re-read them. Files that cannot be read or
+    /// parsed are left out.
+    pub fn rescan(&self) {
+        let mut contents = HashMap::new();
+        for file in self.matched_files() {
+            let path = self.path(&file);
+            let (Ok(metadata), Ok(content)) = (fs::metadata(&path), fs::read_to_string(&path)) else {
+                continue;
+            };
+            match ResponseFile::parse(&file, &content) {
+                Ok(parsed) => {
+                    let stamp = Stamp::from(&metadata);
+                    contents.insert(file, CachedFile { stamp, file: Arc::new(parsed) });
+                }
+                Err(e) => warn!("Skipping response file {}: {}", path, e),
+            }
+        }
+
+        let mut files: Vec<String> = contents.keys().cloned().collect();
+        files.sort();
+        let mut subfolders: Vec<String> = files
+            .iter()
+            .filter_map(|file| file.split_once('/').map(|(subfolder, _)| subfolder.to_string()))
+            .collect();
+        subfolders.dedup();
+        if !subfolders.is_empty() {
+            debug!("Per-model response folders in {}: {:?}", self.root, subfolders);
+        }
+
+        *self.index.write().unwrap() = FolderIndex { files, subfolders, contents };
+    }
+
+    pub fn path(&self, file: &str) -> String {
+        format!("{}/{}", self.root, file)
+    }
+
+    /// `file` (relative to the root), read from disk only when its size or
+    /// modification time changed since it was last read
+    pub async fn read(&self, file: &str) -> io::Result<Arc<ResponseFile>> {
+        let path = self.path(file);
+        let stamp = Stamp::from(&tokio::fs::metadata(&path).await?);
+        let cached = {
+            let index = self.index.read().unwrap();
+            index.contents.get(file).filter(|cached| cached.stamp == stamp).map(|cached| cached.file.clone())
+        };
+        if let Some(parsed) = cached {
+            return Ok(parsed);
+        }
+
+        debug!("Reading response file {}", path);
+        let content = tokio::fs::read_to_string(&path).await?;
+        let parsed = ResponseFile::parse(file, &content)
+            .map(Arc::new)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+        let cached = CachedFile { stamp, file: parsed.clone() };
+        self.index.write().unwrap().contents.insert(file.to_string(), cached);
+        Ok(parsed)
+    }
+
+    /// Files that may be served for `filter`, with their weights. They come
+    /// from the subfolder named after the requested model (see
+    /// [`subfolder_for`]), else from the root (or from every folder when no
+    /// file sits directly in the root), and are narrowed by front-matter and
+    /// then to the preferred language when any file is in it.
+    pub fn candidates(&self, filter: &CorpusFilter) -> Vec<(String, f64)> {
+        let index = self.index.read().unwrap();
+        let subfolder = subfolder_for(&index.subfolders, filter.model.as_deref());
+        let nested_only = index.files.iter().all(|file| file.contains('/'));
+        let candidates: Vec<(&String, &FrontMatter)> = index
+            .files
+            .iter()
+            .filter(|file| match (&subfolder, file.split_once('/')) {
+                (Some(subfolder), Some((folder, _))) => folder == subfolder,
+                (None, None) => true,
+                (None, Some(_)) => nested_only,
+                _ => false,
+            })
+            .filter_map(|file| index.contents.get(file).map(|cached| (file, &cached.file.front_matter)))
+            .filter(|(_, meta)| {
+                meta.serves_model(filter.model.as_deref())
+                    && filter.matches_fields(
+                        meta.category.as_deref().unwrap_or_default(),
+                        meta.persona.as_deref().unwrap_or_default(),
+                    )
+                    && meta.weight.unwrap_or(1.0) > 0.0
+            })
+            .collect();
+        let in_language = |meta: &FrontMatter| filter.prefers_language(meta.language.as_deref().unwrap_or_default());
+        let prefer_language = candidates.iter().any(|(_, meta)| in_language(meta));
+        candidates
+            .into_iter()
+            .filter(|(_, meta)| !prefer_language || in_language(meta))
+            .map(|(file, meta)| (file.clone(), meta.weight.unwrap_or(1.0)))
+            .collect()
+    }
+
+    /// Cheap signature of the matched files (names, sizes and modification
+    /// times)
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for file in self.matched_files() {
+            file.hash(&mut hasher);
+            if let Ok(metadata) = fs::metadata(self.path(&file)) {
+                metadata.len().hash(&mut hasher);
+                metadata.modified().ok().hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+}
+
+/// Subfolder serving `model`: the one named exactly like the model, else
+/// the most specific one whose name is a run of the model's `-`/`_`/`.`
+/// separated tokens. Runs ending closer to the end of the model name win,
+/// as the variant comes last (`mini/` serves `gpt-4o-mini` even next to
+/// `gpt-4o/`), then longer runs. `None` means the root folder.
+fn subfolder_for(subfolders: &[String], model: Option<&str>) -> Option<String> {
+    let model = model?.to_lowercase();
+    if let Some(exact) = subfolders.iter().find(|name| name.to_lowercase() == model) {
+        return Some(exact.clone());
+    }
+    let model_tokens = tokens(&model);
+    subfolders
+        .iter()
+        .filter_map(|name| {
+            let lowercase = name.to_lowercase();
+            let name_tokens = tokens(&lowercase);
+            let end = model_tokens
+                .windows(name_tokens.len().max(1))
+                .rposition(|window| window == name_tokens.as_slice())?
+                + name_tokens.len();
+            Some(((end, name_tokens.len()), name))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, name)| name.clone())
+}
+
+fn tokens(name: &str) -> Vec<&str> {
+    name.split(['-', '_', '.']).filter(|token| !token.is_empty()).collect()
+}
+
+/// Poll the response folder and, when anything changed, re-index it so the
+/// next request sees the new files
+pub async fn watch(state: Arc<AppState>, interval: Duration) {
+    let fingerprint = |state: Arc<AppState>| async move {
+        tokio::task::spawn_blocking(move || state.folders.fingerprint()).await.ok()
+    };
+    let mut last = fingerprint(state.clone()).await;
+    loop {
+        tokio::time::sleep(interval).await;
+        let current = fingerprint(state.clone()).await;
+        if current == last {
+            continue;
+        }
+        last = current;
+        info!("event=reload folder={} Response folder changed, reloading", state.folders.root);
+        let rescan_state = state.clone();
+        let _ = tokio::task::spawn_blocking(move || rescan_state.folders.rescan()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::subfolder_for;
+
+    fn folders(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn variant_suffix_beats_base_model() {
+        let subfolders = folders(&["gpt-4o", "mini"]);
+        assert_eq!(subfolder_for(&subfolders, Some("gpt-4o-mini")).as_deref(), Some("mini"));
+        assert_eq!(subfolder_for(&subfolders, Some("gpt-4o-mini-2024-07-18")).as_deref(), Some("mini"));
+        assert_eq!(subfolder_for(&subfolders, Some("gpt-4o")).as_deref(), Some("gpt-4o"));
+        assert_eq!(subfolder_for(&subfolders, Some("gpt-4o-2024-08-06")).as_deref(), Some("gpt-4o"));
+    }
+
+    #[test]
+    fn exact_name_wins_and_tokens_must_match_whole() {
+        let subfolders = folders(&["gpt-4o", "GPT-4o-mini", "o"]);
+        assert_eq!(subfolder_for(&subfolders, Some("gpt-4o-mini")).as_deref(), Some("GPT-4o-mini"));
+        assert_eq!(subfolder_for(&subfolders, Some("gpt-4omni")), None);
+        assert_eq!(subfolder_for(&subfolders, None), None);
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+use std::time::Instant;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest};
+use glob::Pattern;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+use crate::config_loader::ResponseHeadersConfig;
+use crate::error::CustomError;
+use crate::request_id::request_id;
+use crate::server::AppState;
+
+/// An extra response header. `value` may hold placeholders filled in per
+/// response:
+///
+/// - `{request_id}`, `{method}`, `{path}`, `{region}`, `{processing_ms}`
+/// - `{uuid}`, `{hex:N}` (N random hex digits), `{digits:N}`
+/// - `{unix}`, `{unix_ms}`, `{date}` (HTTP date)
+/// - `{random:a|b|c}`, one of the listed values
+#[derive(Deserialize, Clone, Debug)]
+pub struct CustomHeader {
+    pub name: String,
+    pub value: String,
+    /// Path globs the header is limited to, e.g. `/v1/*`; every endpoint
+    /// when empty
+    #[serde(default, deserialize_with = "path_patterns")]
+    pub paths: Vec<Pattern>,
+}
+
+fn path_patterns<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Pattern>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+impl CustomHeader {
+    fn applies_to(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|pattern| pattern.matches(path))
+    }
+}
+
+/// Reject custom headers whose name is not a valid header name
+pub fn validate(config: &ResponseHeadersConfig) -> Result<(), CustomError> {
+    for header in &config.custom {
+        HeaderName::from_bytes(header.name.as_bytes()).map_err(|_| {
+            CustomError::ConfigError(format!("invalid response header name '{}'", header.name))
+        })?;
+    }
+    Ok(())
+}
+
+/// `template` with its placeholders filled in for `req`; unknown
+/// placeholders are left as written
+fn render(template: &str, req: &HttpRequest, config: &ResponseHeadersConfig, processing_ms: &str) -> String {
+    let mut rng = rand::thread_rng();
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start + 1..end];
+        let (name, argument) = placeholder.split_once(':').unwrap_or((placeholder, ""));
+        let length = || argument.parse::<usize>().unwrap_or(16);
+        let value = match name {
+            "request_id" => request_id(req),
+            "method" => req.method().to_string(),
+            "path" => req.path().to_string(),
+            "region" => config.region.clone(),
+            "processing_ms" => processing_ms.to_string(),
+            "uuid" => uuid::Uuid::new_v4().to_string(),
+            "hex" => (0..length()).map(|_| format!("{:x}", rng.gen_range(0..16))).collect(),
+            "digits" => (0..length()).map(|_| rng.gen_range(0..10).to_string()).collect(),
+            "unix" => chrono::Utc::now().timestamp().to_string(),
+            "unix_ms" => chrono::Utc::now().timestamp_millis().to_string(),
+            "date" => chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            "random" => argument.split('|').collect::<Vec<_>>().choose(&mut rng).unwrap_or(&"").to_string(),
+            _ => rest[start..=end].to_string(),
+        };
+        rendered.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Middleware adding the metadata headers OpenAI sends on every response,
+/// so client telemetry that parses them works against the simulator, plus
+/// the configured custom headers.
+/// `openai-processing-ms` is the time until the response head is ready,
+/// which for streams is before the first chunk.
+pub async fn provider_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let state = req.app_data::<web::Data<Arc<AppState>>>().cloned();
+
+    let mut res = next.call(req).await?;
+    let Some(state) = state else {
+        return Ok(res);
+    };
+    let config = &state.config.response_headers;
+
+    let processing_ms = started.elapsed().as_millis().to_string();
+    let headers = res.headers_mut();
+    for (name, value) in [
+        ("openai-processing-ms", processing_ms.as_str()),
+        ("openai-organization", config.organization.as_str()),
+        ("openai-version", "2020-10-01"),
+        ("x-sim-region", config.region.as_str()),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+    let path = res.request().path().to_string();
+    for header in config.custom.iter().filter(|header| header.applies_to(&path)) {
+        let value = render(&header.value, res.request(), config, &processing_ms);
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(header.name.as_bytes()), HeaderValue::from_str(&value)) {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,76 @@
+use std::fmt::Display;
+use std::sync::Mutex;
+use serde::Serialize;
+
+/// How well a subsystem works, from best to worst
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    /// Working with reduced function, e.g. serving a fallback or dropping
+    /// output
+    Degraded,
+    /// Not working at all
+    Down,
+}
+
+/// One subsystem as `/readyz` reports it
+#[derive(Serialize, Debug)]
+pub struct SubsystemHealth {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<SinkFailure>,
+}
+
+impl SubsystemHealth {
+    pub fn new(status: HealthStatus, detail: impl Into<String>) -> Self {
+        Self { status, detail: Some(detail.into()), failure: None }
+    }
+}
+
+/// Failures of an output since it last delivered
+#[derive(Serialize, Clone, Debug)]
+pub struct SinkFailure {
+    pub error: String,
+    /// First failure of the streak, RFC 3339
+    pub since: String,
+    pub last_at: String,
+    pub failures: u64,
+}
+
+/// Outcome of the latest deliveries to an output (access log, webhooks,
+/// error reports): failing from a failed delivery until one succeeds again
+#[derive(Default)]
+pub struct SinkHealth {
+    failure: Mutex<Option<SinkFailure>>,
+}
+
+impl SinkHealth {
+    pub fn record_success(&self) {
+        self.failure.lock().unwrap().take();
+    }
+
+    pub fn record_failure(&self, error: impl Display) {
+        let now = chrono::Utc::now().to_rfc3339();
+        let mut failure = self.failure.lock().unwrap();
+        match &mut *failure {
+            Some(failure) => {
+                failure.error = error.to_string();
+                failure.last_at = now;
+                failure.failures += 1;
+            }
+            None => {
+                *failure = Some(SinkFailure { error: error.to_string(), since: now.clone(), last_at: now, failures: 1 })
+            }
+        }
+    }
+
+    pub fn health(&self) -> SubsystemHealth {
+        match self.failure.lock().unwrap().clone() {
+            Some(failure) => SubsystemHealth { status: HealthStatus::Degraded, detail: None, failure: Some(failure) },
+            None => SubsystemHealth { status: HealthStatus::Ok, detail: None, failure: None },
+        }
+    }
+}
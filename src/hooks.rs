@@ -0,0 +1,261 @@
+//! Rhai scripts run at fixed points of a chat completion, so test authors
+//! can reshape behaviour without recompiling.
+//!
+//! Every hook is a script file run with one variable in scope, which the
+//! script changes in place:
+//!
+//! - `on_request`: `request`, the request body, before anything reads it
+//! - `on_select`: `reply`, the `{"choices": [...]}` about to be streamed,
+//!   next to the (read-only) `request`
+//! - `on_chunk`: `chunk`, the content of one streamed delta, next to its
+//!   position `index` within the choice
+
+use std::fmt;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use log::warn;
+use serde::Deserialize;
+use serde_json::Value;
+use crate::error::{ApiError, CustomError};
+use crate::reply::Reply;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct HooksConfig {
+    #[serde(default)]
+    pub on_request: Option<String>,
+    #[serde(default)]
+    pub on_select: Option<String>,
+    #[serde(default)]
+    pub on_chunk: Option<String>,
+    /// Operations a single hook run may take before it is aborted
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            on_request: None,
+            on_select: None,
+            on_chunk: None,
+            max_operations: default_max_operations(),
+        }
+    }
+}
+
+fn default_max_operations() -> u64 {
+    1_000_000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hook {
+    OnRequest,
+    OnSelect,
+    OnChunk,
+}
+
+impl Hook {
+    const ALL: [Hook; 3] = [Hook::OnRequest, Hook::OnSelect, Hook::OnChunk];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::OnRequest => "on_request",
+            Hook::OnSelect => "on_select",
+            Hook::OnChunk => "on_chunk",
+        }
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl HooksConfig {
+    fn script(&self, hook: Hook) -> Option<&str> {
+        match hook {
+            Hook::OnRequest => self.on_request.as_deref(),
+            Hook::OnSelect => self.on_select.as_deref(),
+            Hook::OnChunk => self.on_chunk.as_deref(),
+        }
+    }
+}
+
+/// The configured hook scripts, compiled at startup
+#[derive(Default)]
+pub struct Hooks {
+    scripts: Option<imp::Scripts>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks").field("enabled", &self.scripts.is_some()).finish()
+    }
+}
+
+impl Hooks {
+    pub fn load(config: &HooksConfig) -> Result<Self, CustomError> {
+        let scripts: Vec<_> = Hook::ALL
+            .into_iter()
+            .filter_map(|hook| config.script(hook).map(|path| (hook, path)))
+            .collect();
+        if scripts.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(Self {
+            scripts: Some(imp::Scripts::load(&scripts, config.max_operations)?),
+        })
+    }
+
+    pub fn has(&self, hook: Hook) -> bool {
+        self.scripts.as_ref().is_some_and(|scripts| scripts.has(hook))
+    }
+
+    fn run(&self, hook: Hook, value: Value, context: Vec<(&'static str, Value)>) -> Result<Value, CustomError> {
+        match &self.scripts {
+            Some(scripts) if scripts.has(hook) => scripts.run(hook, value, context).map_err(|e| {
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", format!("Hook {} failed: {}", hook, e)).into()
+            }),
+            _ => Ok(value),
+        }
+    }
+
+    /// The request body as rewritten by `on_request`. Bodies that are not
+    /// JSON are left alone.
+    pub fn on_request(&self, body: Bytes) -> Result<Bytes, CustomError> {
+        if !self.has(Hook::OnRequest) {
+            return Ok(body);
+        }
+        let Ok(request) = serde_json::from_slice::<Value>(&body) else {
+            return Ok(body);
+        };
+        let request = self.run(Hook::OnRequest, request, Vec::new())?;
+        Ok(Bytes::from(request.to_string()))
+    }
+
+    /// `reply` as rewritten by `on_select` for the request in `body`
+    pub fn on_select(&self, reply: Reply, body: &[u8]) -> Result<Reply, CustomError> {
+        if !self.has(Hook::OnSelect) {
+            return Ok(reply);
+        }
+        let request = serde_json::from_slice(body).unwrap_or(Value::Null);
+        let value = serde_json::to_value(&reply).unwrap_or(Value::Null);
+        let value = self.run(Hook::OnSelect, value, vec![("request", request)])?;
+        serde_json::from_value(value).map_err(|e| {
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server_error",
+                format!("Hook on_select returned an invalid reply: {}", e),
+            )
+            .into()
+        })
+    }
+
+    /// The `index`th content delta of a choice as rewritten by `on_chunk`.
+    /// The stream is already under way, so a failing hook only logs and
+    /// leaves the chunk unchanged.
+    pub fn on_chunk(&self, chunk: String, index: usize) -> String {
+        if !self.has(Hook::OnChunk) {
+            return chunk;
+        }
+        match self.run(Hook::OnChunk, Value::String(chunk.clone()), vec![("index", Value::from(index))]) {
+            Ok(Value::String(rewritten)) => rewritten,
+            Ok(other) => other.to_string(),
+            Err(e) => {
+                warn!("{}", e);
+                chunk
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rhai")]
+mod imp {
+    use std::collections::HashMap;
+    use log::info;
+    use rhai::{Dynamic, Engine, Scope, AST};
+    use serde_json::Value;
+    use crate::error::CustomError;
+    use super::Hook;
+
+    pub struct Scripts {
+        engine: Engine,
+        asts: HashMap<Hook, AST>,
+    }
+
+    impl Scripts {
+        pub(super) fn load(scripts: &[(Hook, &str)], max_operations: u64) -> Result<Self, CustomError> {
+            let mut engine = Engine::new();
+            engine.set_max_operations(max_operations);
+            let mut asts = HashMap::new();
+            for (hook, path) in scripts {
+                let ast = engine
+                    .compile_file(path.into())
+                    .map_err(|e| CustomError::ConfigError(format!("cannot compile {} hook {}: {}", hook, path, e)))?;
+                info!("Loaded {} hook from {}", hook, path);
+                asts.insert(*hook, ast);
+            }
+            Ok(Self { engine, asts })
+        }
+
+        pub(super) fn has(&self, hook: Hook) -> bool {
+            self.asts.contains_key(&hook)
+        }
+
+        pub(super) fn run(&self, hook: Hook, value: Value, context: Vec<(&'static str, Value)>) -> Result<Value, String> {
+            let Some(ast) = self.asts.get(&hook) else {
+                return Ok(value);
+            };
+            let mut scope = Scope::new();
+            for (name, value) in context {
+                scope.push_constant_dynamic(name, to_dynamic(&value)?);
+            }
+            scope.push_dynamic(variable(hook), to_dynamic(&value)?);
+            self.engine.run_ast_with_scope(&mut scope, ast).map_err(|e| e.to_string())?;
+            let result = scope.get_value::<Dynamic>(variable(hook)).unwrap_or_default();
+            rhai::serde::from_dynamic(&result).map_err(|e| e.to_string())
+        }
+    }
+
+    /// Variable the script of `hook` changes in place
+    fn variable(hook: Hook) -> &'static str {
+        match hook {
+            Hook::OnRequest => "request",
+            Hook::OnSelect => "reply",
+            Hook::OnChunk => "chunk",
+        }
+    }
+
+    fn to_dynamic(value: &Value) -> Result<Dynamic, String> {
+        rhai::serde::to_dynamic(value).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(not(feature = "rhai"))]
+mod imp {
+    use serde_json::Value;
+    use crate::error::CustomError;
+    use super::Hook;
+
+    /// Stand-in when the crate is built without the `rhai` feature: no
+    /// script can be loaded
+    pub enum Scripts {}
+
+    impl Scripts {
+        pub(super) fn load(scripts: &[(Hook, &str)], _max_operations: u64) -> Result<Self, CustomError> {
+            let (hook, path) = scripts[0];
+            Err(CustomError::ConfigError(format!(
+                "cannot load {} hook {}: built without the `rhai` feature", hook, path
+            )))
+        }
+
+        pub(super) fn has(&self, _hook: Hook) -> bool {
+            match *self {}
+        }
+
+        pub(super) fn run(&self, _hook: Hook, _value: Value, _context: Vec<(&'static str, Value)>) -> Result<Value, String> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::path::Path;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+use crate::cli::Args;
+use crate::config_loader::Config;
+use crate::db::{self, DATABASE_NAME};
+use crate::error::CustomError;
+use crate::failover::DbEndpoints;
+use crate::migrations;
+
+/// One row of an import file; only `jawaban` is required, so the JSONL
+/// `export` writes imports as is
+#[derive(Deserialize, Debug)]
+struct ImportRow {
+    /// Kept when given, generated otherwise
+    #[serde(default)]
+    qa_id: Option<String>,
+    #[serde(default)]
+    pertanyaan: String,
+    jawaban: String,
+    #[serde(default)]
+    referensi: String,
+    #[serde(default)]
+    category: String,
+    #[serde(default)]
+    language: String,
+    #[serde(default)]
+    persona: String,
+    #[serde(default)]
+    weight: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Jsonl,
+    Csv,
+}
+
+/// Parse every `--file` (JSONL, or CSV with a header row; by extension
+/// unless `--format` is given), then insert the rows into `database.table`
+/// in batches of `--batch-size` (1000), printing progress. Nothing is
+/// inserted when any row is invalid, nor with `--dry-run`. With
+/// `database.create_schema` the table is created first when missing.
+pub async fn run(args: &Args) -> Result<(), CustomError> {
+    let config = Config::read(args.profile())?;
+    let files = args.values("file");
+    if files.is_empty() {
+        return Err(CustomError::ConfigError("missing --file".to_string()));
+    }
+    let format = args.value("format").map(parse_format).transpose()?;
+    let batch_size: usize = args.parse_or("batch-size", 1000)?;
+    let columns = db::table_columns(&config.database)
+        .map_err(|e| CustomError::ConfigError(format!("cannot import into {}: {}", config.database.table, e)))?;
+
+    let mut rows = Vec::new();
+    for file in files {
+        let format = format.unwrap_or(if file.ends_with(".csv") { Format::Csv } else { Format::Jsonl });
+        let parsed = read_rows(file, format)?;
+        println!("{}: {} rows", file, parsed.len());
+        rows.extend(parsed);
+    }
+    let lines = rows
+        .into_iter()
+        .map(|(origin, row)| to_json(&row, &columns).map_err(|e| CustomError::ConfigError(format!("{}: {}", origin, e))))
+        .collect::<Result<Vec<String>, CustomError>>()?;
+
+    let target = format!("{}.{}", DATABASE_NAME, config.database.table);
+    if args.flag("dry-run") {
+        println!("Dry run: {} rows would be inserted into {}", lines.len(), target);
+        return Ok(());
+    }
+    let endpoints = DbEndpoints::new(&config.database)?;
+    if endpoints.has_replicas() {
+        endpoints.check().await;
+    }
+    let client = endpoints.client();
+    if config.database.create_schema {
+        migrations::create_schema(&client, &config.database).await?;
+    }
+    let insert = format!(
+        "INSERT INTO {} ({}) FORMAT JSONEachRow",
+        config.database.table,
+        columns.iter().map(|(_, column, _)| column.as_str()).collect::<Vec<_>>().join(", ")
+    );
+    let mut inserted = 0;
+    for batch in lines.chunks(batch_size.max(1)) {
+        // `?` binds query arguments; `??` is a literal one
+        let query = format!("{}\n{}", insert, batch.join("\n").replace('?', "??"));
+        client.query(&query).execute().await.map_err(|e| {
+            CustomError::ConfigError(format!(
+                "inserting into {} failed after {} of {} rows: {}",
+                target,
+                inserted,
+                lines.len(),
+                e
+            ))
+        })?;
+        inserted += batch.len();
+        println!("Inserted {}/{} rows", inserted, lines.len());
+    }
+    println!("Imported {} rows into {}", inserted, target);
+    Ok(())
+}
+
+fn parse_format(format: &str) -> Result<Format, CustomError> {
+    match format {
+        "jsonl" => Ok(Format::Jsonl),
+        "csv" => Ok(Format::Csv),
+        _ => Err(CustomError::ConfigError(format!("unsupported --format '{}', expected jsonl or csv", format))),
+    }
+}
+
+/// Rows of one file, each with where it was read from for error messages
+fn read_rows(file: &str, format: Format) -> Result<Vec<(String, ImportRow)>, CustomError> {
+    let invalid = |at: String, e: String| CustomError::ConfigError(format!("{}: {}: {}", file, at, e));
+    let content = std::fs::read_to_string(Path::new(file))
+        .map_err(|e| CustomError::ConfigError(format!("cannot read {}: {}", file, e)))?;
+    let mut rows = Vec::new();
+    match format {
+        Format::Jsonl => {
+            for (index, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let at = format!("line {}", index + 1);
+                let row = serde_json::from_str(line).map_err(|e| invalid(at.clone(), e.to_string()))?;
+                rows.push((at, row));
+            }
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(content.as_bytes());
+            for row in reader.deserialize() {
+                let row: ImportRow = row.map_err(|e| invalid("csv".to_string(), e.to_string()))?;
+                rows.push((format!("record {}", rows.len() + 1), row));
+            }
+        }
+    }
+    for (at, row) in &rows {
+        if row.jawaban.trim().is_empty() {
+            return Err(invalid(at.clone(), "empty jawaban".to_string()));
+        }
+    }
+    Ok(rows.into_iter().map(|(at, row)| (format!("{}: {}", file, at), row)).collect())
+}
+
+/// A `JSONEachRow` line of `row` keyed by its mapped columns
+fn to_json(row: &ImportRow, columns: &[(&'static str, String, &'static str)]) -> Result<String, String> {
+    let qa_id = match row.qa_id.as_deref().map(str::trim).filter(|id| !id.is_empty()) {
+        Some(id) => Uuid::parse_str(id).map_err(|e| format!("invalid qa_id '{}': {}", id, e))?,
+        None => Uuid::new_v4(),
+    };
+    let mut object = Map::new();
+    for (field, column, _) in columns {
+        let value = match *field {
+            "qa_id" => Value::from(qa_id.to_string()),
+            "pertanyaan" => Value::from(row.pertanyaan.as_str()),
+            "jawaban" => Value::from(row.jawaban.as_str()),
+            "referensi" => Value::from(row.referensi.as_str()),
+            "category" => Value::from(row.category.as_str()),
+            "language" => Value::from(row.language.as_str()),
+            "persona" => Value::from(row.persona.as_str()),
+            _ => Value::from(row.weight.unwrap_or(1.0)),
+        };
+        object.insert(column.trim_matches('`').to_string(), value);
+    }
+    Ok(Value::Object(object).to_string())
+}
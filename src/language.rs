@@ -0,0 +1,39 @@
+/// Header naming the language a response should be in, e.g. `id` or `en`
+pub const LANGUAGE_HEADER: &str = "x-sim-lang";
+/// Query parameter naming the language a response should be in
+pub const LANGUAGE_QUERY_PARAM: &str = "lang";
+
+/// Frequent function words that rarely appear in the other language
+const INDONESIAN_WORDS: &[&str] = &[
+    "yang", "dan", "di", "ke", "dari", "ini", "itu", "dengan", "untuk", "tidak", "ada", "saya", "apa",
+    "bagaimana", "adalah", "akan", "bisa", "kami", "anda", "kamu", "atau", "juga", "sudah", "belum",
+    "dalam", "pada", "tolong", "mohon", "berapa", "kapan", "mengapa", "kenapa", "bagi", "seperti",
+];
+const ENGLISH_WORDS: &[&str] = &[
+    "the", "and", "is", "are", "of", "to", "in", "for", "with", "this", "that", "what", "how", "can",
+    "you", "your", "my", "not", "please", "does", "do", "will", "would", "should", "when", "why",
+    "which", "about", "from", "have", "has", "be", "it",
+];
+
+/// Guess whether `text` is Indonesian (`id`) or English (`en`) by counting
+/// common function words. `None` when neither clearly dominates.
+pub fn detect(text: &str) -> Option<&'static str> {
+    let (mut indonesian, mut english) = (0, 0);
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+    {
+        if INDONESIAN_WORDS.contains(&word.as_str()) {
+            indonesian += 1;
+        }
+        if ENGLISH_WORDS.contains(&word.as_str()) {
+            english += 1;
+        }
+    }
+    match indonesian.cmp(&english) {
+        std::cmp::Ordering::Greater => Some("id"),
+        std::cmp::Ordering::Less => Some("en"),
+        std::cmp::Ordering::Equal => None,
+    }
+}
@@ -0,0 +1,90 @@
+//! OpenAI chat completions simulator.
+//!
+//! The binary in `main.rs` is a thin wrapper around [`Simulator`]; the same
+//! builder can be used to boot the simulator inside integration tests:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), rai_endpoint_simulator::CustomError> {
+//! use rai_endpoint_simulator::{Simulator, SourceKind};
+//!
+//! let sim = Simulator::builder()
+//!     .source(SourceKind::File)
+//!     .response_dir("tests/fixtures")
+//!     .bind_random_port()
+//!     .spawn()
+//!     .await?;
+//! println!("simulator listening on {}", sim.url());
+//! sim.stop().await;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod azure;
+pub mod batch;
+pub mod bench;
+pub mod capture;
+pub mod check;
+pub mod circuit;
+pub mod cli;
+pub mod code_interpreter;
+pub mod completion;
+pub mod config_loader;
+pub mod content_filter;
+pub mod corpus;
+pub mod dataset;
+pub mod db;
+pub mod echo;
+pub mod embedding;
+pub mod error;
+pub mod error_reporting;
+pub mod export;
+pub mod failover;
+pub mod fault;
+pub mod file_search;
+pub mod fixtures;
+pub mod folder;
+pub mod headers;
+pub mod health;
+pub mod hooks;
+pub mod import;
+pub mod language;
+pub mod load;
+pub mod logging;
+pub mod mcp;
+pub mod metrics;
+pub mod migrations;
+pub mod models;
+pub mod openrouter;
+pub mod outbound;
+pub mod profile;
+pub mod prompt_cache;
+pub mod quota;
+pub mod reply;
+pub mod request;
+pub mod request_id;
+pub mod response;
+pub mod resume;
+pub mod schema;
+pub mod script;
+pub mod selection;
+pub mod self_test;
+pub mod server;
+pub mod session;
+pub mod signals;
+pub mod simulator;
+pub mod stats;
+pub mod stored;
+pub mod stream;
+pub mod systemd;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod tiers;
+pub mod validate;
+pub mod warmup;
+pub mod wasm;
+pub mod webhook;
+pub mod websocket;
+
+pub use config_loader::{Config, SourceKind};
+pub use error::CustomError;
+pub use simulator::{RunningSimulator, Simulator, SimulatorBuilder};
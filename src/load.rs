@@ -0,0 +1,431 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use crate::config_loader::LatencyConfig;
+use crate::error::CustomError;
+use crate::metrics::{self, Metrics};
+use crate::webhook::{Event, Webhooks};
+
+/// Slow responses down as concurrency grows, so capacity tests see the
+/// knee of the latency curve instead of flat latency
+#[derive(Deserialize, Clone, Debug)]
+pub struct AdaptiveLatencyConfig {
+    #[serde(default)]
+    pub model: LatencyModel,
+    /// In-flight requests the simulated backend serves without slowing
+    /// down: the threshold of `linear`, the server count of `queueing`
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    /// `linear` only: multiplier added per request above `capacity`
+    #[serde(default = "default_per_request")]
+    pub per_request: f64,
+    /// Upper bound of the multiplier, also used once `queueing` is saturated
+    #[serde(default = "default_max_multiplier")]
+    pub max_multiplier: f64,
+}
+
+impl Default for AdaptiveLatencyConfig {
+    fn default() -> Self {
+        Self {
+            model: LatencyModel::default(),
+            capacity: default_capacity(),
+            per_request: default_per_request(),
+            max_multiplier: default_max_multiplier(),
+        }
+    }
+}
+
+fn default_capacity() -> usize {
+    100
+}
+
+fn default_per_request() -> f64 {
+    0.05
+}
+
+fn default_max_multiplier() -> f64 {
+    20.0
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyModel {
+    /// Latency does not depend on load
+    #[default]
+    None,
+    /// `1 + per_request` for every in-flight request above `capacity`
+    Linear,
+    /// Mean time in an M/M/c queue with `capacity` servers relative to the
+    /// service time, taking the in-flight requests as the offered load
+    Queueing,
+}
+
+impl AdaptiveLatencyConfig {
+    /// Factor applied to the configured latency with `in_flight` requests
+    pub fn multiplier(&self, in_flight: usize) -> f64 {
+        let multiplier = match self.model {
+            LatencyModel::None => 1.0,
+            LatencyModel::Linear => 1.0 + in_flight.saturating_sub(self.capacity) as f64 * self.per_request,
+            LatencyModel::Queueing => {
+                let servers = self.capacity.max(1);
+                let load = in_flight as f64;
+                if load >= servers as f64 {
+                    self.max_multiplier
+                } else {
+                    1.0 + erlang_c(servers, load) / (servers as f64 - load)
+                }
+            }
+        };
+        multiplier.clamp(1.0, self.max_multiplier.max(1.0))
+    }
+}
+
+/// Probability that a request has to queue in an M/M/c system with
+/// `servers` servers and `load` Erlangs of offered load (`load < servers`)
+fn erlang_c(servers: usize, load: f64) -> f64 {
+    let mut blocking = 1.0;
+    for k in 1..=servers {
+        blocking = load * blocking / (k as f64 + load * blocking);
+    }
+    let servers = servers as f64;
+    servers * blocking / (servers - load * (1.0 - blocking))
+}
+
+/// Tracks concurrency (semaphore permits and open streams) so load tests can
+/// tell when the simulator itself, not the system under test, is saturated
+pub struct LoadTracker {
+    semaphore: Arc<Semaphore>,
+    limit: usize,
+    warn_ratio: f64,
+    shed_above: Option<usize>,
+    adaptive: AdaptiveLatencyConfig,
+    active_streams: AtomicUsize,
+    /// Requests waiting for a permit
+    waiting: AtomicUsize,
+    /// Set when `limits.fair_queuing` is on
+    fair_queue: Option<Arc<Mutex<FairQueue>>>,
+    saturated: AtomicBool,
+    metrics: Arc<Metrics>,
+    webhooks: Arc<Webhooks>,
+}
+
+impl LoadTracker {
+    pub fn new(
+        limit: usize,
+        warn_ratio: f64,
+        shed_above: Option<usize>,
+        fair_queuing: bool,
+        adaptive: AdaptiveLatencyConfig,
+        metrics: Arc<Metrics>,
+        webhooks: Arc<Webhooks>,
+    ) -> Self {
+        metrics.set_gauge(&metrics::SEMAPHORE_PERMITS_TOTAL, limit as f64);
+        metrics.set_gauge(&metrics::SEMAPHORE_PERMITS_IN_USE, 0.0);
+        metrics.set_gauge(&metrics::ACTIVE_STREAMS, 0.0);
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            limit,
+            warn_ratio,
+            shed_above,
+            adaptive,
+            active_streams: AtomicUsize::new(0),
+            waiting: AtomicUsize::new(0),
+            fair_queue: fair_queuing.then(Arc::default),
+            saturated: AtomicBool::new(false),
+            metrics,
+            webhooks,
+        }
+    }
+
+    pub fn permits_in_use(&self) -> usize {
+        self.limit.saturating_sub(self.semaphore.available_permits())
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    /// Requests holding a permit or waiting for one
+    pub fn in_flight(&self) -> usize {
+        self.permits_in_use() + self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Whether a new request should be turned away instead of queued,
+    /// because `limits.shed_above` requests are already in flight
+    pub fn should_shed(&self) -> bool {
+        self.shed_above.is_some_and(|watermark| self.in_flight() >= watermark)
+    }
+
+    /// Fraction of `semaphore_limit` currently held
+    pub fn occupancy(&self) -> f64 {
+        if self.limit == 0 {
+            return 1.0;
+        }
+        self.permits_in_use() as f64 / self.limit as f64
+    }
+
+    /// `latency` stretched for the requests currently in flight
+    pub fn adapt(&self, latency: LatencyConfig) -> LatencyConfig {
+        latency.scaled(self.adaptive.multiplier(self.permits_in_use()))
+    }
+
+    /// Wait for a concurrency permit for a request of `api_key`. The
+    /// returned guard holds it until the request, including its response
+    /// stream, is finished.
+    pub async fn acquire(self: &Arc<Self>, api_key: &str) -> Result<LoadGuard, CustomError> {
+        let waiting = Waiting::new(&self.waiting);
+        let permit = match &self.fair_queue {
+            Some(queue) => {
+                let receiver = {
+                    let mut fair_queue = queue.lock().unwrap();
+                    match self.semaphore.clone().try_acquire_owned() {
+                        Ok(permit) if fair_queue.is_empty() => Ok(permit),
+                        // Not ours to take while others wait for it
+                        Ok(permit) => {
+                            let receiver = fair_queue.push(api_key);
+                            fair_queue.hand_over(queue, permit);
+                            Err(receiver)
+                        }
+                        Err(_) => Err(fair_queue.push(api_key)),
+                    }
+                };
+                match receiver {
+                    Ok(permit) => permit,
+                    Err(receiver) => receiver.await.map_err(|_| CustomError::FetchError)?.take(),
+                }
+            }
+            None => self
+                .semaphore
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|_| CustomError::FetchError)?,
+        };
+        drop(waiting);
+        self.refresh();
+        Ok(LoadGuard {
+            tracker: self.clone(),
+            permit: Some(permit),
+            tier_permit: None,
+            streaming: false,
+        })
+    }
+
+    /// Return a permit, handing it to the next waiting request when fair
+    /// queuing is on
+    fn release(&self, permit: OwnedSemaphorePermit) {
+        match &self.fair_queue {
+            Some(queue) => queue.lock().unwrap().hand_over(queue, permit),
+            None => drop(permit),
+        }
+    }
+
+    /// Update the gauges and log when occupancy crosses the warn threshold
+    fn refresh(&self) {
+        let in_use = self.permits_in_use();
+        let active_streams = self.active_streams();
+        self.metrics.set_gauge(&metrics::SEMAPHORE_PERMITS_IN_USE, in_use as f64);
+        self.metrics.set_gauge(&metrics::ACTIVE_STREAMS, active_streams as f64);
+
+        let occupancy = self.occupancy();
+        if occupancy >= self.warn_ratio {
+            if !self.saturated.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "event=saturation state=saturated permits_in_use={} limit={} occupancy={:.2} threshold={:.2} active_streams={}",
+                    in_use, self.limit, occupancy, self.warn_ratio, active_streams
+                );
+                self.notify(Event::SaturationBreached, in_use, occupancy, active_streams);
+            }
+        } else if self.saturated.swap(false, Ordering::Relaxed) {
+            info!(
+                "event=saturation state=recovered permits_in_use={} limit={} occupancy={:.2} threshold={:.2} active_streams={}",
+                in_use, self.limit, occupancy, self.warn_ratio, active_streams
+            );
+            self.notify(Event::SaturationRecovered, in_use, occupancy, active_streams);
+        }
+    }
+
+    fn notify(&self, event: Event, in_use: usize, occupancy: f64, active_streams: usize) {
+        self.webhooks.send(
+            event,
+            serde_json::json!({
+                "permits_in_use": in_use,
+                "limit": self.limit,
+                "occupancy": occupancy,
+                "threshold": self.warn_ratio,
+                "active_streams": active_streams,
+            }),
+        );
+    }
+}
+
+/// Requests waiting for a permit, one queue per API key. Keys take turns,
+/// so each key with waiting requests gets the same share of freed permits.
+#[derive(Default)]
+struct FairQueue {
+    /// Keys with waiting requests, the next to be served first
+    turns: VecDeque<String>,
+    waiting: HashMap<String, VecDeque<oneshot::Sender<HandedOver>>>,
+}
+
+impl FairQueue {
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    fn push(&mut self, api_key: &str) -> oneshot::Receiver<HandedOver> {
+        let (sender, receiver) = oneshot::channel();
+        let queue = self.waiting.entry(api_key.to_string()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(api_key.to_string());
+        }
+        queue.push_back(sender);
+        receiver
+    }
+
+    /// Give `permit` to the oldest waiting request of the key whose turn it
+    /// is, skipping requests whose client went away; back to the semaphore
+    /// when nobody waits. `queue` is the mutex holding `self`.
+    fn hand_over(&mut self, queue: &Arc<Mutex<FairQueue>>, mut permit: OwnedSemaphorePermit) {
+        while let Some(api_key) = self.turns.pop_front() {
+            let Some(requests) = self.waiting.get_mut(&api_key) else {
+                continue;
+            };
+            while let Some(sender) = requests.pop_front() {
+                match sender.send(HandedOver { permit: Some(permit), queue: queue.clone() }) {
+                    Ok(()) => {
+                        if requests.is_empty() {
+                            self.waiting.remove(&api_key);
+                        } else {
+                            self.turns.push_back(api_key);
+                        }
+                        return;
+                    }
+                    Err(returned) => permit = returned.take(),
+                }
+            }
+            self.waiting.remove(&api_key);
+        }
+    }
+}
+
+/// A permit on its way to a waiting request. When the request goes away
+/// after the permit was sent but before it took it, dropping this hands
+/// the permit on to the next waiting request instead of returning it to
+/// the semaphore past the queue, where the others would stall.
+struct HandedOver {
+    permit: Option<OwnedSemaphorePermit>,
+    queue: Arc<Mutex<FairQueue>>,
+}
+
+impl HandedOver {
+    fn take(mut self) -> OwnedSemaphorePermit {
+        self.permit.take().expect("a handed over permit is taken once")
+    }
+}
+
+impl Drop for HandedOver {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.queue.lock().unwrap().hand_over(&self.queue, permit);
+        }
+    }
+}
+
+/// Counts a request as waiting for a permit until dropped, also when the
+/// client goes away while it waits
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Self(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A held concurrency permit, optionally counted as an open stream
+pub struct LoadGuard {
+    tracker: Arc<LoadTracker>,
+    permit: Option<OwnedSemaphorePermit>,
+    /// Permit of the API key's tier pool, released along with `permit`
+    tier_permit: Option<OwnedSemaphorePermit>,
+    streaming: bool,
+}
+
+impl LoadGuard {
+    /// Also hold the permit of a tier pool until the request is finished
+    pub fn hold(&mut self, tier_permit: Option<OwnedSemaphorePermit>) {
+        self.tier_permit = tier_permit;
+    }
+
+    /// Count this request as an open SSE stream until the guard is dropped
+    pub fn start_stream(&mut self) {
+        if !self.streaming {
+            self.streaming = true;
+            self.tracker.active_streams.fetch_add(1, Ordering::Relaxed);
+            self.tracker.refresh();
+        }
+    }
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        if self.streaming {
+            self.tracker.active_streams.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Some(permit) = self.permit.take() {
+            self.tracker.release(permit);
+        }
+        drop(self.tier_permit.take());
+        self.tracker.refresh();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::{AdaptiveLatencyConfig, LoadTracker};
+
+    fn fair_tracker(limit: usize) -> Arc<LoadTracker> {
+        Arc::new(LoadTracker::new(limit, 1.0, None, true, AdaptiveLatencyConfig::default(), Arc::default(), Arc::default()))
+    }
+
+    #[tokio::test]
+    async fn permit_handed_to_a_departed_request_goes_to_the_next_one() {
+        let tracker = fair_tracker(1);
+        let held = tracker.acquire("a").await.unwrap();
+        let departing = tokio::spawn({
+            let tracker = tracker.clone();
+            async move { tracker.acquire("b").await.map(drop) }
+        });
+        let next = tokio::spawn({
+            let tracker = tracker.clone();
+            async move { tracker.acquire("c").await }
+        });
+        // Both queue up behind the held permit
+        while tracker.waiting.load(std::sync::atomic::Ordering::Relaxed) < 2 {
+            tokio::task::yield_now().await;
+        }
+        // Sent to the first in line, which goes away before taking it
+        drop(held);
+        departing.abort();
+        let guard = tokio::time::timeout(Duration::from_secs(1), next)
+            .await
+            .expect("the next waiting request gets the permit")
+            .unwrap()
+            .unwrap();
+        assert_eq!(tracker.permits_in_use(), 1);
+        drop(guard);
+        assert_eq!(tracker.permits_in_use(), 0);
+    }
+}
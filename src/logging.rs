@@ -0,0 +1,445 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use env_logger::{Target, WriteStyle};
+use log::{info, warn, Level, LevelFilter, Log, Metadata, Record};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use crate::capture::CaptureRecord;
+use crate::error::CustomError;
+use crate::health::SinkHealth;
+
+/// Log target of sampled request records
+pub const SAMPLE_TARGET: &str = "rai_endpoint_simulator::sample";
+
+/// Where and how much the simulator logs, beyond `log_level`
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct LoggingConfig {
+    /// Share of requests, from 0.0 to 1.0, whose full record (request and
+    /// response bodies, tokens, timings) is logged as one JSON line
+    #[serde(default)]
+    pub sample_rate: f64,
+    /// File written alongside stderr
+    #[serde(default)]
+    pub file: Option<LogFileConfig>,
+    /// Syslog daemon also receiving every record
+    #[serde(default)]
+    pub syslog: Option<SyslogConfig>,
+    /// One line per chat completion, for offline load-test analysis
+    #[serde(default)]
+    pub access: Option<AccessLogConfig>,
+}
+
+/// A log file rotated by size and/or time
+#[derive(Deserialize, Clone, Debug)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Rotate once the file would grow past this many megabytes; 0 never
+    /// rotates by size
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// Rotated files kept as `<path>.1` (newest) to `<path>.<keep>`
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_max_size_mb() -> u64 {
+    100
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+/// The access log file and the format of its lines
+#[derive(Deserialize, Clone, Debug)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    #[serde(flatten)]
+    pub file: LogFileConfig,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// Common Log Format followed by `key=value` fields
+    Clf,
+}
+
+/// Time-based rotation of a log file
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Hourly,
+    Daily,
+    #[default]
+    Never,
+}
+
+impl Rotation {
+    /// Start of the period `time` falls in, in UTC
+    fn period(self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let length = match self {
+            Rotation::Hourly => TimeDelta::hours(1),
+            Rotation::Daily => TimeDelta::days(1),
+            Rotation::Never => return None,
+        };
+        time.duration_trunc(length).ok()
+    }
+}
+
+/// Where records are sent as syslog messages
+#[derive(Deserialize, Clone, Debug)]
+pub struct SyslogConfig {
+    /// Local socket path, or `host:port` of a daemon listening on UDP
+    #[serde(default = "default_syslog_address")]
+    pub address: String,
+    #[serde(default)]
+    pub facility: Facility,
+    /// Tag messages are sent under
+    #[serde(default = "default_syslog_tag")]
+    pub tag: String,
+}
+
+fn default_syslog_address() -> String {
+    "/dev/log".to_string()
+}
+
+fn default_syslog_tag() -> String {
+    env!("CARGO_PKG_NAME").to_string()
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    User,
+    #[default]
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Log `record` as JSON at info level under `SAMPLE_TARGET` when it
+    /// falls within `sample_rate`
+    pub fn sample(&self, record: &CaptureRecord) {
+        if self.sample_rate <= 0.0 || !rand::thread_rng().gen_bool(self.sample_rate.min(1.0)) {
+            return;
+        }
+        if let Ok(json) = serde_json::to_string(record) {
+            info!(target: SAMPLE_TARGET, "{}", json);
+        }
+    }
+}
+
+/// Install the global logger: stderr, plus the configured outputs, all at
+/// `level`
+pub fn init(config: &LoggingConfig, level: LevelFilter) -> Result<(), CustomError> {
+    let mut loggers: Vec<Box<dyn Log>> = vec![Box::new(env_logger::Builder::new().filter(None, level).build())];
+    if let Some(file) = &config.file {
+        let writer = RotatingFile::open(file).map_err(|e| {
+            CustomError::ConfigError(format!("Cannot open log file {}: {}", file.path.display(), e))
+        })?;
+        let logger = env_logger::Builder::new()
+            .filter(None, level)
+            .target(Target::Pipe(Box::new(writer)))
+            .write_style(WriteStyle::Never)
+            .build();
+        loggers.push(Box::new(logger));
+    }
+    if let Some(syslog) = &config.syslog {
+        let logger = Syslog::connect(syslog, level).map_err(|e| {
+            CustomError::ConfigError(format!("Cannot connect to syslog at {}: {}", syslog.address, e))
+        })?;
+        loggers.push(Box::new(logger));
+    }
+    log::set_boxed_logger(Box::new(Outputs(loggers)))
+        .map_err(|e| CustomError::ConfigError(format!("Cannot install logger: {}", e)))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Every record goes to each output enabled for it
+struct Outputs(Vec<Box<dyn Log>>);
+
+impl Log for Outputs {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.iter().any(|output| output.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        for output in &self.0 {
+            if output.enabled(record.metadata()) {
+                output.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for output in &self.0 {
+            output.flush();
+        }
+    }
+}
+
+/// Appends to `path`, moving it to `<path>.1` (and older files one number
+/// up) when it grows too big or a new period starts
+struct RotatingFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+    period: Option<DateTime<Utc>>,
+}
+
+impl RotatingFile {
+    fn open(config: &LogFileConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        // A file left by an earlier run belongs to the period it was last
+        // written in
+        let modified = file.metadata()?.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now());
+        Ok(Self {
+            config: config.clone(),
+            file,
+            size,
+            period: config.rotation.period(modified),
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.config.keep == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            for index in (1..self.config.keep).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.config.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.config.rotation.period(Utc::now());
+        let max_bytes = self.config.max_size_mb * 1024 * 1024;
+        let oversized = max_bytes > 0 && self.size > 0 && self.size + buf.len() as u64 > max_bytes;
+        if oversized || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes a line per finished chat completion to `logging.access`
+pub struct AccessLog {
+    format: AccessLogFormat,
+    file: Mutex<RotatingFile>,
+    health: SinkHealth,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> Result<Self, CustomError> {
+        let file = RotatingFile::open(&config.file).map_err(|e| {
+            CustomError::ConfigError(format!("Cannot open access log {}: {}", config.file.path.display(), e))
+        })?;
+        Ok(Self {
+            format: config.format,
+            file: Mutex::new(file),
+            health: SinkHealth::default(),
+        })
+    }
+
+    /// Log the completion `record` describes, requested by `client` with
+    /// the request line `request_line` (e.g. `POST /v1/chat/completions
+    /// HTTP/1.1`)
+    pub fn write(&self, client: &str, request_line: &str, record: &CaptureRecord) {
+        let ttft_ms = record.chunks.first().map(|chunk| chunk.offset_ms.round() as u64);
+        let duration_ms = record.duration_ms.round() as u64;
+        let line = match self.format {
+            AccessLogFormat::Json => json!({
+                "time": record.received_at,
+                "client": client,
+                "request": request_line,
+                "status": record.status,
+                "model": record.model,
+                "api_key": record.api_key,
+                "request_id": record.request_id,
+                "completion_id": record.id,
+                "ttft_ms": ttft_ms,
+                "duration_ms": duration_ms,
+                "prompt_tokens": record.prompt_tokens,
+                "cached_tokens": record.cached_tokens,
+                "completion_tokens": record.completion_tokens,
+                "finish_reason": record.finish_reason,
+                "fault": record.fault,
+                "completed": record.completed,
+            })
+            .to_string(),
+            AccessLogFormat::Clf => {
+                let time = DateTime::parse_from_rfc3339(&record.received_at)
+                    .map(|time| time.format("%d/%b/%Y:%H:%M:%S %z").to_string())
+                    .unwrap_or_else(|_| record.received_at.clone());
+                format!(
+                    "{} - {} [{}] \"{}\" {} - model=\"{}\" request_id={} ttft_ms={} duration_ms={} prompt_tokens={} completion_tokens={}",
+                    client,
+                    record.api_key,
+                    time,
+                    request_line,
+                    record.status,
+                    record.model,
+                    record.request_id,
+                    ttft_ms.map_or_else(|| "-".to_string(), |ttft| ttft.to_string()),
+                    duration_ms,
+                    record.prompt_tokens,
+                    record.completion_tokens
+                )
+            }
+        };
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match writeln!(file, "{}", line) {
+            Ok(()) => self.health.record_success(),
+            Err(e) => {
+                warn!("Failed to write access log: {}", e);
+                self.health.record_failure(e);
+            }
+        }
+    }
+
+    pub fn health(&self) -> &SinkHealth {
+        &self.health
+    }
+}
+
+/// Sends records to a syslog daemon in the BSD format glibc uses, with the
+/// severity matching the record's level
+struct Syslog {
+    socket: SyslogSocket,
+    facility: Facility,
+    tag: String,
+    level: LevelFilter,
+}
+
+enum SyslogSocket {
+    #[cfg(unix)]
+    Local(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+impl Syslog {
+    fn connect(config: &SyslogConfig, level: LevelFilter) -> io::Result<Self> {
+        let socket = if config.address.starts_with('/') {
+            #[cfg(unix)]
+            {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&config.address)?;
+                SyslogSocket::Local(socket)
+            }
+            #[cfg(not(unix))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "local syslog sockets need a Unix host"));
+        } else {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(&config.address)?;
+            SyslogSocket::Udp(socket)
+        };
+        Ok(Self {
+            socket,
+            facility: config.facility,
+            tag: config.tag.clone(),
+            level,
+        })
+    }
+
+    fn severity(level: Level) -> u8 {
+        match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+impl Log for Syslog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        let message = format!(
+            "<{}>{} {}[{}]: {}: {}",
+            self.facility.code() * 8 + Self::severity(record.level()),
+            chrono::Local::now().format("%b %e %H:%M:%S"),
+            self.tag,
+            std::process::id(),
+            record.target(),
+            record.args()
+        );
+        // Logging must never fail a request; a daemon that is down loses
+        // the record
+        let _ = match &self.socket {
+            #[cfg(unix)]
+            SyslogSocket::Local(socket) => socket.send(message.as_bytes()),
+            SyslogSocket::Udp(socket) => socket.send(message.as_bytes()),
+        };
+    }
+
+    fn flush(&self) {}
+}
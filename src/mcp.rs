@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use log::debug;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::reply::BuiltinToolCall;
+use crate::request::ChatCompletionRequest;
+use crate::schema;
+
+/// Tool type connecting a remote MCP server
+pub const TOOL_TYPE: &str = "mcp";
+
+/// Remote MCP servers requests may connect with an `mcp` tool
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct McpConfig {
+    /// Tool manifests keyed by the `server_label` requests name
+    #[serde(default)]
+    pub servers: HashMap<String, McpServer>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct McpServer {
+    #[serde(default)]
+    pub tools: Vec<McpTool>,
+}
+
+/// A tool as an MCP server lists it, with the result of calling it
+#[derive(Deserialize, Clone, Debug)]
+pub struct McpTool {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Call arguments are generated from this schema
+    #[serde(default = "default_input_schema")]
+    pub input_schema: Value,
+    /// `output` of every call; a short text naming the tool when unset
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+fn default_input_schema() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+/// The `mcp_list_tools` item and then the `mcp_call` (or, unless the tool
+/// sets `require_approval: never`, the `mcp_approval_request`) for every
+/// `mcp` tool of `request` naming a configured server. The tool called is
+/// the first allowed one the last user message mentions, else the first
+/// allowed one.
+pub fn calls(config: &McpConfig, request: &ChatCompletionRequest) -> Vec<BuiltinToolCall> {
+    let message = request.last_user_message().unwrap_or_default().to_lowercase();
+    let mut calls = Vec::new();
+    for tool in request.tools.iter().flatten().filter(|tool| tool.get("type").and_then(Value::as_str) == Some(TOOL_TYPE)) {
+        let label = tool.get("server_label").and_then(Value::as_str).unwrap_or_default();
+        let Some(server) = config.servers.get(label) else {
+            debug!("No manifest for MCP server '{}', listing no tools", label);
+            calls.push(BuiltinToolCall::new("mcp_list_tools", json!({ "server_label": label, "tools": [] })));
+            continue;
+        };
+        let allowed: Vec<&McpTool> = server.tools.iter().filter(|listed| is_allowed(tool, &listed.name)).collect();
+        let listed: Vec<Value> = allowed
+            .iter()
+            .map(|listed| {
+                json!({
+                    "name": listed.name,
+                    "description": listed.description,
+                    "input_schema": listed.input_schema,
+                    "annotations": null,
+                })
+            })
+            .collect();
+        calls.push(BuiltinToolCall::new("mcp_list_tools", json!({ "server_label": label, "tools": listed })));
+
+        let called = allowed
+            .iter()
+            .find(|listed| message.contains(&listed.name.to_lowercase()))
+            .or_else(|| allowed.first());
+        let Some(called) = called else {
+            continue;
+        };
+        let arguments = schema::generate(&called.input_schema, false);
+        if needs_approval(tool, &called.name) {
+            calls.push(BuiltinToolCall::new(
+                "mcp_approval_request",
+                json!({ "server_label": label, "name": called.name, "arguments": arguments }),
+            ));
+            continue;
+        }
+        let output = called.output.clone().unwrap_or_else(|| format!("Result of {} from {}", called.name, label));
+        calls.push(BuiltinToolCall::new(
+            "mcp_call",
+            json!({
+                "server_label": label,
+                "name": called.name,
+                "arguments": arguments,
+                "output": output,
+                "error": null,
+            }),
+        ));
+    }
+    calls
+}
+
+/// Tool names of `allowed_tools`, given as a list or `{"tool_names": [...]}`
+fn tool_names(value: Option<&Value>) -> Option<Vec<&str>> {
+    let names = match value? {
+        Value::Object(filter) => filter.get("tool_names")?,
+        names => names,
+    };
+    Some(names.as_array()?.iter().filter_map(Value::as_str).collect())
+}
+
+fn is_allowed(tool: &Value, name: &str) -> bool {
+    tool_names(tool.get("allowed_tools")).is_none_or(|names| names.contains(&name))
+}
+
+/// OpenAI asks for approval of every call unless `require_approval` is
+/// `never` or its `never.tool_names` lists the tool
+fn needs_approval(tool: &Value, name: &str) -> bool {
+    match tool.get("require_approval") {
+        Some(Value::String(policy)) => policy != "never",
+        Some(policy @ Value::Object(_)) => {
+            !tool_names(policy.get("never")).is_some_and(|names| names.contains(&name))
+        }
+        _ => true,
+    }
+}
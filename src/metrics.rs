@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Label pairs attached to one series, e.g. `[("model", "gpt-4o")]`
+pub type Labels = Vec<(&'static str, String)>;
+
+/// Static description of a histogram family
+pub struct HistogramSpec {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub buckets: &'static [f64],
+}
+
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+pub const TTFT_SECONDS: HistogramSpec = HistogramSpec {
+    name: "sim_ttft_seconds",
+    help: "Time from request to the first streamed frame",
+    buckets: LATENCY_BUCKETS,
+};
+
+pub const STREAM_DURATION_SECONDS: HistogramSpec = HistogramSpec {
+    name: "sim_stream_duration_seconds",
+    help: "Time from request to the end of the stream",
+    buckets: LATENCY_BUCKETS,
+};
+
+pub const CHUNKS_PER_RESPONSE: HistogramSpec = HistogramSpec {
+    name: "sim_chunks_per_response",
+    help: "Frames delivered per streamed response",
+    buckets: &[1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0],
+};
+
+pub const TOKENS_PER_SECOND: HistogramSpec = HistogramSpec {
+    name: "sim_tokens_per_second",
+    help: "Completion tokens actually delivered per second of stream",
+    buckets: &[1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 5000.0, 10000.0],
+};
+
+/// Static description of a gauge
+pub struct GaugeSpec {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+pub const ACTIVE_STREAMS: GaugeSpec = GaugeSpec {
+    name: "sim_active_streams",
+    help: "SSE streams currently open",
+};
+
+pub const SEMAPHORE_PERMITS_IN_USE: GaugeSpec = GaugeSpec {
+    name: "sim_semaphore_permits_in_use",
+    help: "Concurrency permits currently held",
+};
+
+pub const SEMAPHORE_PERMITS_TOTAL: GaugeSpec = GaugeSpec {
+    name: "sim_semaphore_permits_total",
+    help: "Configured semaphore_limit",
+};
+
+pub const DB_CIRCUIT_OPEN: GaugeSpec = GaugeSpec {
+    name: "sim_db_circuit_open",
+    help: "1 while the database circuit breaker is open and responses come from files",
+};
+
+/// Static description of a counter family
+pub struct CounterSpec {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+pub const REQUESTS_TOTAL: CounterSpec = CounterSpec {
+    name: "sim_requests_total",
+    help: "Requests received, by model and API key",
+};
+
+pub const REQUEST_ERRORS_TOTAL: CounterSpec = CounterSpec {
+    name: "sim_request_errors_total",
+    help: "Requests answered with an error, by model, API key and error type",
+};
+
+pub const REQUESTS_SHED_TOTAL: CounterSpec = CounterSpec {
+    name: "sim_requests_shed_total",
+    help: "Requests answered 503 right away because limits.shed_above was reached",
+};
+
+pub const STREAMS_COALESCED_TOTAL: CounterSpec = CounterSpec {
+    name: "sim_streams_coalesced_total",
+    help: "Streams whose deltas were merged into fewer chunks because the server was near saturation, by model",
+};
+
+pub const DB_FAILOVERS_TOTAL: CounterSpec = CounterSpec {
+    name: "sim_db_failovers_total",
+    help: "Switches of ClickHouse queries to another endpoint after health checks, by the URL switched to",
+};
+
+pub const TOKENS_TOTAL: CounterSpec = CounterSpec {
+    name: "sim_tokens_total",
+    help: "Estimated tokens, by model, API key and kind (prompt/completion)",
+};
+
+#[derive(Clone)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = self.buckets.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Clone)]
+struct CounterFamily {
+    help: &'static str,
+    series: BTreeMap<Labels, f64>,
+}
+
+#[derive(Clone)]
+struct HistogramFamily {
+    help: &'static str,
+    series: BTreeMap<Labels, Histogram>,
+}
+
+/// In-process metrics registry rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    histograms: Mutex<BTreeMap<&'static str, HistogramFamily>>,
+    gauges: Mutex<BTreeMap<&'static str, (&'static str, f64)>>,
+    counters: Mutex<BTreeMap<&'static str, CounterFamily>>,
+}
+
+impl Metrics {
+    /// Keep counting on from the counters and histograms of `previous`,
+    /// the registry before a reload. Gauges are set by the new state.
+    pub fn carry_over(&self, previous: &Metrics) {
+        *self.counters.lock().unwrap() = previous.counters.lock().unwrap().clone();
+        *self.histograms.lock().unwrap() = previous.histograms.lock().unwrap().clone();
+    }
+
+    pub fn inc_counter(&self, spec: &CounterSpec, labels: &Labels, value: f64) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters
+            .entry(spec.name)
+            .or_insert_with(|| CounterFamily { help: spec.help, series: BTreeMap::new() })
+            .series
+            .entry(labels.clone())
+            .or_insert(0.0) += value;
+    }
+
+    /// Current value of every series of a counter family
+    pub fn counter_values(&self, spec: &CounterSpec) -> Vec<(Labels, f64)> {
+        let counters = self.counters.lock().unwrap();
+        counters
+            .get(spec.name)
+            .map(|family| family.series.iter().map(|(labels, value)| (labels.clone(), *value)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_gauge(&self, spec: &GaugeSpec, value: f64) {
+        self.gauges.lock().unwrap().insert(spec.name, (spec.help, value));
+    }
+
+    pub fn observe(&self, spec: &HistogramSpec, labels: &Labels, value: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms
+            .entry(spec.name)
+            .or_insert_with(|| HistogramFamily { help: spec.help, series: BTreeMap::new() })
+            .series
+            .entry(labels.clone())
+            .or_insert_with(|| Histogram::new(spec.buckets))
+            .observe(value);
+    }
+
+    /// Prometheus text exposition format (version 0.0.4)
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, (help, value)) in self.gauges.lock().unwrap().iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        for (name, family) in self.counters.lock().unwrap().iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (labels, value) in &family.series {
+                let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+            }
+        }
+        let histograms = self.histograms.lock().unwrap();
+        for (name, family) in histograms.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (labels, histogram) in &family.series {
+                let mut cumulative = 0;
+                for (bound, count) in histogram.buckets.iter().zip(&histogram.counts) {
+                    cumulative += count;
+                    let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some(&bound.to_string())), cumulative);
+                }
+                let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some("+Inf")), histogram.count);
+                let _ = writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), histogram.sum);
+                let _ = writeln!(out, "{}_count{} {}", name, format_labels(labels, None), histogram.count);
+            }
+        }
+        out
+    }
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
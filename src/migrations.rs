@@ -0,0 +1,203 @@
+use clickhouse::{Client, Row};
+use log::{info, warn};
+use serde::Deserialize;
+use crate::config_loader::DatabaseConfig;
+use crate::db::{self, DATABASE_NAME};
+use crate::error::CustomError;
+
+/// Database that exists on every server, to run `CREATE DATABASE` from
+const BOOTSTRAP_DATABASE: &str = "default";
+
+/// Layout version of the response table this binary reads. Bump it with
+/// every change to the expected columns; older versions are migrated by
+/// adding the missing columns.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Table recording the schema versions applied, in `midai_simulator`
+const VERSION_TABLE: &str = "schema_version";
+
+#[derive(Row, Deserialize, Debug)]
+struct ColumnRow {
+    name: String,
+    kind: String,
+}
+
+/// The response table as the database holds it, against the layout this
+/// binary expects
+#[derive(Debug, Default)]
+pub struct SchemaState {
+    pub table_exists: bool,
+    /// Highest version in `schema_version`; `None` when none was recorded
+    pub version: Option<u32>,
+    /// Mapped columns the table lacks: field, column and type
+    pub missing: Vec<(&'static str, String, &'static str)>,
+    /// Columns whose type cannot be read into their field
+    pub mismatched: Vec<String>,
+}
+
+impl SchemaState {
+    /// Whether the binary can serve from the table as it is
+    pub fn verdict(&self, config: &DatabaseConfig) -> Result<String, CustomError> {
+        let table = format!("{}.{}", DATABASE_NAME, config.table);
+        if let Some(version) = self.version.filter(|&version| version > SCHEMA_VERSION) {
+            return Err(CustomError::ConfigError(format!(
+                "{} is at schema version {}, newer than the {} this binary supports",
+                table, version, SCHEMA_VERSION
+            )));
+        }
+        if !self.table_exists {
+            return Err(CustomError::ConfigError(format!(
+                "{} does not exist; set database.create_schema to create it",
+                table
+            )));
+        }
+        let mut problems: Vec<String> = self
+            .missing
+            .iter()
+            .map(|(field, column, kind)| format!("missing column {} {} for {}", column, kind, field))
+            .collect();
+        problems.extend(self.mismatched.iter().cloned());
+        if !problems.is_empty() {
+            let hint = if self.mismatched.is_empty() { "; set database.create_schema to add them" } else { "" };
+            return Err(CustomError::ConfigError(format!(
+                "{} does not match the expected layout: {}{}",
+                table,
+                problems.join(", "),
+                hint
+            )));
+        }
+        Ok(match self.version {
+            Some(version) => format!("{} at schema version {}", table, version),
+            None => format!("{} matches schema version {} (not recorded)", table, SCHEMA_VERSION),
+        })
+    }
+}
+
+/// Create the database and response table when they are missing, with the
+/// layout `database.columns` maps; existing tables are left as they are.
+/// A custom `database.query` reads tables this cannot know, so it is
+/// refused.
+pub async fn create_schema(client: &Client, config: &DatabaseConfig) -> Result<(), CustomError> {
+    let failed = |what: &str, e: String| CustomError::ConfigError(format!("cannot create {}: {}", what, e));
+    if config.query.is_some() {
+        return Err(failed(&config.table, "database.create_schema cannot be used with database.query".to_string()));
+    }
+    let create_table = db::create_table_query(config).map_err(|e| failed(&config.table, e))?;
+
+    let bootstrap = client.clone().with_database(BOOTSTRAP_DATABASE);
+    execute(&bootstrap, config, &format!("CREATE DATABASE IF NOT EXISTS {}", DATABASE_NAME))
+        .await
+        .map_err(|e| failed(DATABASE_NAME, e))?;
+    execute(client, config, &create_table).await.map_err(|e| failed(&config.table, e))?;
+    let create_versions = format!(
+        "CREATE TABLE IF NOT EXISTS {} (version UInt32, applied_at DateTime DEFAULT now()) \
+         ENGINE = MergeTree ORDER BY version",
+        VERSION_TABLE
+    );
+    execute(client, config, &create_versions).await.map_err(|e| failed(VERSION_TABLE, e))?;
+    info!("ClickHouse schema ready: {}.{}", DATABASE_NAME, config.table);
+    Ok(())
+}
+
+/// Startup schema step: create the schema when `database.create_schema`
+/// asks for it, then compare the table with the expected layout, unless
+/// `database.verify_schema` is off, a custom `database.query` is used or
+/// a column is mapped to an expression. Missing columns are added and the
+/// version recorded when creating the schema is allowed; otherwise a
+/// mismatch refuses to start rather than failing row deserialization
+/// later.
+pub async fn prepare(client: &Client, config: &DatabaseConfig) -> Result<(), CustomError> {
+    if config.create_schema {
+        create_schema(client, config).await?;
+    }
+    if !verifies(config) {
+        return Ok(());
+    }
+    let mut state = inspect(client, config).await?;
+    if config.create_schema && state.mismatched.is_empty() {
+        for (field, column, kind) in std::mem::take(&mut state.missing) {
+            let default = if field == "weight" { " DEFAULT 1" } else { "" };
+            let alter = format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}{}", config.table, column, kind, default);
+            execute(client, config, &alter)
+                .await
+                .map_err(|e| CustomError::ConfigError(format!("cannot add column {}: {}", column, e)))?;
+        }
+    }
+    let verdict = state.verdict(config)?;
+    if config.create_schema && state.version.is_none_or(|version| version < SCHEMA_VERSION) {
+        let record = format!("INSERT INTO {} (version) VALUES ({})", VERSION_TABLE, SCHEMA_VERSION);
+        execute(client, config, &record)
+            .await
+            .map_err(|e| CustomError::ConfigError(format!("cannot record schema version: {}", e)))?;
+        info!("Recorded schema version {}", SCHEMA_VERSION);
+    } else if state.version.is_none() {
+        warn!(
+            "No schema version recorded in {}.{}; set database.create_schema to record it",
+            DATABASE_NAME, VERSION_TABLE
+        );
+    }
+    info!("ClickHouse schema verified: {}", verdict);
+    Ok(())
+}
+
+/// Whether the table layout is checked: `database.verify_schema` is on,
+/// and no custom query or expression mapping hides the table
+pub fn verifies(config: &DatabaseConfig) -> bool {
+    config.verify_schema && config.query.is_none() && db::table_columns(config).is_ok()
+}
+
+/// Read the response table's columns and the recorded schema version
+pub async fn inspect(client: &Client, config: &DatabaseConfig) -> Result<SchemaState, CustomError> {
+    let failed = |e: clickhouse::error::Error| CustomError::ConfigError(format!("cannot inspect the schema: {}", e));
+    let expected = db::table_columns(config)
+        .map_err(|e| CustomError::ConfigError(format!("cannot verify {}: {}", config.table, e)))?;
+    let columns_query = "SELECT name, type FROM system.columns WHERE database = ? AND table = ?";
+    let columns = db::retry(&config.retry, "Inspecting the schema", || {
+        client.query(columns_query).bind(DATABASE_NAME).bind(config.table.trim_matches('`')).fetch_all::<ColumnRow>()
+    })
+    .await
+    .map_err(failed)?;
+    let version_table = "SELECT count() FROM system.tables WHERE database = ? AND name = ?";
+    let has_versions =
+        client.query(version_table).bind(DATABASE_NAME).bind(VERSION_TABLE).fetch_one::<u64>().await.map_err(failed)?;
+    let version = match has_versions {
+        0 => None,
+        _ => {
+            let recorded = format!("SELECT max(version) FROM {}", VERSION_TABLE);
+            Some(client.query(&recorded).fetch_one::<u32>().await.map_err(failed)?).filter(|&version| version > 0)
+        }
+    };
+
+    let mut state = SchemaState { table_exists: !columns.is_empty(), version, ..SchemaState::default() };
+    if !state.table_exists {
+        return Ok(state);
+    }
+    for (field, column, kind) in expected {
+        let name = column.trim_matches('`');
+        match columns.iter().find(|row| row.name == name) {
+            None => state.missing.push((field, column, kind)),
+            Some(row) if !compatible(field, &row.kind) => {
+                state.mismatched.push(format!("{} is {}, expected {}", name, row.kind, kind))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(state)
+}
+
+/// Whether a column of ClickHouse type `kind` can be read into `field`
+fn compatible(field: &str, kind: &str) -> bool {
+    match field {
+        "qa_id" => matches!(kind, "UUID" | "Nullable(UUID)"),
+        // Read through `toFloat64`
+        "weight" => ["Int", "UInt", "Float", "Decimal"].iter().any(|prefix| kind.starts_with(prefix)),
+        _ => matches!(kind, "String" | "LowCardinality(String)"),
+    }
+}
+
+async fn execute(client: &Client, config: &DatabaseConfig, query: &str) -> Result<(), String> {
+    info!("Executing {}", query);
+    db::retry(&config.retry, "Updating the schema", || client.query(query).execute())
+        .await
+        .map_err(|e| e.to_string())
+}
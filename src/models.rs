@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::error::ApiError;
+use crate::request::ChatCompletionRequest;
+use crate::stream::estimate_tokens;
+
+/// A model listed by `/v1/models`, configured under `models` keyed by id
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ModelInfo {
+    #[serde(default = "default_owned_by")]
+    pub owned_by: String,
+    /// Unix timestamp reported as `created`
+    #[serde(default)]
+    pub created: u64,
+    /// Prompt plus completion tokens the model accepts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(default)]
+    pub capabilities: ModelCapabilities,
+    /// Provider profile for requests naming this model, unless they select
+    /// one themselves
+    #[serde(default, skip_serializing)]
+    pub profile: Option<String>,
+}
+
+impl ModelInfo {
+    /// Reject a request using a feature the model does not support, with
+    /// the error OpenAI returns for it
+    pub fn check(&self, request: &ChatCompletionRequest) -> Result<(), ApiError> {
+        if !self.capabilities.vision {
+            for (i, message) in request.messages.iter().enumerate() {
+                for (j, part) in message.content.as_array().into_iter().flatten().enumerate() {
+                    let kind = part.get("type").and_then(|kind| kind.as_str()).unwrap_or_default();
+                    if matches!(kind, "image_url" | "input_image" | "image") {
+                        let message = format!("Invalid content type. {} is only supported by certain models.", kind);
+                        return Err(ApiError::invalid_request(message)
+                            .with_param(format!("messages.[{}].content.[{}].type", i, j)));
+                    }
+                }
+            }
+        }
+        if !self.capabilities.tools {
+            let param = [("tools", &request.tools), ("functions", &request.functions)]
+                .into_iter()
+                .find_map(|(param, value)| value.as_ref().filter(|tools| !tools.is_empty()).map(|_| param));
+            if let Some(param) = param {
+                let message = format!("Unsupported parameter: '{}' is not supported with this model.", param);
+                return Err(ApiError::invalid_request(message).with_param(param).with_code("unsupported_parameter"));
+            }
+        }
+        if !self.capabilities.json_mode {
+            let kind = request
+                .response_format
+                .as_ref()
+                .and_then(|format| format.get("type"))
+                .and_then(|kind| kind.as_str())
+                .filter(|kind| *kind != "text");
+            if let Some(kind) = kind {
+                let message = format!("Invalid parameter: 'response_format' of type '{}' is not supported with this model.", kind);
+                return Err(ApiError::invalid_request(message).with_param("response_format"));
+            }
+        }
+        let max_tokens = [("max_completion_tokens", request.max_completion_tokens), ("max_tokens", request.max_tokens)]
+            .into_iter()
+            .find_map(|(param, value)| value.map(|value| (param, value)));
+        if let (Some(limit), Some((param, requested))) = (self.max_output_tokens, max_tokens) {
+            if requested > limit {
+                let message = format!(
+                    "{} is too large: {}. This model supports at most {} completion tokens, whereas you provided {}.",
+                    param, requested, limit, requested
+                );
+                return Err(ApiError::invalid_request(message).with_param(param).with_code("invalid_value"));
+            }
+        }
+        Ok(())
+    }
+
+    /// OpenAI's `context_length_exceeded` when the `prompt_tokens` of the
+    /// messages, plus the tools and the requested completion tokens, do
+    /// not fit `context_window`. Counts are the estimates usage reports.
+    pub fn check_context_length(&self, request: &ChatCompletionRequest, prompt_tokens: u32) -> Result<(), ApiError> {
+        let Some(context_window) = self.context_window else {
+            return Ok(());
+        };
+        let tools = request
+            .tools
+            .iter()
+            .chain(&request.functions)
+            .flatten()
+            .map(|tool| estimate_tokens(&tool.to_string()))
+            .sum::<u32>();
+        let completion = request.max_completion_tokens.or(request.max_tokens).unwrap_or(0);
+        let total = prompt_tokens + tools + completion;
+        if total <= context_window {
+            return Ok(());
+        }
+
+        let parts: Vec<(&str, u32)> = [("messages", prompt_tokens), ("functions", tools), ("completion", completion)]
+            .into_iter()
+            .filter(|(part, tokens)| *part == "messages" || *tokens > 0)
+            .collect();
+        let names: Vec<&str> = parts.iter().map(|(part, _)| *part).collect();
+        let reduce = match names.as_slice() {
+            [only] => only.to_string(),
+            [first, second] => format!("{} or {}", first, second),
+            [rest @ .., last] => format!("{}, or {}", rest.join(", "), last),
+            [] => unreachable!("messages are always counted"),
+        };
+        let message = if parts.len() == 1 {
+            format!(
+                "This model's maximum context length is {} tokens. However, your messages resulted in {} tokens. Please reduce the length of the messages.",
+                context_window, total
+            )
+        } else {
+            let breakdown: Vec<String> = parts.iter().map(|(part, tokens)| format!("{} in the {}", tokens, part)).collect();
+            let verb = if completion > 0 { "you requested" } else { "your messages resulted in" };
+            format!(
+                "This model's maximum context length is {} tokens. However, {} {} tokens ({}). Please reduce the length of the {}.",
+                context_window,
+                verb,
+                total,
+                breakdown.join(", "),
+                reduce
+            )
+        };
+        Err(ApiError::invalid_request(message).with_param("messages").with_code("context_length_exceeded"))
+    }
+}
+
+fn default_owned_by() -> String {
+    "system".to_string()
+}
+
+/// Request features a model supports; anything not declared is supported
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct ModelCapabilities {
+    /// Image content parts
+    #[serde(default = "default_supported")]
+    pub vision: bool,
+    #[serde(default = "default_supported")]
+    pub tools: bool,
+    /// `response_format` other than `text`
+    #[serde(default = "default_supported")]
+    pub json_mode: bool,
+}
+
+impl Default for ModelCapabilities {
+    fn default() -> Self {
+        Self {
+            vision: true,
+            tools: true,
+            json_mode: true,
+        }
+    }
+}
+
+fn default_supported() -> bool {
+    true
+}
+
+/// A model in the shape of OpenAI's model object, plus its metadata
+#[derive(Serialize)]
+pub struct ModelObject<'a> {
+    pub id: &'a str,
+    pub object: &'static str,
+    #[serde(flatten)]
+    pub info: &'a ModelInfo,
+}
+
+impl<'a> ModelObject<'a> {
+    pub fn new(id: &'a str, info: &'a ModelInfo) -> Self {
+        Self { id, object: "model", info }
+    }
+}
+
+/// Body of `GET /v1/models`
+pub fn list(models: &BTreeMap<String, ModelInfo>) -> serde_json::Value {
+    let data: Vec<ModelObject> = models.iter().map(|(id, info)| ModelObject::new(id, info)).collect();
+    serde_json::json!({
+        "object": "list",
+        "data": data,
+    })
+}
@@ -0,0 +1,144 @@
+use actix_web::http::StatusCode;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::capture::CaptureRecord;
+use crate::error::ApiError;
+
+/// Response header carrying the id to look a generation up by
+pub const GENERATION_HEADER: &str = "x-generation-id";
+/// Profile applied to `/api/v1/chat/completions` unless the request selects
+/// another one
+pub const PROFILE: &str = "openrouter";
+
+/// OpenRouter emulation under `/api/v1`
+#[derive(Deserialize, Clone, Debug)]
+pub struct OpenRouterConfig {
+    /// Upstream providers serving every model, in the order requests
+    /// without preferences are routed to them
+    #[serde(default = "default_providers")]
+    pub providers: Vec<String>,
+    /// USD per million prompt tokens, for the `total_cost` of generations
+    #[serde(default)]
+    pub prompt_price: f64,
+    /// USD per million completion tokens
+    #[serde(default)]
+    pub completion_price: f64,
+}
+
+impl Default for OpenRouterConfig {
+    fn default() -> Self {
+        Self {
+            providers: default_providers(),
+            prompt_price: 0.0,
+            completion_price: 0.0,
+        }
+    }
+}
+
+fn default_providers() -> Vec<String> {
+    vec!["OpenAI".to_string()]
+}
+
+/// The `provider` object of an OpenRouter request
+#[derive(Deserialize)]
+struct ProviderPreferences {
+    #[serde(default)]
+    order: Vec<String>,
+    #[serde(default)]
+    only: Option<Vec<String>>,
+    #[serde(default)]
+    ignore: Vec<String>,
+    #[serde(default = "default_allow_fallbacks")]
+    allow_fallbacks: bool,
+}
+
+impl Default for ProviderPreferences {
+    fn default() -> Self {
+        Self {
+            order: Vec::new(),
+            only: None,
+            ignore: Vec::new(),
+            allow_fallbacks: true,
+        }
+    }
+}
+
+fn default_allow_fallbacks() -> bool {
+    true
+}
+
+/// `gen-<unix>-<20 alphanumerics>`, the id of an OpenRouter completion
+pub fn generation_id() -> String {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(20)
+        .map(char::from)
+        .collect();
+    format!("gen-{}-{}", chrono::Utc::now().timestamp(), suffix)
+}
+
+/// The provider serving a request: the first configured provider of its
+/// `order`, then (unless fallbacks are off) the configured ones in turn,
+/// skipping any not in `only` or listed in `ignore`
+pub fn choose_provider(config: &OpenRouterConfig, preferences: Option<&Value>) -> Result<String, ApiError> {
+    let preferences: ProviderPreferences = preferences
+        .and_then(|preferences| serde_json::from_value(preferences.clone()).ok())
+        .unwrap_or_default();
+    let listed = |names: &[String], name: &str| names.iter().any(|listed| listed.eq_ignore_ascii_case(name));
+    let allowed = |name: &&String| {
+        preferences.only.as_deref().is_none_or(|only| listed(only, name)) && !listed(&preferences.ignore, name)
+    };
+    let ordered = preferences
+        .order
+        .iter()
+        .filter_map(|name| config.providers.iter().find(|provider| provider.eq_ignore_ascii_case(name)));
+    let fallbacks = config
+        .providers
+        .iter()
+        .filter(|_| preferences.allow_fallbacks || preferences.order.is_empty());
+    ordered.chain(fallbacks).find(allowed).cloned().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            "No allowed providers are available for the selected model.",
+        )
+    })
+}
+
+/// Body of `GET /api/v1/generation` for a captured completion
+pub fn generation(record: &CaptureRecord, config: &OpenRouterConfig) -> Value {
+    let cost = (record.prompt_tokens as f64 * config.prompt_price
+        + record.completion_tokens as f64 * config.completion_price)
+        / 1_000_000.0;
+    let streamed = record.request.get("stream").and_then(Value::as_bool) != Some(false);
+    json!({
+        "data": {
+            "id": record.id,
+            "total_cost": cost,
+            "created_at": record.received_at,
+            "model": record.model,
+            "origin": "",
+            "usage": cost,
+            "is_byok": false,
+            "upstream_id": null,
+            "cache_discount": null,
+            "streamed": streamed,
+            "cancelled": !record.completed,
+            "provider_name": record.provider,
+            "latency": record.chunks.first().map(|chunk| chunk.offset_ms.round() as u64),
+            "moderation_latency": null,
+            "generation_time": record.duration_ms.round() as u64,
+            "finish_reason": record.finish_reason,
+            "native_finish_reason": record.finish_reason,
+            "tokens_prompt": record.prompt_tokens,
+            "tokens_completion": record.completion_tokens,
+            "native_tokens_prompt": record.prompt_tokens,
+            "native_tokens_completion": record.completion_tokens,
+            "native_tokens_reasoning": 0,
+            "num_media_prompt": null,
+            "num_media_completion": null,
+            "num_search_results": null,
+        }
+    })
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+use actix_web::web::Bytes;
+use http_body_util::Full;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::TokioExecutor;
+use crate::config_loader::DatabaseTlsConfig;
+use crate::db;
+
+/// Client for notifications leaving the simulator (webhooks, error reports)
+pub type Client = HyperClient<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+/// A client for `http://` and `https://` URLs, checking server
+/// certificates against the built-in web roots
+pub fn client() -> Client {
+    let connector = db::https_connector(HttpConnector::new(), &DatabaseTlsConfig::default())
+        .expect("the built-in web roots need no loading");
+    HyperClient::builder(TokioExecutor::new()).build(connector)
+}
+
+/// POST the JSON `body` to `url` with the extra `headers`. Resolves to the
+/// response status, or fails on a non-2xx status, a connection error or
+/// no response within `timeout`.
+pub async fn post_json(
+    client: &Client,
+    url: &http::Uri,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+    timeout: Duration,
+) -> Result<u16, String> {
+    let mut request = http::Request::post(url.clone()).header(http::header::CONTENT_TYPE, "application/json");
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let request = request.body(Full::new(Bytes::from(body))).map_err(|e| e.to_string())?;
+    match tokio::time::timeout(timeout, client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => Ok(response.status().as_u16()),
+        Ok(Ok(response)) => Err(format!("status {}", response.status().as_u16())),
+        Ok(Err(e)) => Err(describe(&e)),
+        Err(_) => Err(format!("timed out after {:?}", timeout)),
+    }
+}
+
+/// `error` with its causes, which hold the reason a connection failed
+/// (refused, certificate rejected, ...)
+fn describe(error: &dyn std::error::Error) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        description.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    description
+}
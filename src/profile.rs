@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::error::ErrorShape;
+
+/// Header selecting a provider profile by name
+pub const PROFILE_HEADER: &str = "x-sim-profile";
+/// Query parameter selecting a provider profile by name
+pub const PROFILE_QUERY_PARAM: &str = "profile";
+
+/// Wire-format quirks of one upstream provider, configured under `profiles`
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ProviderProfile {
+    /// Reproduce OpenAI's exact chunk anatomy: a single completion id, an
+    /// initial role chunk with empty content, token-sized (sometimes
+    /// grouped) deltas, an empty final delta carrying `finish_reason`, real
+    /// usage numbers and the closing `data: [DONE]`
+    #[serde(default)]
+    pub fidelity: bool,
+    /// Annotate choices with Azure's `content_filter_results` and the first
+    /// chunk with `prompt_filter_results`
+    #[serde(default)]
+    pub content_filter_results: bool,
+    /// Attach the usage so far to every chunk, as vLLM's
+    /// `continuous_usage_stats` and some other OpenAI-compatible backends do
+    #[serde(default)]
+    pub continuous_usage: bool,
+    /// Honour Anthropic `cache_control` breakpoints and report
+    /// `cache_creation_input_tokens` / `cache_read_input_tokens` in usage
+    #[serde(default)]
+    pub cache_control: bool,
+    /// Body of error responses: `openai`, `azure` or `anthropic`
+    #[serde(default)]
+    pub error_shape: ErrorShape,
+    /// Finish reasons renamed on the wire, e.g. `stop: eos` as Together
+    /// sends it
+    #[serde(default)]
+    pub finish_reasons: HashMap<String, String>,
+    /// `system_fingerprint` sent instead of OpenAI's; an empty string
+    /// sends `null`
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+    /// Groq's `x_groq` block: the request id on the first chunk, usage and
+    /// timings on the last one
+    #[serde(default)]
+    pub x_groq: bool,
+    /// Perplexity's citations: `[1]` markers in the content and the
+    /// sources of the answer in a top-level `citations` array, instead of
+    /// the reference list after the content
+    #[serde(default)]
+    pub citations: bool,
+}
+
+/// Profiles usable without configuring them; a profile of the same name
+/// under `profiles` takes precedence
+pub fn builtin(name: &str) -> Option<ProviderProfile> {
+    let profile = match name {
+        "groq" => ProviderProfile {
+            fidelity: true,
+            x_groq: true,
+            system_fingerprint: Some("fp_179b0f92c9".to_string()),
+            ..ProviderProfile::default()
+        },
+        "together" => ProviderProfile {
+            fidelity: true,
+            finish_reasons: HashMap::from([("stop".to_string(), "eos".to_string())]),
+            system_fingerprint: Some(String::new()),
+            ..ProviderProfile::default()
+        },
+        "openrouter" => ProviderProfile {
+            fidelity: true,
+            ..ProviderProfile::default()
+        },
+        "perplexity" => ProviderProfile {
+            fidelity: true,
+            citations: true,
+            system_fingerprint: Some(String::new()),
+            ..ProviderProfile::default()
+        },
+        "fireworks" => ProviderProfile {
+            fidelity: true,
+            system_fingerprint: Some(String::new()),
+            ..ProviderProfile::default()
+        },
+        _ => return None,
+    };
+    Some(profile)
+}
+
+/// The profile called `name`, configured or built in
+pub fn resolve(profiles: &HashMap<String, ProviderProfile>, name: &str) -> Option<ProviderProfile> {
+    profiles.get(name).cloned().or_else(|| builtin(name))
+}
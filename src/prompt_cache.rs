@@ -0,0 +1,210 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use crate::request::CacheBreakpoint;
+use crate::stream::estimate_tokens;
+
+/// Characters per token, as in `estimate_tokens`
+const CHARS_PER_TOKEN: usize = 4;
+
+/// OpenAI-style automatic prompt caching: a prompt sharing a long enough
+/// prefix with a recent prompt of the same API key reports that prefix as
+/// `cached_tokens`. Anthropic's explicit `cache_control` breakpoints (see
+/// `ProviderProfile::cache_control`) share the limits and TTL but are
+/// honoured even when automatic caching is off.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PromptCacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Prompts shorter than this are never cached
+    #[serde(default = "default_min_tokens")]
+    pub min_tokens: u32,
+    /// Cache hits grow in steps of this many tokens
+    #[serde(default = "default_block_tokens")]
+    pub block_tokens: u32,
+    /// How long a prefix stays cached after it was last used, unless a
+    /// `cache_control` block gives its own `ttl`
+    #[serde(default = "default_ttl_ms")]
+    pub ttl_ms: u64,
+    /// Share of the time to first token saved for a fully cached prompt,
+    /// from 0.0 to 1.0; partial hits save proportionally less
+    #[serde(default)]
+    pub ttft_reduction: f64,
+    /// Prefixes remembered; the least recently stored are forgotten first
+    #[serde(default = "default_max_prefixes")]
+    pub max_prefixes: usize,
+}
+
+impl Default for PromptCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_tokens: default_min_tokens(),
+            block_tokens: default_block_tokens(),
+            ttl_ms: default_ttl_ms(),
+            ttft_reduction: 0.0,
+            max_prefixes: default_max_prefixes(),
+        }
+    }
+}
+
+fn default_min_tokens() -> u32 {
+    1024
+}
+
+fn default_block_tokens() -> u32 {
+    128
+}
+
+fn default_ttl_ms() -> u64 {
+    300000
+}
+
+fn default_max_prefixes() -> usize {
+    100000
+}
+
+#[derive(Default, Clone)]
+struct Prefixes {
+    /// Expiry of each (API key, prefix hash)
+    expires: HashMap<(String, u64), Instant>,
+    order: VecDeque<(String, u64)>,
+}
+
+impl Prefixes {
+    fn cached(&self, api_key: &str, hash: u64, now: Instant) -> bool {
+        self.expires.get(&(api_key.to_string(), hash)).is_some_and(|expires| now < *expires)
+    }
+
+    /// Cache a prefix, or refresh its expiry, forgetting the oldest prefixes
+    /// beyond `max_prefixes`
+    fn store(&mut self, api_key: &str, hash: u64, expires: Instant, max_prefixes: usize) {
+        let key = (api_key.to_string(), hash);
+        if self.expires.insert(key.clone(), expires).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > max_prefixes {
+            if let Some(oldest) = self.order.pop_front() {
+                self.expires.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Prompt tokens of an Anthropic request served from and written to the cache
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheUsage {
+    pub read_tokens: u32,
+    pub creation_tokens: u32,
+}
+
+/// Prompt prefixes recently seen per API key
+pub struct PromptCache {
+    config: PromptCacheConfig,
+    prefixes: Mutex<Prefixes>,
+}
+
+impl PromptCache {
+    pub fn new(config: PromptCacheConfig) -> Self {
+        Self {
+            config,
+            prefixes: Mutex::default(),
+        }
+    }
+
+    /// Keep the prefixes cached by `previous`, the cache before a reload, up
+    /// to this cache's `max_prefixes`
+    pub fn carry_over(&self, previous: &PromptCache) {
+        let mut prefixes = previous.prefixes.lock().unwrap().clone();
+        while prefixes.order.len() > self.config.max_prefixes {
+            if let Some(oldest) = prefixes.order.pop_front() {
+                prefixes.expires.remove(&oldest);
+            }
+        }
+        *self.prefixes.lock().unwrap() = prefixes;
+    }
+
+    /// Tokens of `prompt` served from the cache of `api_key`, which from
+    /// now on also holds every block-aligned prefix of `prompt`
+    pub fn lookup(&self, api_key: &str, prompt: &str) -> u32 {
+        if !self.config.enabled {
+            return 0;
+        }
+        let chars: Vec<char> = prompt.chars().collect();
+        if ((chars.len() / CHARS_PER_TOKEN) as u32) < self.config.min_tokens {
+            return 0;
+        }
+        let block = self.config.block_tokens.max(1) as usize * CHARS_PER_TOKEN;
+        let min_blocks = (self.config.min_tokens as usize * CHARS_PER_TOKEN).div_ceil(block).max(1);
+        let mut hasher = DefaultHasher::new();
+        let hashes: Vec<u64> = chars
+            .chunks_exact(block)
+            .map(|chunk| {
+                chunk.hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect();
+
+        let now = Instant::now();
+        let expires = now + Duration::from_millis(self.config.ttl_ms);
+        let mut prefixes = self.prefixes.lock().unwrap();
+        // Hashes are cumulative, so a known hash means the whole prefix
+        // up to it is cached
+        let cached_blocks = (min_blocks..=hashes.len())
+            .rev()
+            .find(|blocks| prefixes.cached(api_key, hashes[blocks - 1], now))
+            .unwrap_or(0);
+        for hash in hashes.iter().skip(min_blocks - 1) {
+            prefixes.store(api_key, *hash, expires, self.config.max_prefixes);
+        }
+
+        (cached_blocks * block / CHARS_PER_TOKEN) as u32
+    }
+
+    /// Anthropic's explicit caching: the longest breakpoint prefix cached
+    /// before is read, and the prompt up to the last breakpoint beyond it is
+    /// written. Prefixes shorter than `min_tokens` are never cached.
+    pub fn lookup_breakpoints(&self, api_key: &str, breakpoints: &[CacheBreakpoint]) -> CacheUsage {
+        let now = Instant::now();
+        let cacheable: Vec<(u64, u32, Instant)> = breakpoints
+            .iter()
+            .map(|breakpoint| {
+                let mut hasher = DefaultHasher::new();
+                breakpoint.prefix.hash(&mut hasher);
+                let ttl = breakpoint.ttl_ms.unwrap_or(self.config.ttl_ms);
+                (hasher.finish(), estimate_tokens(&breakpoint.prefix), now + Duration::from_millis(ttl))
+            })
+            .filter(|(_, tokens, _)| *tokens >= self.config.min_tokens)
+            .collect();
+
+        let mut prefixes = self.prefixes.lock().unwrap();
+        let read_tokens = cacheable
+            .iter()
+            .filter(|(hash, _, _)| prefixes.cached(api_key, *hash, now))
+            .map(|(_, tokens, _)| *tokens)
+            .max()
+            .unwrap_or(0);
+        let written_tokens = cacheable.iter().map(|(_, tokens, _)| *tokens).max().unwrap_or(0);
+        for (hash, _, expires) in &cacheable {
+            prefixes.store(api_key, *hash, *expires, self.config.max_prefixes);
+        }
+
+        CacheUsage {
+            read_tokens,
+            creation_tokens: written_tokens.saturating_sub(read_tokens),
+        }
+    }
+
+    /// Factor applied to the time to first token when `cached_tokens` of
+    /// `prompt_tokens` were cached
+    pub fn ttft_factor(&self, cached_tokens: u32, prompt_tokens: u32) -> f64 {
+        if cached_tokens == 0 || prompt_tokens == 0 {
+            return 1.0;
+        }
+        let share = (cached_tokens as f64 / prompt_tokens as f64).min(1.0);
+        1.0 - self.config.ttft_reduction.clamp(0.0, 1.0) * share
+    }
+}
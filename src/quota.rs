@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use crate::error::ApiError;
+use crate::webhook::{Event, Webhooks};
+
+/// Token quotas per API key, as billed by OpenAI. Keys are the masked ids
+/// reported by `/admin/stats`, e.g. `sk-...a1b2`.
+#[derive(Deserialize, Clone, Default)]
+pub struct QuotaConfig {
+    /// Limits for keys not listed in `keys`
+    #[serde(flatten)]
+    pub default: QuotaLimits,
+    #[serde(default)]
+    pub keys: HashMap<String, QuotaLimits>,
+}
+
+/// Tokens (prompt plus completion) a key may use; unset means unlimited
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default)]
+pub struct QuotaLimits {
+    /// Per UTC day
+    #[serde(default)]
+    pub daily_tokens: Option<u64>,
+    /// Per UTC calendar month
+    #[serde(default)]
+    pub monthly_tokens: Option<u64>,
+}
+
+impl QuotaConfig {
+    pub fn is_enabled(&self) -> bool {
+        let limited = |limits: &QuotaLimits| limits.daily_tokens.is_some() || limits.monthly_tokens.is_some();
+        limited(&self.default) || self.keys.values().any(limited)
+    }
+
+    fn limits(&self, api_key: &str) -> QuotaLimits {
+        self.keys.get(api_key).copied().unwrap_or(self.default)
+    }
+}
+
+/// Tokens one key used in the current day and month
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct KeyUsage {
+    pub day: NaiveDate,
+    pub daily_tokens: u64,
+    pub monthly_tokens: u64,
+}
+
+impl KeyUsage {
+    fn new(today: NaiveDate) -> Self {
+        Self {
+            day: today,
+            daily_tokens: 0,
+            monthly_tokens: 0,
+        }
+    }
+
+    /// Start new windows when the day or month turned over
+    fn roll(&mut self, today: NaiveDate) {
+        if (self.day.year(), self.day.month()) != (today.year(), today.month()) {
+            self.monthly_tokens = 0;
+        }
+        if self.day != today {
+            self.daily_tokens = 0;
+            self.day = today;
+        }
+    }
+}
+
+/// Usage and limits of one key, for `/admin/quota`
+#[derive(Serialize)]
+pub struct QuotaStatus {
+    pub usage: KeyUsage,
+    pub limits: QuotaLimits,
+}
+
+pub struct QuotaTracker {
+    config: QuotaConfig,
+    usage: Mutex<HashMap<String, KeyUsage>>,
+    webhooks: Arc<Webhooks>,
+}
+
+impl QuotaTracker {
+    pub fn new(config: QuotaConfig, webhooks: Arc<Webhooks>) -> Self {
+        Self {
+            config,
+            usage: Mutex::new(HashMap::new()),
+            webhooks,
+        }
+    }
+
+    /// Keep the usage counted by `previous`, the tracker before a reload
+    pub fn carry_over(&self, previous: &QuotaTracker) {
+        *self.usage.lock().unwrap() = previous.usage.lock().unwrap().clone();
+    }
+
+    /// Reject `api_key` with `insufficient_quota` once its daily or monthly
+    /// quota is used up
+    pub fn check(&self, api_key: &str) -> Result<(), ApiError> {
+        let limits = self.config.limits(api_key);
+        if limits.daily_tokens.is_none() && limits.monthly_tokens.is_none() {
+            return Ok(());
+        }
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(api_key.to_string()).or_insert_with(|| KeyUsage::new(today));
+        usage.roll(today);
+        let exhausted = limits.daily_tokens.is_some_and(|limit| usage.daily_tokens >= limit)
+            || limits.monthly_tokens.is_some_and(|limit| usage.monthly_tokens >= limit);
+        if exhausted {
+            return Err(ApiError::insufficient_quota());
+        }
+        Ok(())
+    }
+
+    /// Bill `tokens` to `api_key`, sending `quota.exhausted` when this uses
+    /// up its daily or monthly quota
+    pub fn record(&self, api_key: &str, tokens: u64) {
+        if !self.config.is_enabled() {
+            return;
+        }
+        let limits = self.config.limits(api_key);
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(api_key.to_string()).or_insert_with(|| KeyUsage::new(today));
+        usage.roll(today);
+        let windows = [
+            ("daily", limits.daily_tokens, &mut usage.daily_tokens),
+            ("monthly", limits.monthly_tokens, &mut usage.monthly_tokens),
+        ];
+        for (window, limit, used) in windows {
+            let before = *used;
+            *used += tokens;
+            if let Some(limit) = limit.filter(|limit| before < *limit && *used >= *limit) {
+                self.webhooks.send(
+                    Event::QuotaExhausted,
+                    serde_json::json!({
+                        "api_key": api_key,
+                        "window": window,
+                        "limit": limit,
+                        "used": *used,
+                    }),
+                );
+            }
+        }
+    }
+
+    /// Forget the usage of `api_key`, or of every key; returns how many keys
+    /// were reset
+    pub fn reset(&self, api_key: Option<&str>) -> usize {
+        let mut usage = self.usage.lock().unwrap();
+        match api_key {
+            Some(api_key) => usize::from(usage.remove(api_key).is_some()),
+            None => {
+                let count = usage.len();
+                usage.clear();
+                count
+            }
+        }
+    }
+
+    pub fn status(&self) -> BTreeMap<String, QuotaStatus> {
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        usage
+            .iter_mut()
+            .map(|(api_key, usage)| {
+                usage.roll(today);
+                let status = QuotaStatus {
+                    usage: *usage,
+                    limits: self.config.limits(api_key),
+                };
+                (api_key.clone(), status)
+            })
+            .collect()
+    }
+}
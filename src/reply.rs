@@ -0,0 +1,358 @@
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::request::ChatCompletionRequest;
+
+/// What the assistant answers: one or more choices, shaped like the
+/// `choices` of a non-streaming OpenAI chat completion
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Reply {
+    pub choices: Vec<ReplyChoice>,
+    /// Sources the content draws on, e.g. the `referensi` of a database
+    /// row; see `Reply::with_citations`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ReplyChoice {
+    #[serde(default)]
+    pub message: ReplyMessage,
+    /// `finish_reason` of the choice; defaults to `tool_calls` when the
+    /// message calls tools, `stop` otherwise
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ReplyMessage {
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Spans of the content citing a web page, as OpenAI's search models
+    /// return them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+    /// Built-in tools the provider ran before answering, streamed ahead of
+    /// the content
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub builtin_tool_calls: Vec<BuiltinToolCall>,
+}
+
+/// Call of a built-in tool such as `file_search`, shaped like the tool
+/// calls of an Assistants run step: `{"id", "type", <type>: <output>}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BuiltinToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The output, under the key named by `kind`
+    #[serde(flatten)]
+    pub output: serde_json::Map<String, serde_json::Value>,
+}
+
+impl BuiltinToolCall {
+    pub fn new(kind: &str, output: serde_json::Value) -> Self {
+        Self {
+            id: generate_tool_call_id(),
+            kind: kind.to_string(),
+            output: serde_json::Map::from_iter([(kind.to_string(), output)]),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Annotation {
+    #[serde(rename = "type", default = "default_annotation_type")]
+    pub kind: String,
+    pub url_citation: UrlCitation,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UrlCitation {
+    /// Character offsets of the citing span in the content, end exclusive
+    pub start_index: usize,
+    pub end_index: usize,
+    pub title: String,
+    pub url: String,
+}
+
+impl UrlCitation {
+    /// The link in a reference list entry, titled by the rest of the entry
+    /// or else by the host; `None` when the entry has no `http(s)://` URL
+    fn parse(entry: &str) -> Option<(String, String)> {
+        let url = entry
+            .split_whitespace()
+            .map(|word| word.trim_matches(|c| matches!(c, '(' | ')' | '<' | '>' | '[' | ']' | ',')))
+            .find(|word| word.starts_with("http://") || word.starts_with("https://"))?;
+        let title = entry.replace(url, "");
+        let title = title.trim_matches(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '<' | '>' | '[' | ']' | '-' | '–' | ':' | ','));
+        let title = if title.is_empty() {
+            url.split("://").nth(1).and_then(|rest| rest.split('/').next()).unwrap_or(url)
+        } else {
+            title
+        };
+        Some((title.to_string(), url.to_string()))
+    }
+}
+
+/// How the `citations` of a reply are presented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CitationStyle {
+    /// Listed under `**Referensi:**` after the content
+    List,
+    /// Perplexity's `[1]` markers, the sources being sent as `citations`
+    Markers,
+    /// OpenAI web search: a markdown link after each of the last
+    /// sentences, spanned by a `url_citation` annotation. Entries without
+    /// a URL are left out.
+    Annotations,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ToolCall {
+    /// Generated for every response when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", default = "default_tool_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON-encoded arguments; fixtures may also give them as an object
+    #[serde(default, deserialize_with = "arguments_as_string")]
+    pub arguments: String,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ToolCallsConfig {
+    #[serde(default)]
+    pub mode: ToolCallMode,
+}
+
+/// Whether a choice calling several tools calls them all at once
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolCallMode {
+    /// Sequential when the request sets `parallel_tool_calls: false`,
+    /// parallel otherwise
+    #[default]
+    Request,
+    /// Every tool call in one response, with distinct indexes
+    Parallel,
+    /// One tool call per response: the first one the conversation has not
+    /// answered with a tool result yet, then the choice's content once all
+    /// were answered
+    Sequential,
+}
+
+fn default_tool_type() -> String {
+    "function".to_string()
+}
+
+/// Tool call id in OpenAI's format, e.g. `call_Qx2...`
+pub fn generate_tool_call_id() -> String {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    format!("call_{}", suffix)
+}
+
+fn arguments_as_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(arguments) => arguments,
+        serde_json::Value::Null => "{}".to_string(),
+        arguments => arguments.to_string(),
+    })
+}
+
+impl Reply {
+    /// A single choice answering `content`
+    pub fn text(content: impl Into<String>) -> Self {
+        Self {
+            choices: vec![ReplyChoice {
+                message: ReplyMessage {
+                    content: Some(content.into()),
+                    tool_calls: Vec::new(),
+                    annotations: Vec::new(),
+                    builtin_tool_calls: Vec::new(),
+                },
+                finish_reason: None,
+            }],
+            citations: Vec::new(),
+        }
+    }
+
+    /// Present the `citations` in the first choice in `style`. Only
+    /// `Markers` keeps them (without list numbering), for the `citations`
+    /// of the chunks.
+    pub fn with_citations(mut self, style: CitationStyle) -> Self {
+        if self.citations.is_empty() {
+            return self;
+        }
+        let citations = std::mem::take(&mut self.citations);
+        let Some(message) = self.choices.first_mut().map(|choice| &mut choice.message) else {
+            return self;
+        };
+        let Some(content) = message.content.as_mut() else {
+            return self;
+        };
+        match style {
+            CitationStyle::List => {
+                content.push_str(&format!("\n\n**Referensi:**\n{}", citations.join("\n")));
+            }
+            CitationStyle::Markers => {
+                let markers: Vec<String> = (1..=citations.len()).map(|number| format!("[{}]", number)).collect();
+                *content = cite_after_sentences(content, &markers).0;
+                self.citations = citations.iter().map(|citation| strip_list_marker(citation).to_string()).collect();
+            }
+            CitationStyle::Annotations => {
+                let links: Vec<(String, String)> = citations.iter().filter_map(|entry| UrlCitation::parse(strip_list_marker(entry))).collect();
+                let inserted: Vec<String> = links.iter().map(|(title, url)| format!(" ([{}]({}))", title, url)).collect();
+                let (cited, spans) = cite_after_sentences(content, &inserted);
+                *content = cited;
+                // The span covers the markdown link, not the space and
+                // parentheses around it
+                message.annotations = links
+                    .into_iter()
+                    .zip(spans)
+                    .map(|((title, url), (start, end))| Annotation {
+                        kind: default_annotation_type(),
+                        url_citation: UrlCitation { start_index: start + 2, end_index: end - 1, title, url },
+                    })
+                    .collect();
+            }
+        }
+        self
+    }
+
+    /// Add built-in tool `calls` to every choice
+    pub fn with_builtin_tool_calls(mut self, calls: Vec<BuiltinToolCall>) -> Self {
+        if !calls.is_empty() {
+            for choice in &mut self.choices {
+                choice.message.builtin_tool_calls.extend(calls.iter().cloned());
+            }
+        }
+        self
+    }
+
+    /// Set the `finish_reason` of every choice that does not set its own
+    pub fn with_finish_reason(mut self, finish_reason: Option<String>) -> Self {
+        if let Some(finish_reason) = finish_reason {
+            for choice in self.choices.iter_mut().filter(|choice| choice.finish_reason.is_none()) {
+                choice.finish_reason = Some(finish_reason.clone());
+            }
+        }
+        self
+    }
+
+    /// Keep only the tool call due next in `request`'s conversation when
+    /// tools are to be called one at a time
+    pub fn with_tool_call_mode(mut self, mode: ToolCallMode, request: &ChatCompletionRequest) -> Self {
+        let sequential = match mode {
+            ToolCallMode::Request => request.parallel_tool_calls == Some(false),
+            ToolCallMode::Parallel => false,
+            ToolCallMode::Sequential => true,
+        };
+        if sequential {
+            let answered = request.tool_results_since_user();
+            for choice in self.choices.iter_mut().filter(|choice| !choice.message.tool_calls.is_empty()) {
+                let calls = std::mem::take(&mut choice.message.tool_calls);
+                choice.message.tool_calls.extend(calls.into_iter().nth(answered));
+                if choice.message.tool_calls.is_empty() && choice.finish_reason.as_deref() == Some("tool_calls") {
+                    choice.finish_reason = None;
+                }
+            }
+        }
+        self
+    }
+
+    /// Content of every choice, for captures and token counts
+    pub fn text_content(&self) -> String {
+        self.choices
+            .iter()
+            .filter_map(|choice| choice.message.content.as_deref())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Everything the completion is billed for: content and tool call
+    /// names and arguments
+    pub fn completion_text(&self) -> String {
+        let mut text = self.text_content();
+        for call in self.choices.iter().flat_map(|choice| &choice.message.tool_calls) {
+            text.push_str(&call.function.name);
+            text.push_str(&call.function.arguments);
+        }
+        text
+    }
+}
+
+impl ReplyChoice {
+    pub fn finish_reason(&self) -> &str {
+        match &self.finish_reason {
+            Some(finish_reason) => finish_reason,
+            None if !self.message.tool_calls.is_empty() => "tool_calls",
+            None => "stop",
+        }
+    }
+}
+
+fn default_annotation_type() -> String {
+    "url_citation".to_string()
+}
+
+/// `content` with the `citations` inserted after its last sentences, one
+/// per sentence; the final sentence takes the rest when there are fewer
+/// sentences than citations. Also returns the character offsets each
+/// citation was inserted at, end exclusive.
+fn cite_after_sentences(content: &str, citations: &[String]) -> (String, Vec<(usize, usize)>) {
+    let text = content.trim_end();
+    let mut ends: Vec<usize> = text
+        .char_indices()
+        .filter(|(at, c)| matches!(c, '.' | '!' | '?') && text[at + 1..].starts_with(char::is_whitespace))
+        .map(|(at, _)| at + 1)
+        .collect();
+    ends.push(text.len());
+    let ends = &ends[ends.len().saturating_sub(citations.len())..];
+
+    let mut cited = String::with_capacity(content.len() + citations.iter().map(String::len).sum::<usize>());
+    let mut spans = Vec::with_capacity(citations.len());
+    let mut chars = 0;
+    let mut from = 0;
+    for (index, end) in ends.iter().enumerate() {
+        cited.push_str(&text[from..*end]);
+        chars += text[from..*end].chars().count();
+        let last = if index + 1 == ends.len() { citations.len() } else { index + 1 };
+        for citation in &citations[index..last] {
+            cited.push_str(citation);
+            let length = citation.chars().count();
+            spans.push((chars, chars + length));
+            chars += length;
+        }
+        from = *end;
+    }
+    cited.push_str(&content[from..]);
+    (cited, spans)
+}
+
+/// A reference list entry without its `-`, `*`, `1.`, `1)` or `[1]` prefix
+fn strip_list_marker(entry: &str) -> &str {
+    let entry = entry.trim();
+    if let Some(rest) = entry.strip_prefix(['-', '*', '•']) {
+        return rest.trim_start();
+    }
+    let numbered = entry
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once(']'))
+        .or_else(|| entry.split_once(['.', ')']));
+    match numbered {
+        Some((number, rest)) if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) => rest.trim_start(),
+        _ => entry,
+    }
+}
@@ -0,0 +1,276 @@
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use log::debug;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::error::ApiError;
+
+/// Most pairs a request's `metadata` may hold
+const METADATA_MAX_PAIRS: usize = 16;
+const METADATA_MAX_KEY_CHARS: usize = 64;
+const METADATA_MAX_VALUE_CHARS: usize = 512;
+
+/// The parts of an OpenAI chat completion request the simulator looks at.
+/// Unknown fields are ignored so any client payload is accepted.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ChatCompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    /// End-user identifier
+    #[serde(default)]
+    pub user: Option<String>,
+    /// `{"type": "json_schema", "json_schema": {"schema": ...}}` asks for
+    /// structured output
+    #[serde(default)]
+    pub response_format: Option<serde_json::Value>,
+    /// `false` allows at most one tool call per response
+    #[serde(default)]
+    pub parallel_tool_calls: Option<bool>,
+    #[serde(default)]
+    pub tools: Option<Vec<serde_json::Value>>,
+    /// Legacy spelling of `tools`
+    #[serde(default)]
+    pub functions: Option<Vec<serde_json::Value>>,
+    /// Legacy spelling of `max_completion_tokens`
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+    /// OpenRouter provider preferences (`order`, `only`, `ignore`,
+    /// `allow_fallbacks`)
+    #[serde(default)]
+    pub provider: Option<serde_json::Value>,
+    /// Asks an OpenAI search model to search the web
+    #[serde(default)]
+    pub web_search_options: Option<serde_json::Value>,
+    /// Keep the completion for the stored-completions API
+    #[serde(default)]
+    pub store: Option<bool>,
+    /// String pairs stored with the completion, checked by `metadata()`
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+}
+
+/// Tuning parameters the simulator does not act on but records, so tests
+/// can check that middleware forwards them intact. Numbers are kept as
+/// sent, `-100` is not turned into `-100.0`.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct SamplingParams {
+    /// Token id to bias, from -100 to 100
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<BTreeMap<String, serde_json::Number>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<serde_json::Number>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<serde_json::Number>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ChatMessage {
+    #[serde(default)]
+    pub role: String,
+    /// Either a plain string or an array of content parts
+    #[serde(default)]
+    pub content: serde_json::Value,
+    /// Anthropic cache breakpoint on the whole message
+    #[serde(default)]
+    pub cache_control: Option<serde_json::Value>,
+}
+
+/// A prompt prefix an Anthropic `cache_control` block asks to cache
+#[derive(Debug, Clone)]
+pub struct CacheBreakpoint {
+    pub prefix: String,
+    /// `ttl` of the block (`5m`, `1h`), if given
+    pub ttl_ms: Option<u64>,
+}
+
+impl CacheBreakpoint {
+    fn new(prefix: String, cache_control: &serde_json::Value) -> Self {
+        let ttl_ms = cache_control.get("ttl").and_then(|ttl| ttl.as_str()).and_then(|ttl| {
+            let (amount, unit) = ttl.split_at(ttl.find(|c: char| !c.is_ascii_digit())?);
+            let amount: u64 = amount.parse().ok()?;
+            match unit {
+                "s" => Some(amount * 1000),
+                "m" => Some(amount * 60_000),
+                "h" => Some(amount * 3_600_000),
+                _ => None,
+            }
+        });
+        Self { prefix, ttl_ms }
+    }
+}
+
+impl ChatMessage {
+    /// Text of the message, joining the `text` parts of multi-part content
+    pub fn text(&self) -> String {
+        match &self.content {
+            serde_json::Value::String(text) => text.clone(),
+            serde_json::Value::Array(parts) => parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(|t| t.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+impl ChatCompletionRequest {
+    /// Parse a request body, falling back to an empty request when the body
+    /// is not valid JSON (the simulator never rejected such bodies)
+    pub fn parse(body: &[u8]) -> Self {
+        if body.is_empty() {
+            return Self::default();
+        }
+        serde_json::from_slice(body).unwrap_or_else(|e| {
+            debug!("Ignoring unparseable request body: {}", e);
+            Self::default()
+        })
+    }
+
+    /// The request's `metadata`, rejected as OpenAI does unless it holds at
+    /// most 16 pairs of strings with keys up to 64 and values up to 512
+    /// characters
+    pub fn metadata(&self) -> Result<Map<String, Value>, ApiError> {
+        check_metadata(self.metadata.as_ref())
+    }
+
+    /// Model name for labels and logs
+    pub fn model_name(&self) -> String {
+        self.model.clone().unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// All message text, as counted towards prompt tokens
+    pub fn prompt_text(&self) -> String {
+        self.messages.iter().map(ChatMessage::text).collect::<Vec<_>>().join("\n")
+    }
+
+    /// Prompt prefixes ending at each content part or message carrying a
+    /// `cache_control` block, in prompt order. Prefixes are joined the way
+    /// `prompt_text` joins the whole prompt.
+    pub fn cache_breakpoints(&self) -> Vec<CacheBreakpoint> {
+        let mut breakpoints = Vec::new();
+        let mut done: Vec<String> = Vec::new();
+        for message in &self.messages {
+            let mut parts: Vec<&str> = Vec::new();
+            for part in message.content.as_array().into_iter().flatten() {
+                if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
+                    parts.push(text);
+                }
+                if let Some(cache_control) = part.get("cache_control") {
+                    let mut prefix = done.clone();
+                    prefix.push(parts.join("\n"));
+                    breakpoints.push(CacheBreakpoint::new(prefix.join("\n"), cache_control));
+                }
+            }
+            done.push(message.text());
+            if let Some(cache_control) = &message.cache_control {
+                breakpoints.push(CacheBreakpoint::new(done.join("\n"), cache_control));
+            }
+        }
+        breakpoints
+    }
+
+    /// Whether `tools` declares a built-in tool of one of these `kinds`,
+    /// e.g. `web_search`
+    pub fn declares_tool(&self, kinds: &[&str]) -> bool {
+        self.tools
+            .iter()
+            .flatten()
+            .filter_map(|tool| tool.get("type").and_then(|kind| kind.as_str()))
+            .any(|kind| kinds.contains(&kind))
+    }
+
+    /// Whether the request searches the web, through `web_search_options`
+    /// or a `web_search` tool
+    pub fn searches_web(&self) -> bool {
+        self.web_search_options.is_some() || self.declares_tool(&["web_search", "web_search_preview"])
+    }
+
+    /// Tool results sent back since the most recent user message
+    pub fn tool_results_since_user(&self) -> usize {
+        self.messages
+            .iter()
+            .rev()
+            .take_while(|message| message.role != "user")
+            .filter(|message| message.role == "tool")
+            .count()
+    }
+
+    /// Text of the most recent user message, if any
+    pub fn last_user_message(&self) -> Option<String> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| message.role == "user")
+            .map(ChatMessage::text)
+    }
+}
+
+/// `metadata` as a map of strings, or the 400 OpenAI answers it with
+pub fn check_metadata(metadata: Option<&Value>) -> Result<Map<String, Value>, ApiError> {
+    let invalid = |message: String| ApiError::invalid_request(message).with_param("metadata");
+    let metadata = match metadata {
+        None | Some(Value::Null) => return Ok(Map::new()),
+        Some(Value::Object(metadata)) => metadata,
+        Some(_) => return Err(invalid("Invalid 'metadata': expected an object.".to_string())),
+    };
+    if metadata.len() > METADATA_MAX_PAIRS {
+        return Err(invalid(format!(
+            "Invalid 'metadata': too many properties. Expected an object with at most {} properties, but got an object with {} properties instead.",
+            METADATA_MAX_PAIRS,
+            metadata.len()
+        )));
+    }
+    for (key, value) in metadata {
+        if key.chars().count() > METADATA_MAX_KEY_CHARS {
+            return Err(invalid(format!(
+                "Invalid 'metadata': key '{}' is longer than {} characters.",
+                key, METADATA_MAX_KEY_CHARS
+            )));
+        }
+        match value.as_str() {
+            Some(value) if value.chars().count() <= METADATA_MAX_VALUE_CHARS => {}
+            Some(_) => {
+                return Err(invalid(format!(
+                    "Invalid 'metadata.{}': string too long. Expected a string with maximum length {}.",
+                    key, METADATA_MAX_VALUE_CHARS
+                )))
+            }
+            None => return Err(invalid(format!("Invalid 'metadata.{}': expected a string.", key))),
+        }
+    }
+    Ok(metadata.clone())
+}
+
+/// Identifier for the caller's API key that is safe to log and expose: the
+/// key's first three and last four characters, e.g. `sk-...a1b2`. Reads the
+/// OpenAI `Authorization: Bearer` header or the Azure `api-key` header.
+pub fn api_key_id(headers: &HeaderMap) -> String {
+    let key = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get("api-key").and_then(|value| value.to_str().ok()))
+        .map(str::trim)
+        .filter(|key| !key.is_empty());
+
+    let Some(key) = key else {
+        return "anonymous".to_string();
+    };
+    let chars: Vec<char> = key.chars().collect();
+    let suffix: String = chars[chars.len().saturating_sub(4)..].iter().collect();
+    if chars.len() < 12 {
+        format!("...{}", suffix)
+    } else {
+        let prefix: String = chars[..3].iter().collect();
+        format!("{}...{}", prefix, suffix)
+    }
+}
@@ -1,63 +1,278 @@
-use std::fs::{self, File};
-use std::io::{self, Read};
-use log::info;
-use rand::seq::SliceRandom;
-use crate::ResponseSimulator;
+use std::fs;
+use log::{info, debug, error};
+use clickhouse::{Client, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::config_loader::{Config, DatabaseConfig, SourceKind};
+use crate::corpus::CorpusFilter;
+use crate::db;
+use crate::error::CustomError;
+use crate::folder::{ResponseFile, ResponseFolder};
+use crate::reply::Reply;
 
-pub fn read_file_content(file_path: &str) -> io::Result<String> {
-    info!("Reading file content from {}", file_path);
-    let mut file = File::open(file_path)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-    Ok(content)
+#[derive(Row, Deserialize, Serialize, Debug, Clone)]
+pub struct ResponseSimulator {
+    #[serde(default, with = "clickhouse::serde::uuid::option")]
+    pub qa_id: Option<Uuid>,
+    pub pertanyaan: String,
+    pub jawaban: String,
+    pub referensi: String,
+    /// Optional metadata used to narrow selection; empty when the table has
+    /// no such column
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    pub persona: String,
+    /// Relative chance of being picked
+    #[serde(default = "default_weight")]
+    pub weight: f64,
 }
 
-pub fn read_random_markdown_file(folder_path: &str) -> io::Result<String> {
-    let paths = fs::read_dir(folder_path)?
-        .filter_map(Result::ok)
-        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "md"))
-        .collect::<Vec<_>>();
+fn default_weight() -> f64 {
+    1.0
+}
 
-    let mut rng = rand::thread_rng();
-    let random_file = paths.choose(&mut rng).expect("No markdown files found");
+/// One entry of the configured corpus, as the corpus subcommands see it
+#[derive(Debug, Clone)]
+pub struct CorpusEntry {
+    /// Where the entry comes from: its file relative to `files.dir`, or its
+    /// `qa_id` (or row number) in the database
+    pub origin: String,
+    /// A file's content goes to `jawaban`, with its front-matter; its
+    /// `pertanyaan` is empty
+    pub response: ResponseSimulator,
+    /// Why the entry cannot be served as is, e.g. invalid UTF-8
+    pub problem: Option<String>,
+}
 
-    read_file_content(random_file.path().to_str().unwrap())
+/// [`db::checked_query`] row
+#[derive(Row, Deserialize)]
+struct CheckedRow {
+    #[serde(with = "clickhouse::serde::uuid::option")]
+    qa_id: Option<Uuid>,
+    pertanyaan: String,
+    jawaban: String,
+    referensi: String,
+    category: String,
+    language: String,
+    persona: String,
+    weight: f64,
+    valid_utf8: u8,
 }
 
-/// Async version of read_random_markdown_file using spawn_blocking
-/// to avoid blocking the async runtime
-pub async fn read_random_markdown_file_async(folder_path: &str) -> io::Result<String> {
-    let folder_path = folder_path.to_string();
+/// Every entry of the corpus `config.source` serves from, including those
+/// the server skips
+pub async fn load_corpus(config: &Config) -> Result<Vec<CorpusEntry>, CustomError> {
+    match config.source {
+        SourceKind::Database => load_corpus_from_db(&config.database).await,
+        SourceKind::File => load_corpus_from_files(&ResponseFolder::new(&config.files)?),
+    }
+}
 
-    tokio::task::spawn_blocking(move || {
-        read_random_markdown_file(&folder_path)
+async fn load_corpus_from_db(config: &DatabaseConfig) -> Result<Vec<CorpusEntry>, CustomError> {
+    let client = db::build_client(config)?;
+    let query = db::checked_query(config);
+    debug!("Executing query: {}", query);
+    let rows = db::retry(&config.retry, "Fetching responses", || async {
+        let mut cursor = client.query(&query).fetch::<CheckedRow>()?;
+        let mut rows = Vec::new();
+        while let Some(row) = cursor.next().await? {
+            rows.push(row);
+        }
+        Ok(rows)
     })
     .await
-    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Task join error: {}", e)))?
+    .map_err(|e| CustomError::ConfigError(format!("cannot read the corpus from {}: {}", config.url, e)))?;
+    Ok(rows
+        .into_iter()
+        .enumerate()
+        .map(|(row, checked)| CorpusEntry {
+            origin: checked.qa_id.map_or_else(|| format!("row {}", row + 1), |id| id.to_string()),
+            problem: (checked.valid_utf8 == 0).then(|| "invalid UTF-8".to_string()),
+            response: ResponseSimulator {
+                qa_id: checked.qa_id,
+                pertanyaan: checked.pertanyaan,
+                jawaban: checked.jawaban,
+                referensi: checked.referensi,
+                category: checked.category,
+                language: checked.language,
+                persona: checked.persona,
+                weight: checked.weight,
+            },
+        })
+        .collect())
 }
 
-/// Async version of read_file_content using tokio::fs
-pub async fn read_file_content_async(file_path: &str) -> io::Result<String> {
-    info!("Reading file content async from {}", file_path);
-    tokio::fs::read_to_string(file_path).await
+fn load_corpus_from_files(folder: &ResponseFolder) -> Result<Vec<CorpusEntry>, CustomError> {
+    let mut entries = Vec::new();
+    for file in folder.matched_files() {
+        let (content, problem) = match fs::read(folder.path(&file)).map(String::from_utf8) {
+            Ok(Ok(content)) => (content, None),
+            Ok(Err(e)) => (String::from_utf8_lossy(e.as_bytes()).into_owned(), Some("invalid UTF-8".to_string())),
+            Err(e) => (String::new(), Some(format!("cannot read: {}", e))),
+        };
+        let (parsed, problem) = match ResponseFile::parse(&file, &content) {
+            Ok(parsed) => (parsed, problem),
+            Err(e) => (ResponseFile::default(), problem.or(Some(e))),
+        };
+        let front_matter = parsed.front_matter;
+        let jawaban = parsed
+            .reply
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+            .unwrap_or_default();
+        entries.push(CorpusEntry {
+            origin: file,
+            problem,
+            response: ResponseSimulator {
+                qa_id: None,
+                pertanyaan: String::new(),
+                jawaban,
+                referensi: String::new(),
+                category: front_matter.category.unwrap_or_default(),
+                language: front_matter.language.unwrap_or_default(),
+                persona: front_matter.persona.unwrap_or_default(),
+                weight: front_matter.weight.unwrap_or_else(default_weight),
+            },
+        });
+    }
+    Ok(entries)
 }
 
-pub(crate) fn format_response_from_db(response: &ResponseSimulator) -> String {
+/// Fetch responses from database, retrying transient failures
+pub async fn fetch_responses_from_db(
+    client: &Client,
+    config: &DatabaseConfig,
+    tracking: bool,
+) -> Result<Vec<ResponseSimulator>, CustomError> {
+    info!("Fetching responses from the database");
+
+    let query = db::select_query(config);
+    debug!("Executing query: {}", query);
+
+    let fetch = async {
+        db::retry(&config.retry, "Fetching responses", || async {
+            let mut cursor = client.query(&query).fetch::<ResponseSimulator>()?;
+            let mut records = Vec::new();
+            while let Some(row) = cursor.next().await? {
+                records.push(row);
+            }
+            Ok(records)
+        })
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch responses from the database: {}", e);
+            CustomError::FetchError
+        })
+    };
+    let records = db::with_query_timeout(config, "Fetching responses", fetch).await?;
+
+    info!("Fetched {} records from the database", records.len());
+    if tracking {
+        for record in &records {
+            debug!("{:?}", record);
+        }
+    }
+
+    Ok(records)
+}
+
+/// A weighted random row matching `filter`, read by streaming the corpus
+/// query and keeping a single sample (`database.sampling: reservoir`).
+/// Rows in the preferred language win when any match, as with
+/// `corpus::candidates`. `None` when no row may be served.
+pub async fn sample_response_from_db(
+    client: &Client,
+    config: &DatabaseConfig,
+    filter: &CorpusFilter,
+) -> Result<Option<ResponseSimulator>, CustomError> {
+    let query = db::select_query(config);
+    debug!("Sampling with query: {}", query);
+
+    let sample = async {
+        db::retry(&config.retry, "Sampling responses", || async {
+            let mut cursor = client.query(&query).fetch::<ResponseSimulator>()?;
+            // Best row overall and best in the preferred language, by
+            // weighted reservoir key ln(u) / weight (highest wins)
+            let mut any: Option<(f64, ResponseSimulator)> = None;
+            let mut preferred: Option<(f64, ResponseSimulator)> = None;
+            while let Some(row) = cursor.next().await? {
+                if row.weight <= 0.0 || !filter.matches(&row) {
+                    continue;
+                }
+                let key = (1.0 - rand::random::<f64>()).ln() / row.weight;
+                let slot = if filter.prefers_language(&row.language) { &mut preferred } else { &mut any };
+                if slot.as_ref().is_none_or(|(best, _)| key > *best) {
+                    *slot = Some((key, row));
+                }
+            }
+            Ok(preferred.or(any).map(|(_, row)| row))
+        })
+        .await
+        .map_err(|e| {
+            error!("Failed to sample responses from the database: {}", e);
+            CustomError::FetchError
+        })
+    };
+    let sampled = db::with_query_timeout(config, "Sampling responses", sample).await?;
+    debug!("Sampled response: {:?}", sampled);
+    Ok(sampled)
+}
+
+/// The row whose question best matches `text`, or `None` when no question
+/// reaches `database.best_match.min_similarity`
+pub async fn fetch_best_match(
+    client: &Client,
+    config: &DatabaseConfig,
+    filter: &CorpusFilter,
+    text: &str,
+) -> Result<Option<ResponseSimulator>, CustomError> {
+    let query = db::best_match_query(config, filter.category.is_some(), filter.persona.is_some());
+    debug!("Executing query: {}", query);
+
+    let search = async {
+        db::retry(&config.retry, "Searching responses", || {
+            let mut query = client.query(&query).bind(text).bind(config.best_match.min_similarity);
+            if let Some(category) = &filter.category {
+                query = query.bind(category.as_str());
+            }
+            if let Some(persona) = &filter.persona {
+                query = query.bind(persona.as_str());
+            }
+            query.fetch_optional::<ResponseSimulator>()
+        })
+        .await
+        .map_err(|e| {
+            error!("Failed to search responses in the database: {}", e);
+            CustomError::FetchError
+        })
+    };
+    let best = db::with_query_timeout(config, "Searching responses", search).await?;
+    debug!("Best match: {:?}", best);
+    Ok(best)
+}
+
+/// The question and answer of a row, with one citation per non-empty line
+/// of its `referensi` (see `Reply::with_citations`)
+pub fn reply_from_db(response: &ResponseSimulator) -> Reply {
     info!("Formatting response from database");
-    let mut formatted_response = format!(
+    let formatted_response = format!(
         "**Pertanyaan:**\n{}\n\n**Jawaban:**\n{}",
         response.pertanyaan, response.jawaban
     );
 
-    if !response.referensi.is_empty() {
-        formatted_response.push_str(&format!("\n\n**Referensi:**\n{}", response.referensi));
-    }
-
-    formatted_response.replace("\\n", "\n")
+    let mut reply = Reply::text(formatted_response.replace("\\n", "\n"));
+    reply.citations = response
+        .referensi
+        .replace("\\n", "\n")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    reply
 }
 
-pub fn select_random_response_from_db(responses: &[ResponseSimulator]) -> &ResponseSimulator {
-    info!("Selecting random response from database");
-    let mut rng = rand::thread_rng();
-    responses.choose(&mut rng).expect("No responses available")
-}
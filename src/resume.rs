@@ -0,0 +1,59 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use crate::config_loader::LatencyConfig;
+use crate::profile::ProviderProfile;
+use crate::reply::Reply;
+
+/// What is needed to replay a stream from an arbitrary event
+pub struct ResumableStream {
+    pub reply: Reply,
+    pub latency: LatencyConfig,
+    pub fixture: Option<String>,
+    pub profile: ProviderProfile,
+    /// `StreamOptions::seed` of the stream, to replay the same chunks
+    pub seed: u64,
+}
+
+/// Recently started streams, so a client reconnecting with `Last-Event-ID`
+/// can pick up where it was cut off. Once full, the oldest are forgotten.
+pub struct ResumeStore {
+    streams: Mutex<VecDeque<(String, Arc<ResumableStream>)>>,
+    capacity: usize,
+}
+
+impl ResumeStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            streams: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    /// Keep the newest streams of `previous`, the store before a reload, up
+    /// to this store's capacity
+    pub fn carry_over(&self, previous: &ResumeStore) {
+        let previous = previous.streams.lock().unwrap();
+        let skip = previous.len().saturating_sub(self.capacity);
+        *self.streams.lock().unwrap() = previous.iter().skip(skip).cloned().collect();
+    }
+
+    pub fn insert(&self, stream_id: String, stream: ResumableStream) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut streams = self.streams.lock().unwrap();
+        if streams.len() >= self.capacity {
+            streams.pop_front();
+        }
+        streams.push_back((stream_id, Arc::new(stream)));
+    }
+
+    pub fn get(&self, stream_id: &str) -> Option<Arc<ResumableStream>> {
+        let streams = self.streams.lock().unwrap();
+        streams
+            .iter()
+            .rev()
+            .find(|(id, _)| id == stream_id)
+            .map(|(_, stream)| stream.clone())
+    }
+}
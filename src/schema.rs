@@ -0,0 +1,162 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use crate::request::ChatCompletionRequest;
+
+/// Nesting beyond which recursive schemas stop producing optional fields
+const MAX_DEPTH: usize = 8;
+
+const WORDS: [&str; 12] = [
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india", "juliet", "kilo", "lima",
+];
+
+/// The JSON schema of a `response_format: {"type": "json_schema"}` request
+pub fn requested_schema(request: &ChatCompletionRequest) -> Option<&Value> {
+    let format = request.response_format.as_ref()?;
+    if format.get("type").and_then(Value::as_str) != Some("json_schema") {
+        return None;
+    }
+    format.pointer("/json_schema/schema")
+}
+
+/// A random instance of `schema` with values of the right types, as the
+/// assistant would answer it. With `violate` it breaks the schema instead:
+/// a required property goes missing, or a value gets the wrong type.
+pub fn generate(schema: &Value, violate: bool) -> String {
+    let generator = Generator { root: schema };
+    let mut instance = generator.instance(schema, 0);
+    if violate {
+        generator.violate(schema, &mut instance);
+    }
+    instance.to_string()
+}
+
+struct Generator<'a> {
+    root: &'a Value,
+}
+
+impl Generator<'_> {
+    /// `schema` with a local `$ref` (`#/$defs/...`, `#/definitions/...`) followed
+    fn resolve<'s>(&'s self, schema: &'s Value) -> &'s Value {
+        match schema.get("$ref").and_then(Value::as_str).and_then(|reference| reference.strip_prefix('#')) {
+            Some(pointer) => self.root.pointer(pointer).unwrap_or(&Value::Null),
+            None => schema,
+        }
+    }
+
+    fn instance(&self, schema: &Value, depth: usize) -> Value {
+        let schema = self.resolve(schema);
+        let mut rng = rand::thread_rng();
+        if let Some(value) = schema.get("const") {
+            return value.clone();
+        }
+        if let Some(value) = schema.get("enum").and_then(Value::as_array).and_then(|values| values.choose(&mut rng)) {
+            return value.clone();
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(variant) = schema.get(key).and_then(Value::as_array).and_then(|variants| variants.choose(&mut rng)) {
+                return self.instance(variant, depth + 1);
+            }
+        }
+        if let Some(first) = schema.get("allOf").and_then(Value::as_array).and_then(|all| all.first()) {
+            return self.instance(first, depth + 1);
+        }
+
+        match schema_type(schema).as_str() {
+            "object" => self.object(schema, depth),
+            "array" => {
+                let min = schema.get("minItems").and_then(Value::as_u64).unwrap_or(1);
+                let max = schema.get("maxItems").and_then(Value::as_u64).unwrap_or(min.max(3)).max(min);
+                let len = if depth >= MAX_DEPTH { min } else { rng.gen_range(min..=max) };
+                let items = schema.get("items").unwrap_or(&Value::Null);
+                (0..len).map(|_| self.instance(items, depth + 1)).collect()
+            }
+            "integer" => {
+                let min = schema.get("minimum").and_then(Value::as_i64).unwrap_or(0);
+                let max = schema.get("maximum").and_then(Value::as_i64).unwrap_or(min + 100).max(min);
+                json!(rng.gen_range(min..=max))
+            }
+            "number" => {
+                let min = schema.get("minimum").and_then(Value::as_f64).unwrap_or(0.0);
+                let max = schema.get("maximum").and_then(Value::as_f64).unwrap_or(min + 100.0).max(min);
+                json!((rng.gen_range(min..=max) * 100.0).round() / 100.0)
+            }
+            "boolean" => json!(rng.gen_bool(0.5)),
+            "null" => Value::Null,
+            _ => json!(string(schema)),
+        }
+    }
+
+    fn object(&self, schema: &Value, depth: usize) -> Value {
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let mut object = Map::new();
+        for (name, property) in schema.get("properties").and_then(Value::as_object).into_iter().flatten() {
+            if required.contains(&name.as_str()) || depth < MAX_DEPTH {
+                object.insert(name.clone(), self.instance(property, depth + 1));
+            }
+        }
+        Value::Object(object)
+    }
+
+    fn violate(&self, schema: &Value, instance: &mut Value) {
+        let schema = self.resolve(schema);
+        let required = schema.get("required").and_then(Value::as_array).and_then(|required| required.first());
+        if let (Some(object), Some(name)) = (instance.as_object_mut(), required.and_then(Value::as_str)) {
+            object.remove(name);
+            return;
+        }
+        let first = schema.get("properties").and_then(Value::as_object).and_then(|properties| properties.iter().next());
+        if let (Some(object), Some((name, property))) = (instance.as_object_mut(), first) {
+            object.insert(name.clone(), wrong_type(self.resolve(property)));
+            return;
+        }
+        *instance = wrong_type(schema);
+    }
+}
+
+fn schema_type(schema: &Value) -> String {
+    match schema.get("type") {
+        Some(Value::String(kind)) => kind.clone(),
+        // `["string", "null"]`: the first non-null type
+        Some(Value::Array(kinds)) => kinds
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|kind| *kind != "null")
+            .unwrap_or("null")
+            .to_string(),
+        _ if schema.get("properties").is_some() => "object".to_string(),
+        _ if schema.get("items").is_some() => "array".to_string(),
+        _ => "string".to_string(),
+    }
+}
+
+fn string(schema: &Value) -> String {
+    let mut rng = rand::thread_rng();
+    let text = match schema.get("format").and_then(Value::as_str) {
+        Some("date-time") => return chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        Some("date") => return chrono::Utc::now().format("%Y-%m-%d").to_string(),
+        Some("email") => return format!("{}@example.com", WORDS.choose(&mut rng).unwrap()),
+        Some("uuid") => return uuid::Uuid::new_v4().to_string(),
+        Some("uri") => return format!("https://example.com/{}", WORDS.choose(&mut rng).unwrap()),
+        _ => (0..rng.gen_range(1..=3)).map(|_| *WORDS.choose(&mut rng).unwrap()).collect::<Vec<_>>().join(" "),
+    };
+    let min = schema.get("minLength").and_then(Value::as_u64).unwrap_or(0) as usize;
+    let max = schema.get("maxLength").and_then(Value::as_u64).map_or(usize::MAX, |max| max as usize);
+    let mut text: String = text.chars().take(max).collect();
+    while text.chars().count() < min {
+        text.push('x');
+    }
+    text
+}
+
+/// A value that does not have the type `schema` asks for
+fn wrong_type(schema: &Value) -> Value {
+    match schema_type(schema).as_str() {
+        "string" => json!(42),
+        kind => json!(format!("not a {}", kind)),
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+use actix_web::http::StatusCode;
+use log::{info, debug};
+use serde::Deserialize;
+use crate::config_loader::LatencyConfig;
+use crate::error::{ApiError, CustomError};
+use crate::reply::{Reply, ReplyChoice, ReplyMessage};
+use crate::request::ChatCompletionRequest;
+
+/// Header selecting a conversation script by name
+pub const SCRIPT_HEADER: &str = "x-sim-script";
+/// Query parameter selecting a conversation script by name
+pub const SCRIPT_QUERY_PARAM: &str = "script";
+/// Sessions whose progress is remembered; the least recently started are
+/// forgotten first
+const MAX_TRACKED_SESSIONS: usize = 10000;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ScriptsConfig {
+    /// Directory of YAML conversation scripts, selectable per request
+    #[serde(default)]
+    pub dir: Option<String>,
+}
+
+/// Assistant turns served in order to one session
+#[derive(Deserialize, Debug, Clone)]
+pub struct Script {
+    /// Defaults to the file stem of the script
+    #[serde(default)]
+    pub name: String,
+    /// Overrides the global `latency` section for this script
+    #[serde(default)]
+    pub latency: Option<LatencyConfig>,
+    #[serde(default)]
+    pub on_end: ScriptEnd,
+    /// Error returned when a user message does not meet a turn's `expect`
+    #[serde(default)]
+    pub mismatch: MismatchError,
+    pub turns: Vec<ScriptTurn>,
+}
+
+/// What a session gets after the last turn
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptEnd {
+    /// Start over from the first turn
+    #[default]
+    Restart,
+    /// Answer with `mismatch` error
+    Error,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MismatchError {
+    #[serde(default = "default_mismatch_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub code: Option<String>,
+    /// Replaces the generated message describing the mismatch
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+impl Default for MismatchError {
+    fn default() -> Self {
+        Self {
+            status: default_mismatch_status(),
+            code: None,
+            message: None,
+        }
+    }
+}
+
+fn default_mismatch_status() -> u16 {
+    400
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScriptTurn {
+    /// Condition the last user message must meet for this turn
+    #[serde(default)]
+    pub expect: Option<Expectation>,
+    /// `content` and/or `tool_calls` of the assistant message
+    #[serde(flatten)]
+    pub message: ReplyMessage,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    /// Overrides the script's latency for this turn
+    #[serde(default)]
+    pub latency: Option<LatencyConfig>,
+}
+
+/// Every condition given must hold; matching is case-insensitive
+#[derive(Deserialize, Debug, Clone)]
+pub struct Expectation {
+    /// Substring of the user message
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// The whole user message, ignoring surrounding whitespace
+    #[serde(default)]
+    pub equals: Option<String>,
+}
+
+impl Expectation {
+    fn matches(&self, message: &str) -> bool {
+        let message = message.trim().to_lowercase();
+        self.contains.as_ref().is_none_or(|needle| message.contains(&needle.to_lowercase()))
+            && self.equals.as_ref().is_none_or(|expected| message == expected.trim().to_lowercase())
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(needle) = &self.contains {
+            parts.push(format!("containing '{}'", needle));
+        }
+        if let Some(expected) = &self.equals {
+            parts.push(format!("equal to '{}'", expected));
+        }
+        write!(f, "{}", parts.join(" and "))
+    }
+}
+
+impl Script {
+    fn validate(&self) -> Result<(), String> {
+        if self.turns.is_empty() {
+            return Err("declares no turns".to_string());
+        }
+        if StatusCode::from_u16(self.mismatch.status).is_err() {
+            return Err(format!("invalid mismatch status {}", self.mismatch.status));
+        }
+        Ok(())
+    }
+
+    fn mismatch(&self, message: String) -> ApiError {
+        let status = StatusCode::from_u16(self.mismatch.status).unwrap_or(StatusCode::BAD_REQUEST);
+        let mut error = ApiError::new(status, "invalid_request_error", self.mismatch.message.clone().unwrap_or(message));
+        error.code = self.mismatch.code.clone();
+        error
+    }
+
+    /// The turn at `index` as a reply with its pacing, checking the user
+    /// message against the turn's expectation
+    fn serve(&self, index: usize, request: &ChatCompletionRequest) -> Result<(Reply, Option<LatencyConfig>), ApiError> {
+        let turn = &self.turns[index];
+        if let Some(expect) = &turn.expect {
+            let message = request.last_user_message().unwrap_or_default();
+            if !expect.matches(&message) {
+                return Err(self.mismatch(format!(
+                    "Script '{}' turn {} expected a user message {}, got '{}'",
+                    self.name,
+                    index + 1,
+                    expect,
+                    message
+                )));
+            }
+        }
+        let reply = Reply {
+            choices: vec![ReplyChoice {
+                message: turn.message.clone(),
+                finish_reason: turn.finish_reason.clone(),
+            }],
+            citations: Vec::new(),
+        };
+        Ok((reply, turn.latency.or(self.latency)))
+    }
+}
+
+#[derive(Default)]
+struct Progress {
+    /// Next turn per script and session
+    next: HashMap<(String, String), usize>,
+    order: VecDeque<(String, String)>,
+}
+
+/// All scripts loaded from the scripts directory, keyed by name, and how
+/// far each session has got through them
+#[derive(Default)]
+pub struct ScriptSet {
+    scripts: HashMap<String, Script>,
+    progress: Mutex<Progress>,
+}
+
+impl ScriptSet {
+    /// Load every `.yml`/`.yaml` file in `dir` as a script. Any unreadable
+    /// or invalid script fails the whole load so mistakes surface at startup.
+    pub fn load_dir(dir: &str) -> Result<Self, CustomError> {
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| CustomError::ConfigError(format!("cannot read scripts dir {}: {}", dir, e)))?;
+
+        let mut scripts = HashMap::new();
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if !path.extension().is_some_and(|ext| ext == "yml" || ext == "yaml") {
+                continue;
+            }
+            let script = load_script(&path)?;
+            if scripts.contains_key(&script.name) {
+                return Err(CustomError::ConfigError(format!(
+                    "duplicate script name '{}' in {}", script.name, path.display()
+                )));
+            }
+            scripts.insert(script.name.clone(), script);
+        }
+
+        info!("Loaded {} conversation script(s) from {}", scripts.len(), dir);
+        Ok(Self { scripts, progress: Mutex::default() })
+    }
+
+    /// The next turn of script `name` for `session`. Without a session the
+    /// turn is the number of assistant messages already in the request. A
+    /// session only moves on when the turn's expectation is met.
+    pub fn next_turn(
+        &self,
+        name: &str,
+        session: Option<&str>,
+        request: &ChatCompletionRequest,
+    ) -> Result<(Reply, Option<LatencyConfig>), ApiError> {
+        let script = self
+            .scripts
+            .get(name)
+            .ok_or_else(|| ApiError::invalid_request(format!("Unknown script '{}'", name)))?;
+        let mut progress = self.progress.lock().unwrap();
+        let key = session.map(|session| (name.to_string(), session.to_string()));
+        let turn = match &key {
+            Some(key) => progress.next.get(key).copied().unwrap_or(0),
+            None => request.messages.iter().filter(|message| message.role == "assistant").count(),
+        };
+        let index = match script.on_end {
+            ScriptEnd::Restart => turn % script.turns.len(),
+            ScriptEnd::Error if turn < script.turns.len() => turn,
+            ScriptEnd::Error => {
+                return Err(script.mismatch(format!("Script '{}' has no turn {}", name, turn + 1)));
+            }
+        };
+        let served = script.serve(index, request)?;
+        debug!("Script {}: serving turn {} of {}", name, index + 1, script.turns.len());
+
+        if let Some(key) = key {
+            if !progress.next.contains_key(&key) {
+                if progress.order.len() >= MAX_TRACKED_SESSIONS {
+                    if let Some(oldest) = progress.order.pop_front() {
+                        progress.next.remove(&oldest);
+                    }
+                }
+                progress.order.push_back(key.clone());
+            }
+            progress.next.insert(key, index + 1);
+        }
+        Ok(served)
+    }
+}
+
+fn load_script(path: &Path) -> Result<Script, CustomError> {
+    let invalid = |reason: String| CustomError::ConfigError(format!("script {}: {}", path.display(), reason));
+
+    let content = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let mut script: Script = serde_yaml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+    if script.name.is_empty() {
+        script.name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
+    script.validate().map_err(invalid)?;
+    debug!("Loaded script {} with {} turns", script.name, script.turns.len());
+    Ok(script)
+}
@@ -0,0 +1,116 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use serde::Deserialize;
+
+/// Pools whose position is remembered; the least recently started are
+/// forgotten first
+const MAX_TRACKED_POSITIONS: usize = 10000;
+
+/// How one of several servable entries (response files, database rows,
+/// fixture responses) is picked for a request
+#[derive(Deserialize, Clone, Default)]
+pub struct SelectionConfig {
+    #[serde(default)]
+    pub strategy: PickStrategy,
+    /// Keep a separate position per session (`x-sim-session` or `user`)
+    /// instead of one shared by every client
+    #[serde(default)]
+    pub per_session: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PickStrategy {
+    /// Weighted random choice
+    #[default]
+    Random,
+    /// Every entry in turn, ordered by name, starting over after the last
+    RoundRobin,
+    /// Every entry in turn, ordered by name, repeating the last one once
+    /// all were served
+    Sequential,
+    /// The entry a hash of the last user message points to, so the same
+    /// prompt always gets the same answer, across runs and instances
+    Hash,
+}
+
+#[derive(Default)]
+struct Positions {
+    /// Next entry per pool of candidates and session
+    next: HashMap<(u64, Option<String>), usize>,
+    order: VecDeque<(u64, Option<String>)>,
+}
+
+/// Picks entries with a predictable strategy, remembering how far each
+/// pool of candidates (and session, with `per_session`) has got
+pub struct Selector {
+    config: SelectionConfig,
+    positions: Mutex<Positions>,
+}
+
+impl Selector {
+    pub fn new(config: SelectionConfig) -> Self {
+        Self {
+            config,
+            positions: Mutex::default(),
+        }
+    }
+
+    pub fn is_random(&self) -> bool {
+        self.config.strategy == PickStrategy::Random
+    }
+
+    /// The next of `items` (identified by `key`) for `session`, or the one
+    /// `prompt` hashes to. Candidates are ordered by key, so the same set
+    /// always gets the same sequence whatever order it was listed in.
+    /// `None` when `items` is empty or the strategy is `random`.
+    pub fn pick<'a, T>(
+        &self,
+        items: &[&'a T],
+        key: impl Fn(&T) -> String,
+        session: Option<&str>,
+        prompt: Option<&str>,
+    ) -> Option<&'a T> {
+        let mut keyed: Vec<(String, &'a T)> = items.iter().map(|item| (key(item), *item)).collect();
+        keyed.sort_by(|a, b| a.0.cmp(&b.0));
+        if keyed.is_empty() {
+            return None;
+        }
+        if self.config.strategy == PickStrategy::Hash {
+            let hash = fnv1a(prompt.unwrap_or_default().trim().as_bytes());
+            return Some(keyed[(hash % keyed.len() as u64) as usize].1);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        for (key, _) in &keyed {
+            key.hash(&mut hasher);
+        }
+        let pool = (hasher.finish(), session.filter(|_| self.config.per_session).map(str::to_string));
+
+        let mut positions = self.positions.lock().unwrap();
+        let turn = positions.next.get(&pool).copied().unwrap_or(0);
+        let index = match self.config.strategy {
+            PickStrategy::Random | PickStrategy::Hash => return None,
+            PickStrategy::RoundRobin => turn % keyed.len(),
+            PickStrategy::Sequential => turn.min(keyed.len() - 1),
+        };
+        if !positions.next.contains_key(&pool) {
+            if positions.order.len() >= MAX_TRACKED_POSITIONS {
+                if let Some(oldest) = positions.order.pop_front() {
+                    positions.next.remove(&oldest);
+                }
+            }
+            positions.order.push_back(pool.clone());
+        }
+        positions.next.insert(pool, index + 1);
+        Some(keyed[index].1)
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the std hashers is fixed across Rust
+/// versions and platforms
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3))
+}
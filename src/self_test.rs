@@ -0,0 +1,184 @@
+use std::time::Duration;
+use actix_web::web::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::TokioExecutor;
+use serde_json::{json, Value};
+use crate::check::Report;
+use crate::cli::Args;
+use crate::config_loader::Config;
+use crate::error::CustomError;
+use crate::simulator::Simulator;
+use crate::stream::DEFAULT_MODEL;
+
+type Client = HyperClient<HttpConnector, Full<Bytes>>;
+
+const PROMPT: &str = "Self-test: reply with anything.";
+
+/// A response as the self-test saw it
+struct Exchange {
+    status: u16,
+    content_type: String,
+    body: String,
+}
+
+/// Boot the configured simulator on an ephemeral loopback port, send it a
+/// non-streaming and a streaming chat completion for `--model` (the first
+/// of `models`, else the default), check both follow the OpenAI protocol,
+/// print the outcome and stop. Fails when either check does, so it serves
+/// as a container healthcheck and smoke test.
+pub async fn run(args: &Args) -> Result<(), CustomError> {
+    let mut config = Config::read(args.profile())?;
+    // Run as a healthcheck, the probes would fill the served instance's
+    // access log
+    config.logging.access = None;
+    if !config.endpoints.chat_completions {
+        return Err(CustomError::ConfigError("endpoints.chat_completions is disabled, nothing to self-test".to_string()));
+    }
+    let model = args
+        .value("model")
+        .map(str::to_string)
+        .or_else(|| config.models.keys().next().cloned())
+        .unwrap_or_else(|| DEFAULT_MODEL.to_string());
+    let timeout = Duration::from_secs(args.parse_or("timeout", 30)?);
+
+    let running = Simulator::builder().config(config).workers(1).bind_random_port().spawn().await?;
+    let url = format!("{}/v1/chat/completions", running.url());
+    println!("Self-testing {} with model {}\n", url, model);
+    let client: Client = HyperClient::builder(TokioExecutor::new()).build(HttpConnector::new());
+    let mut report = Report::default();
+    let completion = post(&client, &url, &model, false, timeout).await;
+    report.record("non-streaming", completion.and_then(|exchange| check_completion(&exchange)));
+    let stream = post(&client, &url, &model, true, timeout).await;
+    report.record("streaming", stream.and_then(|exchange| check_stream(&exchange)));
+    running.stop().await;
+
+    if report.failed > 0 {
+        return Err(CustomError::ConfigError(format!(
+            "{} of {} self-test checks failed",
+            report.failed,
+            report.failed + report.passed
+        )));
+    }
+    println!("\nSelf-test passed");
+    Ok(())
+}
+
+async fn post(client: &Client, url: &str, model: &str, stream: bool, timeout: Duration) -> Result<Exchange, CustomError> {
+    let failed = |e: String| CustomError::ConfigError(e);
+    let mut body = json!({
+        "model": model,
+        "messages": [{"role": "user", "content": PROMPT}],
+        "stream": stream,
+    });
+    if stream {
+        body["stream_options"] = json!({"include_usage": true});
+    }
+    let request = http::Request::post(url)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .map_err(|e| failed(e.to_string()))?;
+    let exchange = async {
+        let response = client.request(request).await.map_err(|e| failed(e.to_string()))?;
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = response.into_body().collect().await.map_err(|e| failed(e.to_string()))?.to_bytes();
+        Ok(Exchange { status, content_type, body: String::from_utf8_lossy(&body).into_owned() })
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .unwrap_or_else(|_| Err(failed(format!("no complete response within {:?}", timeout))))
+}
+
+/// A `chat.completion` object with an assistant message and usage adding up
+fn check_completion(exchange: &Exchange) -> Result<String, CustomError> {
+    let document = expect_ok(exchange, "application/json")?;
+    let completion: Value = serde_json::from_str(&document).map_err(|e| invalid(format!("not JSON: {}", e)))?;
+    expect(completion["object"] == "chat.completion", "object is not chat.completion")?;
+    expect(completion["id"].as_str().is_some_and(|id| !id.is_empty()), "missing id")?;
+    expect(completion["model"].is_string(), "missing model")?;
+    let choice = &completion["choices"][0];
+    expect(choice["message"]["role"] == "assistant", "choices[0].message.role is not assistant")?;
+    let content = choice["message"]["content"].as_str().unwrap_or_default();
+    let tool_calls = choice["message"]["tool_calls"].as_array().map_or(0, Vec::len);
+    expect(!content.is_empty() || tool_calls > 0, "choices[0].message has neither content nor tool_calls")?;
+    let finish_reason = choice["finish_reason"].as_str().ok_or_else(|| invalid("missing finish_reason".to_string()))?;
+    let usage = check_usage(&completion["usage"])?;
+    Ok(format!("{} chars, finish_reason {}, {}", content.chars().count(), finish_reason, usage))
+}
+
+/// Server-sent `chat.completion.chunk` events streaming content and a
+/// usage chunk. A finish reason and the closing `[DONE]` are only sent
+/// with a `fidelity` provider profile.
+fn check_stream(exchange: &Exchange) -> Result<String, CustomError> {
+    let body = expect_ok(exchange, "text/event-stream")?;
+    let mut chunks: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("data:")).map(str::trim).collect();
+    let done = chunks.last() == Some(&"[DONE]");
+    if done {
+        chunks.pop();
+    }
+    expect(!chunks.contains(&"[DONE]"), "chunks after data: [DONE]")?;
+    expect(!chunks.is_empty(), "no chunks")?;
+    let (mut content, mut finish_reason, mut usage) = (String::new(), None, None);
+    for (index, data) in chunks.iter().enumerate() {
+        let chunk: Value = serde_json::from_str(data).map_err(|e| invalid(format!("chunk {} is not JSON: {}", index, e)))?;
+        expect(chunk["object"] == "chat.completion.chunk", &format!("chunk {} is not a chat.completion.chunk", index))?;
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            content.push_str(choice["delta"]["content"].as_str().unwrap_or_default());
+            if let Some(reason) = choice["finish_reason"].as_str() {
+                finish_reason = Some(reason.to_string());
+            }
+        }
+        if !chunk["usage"].is_null() {
+            usage = Some(check_usage(&chunk["usage"])?);
+        }
+    }
+    expect(!content.is_empty(), "no chunk has delta content")?;
+    let usage = usage.ok_or_else(|| invalid("no usage chunk despite stream_options.include_usage".to_string()))?;
+    Ok(format!(
+        "{} chunks{}, {} chars, finish_reason {}, {}",
+        chunks.len(),
+        if done { " and [DONE]" } else { "" },
+        content.chars().count(),
+        finish_reason.as_deref().unwrap_or("none"),
+        usage
+    ))
+}
+
+fn check_usage(usage: &Value) -> Result<String, CustomError> {
+    let tokens = |field: &str| usage[field].as_u64().ok_or_else(|| invalid(format!("usage.{} is missing", field)));
+    let (prompt, completion, total) = (tokens("prompt_tokens")?, tokens("completion_tokens")?, tokens("total_tokens")?);
+    expect(prompt + completion == total, "usage.total_tokens is not prompt_tokens + completion_tokens")?;
+    Ok(format!("{} + {} tokens", prompt, completion))
+}
+
+/// The body of a 200 response of `content_type`
+fn expect_ok(exchange: &Exchange, content_type: &str) -> Result<String, CustomError> {
+    if exchange.status != 200 {
+        let body: String = exchange.body.chars().take(200).collect();
+        return Err(invalid(format!("status {}: {}", exchange.status, body)));
+    }
+    expect(
+        exchange.content_type.starts_with(content_type),
+        &format!("content-type is '{}', expected {}", exchange.content_type, content_type),
+    )?;
+    Ok(exchange.body.clone())
+}
+
+fn expect(condition: bool, problem: &str) -> Result<(), CustomError> {
+    if condition {
+        Ok(())
+    } else {
+        Err(invalid(problem.to_string()))
+    }
+}
+
+fn invalid(problem: String) -> CustomError {
+    CustomError::ConfigError(problem)
+}
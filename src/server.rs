@@ -0,0 +1,1646 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use futures_util::{Stream, StreamExt};
+use rand::seq::SliceRandom;
+use log::{info, debug, error, warn};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use crate::batch::{self, BatchStore};
+use crate::capture::{CaptureRecord, CaptureStore};
+use crate::circuit::CircuitBreaker;
+use crate::completion;
+use crate::config_loader::{Config, EndpointsConfig, LatencyConfig, SamplingMode, SelectionStrategy, SourceKind};
+use crate::corpus::{self, CorpusFilter, CATEGORY_HEADER, CATEGORY_QUERY_PARAM, PERSONA_HEADER, PERSONA_QUERY_PARAM};
+use crate::dataset::{Datasets, DATASET_HEADER, DATASET_QUERY_PARAM};
+use crate::db;
+use crate::echo::{self, ECHO_HEADER, ECHO_QUERY_PARAM};
+use crate::embedding::{SemanticIndex, SemanticSearch};
+use crate::error::{ApiError, CustomError, ErrorShape};
+use crate::error_reporting::ErrorReporter;
+use crate::failover::DbEndpoints;
+use crate::fault::{self, Fault, HangConfig, FAULT_HEADER, FAULT_QUERY_PARAM};
+use crate::fixtures::{FixtureSet, FIXTURE_HEADER, FIXTURE_QUERY_PARAM};
+use crate::folder::{ResponseFile, ResponseFolder};
+use crate::health::{HealthStatus, SubsystemHealth};
+use crate::hooks::Hooks;
+use crate::language::{self, LANGUAGE_HEADER, LANGUAGE_QUERY_PARAM};
+use crate::load::LoadTracker;
+use crate::logging::AccessLog;
+use crate::metrics::{self, Labels, Metrics};
+use crate::models::{self, ModelObject};
+use crate::openrouter;
+use crate::profile::{self, ProviderProfile, PROFILE_HEADER, PROFILE_QUERY_PARAM};
+use crate::prompt_cache::{CacheUsage, PromptCache};
+use crate::quota::QuotaTracker;
+use crate::code_interpreter;
+use crate::mcp;
+use crate::file_search::{self, Document};
+use crate::reply::{BuiltinToolCall, CitationStyle, Reply};
+use crate::request::{api_key_id, check_metadata, ChatCompletionRequest};
+use crate::request_id::request_id;
+use crate::response::{
+    fetch_best_match, fetch_responses_from_db, reply_from_db, sample_response_from_db,
+    ResponseSimulator,
+};
+use crate::resume::{ResumableStream, ResumeStore};
+use crate::schema;
+use crate::script::{ScriptSet, SCRIPT_HEADER, SCRIPT_QUERY_PARAM};
+use crate::selection::Selector;
+use crate::session::{SessionHistory, SESSION_HEADER};
+use crate::stats;
+use crate::stored::{ListQuery, StoredCompletions};
+use crate::stream::{
+    self, estimate_tokens, openai_simulator, parse_event_id, generate_id, StreamOptions, StreamTrace, TracedStream,
+};
+use crate::tiers::Tiers;
+use crate::warmup::Warmup;
+use crate::wasm::WasmPlugin;
+use crate::webhook::Webhooks;
+use crate::websocket;
+
+/// Application state shared across workers
+pub struct AppState {
+    pub config: Config,
+    pub started_at: Instant,
+    /// ClickHouse endpoints, queried through the healthy one
+    pub db: Arc<DbEndpoints>,
+    pub redis: Option<ConnectionManager>,
+    pub fixtures: FixtureSet,
+    pub scripts: ScriptSet,
+    pub capture: Arc<CaptureStore>,
+    pub stored: Arc<StoredCompletions>,
+    pub batches: BatchStore,
+    pub metrics: Arc<Metrics>,
+    pub load: Arc<LoadTracker>,
+    pub resume: ResumeStore,
+    pub db_breaker: CircuitBreaker,
+    /// Set when `database.selection` is `semantic`
+    pub semantic: Option<SemanticSearch>,
+    pub sessions: SessionHistory,
+    pub folders: ResponseFolder,
+    pub quota: QuotaTracker,
+    pub tiers: Tiers,
+    pub datasets: Datasets,
+    pub warmup: Warmup,
+    pub selector: Selector,
+    pub prompt_cache: PromptCache,
+    /// Set when `wasm.module` is configured
+    pub wasm: Option<WasmPlugin>,
+    pub hooks: Arc<Hooks>,
+    pub errors: Arc<ErrorReporter>,
+    pub webhooks: Arc<Webhooks>,
+    /// Set when `logging.access` is configured
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+impl AppState {
+    pub fn new(
+        config: Config,
+        db: DbEndpoints,
+        redis: Option<ConnectionManager>,
+        folders: ResponseFolder,
+        fixtures: FixtureSet,
+        scripts: ScriptSet,
+        semantic: Option<SemanticSearch>,
+    ) -> Self {
+        let capture = Arc::new(CaptureStore::new(config.capture.max_records));
+        let stored = Arc::new(StoredCompletions::new(config.stored_completions.max_completions));
+        let batches = BatchStore::new(config.batches.clone());
+        let errors = Arc::new(ErrorReporter::new(&config.error_reporting));
+        let metrics = Arc::new(Metrics::default());
+        let webhooks = Arc::new(Webhooks::new(&config.webhooks));
+        let load = Arc::new(LoadTracker::new(
+            config.semaphore_limit,
+            config.limits.saturation_warn_ratio,
+            config.limits.shed_above,
+            config.limits.fair_queuing,
+            config.adaptive_latency.clone(),
+            metrics.clone(),
+            webhooks.clone(),
+        ));
+        let resume = ResumeStore::new(config.streaming.resume_max_streams);
+        let quota = QuotaTracker::new(config.quota.clone(), webhooks.clone());
+        let tiers = Tiers::new(&config.tiers);
+        let warmup = Warmup::new(config.warmup.clone());
+        let selector = Selector::new(config.selection.clone());
+        let prompt_cache = PromptCache::new(config.prompt_cache.clone());
+        let sessions = SessionHistory::new(if config.dedup.enabled { config.dedup.max_sessions } else { 0 });
+        let breaker_config = &config.database.circuit_breaker;
+        let db_breaker = CircuitBreaker::new(
+            "database",
+            breaker_config.failure_threshold,
+            Duration::from_millis(breaker_config.open_ms),
+            metrics.clone(),
+            webhooks.clone(),
+        );
+        Self {
+            config,
+            started_at: Instant::now(),
+            db: Arc::new(db),
+            redis,
+            fixtures,
+            scripts,
+            capture,
+            stored,
+            batches,
+            metrics,
+            load,
+            resume,
+            db_breaker,
+            semantic,
+            sessions,
+            folders,
+            quota,
+            tiers,
+            datasets: Datasets::default(),
+            warmup,
+            selector,
+            prompt_cache,
+            wasm: None,
+            hooks: Arc::default(),
+            errors,
+            webhooks,
+            access_log: None,
+        }
+    }
+
+    pub fn with_datasets(mut self, datasets: Datasets) -> Self {
+        self.datasets = datasets;
+        self
+    }
+
+    pub fn with_wasm(mut self, wasm: Option<WasmPlugin>) -> Self {
+        self.wasm = wasm;
+        self
+    }
+
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = Arc::new(hooks);
+        self
+    }
+
+    pub fn with_access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log.map(Arc::new);
+        self
+    }
+
+    /// Keep what `previous`, the state before a reload, has recorded while
+    /// serving: uptime, captures, stored completions, batches, metrics,
+    /// quota usage, rate limit allowances, resumable streams, session
+    /// de-duplication, cached prompt prefixes and warmup
+    pub fn carry_over(mut self, previous: &AppState) -> Self {
+        self.started_at = previous.started_at;
+        self.capture.carry_over(&previous.capture);
+        self.stored.carry_over(&previous.stored);
+        self.batches.carry_over(&previous.batches);
+        self.metrics.carry_over(&previous.metrics);
+        self.quota.carry_over(&previous.quota);
+        self.tiers.carry_over(&previous.tiers);
+        self.resume.carry_over(&previous.resume);
+        self.sessions.carry_over(&previous.sessions);
+        self.prompt_cache.carry_over(&previous.prompt_cache);
+        self.warmup.carry_over(&previous.warmup);
+        self
+    }
+}
+
+/// Redis key helpers
+fn redis_key_db_responses(prefix: &str, dataset: Option<&str>) -> String {
+    match dataset {
+        Some(dataset) => format!("{}:db_responses:{}", prefix, dataset),
+        None => format!("{}:db_responses", prefix),
+    }
+}
+
+/// Drop the cached corpus of every dataset, so the next request reads it
+/// from the database again
+pub(crate) async fn drop_cached_corpus(state: &AppState) {
+    let (Some(redis), Some(redis_config)) = (&state.redis, &state.config.redis) else {
+        return;
+    };
+    let keys: Vec<String> = std::iter::once(None)
+        .chain(state.datasets.configs().keys().map(|name| Some(name.as_str())))
+        .map(|dataset| redis_key_db_responses(&redis_config.prefix, dataset))
+        .collect();
+    if let Err(e) = redis.clone().del::<_, ()>(&keys).await {
+        warn!("Failed to drop cached responses from Redis: {}", e);
+    }
+}
+
+/// Responses of a dataset (`None` is `database.table`), noting how many
+/// `database.table` holds for `/readyz`
+async fn get_db_responses(state: &AppState, dataset: Option<&str>) -> Result<Vec<ResponseSimulator>, CustomError> {
+    let responses = get_cached_db_responses(state, dataset).await?;
+    if dataset.is_none() {
+        state.db.set_corpus_rows(responses.len() as u64);
+    }
+    Ok(responses)
+}
+
+/// Get cached responses of a dataset (`None` is `database.table`) from
+/// Redis, or fetch from database if cache miss/expired
+async fn get_cached_db_responses(state: &AppState, dataset: Option<&str>) -> Result<Vec<ResponseSimulator>, CustomError> {
+    let tracking = state.config.tracking.enabled;
+    let database = state.datasets.config(&state.config.database, dataset);
+    let (Some(redis), Some(redis_config)) = (&state.redis, &state.config.redis) else {
+        return fetch_responses_from_db(&state.db.client(), database, tracking).await;
+    };
+    let mut redis = redis.clone();
+    let key = redis_key_db_responses(&redis_config.prefix, dataset);
+
+    // Try to get from Redis cache
+    let cached: Option<String> = redis.get(&key).await.unwrap_or(None);
+
+    if let Some(cached_json) = cached {
+        match serde_json::from_str::<Vec<ResponseSimulator>>(&cached_json) {
+            Ok(responses) => {
+                debug!("Cache hit: returning {} cached responses from Redis", responses.len());
+                return Ok(responses);
+            }
+            Err(e) => {
+                warn!("Failed to deserialize cached responses: {}", e);
+                // Continue to fetch fresh data
+            }
+        }
+    }
+
+    // Cache miss or error, fetch from database
+    info!("Cache miss, fetching from database");
+    let responses = fetch_responses_from_db(&state.db.client(), database, tracking).await?;
+
+    // Store in Redis with TTL
+    if !responses.is_empty() {
+        match serde_json::to_string(&responses) {
+            Ok(json) => {
+                let ttl = state.config.cache_ttl;
+                if let Err(e) = redis.set_ex::<_, _, ()>(&key, &json, ttl).await {
+                    warn!("Failed to cache responses in Redis: {}", e);
+                } else {
+                    debug!("Cached {} responses in Redis with TTL {}s", responses.len(), ttl);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to serialize responses for caching: {}", e);
+            }
+        }
+    }
+
+    Ok(responses)
+}
+
+/// Choose among `items` (identified by `key`) with the `selection`
+/// strategy, or with `choose` when it is `random`, skipping entries the
+/// session was already served when de-duplication is enabled
+fn pick_entry<'a, T>(
+    state: &AppState,
+    filter: &CorpusFilter,
+    items: &[&'a T],
+    key: impl Fn(&T) -> String,
+    choose: impl Fn(&[&'a T]) -> Option<&'a T>,
+) -> Option<&'a T> {
+    let session = filter.session.as_deref();
+    if state.selector.is_random() {
+        return state.sessions.pick(session, items, key, choose);
+    }
+    state.selector.pick(items, key, session, filter.text.as_deref())
+}
+
+/// A random response file that may be served for the request (see
+/// [`ResponseFolder::candidates`]). Files the session was already served
+/// are skipped when de-duplication is enabled.
+async fn get_cached_file_response(state: &AppState, filter: &CorpusFilter) -> Result<ResponseFile, CustomError> {
+    let candidates = state.folders.candidates(filter);
+    let candidates: Vec<&(String, f64)> = candidates.iter().collect();
+    let selected = pick_entry(state, filter, &candidates, |(file, _)| file.clone(), |files| {
+        files.choose_weighted(&mut rand::thread_rng(), |(_, weight)| *weight).ok().copied()
+    });
+    let Some((selected, _)) = selected else {
+        if !state.folders.is_empty() && !filter.is_empty() {
+            return Err(ApiError::invalid_request(format!("No responses match {}", filter)).into());
+        }
+        error!("No response files for model {:?} in {}", filter.model, state.folders.root());
+        return Err(CustomError::FetchError);
+    };
+    let file = state.folders.read(selected).await.map_err(|e| {
+        error!("Failed to read response file {}: {}", selected, e);
+        CustomError::FetchError
+    })?;
+    Ok(file.as_ref().clone())
+}
+
+/// Liveness, listing the subsystems that are not fully working (see
+/// `/readyz` for why)
+#[actix_web::get("/health")]
+async fn health_check(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let degraded: Vec<&str> = subsystems(&state)
+        .into_iter()
+        .filter(|(_, health)| health.status != HealthStatus::Ok)
+        .map(|(name, _)| name)
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "healthy",
+        "service": "rai-endpoint-simulator",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "degraded": degraded,
+    }))
+}
+
+/// Readiness: whether the simulator is serving from its configured source
+/// or has degraded to the file fallback
+#[actix_web::get("/readyz")]
+async fn readyz(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let (source, fallback) = serving_source(&state);
+    let subsystems = subsystems(&state);
+    let degraded = fallback || subsystems.values().any(|health| health.status != HealthStatus::Ok);
+    let mut body = serde_json::json!({
+        "status": if degraded { "degraded" } else { "ready" },
+        "configured_source": state.config.source,
+        "serving_source": source,
+        "degraded": degraded,
+        "subsystems": subsystems,
+    });
+    if state.config.source == SourceKind::Database {
+        body["database"] = serde_json::json!({
+            "connected": state.db.is_connected(),
+            "error": state.db.connect_error(),
+        });
+    }
+    // Not ready while ClickHouse has not been reached since startup and
+    // nothing else can answer
+    let starting = state.config.source == SourceKind::Database && !state.db.is_connected();
+    match (starting, source) {
+        (true, "none") => HttpResponse::ServiceUnavailable().json(body),
+        _ => HttpResponse::Ok().json(body),
+    }
+}
+
+/// Health of every subsystem in use: the database, the corpus served
+/// from, and the outputs requests are written to
+fn subsystems(state: &AppState) -> BTreeMap<&'static str, SubsystemHealth> {
+    let mut subsystems = BTreeMap::new();
+    let fallback = state.config.database.circuit_breaker.fallback_to_file;
+    let fallback_status = if fallback { HealthStatus::Degraded } else { HealthStatus::Down };
+    let corpus = match state.config.source {
+        SourceKind::File => match state.folders.len() {
+            0 => SubsystemHealth::new(HealthStatus::Degraded, format!("no response files in {}", state.folders.root())),
+            files => SubsystemHealth::new(HealthStatus::Ok, format!("{} response files", files)),
+        },
+        SourceKind::Database => {
+            let database = if !state.db.is_connected() {
+                let error = state.db.connect_error().unwrap_or_else(|| "not connected yet".to_string());
+                SubsystemHealth::new(fallback_status, format!("not connected: {}", error))
+            } else if state.db_breaker.is_open() {
+                SubsystemHealth::new(fallback_status, "circuit breaker open after repeated query failures")
+            } else {
+                SubsystemHealth::new(HealthStatus::Ok, format!("querying {}", state.db.active_url()))
+            };
+            subsystems.insert("database", database);
+            let table = &state.config.database.table;
+            match state.db.corpus_rows() {
+                Some(0) => SubsystemHealth::new(HealthStatus::Degraded, format!("{} has no response rows", table)),
+                Some(rows) => SubsystemHealth::new(HealthStatus::Ok, format!("{} response rows in {}", rows, table)),
+                None => SubsystemHealth::new(HealthStatus::Ok, format!("{} not read yet", table)),
+            }
+        }
+    };
+    subsystems.insert("corpus", corpus);
+    if let Some(access_log) = &state.access_log {
+        subsystems.insert("access_log", access_log.health().health());
+    }
+    if state.webhooks.is_enabled() {
+        subsystems.insert("webhooks", state.webhooks.health().health());
+    }
+    if state.errors.is_enabled() {
+        subsystems.insert("error_reporting", state.errors.health().health());
+    }
+    subsystems
+}
+
+/// Source responses currently come from, and whether that is a fallback
+/// because the database is not connected yet or its circuit is open
+fn serving_source(state: &AppState) -> (&'static str, bool) {
+    match state.config.source {
+        SourceKind::File => ("file", false),
+        SourceKind::Database if !state.db.is_connected() || state.db_breaker.is_open() => {
+            let fallback = state.config.database.circuit_breaker.fallback_to_file;
+            (if fallback { "file" } else { "none" }, true)
+        }
+        SourceKind::Database => ("database", false),
+    }
+}
+
+#[actix_web::post("/test_completion")]
+async fn test_completion() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "id": "chatcmpl-AjoahzpVUCsJmOQZRKZUze7qBjEjn",
+        "object": "chat.completion",
+        "created": 1735482595,
+        "model": "gpt-4o-2024-08-06",
+        "choices": [
+            {
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": "============>>  Selamat! Aplikasi anda telah sukses terhubung ke OpenAI Simulator. <============="
+                },
+                "logprobs": null,
+                "finish_reason": "stop"
+            }
+        ],
+        "usage": {
+            "prompt_tokens": 57,
+            "completion_tokens": 92,
+            "total_tokens": 149
+        }
+    }))
+}
+
+/// Draw a response from the configured source (file or database), with
+/// the front-matter of the file it came from
+async fn source_response(state: &AppState, filter: &CorpusFilter) -> Result<ResponseFile, CustomError> {
+    let response = match state.config.source {
+        SourceKind::File => {
+            get_cached_file_response(state, filter).await?
+        },
+        SourceKind::Database => {
+            if !state.db.is_connected() {
+                return file_fallback(state, filter, ApiError::shed(ErrorShape::OpenAi).into()).await;
+            }
+            if !state.db_breaker.allow() {
+                return file_fallback(state, filter, CustomError::FetchError).await;
+            }
+            let matched = match state.config.database.selection {
+                SelectionStrategy::Random => Ok(None),
+                SelectionStrategy::BestMatch => best_match_response(state, filter).await,
+                SelectionStrategy::Semantic => semantic_response(state, filter).await,
+            };
+            match matched {
+                Ok(Some(reply)) => return Ok(ResponseFile { reply, ..Default::default() }),
+                Ok(None) => {}
+                Err(e) => return file_fallback(state, filter, e).await,
+            }
+            if state.config.database.sampling == SamplingMode::Reservoir {
+                return sampled_response(state, filter).await;
+            }
+            let responses = match get_db_responses(state, filter.dataset.as_deref()).await {
+                Ok(responses) => {
+                    state.db_breaker.record_success();
+                    responses
+                }
+                Err(e) => {
+                    state.db_breaker.record_failure();
+                    return file_fallback(state, filter, e).await;
+                }
+            };
+            if responses.is_empty() {
+                error!("No responses available");
+                return Err(CustomError::FetchError);
+            }
+            let candidates = corpus::candidates(&responses, filter);
+            let selected = pick_entry(state, filter, &candidates, corpus::key, corpus::choose);
+            let Some(response) = selected else {
+                if filter.is_empty() {
+                    error!("No responses with a positive weight");
+                    return Err(CustomError::FetchError);
+                }
+                return Err(ApiError::invalid_request(format!("No responses match {}", filter)).into());
+            };
+            debug!("Selected Response: {:?}", response);
+            ResponseFile { reply: reply_from_db(response), ..Default::default() }
+        },
+    };
+    Ok(response)
+}
+
+/// A row drawn while streaming the corpus (`database.sampling: reservoir`)
+async fn sampled_response(state: &AppState, filter: &CorpusFilter) -> Result<ResponseFile, CustomError> {
+    let database = state.datasets.config(&state.config.database, filter.dataset.as_deref());
+    let sampled = match sample_response_from_db(&state.db.client(), database, filter).await {
+        Ok(sampled) => {
+            state.db_breaker.record_success();
+            sampled
+        }
+        Err(e) => {
+            state.db_breaker.record_failure();
+            return file_fallback(state, filter, e).await;
+        }
+    };
+    let Some(response) = sampled else {
+        if filter.is_empty() {
+            error!("No responses with a positive weight");
+            return Err(CustomError::FetchError);
+        }
+        return Err(ApiError::invalid_request(format!("No responses match {}", filter)).into());
+    };
+    Ok(ResponseFile { reply: reply_from_db(&response), ..Default::default() })
+}
+
+/// Answer for `database.selection: best_match`: the closest question's
+/// row, else the configured fallback answer. `None` means select at random.
+async fn best_match_response(state: &AppState, filter: &CorpusFilter) -> Result<Option<Reply>, CustomError> {
+    let database = state.datasets.config(&state.config.database, filter.dataset.as_deref());
+    let Some(text) = &filter.text else {
+        return Ok(None);
+    };
+    let best = fetch_best_match(&state.db.client(), database, filter, text).await;
+    match &best {
+        Ok(_) => state.db_breaker.record_success(),
+        Err(_) => state.db_breaker.record_failure(),
+    }
+    Ok(match best? {
+        Some(response) => Some(reply_from_db(&response)),
+        None => {
+            debug!("No question similar enough to the user message");
+            database.best_match.fallback_answer.clone().map(Reply::text)
+        }
+    })
+}
+
+/// Answer for `database.selection: semantic`: the row whose question
+/// embedding is nearest to the user message, else the configured fallback
+/// answer. `None` means select at random.
+async fn semantic_response(state: &AppState, filter: &CorpusFilter) -> Result<Option<Reply>, CustomError> {
+    let (Some(semantic), Some(text)) = (&state.semantic, &filter.text) else {
+        return Ok(None);
+    };
+    let index = semantic.index(filter.dataset.as_deref());
+    let index = index
+        .get_or_try_init(|| async {
+            let rows = get_cached_db_responses(state, filter.dataset.as_deref()).await;
+            match &rows {
+                Ok(_) => state.db_breaker.record_success(),
+                Err(_) => state.db_breaker.record_failure(),
+            }
+            SemanticIndex::build(&semantic.embedder, rows?).await
+        })
+        .await?;
+    let query = semantic.embed_one(text).await?;
+
+    let config = &state.config.database.semantic;
+    Ok(match index.nearest(&query, filter) {
+        Some((response, similarity)) if f64::from(similarity) >= config.min_similarity => {
+            debug!("Nearest question (similarity {:.3}): {:?}", similarity, response);
+            Some(reply_from_db(response))
+        }
+        _ => {
+            debug!("No question similar enough to the user message");
+            config.fallback_answer.clone().map(Reply::text)
+        }
+    })
+}
+
+/// Built-in tools the request declares, run against the corpus before the
+/// reply is streamed
+async fn builtin_tool_calls(state: &AppState, request: &ChatCompletionRequest, filter: &CorpusFilter) -> Vec<BuiltinToolCall> {
+    let mut calls = Vec::new();
+    if request.declares_tool(&file_search::TOOL_TYPES) {
+        let documents = corpus_documents(state, filter).await;
+        calls.extend(file_search::call(&state.config.file_search, request, &documents));
+    }
+    calls.extend(code_interpreter::call(&state.config.code_interpreter, request));
+    calls.extend(mcp::calls(&state.config.mcp, request));
+    calls
+}
+
+/// The entries of the configured source that may be served for `filter`,
+/// as documents for `file_search`; none when the source is unavailable
+async fn corpus_documents(state: &AppState, filter: &CorpusFilter) -> Vec<Document> {
+    match state.config.source {
+        SourceKind::File => {
+            let mut documents = Vec::new();
+            for (file, _) in state.folders.candidates(filter) {
+                match state.folders.read(&file).await {
+                    Ok(parsed) => documents.push(Document { text: parsed.reply.text_content(), file_name: file }),
+                    Err(e) => debug!("Skipping response file {} for file_search: {}", file, e),
+                }
+            }
+            documents
+        }
+        SourceKind::Database => match get_cached_db_responses(state, filter.dataset.as_deref()).await {
+            Ok(rows) => corpus::candidates(&rows, filter)
+                .into_iter()
+                .map(|row| Document {
+                    file_name: format!("{}.md", corpus::key(row)),
+                    text: format!("{}\n\n{}", row.pertanyaan, row.jawaban).replace("\\n", "\n"),
+                })
+                .collect(),
+            Err(e) => {
+                debug!("No documents for file_search: {}", e);
+                Vec::new()
+            }
+        },
+    }
+}
+
+/// Serve from the markdown folder while the database is unavailable, or
+/// fail with `error` when the fallback is disabled or has nothing to serve
+async fn file_fallback(
+    state: &AppState,
+    filter: &CorpusFilter,
+    error: CustomError,
+) -> Result<ResponseFile, CustomError> {
+    if !state.config.database.circuit_breaker.fallback_to_file {
+        return Err(error);
+    }
+    debug!("Database unavailable ({}), falling back to {}", error, state.folders.root());
+    get_cached_file_response(state, filter).await.map_err(|_| error)
+}
+
+/// Value of a per-request selector, from a header or else a query parameter
+fn request_selector(req: &HttpRequest, header: &str, query_param: &str) -> Option<String> {
+    if let Some(name) = req.headers().get(header).and_then(|v| v.to_str().ok()) {
+        return Some(name.to_string());
+    }
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get(query_param).cloned())
+}
+
+/// Fixture bundle requested via the `x-sim-fixture` header or `?fixture=` query
+fn requested_fixture(req: &HttpRequest) -> Option<String> {
+    request_selector(req, FIXTURE_HEADER, FIXTURE_QUERY_PARAM)
+}
+
+/// Conversation script requested via the `x-sim-script` header or `?script=` query
+fn requested_script(req: &HttpRequest) -> Option<String> {
+    request_selector(req, SCRIPT_HEADER, SCRIPT_QUERY_PARAM)
+}
+
+/// Fault requested via the `x-sim-fault` header or `?fault=` query, else
+/// one rolled from the `faults` probabilities
+fn requested_fault(state: &AppState, req: &HttpRequest) -> Result<Option<Fault>, ApiError> {
+    state.config.faults.select(request_selector(req, FAULT_HEADER, FAULT_QUERY_PARAM).as_deref())
+}
+
+/// Corpus metadata requested via `x-sim-category`/`?category=` (or a
+/// `model@category` suffix) and `x-sim-persona`/`?persona=`, preferring
+/// the language named by `x-sim-lang`/`?lang=` or else detected from the
+/// last user message, from the dataset named by `x-sim-dataset`/`?dataset=`
+/// or else listing the model or API key
+fn requested_corpus_filter(
+    state: &AppState,
+    req: &HttpRequest,
+    request: &ChatCompletionRequest,
+    api_key: &str,
+) -> Result<CorpusFilter, ApiError> {
+    let language = request_selector(req, LANGUAGE_HEADER, LANGUAGE_QUERY_PARAM).or_else(|| {
+        request
+            .last_user_message()
+            .and_then(|message| language::detect(&message))
+            .map(str::to_string)
+    });
+    let mut filter = CorpusFilter::new(
+        request,
+        request_selector(req, CATEGORY_HEADER, CATEGORY_QUERY_PARAM),
+        request_selector(req, PERSONA_HEADER, PERSONA_QUERY_PARAM),
+        language,
+    );
+    filter.session = req
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| request.user.clone())
+        .filter(|session| !session.is_empty());
+    if state.config.source == SourceKind::Database {
+        let requested = request_selector(req, DATASET_HEADER, DATASET_QUERY_PARAM);
+        filter.dataset = state.datasets.select(requested.as_deref(), request.model.as_deref(), api_key)?;
+    }
+    Ok(filter)
+}
+
+/// Provider profile requested via `x-sim-profile` or `?profile=`, falling
+/// back to the model's profile, the route's profile, `default_profile` and
+/// then to the plain simulator format. Also returns the name of the
+/// profile, if any.
+fn requested_profile(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &ApiRoute,
+    model: &str,
+) -> Result<(Option<String>, ProviderProfile), ApiError> {
+    let model_default = state.config.models.get(model).and_then(|info| info.profile.clone());
+    let route_default = match route {
+        ApiRoute::OpenAi => None,
+        ApiRoute::Azure { .. } => state.config.azure.profile.clone(),
+        ApiRoute::OpenRouter { .. } => Some(openrouter::PROFILE.to_string()),
+    };
+    let Some(name) = request_selector(req, PROFILE_HEADER, PROFILE_QUERY_PARAM)
+        .or(model_default)
+        .or(route_default)
+        .or_else(|| state.config.default_profile.clone())
+    else {
+        return Ok((None, ProviderProfile::default()));
+    };
+    match profile::resolve(&state.config.profiles, &name) {
+        Some(profile) => Ok((Some(name), profile)),
+        None => Err(ApiError::invalid_request(format!("Unknown provider profile '{}'", name))),
+    }
+}
+
+/// Stream and last received frame named by the `Last-Event-ID` header
+fn last_event_id(req: &HttpRequest) -> Option<(String, usize)> {
+    req.headers()
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_event_id)
+}
+
+/// Pacing actually achieved by a completed stream
+fn record_stream_metrics(metrics: &Metrics, labels: &Labels, trace: &StreamTrace, completion_tokens: u32) {
+    if !trace.completed {
+        return;
+    }
+    if let Some(first) = trace.chunks.first() {
+        metrics.observe(&metrics::TTFT_SECONDS, labels, first.offset_ms / 1000.0);
+    }
+    let duration = trace.duration.as_secs_f64();
+    metrics.observe(&metrics::STREAM_DURATION_SECONDS, labels, duration);
+    metrics.observe(&metrics::CHUNKS_PER_RESPONSE, labels, trace.chunks.len() as f64);
+    if duration > 0.0 {
+        metrics.observe(&metrics::TOKENS_PER_SECOND, labels, completion_tokens as f64 / duration);
+    }
+}
+
+fn token_labels(usage_labels: &Labels, kind: &str) -> Labels {
+    let mut labels = usage_labels.clone();
+    labels.push(("kind", kind.to_string()));
+    labels
+}
+
+/// Pick the reply to stream and its pacing, from a fixture bundle or a
+/// conversation script when one is requested, otherwise an instance of the
+/// requested JSON schema (off-schema with `violate_schema`), or else the
+/// reply of the WASM plugin or the configured source
+async fn select_content(
+    state: &AppState,
+    body: &[u8],
+    request: &ChatCompletionRequest,
+    fixture: Option<&str>,
+    script: Option<&str>,
+    filter: &CorpusFilter,
+    violate_schema: bool,
+) -> Result<(Reply, LatencyConfig), CustomError> {
+    if let Some(name) = script.filter(|_| fixture.is_none()) {
+        let (reply, latency) = state.scripts.next_turn(name, filter.session.as_deref(), request)?;
+        return Ok((reply, latency.unwrap_or(state.config.latency)));
+    }
+    match fixture {
+        Some(name) => {
+            let bundle = state.fixtures.get(name).ok_or_else(|| {
+                ApiError::invalid_request(format!("Unknown fixture bundle '{}'", name))
+            })?;
+            bundle.check_model(request.model.as_deref())?;
+            let response = bundle.select_response(request, &state.selector, filter.session.as_deref());
+            debug!("Serving fixture response {}/{}", bundle.name, response.id);
+            Ok((Reply::text(response.content.clone()), bundle.latency.unwrap_or(state.config.latency)))
+        }
+        None => {
+            if let Some(schema) = schema::requested_schema(request) {
+                debug!("Serving an instance of the requested JSON schema, violated={}", violate_schema);
+                return Ok((Reply::text(schema::generate(schema, violate_schema)), state.config.latency));
+            }
+            if let Some(reply) = state.wasm.as_ref().map(|wasm| wasm.select_response(body)).transpose()?.flatten() {
+                debug!("Serving the WASM plugin's response");
+                return Ok((reply, state.config.latency));
+            }
+            let file = source_response(state, filter).await?;
+            let latency = file.front_matter.latency.unwrap_or(state.config.latency);
+            Ok((file.reply, latency))
+        }
+    }
+}
+
+/// Hold a request hit by the `hang` fault before its response headers
+/// unless they are to be sent first, then fail with the 504 a gateway
+/// would answer once its upstream timed out
+async fn hang_before_headers(hang: &HangConfig, request_id: &str) -> Result<(), CustomError> {
+    if hang.send_headers {
+        info!("Hanging for {:?} after the response headers request_id={}", hang.duration(), request_id);
+        return Ok(());
+    }
+    info!("Hanging for {:?} before answering request_id={}", hang.duration(), request_id);
+    tokio::time::sleep(hang.duration()).await;
+    Err(hang.gateway_timeout().into())
+}
+
+/// API flavour a chat completion request arrived through
+enum ApiRoute {
+    OpenAi,
+    Azure { deployment: String },
+    /// OpenRouter's `/api/v1`, answering as the generation with this id
+    OpenRouter { generation_id: String },
+}
+
+#[actix_web::post("/v1/chat/completions")]
+async fn chat_completions(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, CustomError> {
+    serve_chat_completion(req, body, state, ApiRoute::OpenAi).await
+}
+
+#[actix_web::post("/openai/deployments/{deployment}/chat/completions")]
+async fn azure_chat_completions(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Bytes,
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, CustomError> {
+    serve_chat_completion(req, body, state, ApiRoute::Azure { deployment: path.into_inner() }).await
+}
+
+/// OpenRouter's chat completions: routed to a provider per the request's
+/// `provider` preferences, with the generation id in `x-generation-id`
+#[actix_web::post("/api/v1/chat/completions")]
+async fn openrouter_chat_completions(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, CustomError> {
+    let generation_id = openrouter::generation_id();
+    let route = ApiRoute::OpenRouter { generation_id: generation_id.clone() };
+    let mut response = serve_chat_completion(req, body, state, route).await?;
+    if let Ok(value) = HeaderValue::from_str(&generation_id) {
+        response.headers_mut().insert(HeaderName::from_static(openrouter::GENERATION_HEADER), value);
+    }
+    Ok(response)
+}
+
+/// Stats of a finished OpenRouter generation, looked up in the capture by
+/// `?id=`
+#[actix_web::get("/api/v1/generation")]
+async fn openrouter_generation(req: HttpRequest, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let id = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("id").cloned())
+        .ok_or_else(|| ApiError::invalid_request("Missing generation id"))?;
+    let generation = state.capture.with_records(|records| {
+        records
+            .iter()
+            .rev()
+            .find(|record| record.id == id)
+            .map(|record| openrouter::generation(record, &state.config.openrouter))
+    });
+    match generation {
+        Some(generation) => Ok(HttpResponse::Ok().json(generation)),
+        None => Err(ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("Generation {} not found", id)).into()),
+    }
+}
+
+/// A stored completion, as OpenAI's `GET /v1/chat/completions/{id}`
+#[actix_web::get("/v1/chat/completions/{completion_id}")]
+async fn retrieve_completion(path: web::Path<String>, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let id = path.into_inner();
+    match state.stored.get(&id) {
+        Some(completion) => Ok(HttpResponse::Ok().json(completion)),
+        None => Err(completion_not_found(&id).into()),
+    }
+}
+
+/// Stored completions, paginated with `after`, `limit` and `order` and
+/// narrowed with `model` and `metadata[<key>]=<value>`
+#[actix_web::get("/v1/chat/completions")]
+async fn list_completions(req: HttpRequest, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let query = ListQuery::parse(req.query_string()).map_err(ApiError::invalid_request)?;
+    Ok(HttpResponse::Ok().json(state.stored.list(&query)))
+}
+
+/// Replace a stored completion's `metadata`, the one field OpenAI lets
+/// callers update
+#[actix_web::post("/v1/chat/completions/{completion_id}")]
+async fn update_completion(
+    path: web::Path<String>,
+    body: web::Json<serde_json::Value>,
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, CustomError> {
+    let id = path.into_inner();
+    let metadata = check_metadata(body.get("metadata"))?;
+    match state.stored.update_metadata(&id, metadata) {
+        Some(completion) => Ok(HttpResponse::Ok().json(completion)),
+        None => Err(completion_not_found(&id).into()),
+    }
+}
+
+#[actix_web::delete("/v1/chat/completions/{completion_id}")]
+async fn delete_completion(path: web::Path<String>, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let id = path.into_inner();
+    if !state.stored.delete(&id) {
+        return Err(completion_not_found(&id).into());
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "object": "chat.completion.deleted",
+        "id": id,
+        "deleted": true,
+    })))
+}
+
+fn completion_not_found(id: &str) -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("Chat completion {} not found", id))
+}
+
+async fn serve_chat_completion(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<Arc<AppState>>,
+    route: ApiRoute,
+) -> Result<HttpResponse, CustomError> {
+    let streamed = ChatCompletionRequest::parse(&body).stream != Some(false);
+    let stream = completion_stream(&req, body, &state, route).await?;
+    let generating = Instant::now();
+    let timing = req.extensions().get::<SimTiming>().cloned();
+    let mut response = if streamed {
+        HttpResponse::Ok().content_type("text/event-stream").streaming(stream)
+    } else {
+        completion::collect(stream).await?
+    };
+    if let Some(timing) = timing {
+        timing.insert_headers(response.headers_mut(), (!streamed).then(|| generating.elapsed()));
+    }
+    Ok(response)
+}
+
+/// What the simulator scheduled for a chat completion, sent in `x-sim-*`
+/// headers so load tests can tell its latency from the network's
+#[derive(Clone)]
+struct SimTiming {
+    /// Id of the completion's record in `/admin/snapshot`
+    response_id: String,
+    /// Waited for a tier and concurrency permit
+    queue_ms: u128,
+    ttft_ms: u64,
+    total_ms: u64,
+}
+
+impl SimTiming {
+    /// `delivered` is how long the body actually took, only known before
+    /// the headers are sent for non-streamed responses
+    fn insert_headers(&self, headers: &mut HeaderMap, delivered: Option<Duration>) {
+        let mut values = vec![
+            ("x-sim-response-id", self.response_id.clone()),
+            ("x-sim-queue-ms", self.queue_ms.to_string()),
+            ("x-sim-ttft-ms", self.ttft_ms.to_string()),
+            ("x-sim-total-ms", self.total_ms.to_string()),
+        ];
+        if let Some(delivered) = delivered {
+            values.push(("x-sim-delivered-ms", delivered.as_millis().to_string()));
+        }
+        for (name, value) in values {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+    }
+}
+
+/// Chat completions over WebSocket for clients that cannot consume SSE.
+/// The client sends the request body as one message and receives every
+/// chunk as a text message, ending with the same frames as SSE and a
+/// normal close.
+#[actix_web::get("/v1/chat/completions/ws")]
+async fn chat_completions_ws(
+    req: HttpRequest,
+    payload: web::Payload,
+    state: web::Data<Arc<AppState>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !state.config.streaming.websocket {
+        let disabled = ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            "WebSocket streaming is disabled, set streaming.websocket to enable it",
+        );
+        return Err(CustomError::from(disabled).into());
+    }
+    let (response, mut session, mut messages) = actix_ws::handle(&req, payload)?;
+    actix_web::rt::spawn(async move {
+        let Some(body) = websocket::read_request(&mut session, &mut messages).await else {
+            return;
+        };
+        match completion_stream(&req, body, &state, ApiRoute::OpenAi).await {
+            Ok(stream) => websocket::relay(session, messages, stream).await,
+            Err(e) => websocket::reject(session, e).await,
+        }
+    });
+    Ok(response)
+}
+
+/// Run a chat completion request up to its SSE frame stream, which also
+/// records metrics, usage and the capture once it ends
+async fn completion_stream(
+    req: &HttpRequest,
+    body: web::Bytes,
+    state: &web::Data<Arc<AppState>>,
+    route: ApiRoute,
+) -> Result<impl Stream<Item = Result<web::Bytes, actix_web::Error>>, CustomError> {
+    let request_id = request_id(req);
+    if state.load.should_shed() {
+        let model = ChatCompletionRequest::parse(&body).model_name();
+        let shape = requested_profile(state, req, &route, &model).map_or(ErrorShape::OpenAi, |(_, profile)| profile.error_shape);
+        debug!("Shedding request with {} in flight request_id={}", state.load.in_flight(), request_id);
+        state.metrics.inc_counter(&metrics::REQUESTS_SHED_TOTAL, &vec![("model", model)], 1.0);
+        return Err(ApiError::shed(shape).into());
+    }
+    let api_key = api_key_id(req.headers());
+    let queued = Instant::now();
+    let tier_permit = state.tiers.acquire(&api_key).await?;
+    let mut load_guard = state.load.acquire(&api_key).await?;
+    load_guard.hold(tier_permit);
+    let queue_ms = queued.elapsed().as_millis();
+
+    info!("Received request for chat completions request_id={}", request_id);
+    let received = body.clone();
+    let body = state.hooks.on_request(body)?;
+    let mut request = ChatCompletionRequest::parse(&body);
+    // Azure serves the deployment's model whatever the body says
+    let mut provider = None;
+    let route_check = match &route {
+        ApiRoute::OpenAi => Ok(()),
+        ApiRoute::OpenRouter { .. } => openrouter::choose_provider(&state.config.openrouter, request.provider.as_ref())
+            .map(|chosen| provider = Some(chosen)),
+        ApiRoute::Azure { deployment } => {
+            let resolved = state.config.azure.resolve(req, deployment);
+            request.model = Some(resolved.as_ref().map_or_else(|_| deployment.clone(), Clone::clone));
+            resolved.map(drop)
+        }
+    };
+    let fixture = requested_fixture(req);
+
+    let usage_labels: Labels = vec![("model", request.model_name()), ("api_key", api_key.clone())];
+    let prompt_tokens = estimate_tokens(&request.prompt_text());
+    state.metrics.inc_counter(&metrics::REQUESTS_TOTAL, &usage_labels, 1.0);
+    state.metrics.inc_counter(&metrics::TOKENS_TOTAL, &token_labels(&usage_labels, "prompt"), prompt_tokens as f64);
+
+    let capture_id = match &route {
+        ApiRoute::OpenRouter { generation_id } => generation_id.clone(),
+        _ => generate_id(),
+    };
+    let mut capture = CaptureRecord::new(capture_id, req.path(), &body);
+    capture.fixture = fixture.clone();
+    capture.request_id = request_id.clone();
+    capture.model = request.model_name();
+    capture.api_key = api_key.clone();
+    capture.prompt_tokens = prompt_tokens;
+    capture.sampling = request.sampling.clone();
+    let client = req.connection_info().realip_remote_addr().unwrap_or("-").to_string();
+    let request_line = format!("{} {} {:?}", req.method(), req.path(), req.version());
+
+    let event_ids = state.config.streaming.event_ids;
+    let resumed = last_event_id(req)
+        .filter(|_| event_ids)
+        .and_then(|(stream_id, seq)| match state.resume.get(&stream_id) {
+            Some(stream) => Some((stream_id, seq, stream)),
+            None => {
+                warn!("Cannot resume unknown stream {}, starting a new one request_id={}", stream_id, request_id);
+                None
+            }
+        });
+
+    let selected = match &resumed {
+        Some((stream_id, seq, stream)) => {
+            info!("Resuming stream {} after event {} request_id={}", stream_id, seq, request_id);
+            capture.fixture = stream.fixture.clone();
+            Ok((stream.reply.clone(), stream.latency, stream.profile.clone(), None))
+        }
+        None => match route_check
+            .and_then(|_| request.metadata().map(drop))
+            .and_then(|_| match state.config.models.get(&request.model_name()) {
+                Some(model) => model.check(&request).and_then(|_| model.check_context_length(&request, prompt_tokens)),
+                None => Ok(()),
+            })
+            .and_then(|_| state.quota.check(&api_key))
+            .and_then(|_| state.tiers.check_rate(&api_key))
+            .and_then(|_| Ok((requested_profile(state, req, &route, &request.model_name())?, requested_fault(state, req)?)))
+        {
+            Ok(((profile_name, profile), fault)) => {
+                capture.fault = fault.map(|fault| fault.to_string());
+                let held = match fault {
+                    Some(Fault::Hang) => hang_before_headers(&state.config.faults.hang, &request_id).await,
+                    Some(Fault::Overloaded) => Err(state.config.faults.overloaded.error().into()),
+                    _ => Ok(()),
+                };
+                let held = held.and_then(|_| Ok(state.warmup.admit(profile_name.as_deref())?));
+                let held = held.and_then(|warmup| Ok((warmup, requested_corpus_filter(state, req, &request, &api_key)?)));
+                let script = requested_script(req);
+                let violate_schema = fault == Some(Fault::SchemaViolation);
+                let echo = echo::requested(&request, request_selector(req, ECHO_HEADER, ECHO_QUERY_PARAM).as_deref());
+                let selected = match held {
+                    Ok((warmup, _)) if echo => {
+                        info!("Echoing the request back request_id={}", request_id);
+                        Ok((Reply::text(echo::dump(req, &received)), state.config.latency.scaled(warmup)))
+                    }
+                    Ok((warmup, filter)) => {
+                        let tool_calls = builtin_tool_calls(state, &request, &filter).await;
+                        select_content(state, &body, &request, fixture.as_deref(), script.as_deref(), &filter, violate_schema)
+                            .await
+                            .and_then(|(reply, latency)| {
+                                let citation_style = if profile.citations {
+                                    CitationStyle::Markers
+                                } else if request.searches_web() {
+                                    CitationStyle::Annotations
+                                } else {
+                                    CitationStyle::List
+                                };
+                                let reply = reply
+                                    .with_citations(citation_style)
+                                    .with_builtin_tool_calls(tool_calls)
+                                    .with_tool_call_mode(state.config.tool_calls.mode, &request);
+                                let reply = match &state.wasm {
+                                    Some(wasm) => wasm.post_process(reply)?,
+                                    None => reply,
+                                };
+                                let reply = state.hooks.on_select(reply, &body)?;
+                                Ok((reply, latency.scaled(warmup)))
+                            })
+                    }
+                    Err(e) => Err(e),
+                };
+                match selected {
+                    Ok((reply, latency)) => Ok((reply, latency, profile, fault)),
+                    Err(CustomError::Api(e)) => Err(e.with_shape(profile.error_shape).into()),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e.into()),
+        },
+    };
+    let (reply, latency, profile, fault) = match selected {
+        Ok(selected) => selected,
+        Err(e) => {
+            let mut error_labels = usage_labels.clone();
+            error_labels.push(("type", e.error_type().to_string()));
+            state.metrics.inc_counter(&metrics::REQUEST_ERRORS_TOTAL, &error_labels, 1.0);
+            capture.status = e.status_code().as_u16();
+            capture.response = e.to_string();
+            capture.completed = true;
+            state.errors.report_error(&e, &capture);
+            state.config.logging.sample(&capture);
+            if let Some(access_log) = &state.access_log {
+                access_log.write(&client, &request_line, &capture);
+            }
+            state.capture.push(capture);
+            return Err(e);
+        }
+    };
+    capture.response = reply.text_content();
+    capture.finish_reason = reply.choices.first().map(|choice| choice.finish_reason().to_string());
+    capture.provider = provider.clone();
+
+    let metric_labels: Labels = vec![
+        ("model", request.model_name()),
+        ("endpoint", req.match_pattern().unwrap_or_else(|| req.path().to_string())),
+    ];
+    let completion_tokens = estimate_tokens(&reply.completion_text());
+    capture.completion_tokens = completion_tokens;
+    let mut cache_usage = CacheUsage::default();
+    if resumed.is_none() {
+        state.quota.record(&api_key, u64::from(prompt_tokens + completion_tokens));
+        cache_usage = if profile.cache_control {
+            state.prompt_cache.lookup_breakpoints(&api_key, &request.cache_breakpoints())
+        } else {
+            CacheUsage {
+                read_tokens: state.prompt_cache.lookup(&api_key, &request.prompt_text()),
+                creation_tokens: 0,
+            }
+        };
+    }
+    let cached_tokens = cache_usage.read_tokens;
+    let latency = match state.prompt_cache.ttft_factor(cached_tokens, prompt_tokens) {
+        factor if factor < 1.0 => LatencyConfig {
+            ttft_ms: (latency.ttft_ms as f64 * factor).round() as u64,
+            ..latency
+        },
+        _ => latency,
+    };
+    if cached_tokens > 0 {
+        debug!("{} of {} prompt tokens cached request_id={}", cached_tokens, prompt_tokens, request_id);
+        state.metrics.inc_counter(&metrics::TOKENS_TOTAL, &token_labels(&usage_labels, "cached"), cached_tokens as f64);
+    }
+    capture.cached_tokens = cached_tokens;
+    capture.cache_creation_tokens = cache_usage.creation_tokens;
+
+    let tracking = state.config.tracking.enabled;
+    let paced = state.load.adapt(latency);
+    if paced.ttft_ms != latency.ttft_ms || paced.chunk_delay_ms != latency.chunk_delay_ms {
+        debug!(
+            "Latency stretched to ttft={}ms chunk_delay={}ms by {} in-flight requests request_id={}",
+            paced.ttft_ms, paced.chunk_delay_ms, state.load.permits_in_use(), request_id
+        );
+    }
+    let mut options = StreamOptions::new(&state.config.streaming, state.config.channel_capacity, paced)
+        .with_profile(&profile)
+        .with_request_id(request_id.clone())
+        .with_hooks(state.hooks.clone());
+    if state.config.prompt_cache.enabled || profile.cache_control {
+        options = options.with_cached_tokens(cached_tokens);
+    }
+    if let Some(coalesce) = state.config.streaming.coalesce {
+        let occupancy = state.load.occupancy();
+        if occupancy >= coalesce.above_occupancy {
+            debug!(
+                "Coalescing {} deltas per chunk at occupancy {:.2} request_id={}",
+                coalesce.deltas, occupancy, request_id
+            );
+            state.metrics.inc_counter(&metrics::STREAMS_COALESCED_TOTAL, &vec![("model", request.model_name())], 1.0);
+            options = options.with_coalesce(coalesce.deltas);
+        }
+    }
+    if profile.cache_control {
+        options = options.with_cache_creation_tokens(cache_usage.creation_tokens);
+    }
+    if let Some(provider) = provider {
+        options = options.with_provider(provider);
+    }
+    if profile.citations {
+        options = options.with_citations(reply.citations.clone());
+    }
+    if state.config.capture.echo_parameters {
+        options = options.with_echo_parameters(serde_json::to_value(&request.sampling).unwrap_or_default());
+    }
+    if event_ids {
+        options = match resumed {
+            Some((stream_id, seq, stream)) => options.with_seed(stream.seed).with_event_ids(stream_id, Some(seq)),
+            None => {
+                state.resume.insert(
+                    capture.id.clone(),
+                    ResumableStream {
+                        reply: reply.clone(),
+                        latency,
+                        fixture: fixture.clone(),
+                        profile,
+                        seed: options.seed,
+                    },
+                );
+                options.with_event_ids(capture.id.clone(), None)
+            }
+        };
+    }
+    if let Some(category) = state.config.content_filter.triggered(req.headers(), &request) {
+        info!("Content filter triggered ({}), cutting the stream short request_id={}", category, request_id);
+        options = options.with_content_filter(category, state.config.content_filter.after_chunks);
+    }
+    if fault == Some(Fault::StreamError) {
+        info!(
+            "Injecting a mid-stream error after {} chunks request_id={}",
+            state.config.faults.stream_error.after_chunks, request_id
+        );
+        options = options.with_stream_error(state.config.faults.stream_error.clone());
+    }
+    let completion_id = options.stream_id.clone().unwrap_or_else(|| capture.id.clone());
+    let deltas = options.deltas(&reply);
+    if fault != Some(Fault::Hang) {
+        let (ttft_ms, total_ms) = options.schedule(&deltas);
+        req.extensions_mut().insert(SimTiming { response_id: completion_id.clone(), queue_ms, ttft_ms, total_ms });
+    }
+    options = options.with_completion(completion_id, request.model.clone(), prompt_tokens);
+    let stream = match fault {
+        Some(Fault::Hang) => fault::hang(state.config.faults.hang.duration()).left_stream(),
+        _ => openai_simulator(deltas, options).right_stream(),
+    };
+
+    let final_stream = stream.map(move |chunk| {
+        if tracking {
+            //debug!("Sending chunk: {}", chunk);
+        }
+        chunk.map(web::Bytes::from).map_err(actix_web::Error::from)
+    });
+
+    let capture_store = state.capture.clone();
+    let stored = state.stored.clone();
+    let store = request.store == Some(true);
+    let keep_frames = state.capture.enabled() && state.config.capture.keep_frames;
+    let metadata = request.metadata().unwrap_or_default();
+    let metrics = state.metrics.clone();
+    let logging = state.config.logging.clone();
+    let access_log = state.access_log.clone();
+    load_guard.start_stream();
+    let final_stream = TracedStream::new(final_stream, move |mut trace| {
+        drop(load_guard);
+        if trace.completed && store {
+            stored.insert_frames(&trace.frames, metadata);
+        }
+        if !keep_frames {
+            trace.frames = Vec::new();
+        }
+        record_stream_metrics(&metrics, &metric_labels, &trace, completion_tokens);
+        metrics.inc_counter(
+            &metrics::TOKENS_TOTAL,
+            &token_labels(&usage_labels, "completion"),
+            completion_tokens as f64,
+        );
+        let capture = capture.with_trace(trace);
+        logging.sample(&capture);
+        if let Some(access_log) = &access_log {
+            access_log.write(&client, &request_line, &capture);
+        }
+        capture_store.push(capture);
+    });
+    let final_stream = if keep_frames || (state.stored.enabled() && store) {
+        final_stream.recording_frames()
+    } else {
+        final_stream
+    };
+    Ok(final_stream)
+}
+
+/// Everything served since startup as JSONL, for post-test auditing
+#[actix_web::get("/admin/snapshot")]
+async fn admin_snapshot(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .body(state.capture.to_jsonl())
+}
+
+/// Send a captured completion's frames again, byte for byte, at the same
+/// offsets from the start of the response
+#[actix_web::post("/admin/replay/{completion_id}")]
+async fn admin_replay(path: web::Path<String>, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let id = path.into_inner();
+    let frames = state.capture.with_records(|records| {
+        records.iter().rev().find(|record| is_completion(record, &id)).map(|record| {
+            record
+                .frames
+                .iter()
+                .cloned()
+                .zip(record.chunks.iter().map(|chunk| chunk.offset_ms))
+                .collect::<Vec<_>>()
+        })
+    });
+    match frames {
+        Some(frames) if !frames.is_empty() => Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .streaming(stream::replay(frames))),
+        Some(_) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            format!("Completion {} has no recorded frames to replay", id),
+        )
+        .into()),
+        None => Err(completion_not_found(&id).into()),
+    }
+}
+
+/// Whether clients know `record` as completion `id`: its capture id or,
+/// for profiles without `fidelity` (whose chunks each get a fresh id), the
+/// id of its first chunk
+fn is_completion(record: &CaptureRecord, id: &str) -> bool {
+    record.id == id
+        || record.frames.first().is_some_and(|frame| {
+            String::from_utf8_lossy(frame)
+                .lines()
+                .filter_map(|line| line.strip_prefix("data: "))
+                .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+                .any(|chunk| chunk["id"] == id)
+        })
+}
+
+/// Runtime state of the simulator and usage attributed to each model and
+/// API key
+#[actix_web::get("/admin/stats")]
+async fn admin_stats(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(stats_snapshot(&state).await)
+}
+
+/// What `/admin/stats` reports, also logged on SIGUSR1
+pub(crate) async fn stats_snapshot(state: &AppState) -> serde_json::Value {
+    let total = |spec| state.metrics.counter_values(spec).iter().map(|(_, value)| *value as u64).sum::<u64>();
+    let (source, degraded) = serving_source(state);
+    let configured = match state.config.source {
+        SourceKind::File => "file",
+        SourceKind::Database => "database",
+    };
+    // Counted live so a table refilled during a run is reported as it is
+    // now; unreachable databases report no count
+    let database_rows = match state.config.source {
+        SourceKind::Database => {
+            let query = db::count_query(&state.config.database);
+            tokio::time::timeout(STATS_COUNT_TIMEOUT, state.db.client().query(&query).fetch_one::<u64>())
+                .await
+                .ok()
+                .and_then(Result::ok)
+        }
+        SourceKind::File => None,
+    };
+    serde_json::json!({
+        "uptime_seconds": state.started_at.elapsed().as_secs(),
+        "requests": total(&metrics::REQUESTS_TOTAL),
+        "errors": total(&metrics::REQUEST_ERRORS_TOTAL),
+        "errors_by_type": stats::errors_by_type(&state.metrics),
+        "active_streams": state.load.active_streams(),
+        "semaphore": {
+            "in_use": state.load.permits_in_use(),
+            "limit": state.config.semaphore_limit,
+            "occupancy": state.load.occupancy(),
+        },
+        "source": {
+            "configured": configured,
+            "serving": source,
+            "degraded": degraded,
+            "circuit_open": state.db_breaker.is_open(),
+            "database_endpoints": match state.config.source {
+                SourceKind::Database => serde_json::json!(state.db.status()),
+                SourceKind::File => serde_json::Value::Null,
+            },
+        },
+        "corpus": {
+            "files": state.folders.len(),
+            "database_rows": database_rows,
+        },
+        "by_model": stats::usage_by(&state.metrics, "model"),
+        "by_api_key": stats::usage_by(&state.metrics, "api_key"),
+        "tiers": state.tiers.status(),
+    })
+}
+
+/// Requests and tokens per day, API key and model, from the captured
+/// requests, optionally narrowed with `start_date`, `end_date`, `api_key`
+/// and `model` query parameters
+#[actix_web::get("/admin/usage")]
+async fn admin_usage(req: HttpRequest, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let query = web::Query::<stats::UsageQuery>::from_query(req.query_string())
+        .map_err(|e| ApiError::invalid_request(e.to_string()))?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "object": "list",
+        "data": stats::daily_usage(&state.capture, &query),
+    })))
+}
+
+/// Streamed responses whose latencies feed the dashboard percentiles
+const DASHBOARD_LATENCY_WINDOW: usize = 1000;
+/// Requests listed on the dashboard
+const DASHBOARD_RECENT_REQUESTS: usize = 25;
+/// How long `/admin/stats` waits for the database row count
+const STATS_COUNT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Live counters for the dashboard: totals, concurrency, recent latency
+/// percentiles and requests, and the active profile
+#[actix_web::get("/admin/live")]
+async fn admin_live(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let total = |spec| state.metrics.counter_values(spec).iter().map(|(_, value)| *value as u64).sum::<u64>();
+    let activity = stats::recent_activity(&state.capture, DASHBOARD_LATENCY_WINDOW, DASHBOARD_RECENT_REQUESTS);
+    let (source, degraded) = serving_source(&state);
+    HttpResponse::Ok().json(serde_json::json!({
+        "requests": total(&metrics::REQUESTS_TOTAL),
+        "errors": total(&metrics::REQUEST_ERRORS_TOTAL),
+        "active_streams": state.load.active_streams(),
+        "permits_in_use": state.load.permits_in_use(),
+        "semaphore_limit": state.config.semaphore_limit,
+        "ttft_ms": activity.ttft_ms,
+        "duration_ms": activity.duration_ms,
+        "recent": activity.recent,
+        "default_profile": state.config.default_profile,
+        "content_filter": !state.config.content_filter.keywords.is_empty(),
+        "serving_source": source,
+        "degraded": degraded,
+    }))
+}
+
+/// Dashboard polling `/admin/live`
+#[actix_web::get("/admin/ui")]
+async fn admin_ui() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("dashboard.html"))
+}
+
+/// Token usage and quota of every API key seen since the last reset
+#[actix_web::get("/admin/quota")]
+async fn admin_quota(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(state.quota.status())
+}
+
+/// Reset quota usage of the key named by `?api_key=` (a masked id as shown
+/// by `/admin/quota`), or of every key
+#[actix_web::post("/admin/quota/reset")]
+async fn admin_quota_reset(req: HttpRequest, state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let api_key = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("api_key").cloned());
+    let reset = state.quota.reset(api_key.as_deref());
+    info!("Reset quota usage of {} key(s)", reset);
+    HttpResponse::Ok().json(serde_json::json!({ "reset": reset }))
+}
+
+/// Prometheus scrape endpoint
+#[actix_web::get("/metrics")]
+async fn metrics_endpoint(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render())
+}
+
+/// Models configured under `models`
+#[actix_web::get("/v1/models")]
+async fn list_models(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(models::list(&state.config.models))
+}
+
+/// One configured model, or OpenAI's `model_not_found` 404. Ids may
+/// contain slashes, as in `meta-llama/Llama-3-8b`.
+#[actix_web::get("/v1/models/{model:.+}")]
+async fn retrieve_model(path: web::Path<String>, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let model = path.into_inner();
+    let (id, info) = state
+        .config
+        .models
+        .get_key_value(&model)
+        .ok_or_else(|| ApiError::model_not_found(&model))?;
+    Ok(HttpResponse::Ok().json(ModelObject::new(id, info)))
+}
+
+/// Start a batch job, as OpenAI's `POST /v1/batches`. The job advances
+/// through its statuses over `batches.*_ms`.
+#[actix_web::post("/v1/batches")]
+async fn create_batch(body: web::Json<serde_json::Value>, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let metadata = check_metadata(body.get("metadata"))?;
+    let now = chrono::Utc::now().timestamp_millis();
+    let job = state.batches.create(&body, metadata, now)?;
+    state.batches.persist(state.db.client(), &state.config.database, &job);
+    Ok(HttpResponse::Ok().json(state.batches.object(&job, now)))
+}
+
+#[actix_web::get("/v1/batches/{batch_id}")]
+async fn retrieve_batch(path: web::Path<String>, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let id = path.into_inner();
+    let job = state.batches.get(&id).ok_or_else(|| batch::not_found(&id))?;
+    Ok(HttpResponse::Ok().json(state.batches.object(&job, chrono::Utc::now().timestamp_millis())))
+}
+
+/// Batches, newest first, paginated with `after` and `limit`
+#[actix_web::get("/v1/batches")]
+async fn list_batches(req: HttpRequest, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let query = web::Query::<batch::ListQuery>::from_query(req.query_string())
+        .map_err(|e| ApiError::invalid_request(e.to_string()))?;
+    Ok(HttpResponse::Ok().json(state.batches.list(&query, chrono::Utc::now().timestamp_millis())))
+}
+
+/// Cancel a batch that has not finished; it is `cancelling` for
+/// `batches.finalizing_ms`, then `cancelled`
+#[actix_web::post("/v1/batches/{batch_id}/cancel")]
+async fn cancel_batch(path: web::Path<String>, state: web::Data<Arc<AppState>>) -> Result<HttpResponse, CustomError> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let job = state.batches.cancel(&path.into_inner(), now)?;
+    state.batches.persist(state.db.client(), &state.config.database, &job);
+    Ok(HttpResponse::Ok().json(state.batches.object(&job, now)))
+}
+
+/// OpenAI's answer to a URL it does not serve
+async fn invalid_url(req: HttpRequest) -> HttpResponse {
+    let message = format!("Invalid URL ({} {})", req.method(), req.path());
+    CustomError::from(ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", message)).error_response()
+}
+
+/// Register every simulator route on an actix `App` or scope
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    configure_endpoints(cfg, &EndpointsConfig::default());
+}
+
+/// Register the routes enabled in `endpoints`; everything else answers
+/// OpenAI's 404
+pub fn configure_endpoints(cfg: &mut web::ServiceConfig, endpoints: &EndpointsConfig) {
+    if endpoints.health {
+        cfg.service(health_check).service(readyz);
+    }
+    if endpoints.chat_completions {
+        // The WebSocket route goes first so `ws` is not taken for an id
+        cfg.service(chat_completions)
+            .service(chat_completions_ws)
+            .service(list_completions)
+            .service(retrieve_completion)
+            .service(update_completion)
+            .service(delete_completion);
+    }
+    if endpoints.batches {
+        cfg.service(create_batch).service(list_batches).service(retrieve_batch).service(cancel_batch);
+    }
+    if endpoints.azure {
+        cfg.service(azure_chat_completions);
+    }
+    if endpoints.test_completion {
+        cfg.service(test_completion);
+    }
+    if endpoints.models {
+        cfg.service(list_models).service(retrieve_model);
+    }
+    if endpoints.openrouter {
+        cfg.service(openrouter_chat_completions).service(openrouter_generation);
+    }
+    if endpoints.admin {
+        cfg.service(admin_snapshot)
+            .service(admin_replay)
+            .service(admin_stats)
+            .service(admin_usage)
+            .service(admin_live)
+            .service(admin_ui)
+            .service(admin_quota)
+            .service(admin_quota_reset);
+    }
+    if endpoints.metrics {
+        cfg.service(metrics_endpoint);
+    }
+    cfg.default_service(web::to(invalid_url));
+}
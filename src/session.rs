@@ -0,0 +1,100 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use serde::Deserialize;
+
+/// Header naming the session responses are de-duplicated within. Without
+/// it the request body's `user` field is used.
+pub const SESSION_HEADER: &str = "x-sim-session";
+
+#[derive(Deserialize, Clone)]
+pub struct DedupConfig {
+    /// Avoid serving a session the same corpus entry twice until it has
+    /// seen every entry it could be served
+    #[serde(default)]
+    pub enabled: bool,
+    /// Sessions remembered; the least recently started are forgotten first
+    #[serde(default = "default_dedup_max_sessions")]
+    pub max_sessions: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_sessions: default_dedup_max_sessions(),
+        }
+    }
+}
+
+fn default_dedup_max_sessions() -> usize {
+    10000
+}
+
+#[derive(Default)]
+struct Sessions {
+    seen: HashMap<String, HashSet<String>>,
+    order: VecDeque<String>,
+}
+
+/// Corpus entries each session has already been served
+pub struct SessionHistory {
+    sessions: Mutex<Sessions>,
+    capacity: usize,
+}
+
+impl SessionHistory {
+    /// History keeping `capacity` sessions; 0 disables de-duplication
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sessions: Mutex::new(Sessions::default()),
+            capacity,
+        }
+    }
+
+    /// Keep the newest sessions of `previous`, the history before a reload,
+    /// up to this history's capacity
+    pub fn carry_over(&self, previous: &SessionHistory) {
+        let previous = previous.sessions.lock().unwrap();
+        let skip = previous.order.len().saturating_sub(self.capacity);
+        let order: VecDeque<String> = previous.order.iter().skip(skip).cloned().collect();
+        let seen = order.iter().filter_map(|session| Some((session.clone(), previous.seen.get(session)?.clone()))).collect();
+        *self.sessions.lock().unwrap() = Sessions { seen, order };
+    }
+
+    /// Choose among `items` with `choose`, skipping those `session` was
+    /// already served (identified by `key`). Once every item has been
+    /// served the session starts over.
+    pub fn pick<'a, T>(
+        &self,
+        session: Option<&str>,
+        items: &[&'a T],
+        key: impl Fn(&T) -> String,
+        choose: impl Fn(&[&'a T]) -> Option<&'a T>,
+    ) -> Option<&'a T> {
+        let Some(session) = session.filter(|_| self.capacity > 0) else {
+            return choose(items);
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        let sessions = &mut *sessions;
+        if !sessions.seen.contains_key(session) {
+            if sessions.order.len() >= self.capacity {
+                if let Some(oldest) = sessions.order.pop_front() {
+                    sessions.seen.remove(&oldest);
+                }
+            }
+            sessions.order.push_back(session.to_string());
+        }
+        let seen = sessions.seen.entry(session.to_string()).or_default();
+
+        let unseen: Vec<&'a T> = items.iter().copied().filter(|item| !seen.contains(&key(item))).collect();
+        let picked = match choose(&unseen) {
+            Some(item) => item,
+            None => {
+                seen.clear();
+                choose(items)?
+            }
+        };
+        seen.insert(key(picked));
+        Some(picked)
+    }
+}
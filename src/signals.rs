@@ -0,0 +1,70 @@
+use log::{error, info, warn};
+use crate::config_loader::Config;
+use crate::error::CustomError;
+use crate::server;
+use crate::simulator::RunningSimulator;
+
+/// Serve until the server is stopped (SIGINT/SIGTERM), meanwhile
+///
+/// - on SIGHUP, re-read `config.yml` (with `profile`) and reload it and the
+///   corpus without dropping the socket or in-flight streams
+/// - on SIGUSR1, log the `/admin/stats` snapshot and what is in flight
+#[cfg(unix)]
+pub async fn serve(mut running: RunningSimulator, profile: Option<&str>) -> Result<(), CustomError> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut user1 = signal(SignalKind::user_defined1())?;
+    loop {
+        tokio::select! {
+            served = running.stopped() => return served,
+            _ = hangup.recv() => running = reload(running, profile).await,
+            _ = user1.recv() => dump_stats(&running).await,
+        }
+    }
+}
+
+/// Without Unix signals there is nothing to handle besides stopping
+#[cfg(not(unix))]
+pub async fn serve(running: RunningSimulator, _profile: Option<&str>) -> Result<(), CustomError> {
+    running.wait().await
+}
+
+/// Serve the config as it is now, or keep serving the current one when it
+/// does not load
+async fn reload(running: RunningSimulator, profile: Option<&str>) -> RunningSimulator {
+    info!("event=reload signal=SIGHUP Reloading config and corpus");
+    let config = match Config::read(profile) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("event=reload_failed Keeping the running config: {}", e);
+            return running;
+        }
+    };
+    let current = &running.state().config;
+    if config.binding.host != current.binding.host || config.binding.port != current.binding.port {
+        warn!("binding changed to {}:{}, still serving on {} until restarted",
+              config.binding.host, config.binding.port, running.addr());
+    }
+    if config.log_level != current.log_level {
+        warn!("log_level changed to {}, applied on restart only", config.log_level);
+    }
+    match running.reload(config).await {
+        Ok(next) => {
+            server::drop_cached_corpus(next.state()).await;
+            info!("event=reloaded files={} Serving the reloaded config", next.state().folders.len());
+            next
+        }
+        Err((running, e)) => {
+            error!("event=reload_failed Keeping the running config: {}", e);
+            running
+        }
+    }
+}
+
+async fn dump_stats(running: &RunningSimulator) {
+    let state = running.state();
+    let stats = server::stats_snapshot(state).await;
+    info!("event=stats signal=SIGUSR1 in_flight={} active_streams={} permits_in_use={} stats={}",
+          state.load.in_flight(), state.load.active_streams(), state.load.permits_in_use(), stats);
+}
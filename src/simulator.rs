@@ -0,0 +1,482 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{web, App, HttpServer, middleware::{from_fn, Logger}};
+use actix_web::dev::ServerHandle;
+use tokio::task::JoinHandle;
+use log::{info, debug, error, warn};
+use clickhouse::Client;
+use redis::aio::ConnectionManager;
+use crate::check::redact_url;
+use crate::config_loader::{Config, DatabaseConfig, SamplingMode, SelectionStrategy, SourceKind};
+use crate::dataset::Datasets;
+use crate::db;
+use crate::embedding::{Embedder, SemanticSearch};
+use crate::error::CustomError;
+use crate::failover::{self, DbEndpoints};
+use crate::fixtures::FixtureSet;
+use crate::folder::{self, ResponseFolder};
+use crate::headers;
+use crate::hooks::Hooks;
+use crate::logging::AccessLog;
+use crate::migrations;
+use crate::profile;
+use crate::request_id;
+use crate::response::fetch_responses_from_db;
+use crate::script::ScriptSet;
+use crate::server::{self, AppState};
+use crate::selection::PickStrategy;
+use crate::wasm::WasmPlugin;
+
+/// actix's default access log format plus the request id
+const ACCESS_LOG_FORMAT: &str = r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#;
+
+/// Entry point for embedding the simulator in another program or test suite
+pub struct Simulator;
+
+impl Simulator {
+    pub fn builder() -> SimulatorBuilder {
+        SimulatorBuilder::default()
+    }
+}
+
+/// Collects configuration overrides before the server is started
+#[derive(Default)]
+pub struct SimulatorBuilder {
+    config: Config,
+    listener: Option<TcpListener>,
+    /// State of the server a reload replaces
+    previous: Option<Arc<AppState>>,
+}
+
+impl SimulatorBuilder {
+    /// Replace the whole configuration, e.g. with one read by `Config::load()`
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn source(mut self, source: SourceKind) -> Self {
+        self.config.source = source;
+        self
+    }
+
+    /// Folder scanned for responses (matching `files.patterns`) when the
+    /// source is `file`
+    pub fn response_dir(mut self, dir: impl Into<String>) -> Self {
+        self.config.files.dir = dir.into();
+        self
+    }
+
+    /// Directory of YAML fixture bundles selectable per request
+    pub fn fixtures_dir(mut self, dir: impl Into<String>) -> Self {
+        self.config.fixtures.dir = Some(dir.into());
+        self
+    }
+
+    /// Directory of YAML conversation scripts selectable per request
+    pub fn scripts_dir(mut self, dir: impl Into<String>) -> Self {
+        self.config.scripts.dir = Some(dir.into());
+        self
+    }
+
+    pub fn bind(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.config.binding.host = host.into();
+        self.config.binding.port = port;
+        self
+    }
+
+    /// Bind to an OS-assigned port on the loopback interface
+    pub fn bind_random_port(self) -> Self {
+        self.bind("127.0.0.1", 0)
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Serve on an already bound listener instead of binding
+    /// `binding.host:binding.port`
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Carry over what the server being reloaded recorded while serving
+    pub(crate) fn carry_over(mut self, previous: Arc<AppState>) -> Self {
+        self.previous = Some(previous);
+        self
+    }
+
+    /// Connect to the configured backends, bind the listener and start serving
+    /// in the background. Resolves once the socket is bound.
+    pub async fn spawn(self) -> Result<RunningSimulator, CustomError> {
+        let config = self.config;
+        let listener = self.listener;
+        let previous = self.previous;
+
+        info!("Configuration: workers={}, semaphore_limit={}, cache_ttl={}s",
+              config.workers, config.semaphore_limit, config.cache_ttl);
+
+        check_profiles(&config)?;
+        check_sampling(&config)?;
+        check_batches(&config)?;
+
+        let redis_conn = match &config.redis {
+            Some(redis_config) => Some(connect_redis(&redis_config.url).await?),
+            None => {
+                info!("Redis not configured, responses will not be cached");
+                None
+            }
+        };
+
+        // Initialize ClickHouse client
+        let db = DbEndpoints::new(&config.database)?;
+        info!("ClickHouse pool: pool_size={}, pool_idle_timeout_ms={}",
+              config.database.pool_size, config.database.pool_idle_timeout_ms);
+
+        let datasets = Datasets::new(&config.database)?;
+        if config.source == SourceKind::Database {
+            for (name, database) in datasets.configs() {
+                info!("Dataset {}: table {}", name, database.table);
+            }
+            match connect_database(&db, &config, &datasets).await {
+                Ok(()) => db.set_connected(Ok(())),
+                Err(e) if config.database.fail_fast => return Err(e),
+                Err(e) => {
+                    let reason = connect_failure(&e);
+                    warn!("ClickHouse not ready ({}), serving degraded and retrying every {}ms",
+                          reason, config.database.connect_retry_ms);
+                    db.set_connected(Err(reason));
+                }
+            }
+        }
+
+        let fixtures = match &config.fixtures.dir {
+            Some(dir) => FixtureSet::load_dir(dir)?,
+            None => FixtureSet::default(),
+        };
+        let scripts = match &config.scripts.dir {
+            Some(dir) => ScriptSet::load_dir(dir)?,
+            None => ScriptSet::default(),
+        };
+
+        headers::validate(&config.response_headers)?;
+        let folders = ResponseFolder::new(&config.files)?;
+        check_response_files(&config, &folders)?;
+        info!("Response files: {} in {} matching {:?}", folders.len(), folders.root(), folders.patterns());
+        debug!("Matched response files: {:?}", folders.files());
+
+        let semantic = match config.database.selection {
+            SelectionStrategy::Semantic => Some(SemanticSearch::new(Embedder::new(&config.database.semantic)?)),
+            _ => None,
+        };
+
+        let workers = config.workers;
+        let endpoints = config.endpoints.clone();
+        let listener = match listener {
+            Some(listener) => listener,
+            None => bind(&format!("{}:{}", config.binding.host, config.binding.port))?,
+        };
+
+        // Create shared application state
+        let watch_interval = Duration::from_millis(config.files.watch_interval_ms);
+        let wasm = WasmPlugin::from_config(&config.wasm)?;
+        let hooks = Hooks::load(&config.hooks)?;
+        let access_log = config.logging.access.as_ref().map(AccessLog::open).transpose()?;
+        let mut app_state = AppState::new(config, db, redis_conn, folders, fixtures, scripts, semantic)
+            .with_datasets(datasets)
+            .with_wasm(wasm)
+            .with_hooks(hooks)
+            .with_access_log(access_log);
+        match &previous {
+            Some(previous) => app_state = app_state.carry_over(previous),
+            None => {
+                if let Err(e) = app_state.batches.load(&app_state.db.client(), &app_state.config.database).await {
+                    warn!("{}; serving the batches created from now on", e);
+                }
+            }
+        }
+        let app_state = Arc::new(app_state);
+        app_state.errors.capture_panics();
+        let mut watchers = Vec::new();
+        if !watch_interval.is_zero() {
+            watchers.push(tokio::spawn(folder::watch(app_state.clone(), watch_interval)));
+        }
+        if app_state.config.source == SourceKind::Database && !app_state.db.is_connected() {
+            let interval = Duration::from_millis(app_state.config.database.connect_retry_ms.max(1));
+            watchers.push(tokio::spawn(reconnect(app_state.clone(), interval)));
+        }
+        if app_state.config.source == SourceKind::Database && app_state.db.has_replicas() {
+            let interval = Duration::from_millis(app_state.config.database.health_check_interval_ms.max(1));
+            watchers.push(tokio::spawn(failover::watch(app_state.clone(), interval)));
+        }
+
+        let state = app_state.clone();
+        let http_server = HttpServer::new(move || {
+            App::new()
+                .wrap(from_fn(headers::provider_headers))
+                .wrap(from_fn(request_id::propagate))
+                .wrap(Logger::new(ACCESS_LOG_FORMAT))
+                .app_data(web::Data::new(app_state.clone()))
+                .configure(|cfg| server::configure_endpoints(cfg, &endpoints))
+        })
+            .workers(workers)
+            .listen(listener.try_clone()?)?;
+        let addr = listener.local_addr()?;
+
+        let server = http_server.run();
+        let handle = server.handle();
+        let task = tokio::spawn(server);
+
+        info!("Starting server at http://{}", addr);
+
+        Ok(RunningSimulator { addr, listener, state, handle, task, watchers })
+    }
+}
+
+/// Bind `addr` the way actix does (address reuse, a backlog of 1024), but
+/// keep the socket so a reload can hand it to the next server
+fn bind(addr: &str) -> Result<TcpListener, CustomError> {
+    let addr = std::net::ToSocketAddrs::to_socket_addrs(addr)?
+        .next()
+        .ok_or_else(|| CustomError::BindError(format!("No address bound for {}", addr)))?;
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Fail unless every profile the config refers to is defined in `profiles`
+pub fn check_profiles(config: &Config) -> Result<(), CustomError> {
+    let model_profiles = config
+        .models
+        .iter()
+        .map(|(model, info)| (format!("models.{}.profile", model), &info.profile));
+    let profile_keys = [("default_profile".to_string(), &config.default_profile), ("azure.profile".to_string(), &config.azure.profile)];
+    for (key, profile) in profile_keys.into_iter().chain(model_profiles) {
+        if let Some(name) = profile.as_ref().filter(|name| profile::resolve(&config.profiles, name).is_none()) {
+            return Err(CustomError::ConfigError(format!("{} '{}' is not defined in profiles", key, name)));
+        }
+    }
+    Ok(())
+}
+
+/// Fail when `database.sampling: reservoir` is combined with a selection
+/// needing every candidate at once, which a single streamed sample cannot
+/// give
+pub fn check_sampling(config: &Config) -> Result<(), CustomError> {
+    if config.database.sampling != SamplingMode::Reservoir {
+        return Ok(());
+    }
+    if config.selection.strategy != PickStrategy::Random {
+        return Err(CustomError::ConfigError(format!(
+            "database.sampling: reservoir needs selection.strategy: random, not {:?}",
+            config.selection.strategy
+        )));
+    }
+    if config.dedup.enabled {
+        return Err(CustomError::ConfigError("database.sampling: reservoir cannot be used with dedup".to_string()));
+    }
+    Ok(())
+}
+
+/// Fail when `batches.table`, which goes into the batch queries as is, is
+/// not a plain table name
+pub fn check_batches(config: &Config) -> Result<(), CustomError> {
+    match &config.batches.table {
+        Some(table) if !db::is_identifier(table) => {
+            Err(CustomError::ConfigError(format!("batches.table '{}' is not a table name", table)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fail when responses may come from files but `folders` has none and
+/// nothing else can answer
+pub fn check_response_files(config: &Config, folders: &ResponseFolder) -> Result<(), CustomError> {
+    let serves_files = config.source == SourceKind::File || config.database.circuit_breaker.fallback_to_file;
+    let has_fixtures = config.fixtures.dir.is_some() || config.scripts.dir.is_some() || config.wasm.module.is_some();
+    if folders.is_empty() && serves_files && !has_fixtures {
+        return Err(CustomError::ConfigError(format!(
+            "No response files in '{}' match files.patterns {:?}",
+            folders.root(),
+            folders.patterns()
+        )));
+    }
+    Ok(())
+}
+
+pub async fn connect_redis(url: &str) -> Result<ConnectionManager, CustomError> {
+    info!("Connecting to Redis at {}", redact_url(url));
+    let redis_client = redis::Client::open(url)
+        .map_err(|e| CustomError::RedisError(format!("Failed to create Redis client: {}", e)))?;
+
+    let redis_conn = ConnectionManager::new(redis_client)
+        .await
+        .map_err(|e| CustomError::RedisError(format!("Failed to connect to Redis: {}", e)))?;
+
+    info!("Successfully connected to Redis");
+    Ok(redis_conn)
+}
+
+/// Get ClickHouse ready to serve from: pick a healthy endpoint, prepare
+/// the schema of the table and every dataset, then run the first queries
+async fn connect_database(db: &DbEndpoints, config: &Config, datasets: &Datasets) -> Result<(), CustomError> {
+    if db.has_replicas() {
+        // Start on a replica when the primary is down, e.g. for maintenance
+        db.check().await;
+        info!("ClickHouse endpoints: {:?}, querying {}", config.database.failover_urls, db.active_url());
+    }
+    let db_client = db.client();
+    migrations::prepare(&db_client, &config.database).await?;
+    for database in datasets.configs().values() {
+        migrations::prepare(&db_client, database).await?;
+    }
+    if let Some(rows) = verify_database(&db_client, &config.database, config.tracking.enabled).await? {
+        db.set_corpus_rows(rows);
+    }
+    Ok(())
+}
+
+/// Retry connecting every `interval` after a failed startup, until the
+/// database is ready
+async fn reconnect(state: Arc<AppState>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match connect_database(&state.db, &state.config, &state.datasets).await {
+            Ok(()) => {
+                info!("Connected to ClickHouse, serving from the database");
+                state.db.set_connected(Ok(()));
+                return;
+            }
+            Err(e) => {
+                let reason = connect_failure(&e);
+                warn!("ClickHouse still not ready: {}", reason);
+                state.db.set_connected(Err(reason));
+            }
+        }
+    }
+}
+
+/// Why connecting failed, without the "Invalid configuration" wording of
+/// the connection and schema errors
+fn connect_failure(e: &CustomError) -> String {
+    match e {
+        CustomError::ConfigError(reason) => reason.clone(),
+        e => e.to_string(),
+    }
+}
+
+/// Ping ClickHouse and count the response rows, `None` when they cannot
+/// be counted
+async fn verify_database(db_client: &Client, config: &DatabaseConfig, tracking: bool) -> Result<Option<u64>, CustomError> {
+    let retry = &config.retry;
+    match db::retry(retry, "Connecting to ClickHouse", || db_client.query("SELECT 1").execute()).await {
+        Ok(_) => info!("Successfully connected to ClickHouse database"),
+        Err(e) => {
+            error!("Failed to connect to ClickHouse database: {}", e);
+            return Err(CustomError::ConfigError(format!("ClickHouse unreachable: {}", e)));
+        }
+    }
+
+    let count_query = db::count_query(config);
+    info!("Executing initial query to count response rows: {}", count_query);
+    let count = db::retry(retry, "Counting rows", || db_client.query(&count_query).fetch_one::<u64>()).await;
+    let count = match count {
+        Ok(count) => {
+            info!("Number of response rows in the database: {}", count);
+            Some(count)
+        }
+        Err(e) => {
+            error!("Failed to count response rows: {}", e);
+            None
+        }
+    };
+
+    if tracking {
+        info!("Executing initial query to fetch all response rows");
+        let records = fetch_responses_from_db(db_client, config, tracking).await?;
+        debug!("Fetched {} response rows", records.len());
+    }
+
+    Ok(count)
+}
+
+/// A simulator serving in the background
+pub struct RunningSimulator {
+    addr: SocketAddr,
+    /// The socket the server accepts on, kept to serve a reloaded
+    /// configuration on it
+    listener: TcpListener,
+    state: Arc<AppState>,
+    handle: ServerHandle,
+    task: JoinHandle<std::io::Result<()>>,
+    /// Response folder hot reload and database health checks, stopped
+    /// with the server
+    watchers: Vec<JoinHandle<()>>,
+}
+
+impl RunningSimulator {
+    /// The address the listener is actually bound to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Base URL, e.g. `http://127.0.0.1:38123`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    pub(crate) fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    /// Start a server for `config` on this one's socket, carrying over
+    /// what this one recorded (see `AppState::carry_over`), then gracefully
+    /// stop this one in the background: new connections go to the new
+    /// server while the old one finishes its requests and streams. When
+    /// the new one cannot start, this one keeps serving and is returned
+    /// with the error.
+    pub async fn reload(self, config: Config) -> Result<RunningSimulator, (RunningSimulator, CustomError)> {
+        let listener = match self.listener.try_clone() {
+            Ok(listener) => listener,
+            Err(e) => return Err((self, e.into())),
+        };
+        let next = Simulator::builder().config(config).listener(listener).carry_over(self.state.clone());
+        match next.spawn().await {
+            Ok(next) => {
+                tokio::spawn(self.stop());
+                Ok(next)
+            }
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Resolves when the server stops, e.g. on SIGTERM
+    pub(crate) async fn stopped(&mut self) -> Result<(), CustomError> {
+        let served = (&mut self.task).await;
+        self.watchers.iter().for_each(JoinHandle::abort);
+        served
+            .map_err(|e| CustomError::BindError(e.to_string()))?
+            .map_err(|e| CustomError::BindError(e.to_string()))
+    }
+
+    /// Gracefully stop the server and wait for the workers to exit
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+        let _ = self.task.await;
+        self.watchers.iter().for_each(JoinHandle::abort);
+    }
+
+    /// Serve until the server is stopped (e.g. by a signal)
+    pub async fn wait(mut self) -> Result<(), CustomError> {
+        self.stopped().await
+    }
+}
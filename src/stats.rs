@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::capture::CaptureStore;
+use crate::metrics::{self, Labels, Metrics};
+
+/// Request and token totals for one model or API key (or day, key and
+/// model in `/admin/usage`)
+#[derive(Serialize, Default, Debug, Clone, Copy)]
+pub struct UsageCounts {
+    pub requests: u64,
+    pub errors: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+fn label<'a>(labels: &'a Labels, key: &str) -> &'a str {
+    labels
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.as_str())
+        .unwrap_or_default()
+}
+
+/// Fold the usage counters into totals keyed by the value of the `by`
+/// label (`"model"` or `"api_key"`)
+pub fn usage_by(metrics: &Metrics, by: &str) -> BTreeMap<String, UsageCounts> {
+    let mut usage: BTreeMap<String, UsageCounts> = BTreeMap::new();
+
+    for (labels, value) in metrics.counter_values(&metrics::REQUESTS_TOTAL) {
+        usage.entry(label(&labels, by).to_string()).or_default().requests += value as u64;
+    }
+    for (labels, value) in metrics.counter_values(&metrics::REQUEST_ERRORS_TOTAL) {
+        usage.entry(label(&labels, by).to_string()).or_default().errors += value as u64;
+    }
+    for (labels, value) in metrics.counter_values(&metrics::TOKENS_TOTAL) {
+        let counts = usage.entry(label(&labels, by).to_string()).or_default();
+        match label(&labels, "kind") {
+            "prompt" => counts.prompt_tokens += value as u64,
+            "completion" => counts.completion_tokens += value as u64,
+            _ => {}
+        }
+    }
+    usage
+}
+
+/// Errors answered since startup, keyed by error type
+pub fn errors_by_type(metrics: &Metrics) -> BTreeMap<String, u64> {
+    let mut errors: BTreeMap<String, u64> = BTreeMap::new();
+    for (labels, value) in metrics.counter_values(&metrics::REQUEST_ERRORS_TOTAL) {
+        *errors.entry(label(&labels, "type").to_string()).or_default() += value as u64;
+    }
+    errors
+}
+
+/// Filters of `/admin/usage`; dates are `YYYY-MM-DD` in UTC, inclusive
+#[derive(Deserialize, Default, Debug)]
+pub struct UsageQuery {
+    #[serde(default)]
+    pub start_date: Option<String>,
+    #[serde(default)]
+    pub end_date: Option<String>,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+/// Usage of one API key and model on one day
+#[derive(Serialize, Debug)]
+pub struct DailyUsage {
+    pub date: String,
+    pub api_key: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub counts: UsageCounts,
+}
+
+/// Fold the captured requests into totals per day, API key and model.
+/// Only requests still held by the capture store are counted.
+pub fn daily_usage(capture: &CaptureStore, query: &UsageQuery) -> Vec<DailyUsage> {
+    let mut usage: BTreeMap<(String, String, String), UsageCounts> = BTreeMap::new();
+    capture.with_records(|records| {
+        for record in records {
+            let date = record.received_at.get(..10).unwrap_or_default();
+            let selected = query.start_date.as_deref().is_none_or(|start| date >= start)
+                && query.end_date.as_deref().is_none_or(|end| date <= end)
+                && query.api_key.as_ref().is_none_or(|api_key| *api_key == record.api_key)
+                && query.model.as_ref().is_none_or(|model| *model == record.model);
+            if !selected {
+                continue;
+            }
+            let key = (date.to_string(), record.api_key.clone(), record.model.clone());
+            let counts = usage.entry(key).or_default();
+            counts.requests += 1;
+            if record.status >= 400 {
+                counts.errors += 1;
+            }
+            counts.prompt_tokens += u64::from(record.prompt_tokens);
+            counts.completion_tokens += u64::from(record.completion_tokens);
+        }
+    });
+    usage
+        .into_iter()
+        .map(|((date, api_key, model), counts)| DailyUsage { date, api_key, model, counts })
+        .collect()
+}
+
+/// Latency percentiles in milliseconds
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+fn percentiles(mut values: Vec<f64>) -> Option<Percentiles> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let at = |quantile: f64| values[((values.len() - 1) as f64 * quantile).round() as usize];
+    Some(Percentiles {
+        p50: at(0.5),
+        p95: at(0.95),
+        p99: at(0.99),
+    })
+}
+
+/// Summary of one captured request for the dashboard
+#[derive(Serialize, Debug)]
+pub struct RecentRequest {
+    pub id: String,
+    pub received_at: String,
+    pub model: String,
+    pub api_key: String,
+    pub status: u16,
+    pub completed: bool,
+    pub ttft_ms: Option<f64>,
+    pub duration_ms: f64,
+    pub completion_tokens: u32,
+}
+
+/// Latencies of the last `window` streamed responses and the last `recent`
+/// requests, newest first
+#[derive(Serialize, Debug)]
+pub struct RecentActivity {
+    pub ttft_ms: Option<Percentiles>,
+    pub duration_ms: Option<Percentiles>,
+    pub recent: Vec<RecentRequest>,
+}
+
+pub fn recent_activity(capture: &CaptureStore, window: usize, recent: usize) -> RecentActivity {
+    capture.with_records(|records| {
+        let streamed: Vec<_> = records
+            .iter()
+            .rev()
+            .filter(|record| record.completed && !record.chunks.is_empty())
+            .take(window)
+            .collect();
+        RecentActivity {
+            ttft_ms: percentiles(streamed.iter().map(|record| record.chunks[0].offset_ms).collect()),
+            duration_ms: percentiles(streamed.iter().map(|record| record.duration_ms).collect()),
+            recent: records
+                .iter()
+                .rev()
+                .take(recent)
+                .map(|record| RecentRequest {
+                    id: record.id.clone(),
+                    received_at: record.received_at.clone(),
+                    model: record.model.clone(),
+                    api_key: record.api_key.clone(),
+                    status: record.status,
+                    completed: record.completed,
+                    ttft_ms: record.chunks.first().map(|chunk| chunk.offset_ms),
+                    duration_ms: record.duration_ms,
+                    completion_tokens: record.completion_tokens,
+                })
+                .collect(),
+        }
+    })
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use actix_web::web::{self, Bytes};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use crate::completion::CompletionCollector;
+
+/// Completions kept for the stored-completions API
+#[derive(Deserialize, Clone, Debug)]
+pub struct StoredCompletionsConfig {
+    /// Completions kept; oldest are evicted first, 0 stores none
+    #[serde(default = "default_max_completions")]
+    pub max_completions: usize,
+}
+
+impl Default for StoredCompletionsConfig {
+    fn default() -> Self {
+        Self {
+            max_completions: default_max_completions(),
+        }
+    }
+}
+
+fn default_max_completions() -> usize {
+    1000
+}
+
+/// Query of `GET /v1/chat/completions`
+#[derive(Deserialize, Debug)]
+pub struct ListQuery {
+    pub model: Option<String>,
+    /// Id of the completion the page starts after
+    pub after: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    #[serde(default)]
+    pub order: Order,
+    /// `metadata[<key>]=<value>` pairs a completion must all have
+    #[serde(skip)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ListQuery {
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut parsed = web::Query::<Self>::from_query(query).map_err(|e| e.to_string())?.into_inner();
+        let pairs = web::Query::<Vec<(String, String)>>::from_query(query).map_err(|e| e.to_string())?;
+        parsed.metadata = pairs
+            .into_inner()
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix("metadata[")?.strip_suffix(']')?;
+                Some((key.to_string(), value))
+            })
+            .collect();
+        Ok(parsed)
+    }
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Bounded in-memory store of served `chat.completion` objects, oldest
+/// first
+pub struct StoredCompletions {
+    completions: Mutex<VecDeque<Value>>,
+    capacity: usize,
+}
+
+impl StoredCompletions {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            completions: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Keep the newest completions of `previous`, the store before a
+    /// reload, up to this store's capacity
+    pub fn carry_over(&self, previous: &StoredCompletions) {
+        let previous = previous.completions.lock().unwrap();
+        let skip = previous.len().saturating_sub(self.capacity);
+        *self.completions.lock().unwrap() = previous.iter().skip(skip).cloned().collect();
+    }
+
+    pub fn insert(&self, completion: Value) {
+        if !self.enabled() {
+            return;
+        }
+        let mut completions = self.completions.lock().unwrap();
+        if completions.len() >= self.capacity {
+            completions.pop_front();
+        }
+        completions.push_back(completion);
+    }
+
+    /// Store the completion streamed as `frames` with the request's
+    /// `metadata`, unless it ended in an error event
+    pub fn insert_frames(&self, frames: &[Bytes], metadata: Map<String, Value>) {
+        let mut collector = CompletionCollector::default();
+        if frames.iter().all(|frame| collector.push(frame).is_ok()) {
+            let mut completion = collector.finish();
+            completion["metadata"] = Value::Object(metadata);
+            self.insert(completion);
+        }
+    }
+
+    /// Replace the `metadata` of a stored completion, returning it updated
+    pub fn update_metadata(&self, id: &str, metadata: Map<String, Value>) -> Option<Value> {
+        let mut completions = self.completions.lock().unwrap();
+        let completion = completions.iter_mut().find(|completion| completion["id"] == id)?;
+        completion["metadata"] = Value::Object(metadata);
+        Some(completion.clone())
+    }
+
+    pub fn get(&self, id: &str) -> Option<Value> {
+        self.completions.lock().unwrap().iter().find(|completion| completion["id"] == id).cloned()
+    }
+
+    /// Whether a completion with `id` was there to delete
+    pub fn delete(&self, id: &str) -> bool {
+        let mut completions = self.completions.lock().unwrap();
+        let before = completions.len();
+        completions.retain(|completion| completion["id"] != id);
+        completions.len() < before
+    }
+
+    /// A page of completions as OpenAI lists them: `{"object": "list",
+    /// "data", "first_id", "last_id", "has_more"}`
+    pub fn list(&self, query: &ListQuery) -> Value {
+        let completions = self.completions.lock().unwrap();
+        let mut matching: Vec<&Value> = completions
+            .iter()
+            .filter(|completion| query.model.as_ref().is_none_or(|model| completion["model"] == *model))
+            .filter(|completion| query.metadata.iter().all(|(key, value)| completion["metadata"][key] == *value))
+            .collect();
+        if query.order == Order::Desc {
+            matching.reverse();
+        }
+        let start = query
+            .after
+            .as_ref()
+            .and_then(|after| matching.iter().position(|completion| completion["id"] == *after))
+            .map_or(0, |position| position + 1);
+        let limit = query.limit.clamp(1, 100);
+        let page: Vec<Value> = matching.iter().skip(start).take(limit).map(|completion| (*completion).clone()).collect();
+        json!({
+            "object": "list",
+            "first_id": page.first().map(|completion| completion["id"].clone()),
+            "last_id": page.last().map(|completion| completion["id"].clone()),
+            "has_more": matching.len() > start + page.len(),
+            "data": page,
+        })
+    }
+}
@@ -1,12 +1,37 @@
 // src/stream.rs
 
-use futures_util::Stream;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
 use tokio_stream::wrappers::ReceiverStream;
+use tokio::sync::mpsc::error::SendTimeoutError;
 use tokio::sync::mpsc::{channel, Sender};
-use log::{info, debug, error};
-use rand::Rng;
+use tokio::sync::oneshot;
+use log::{info, debug, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::Serialize;
-use crate::CONFIG;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::config_loader::{ChunkTransform, Chunking, LatencyConfig, SlowConsumerPolicy, StreamingConfig};
+use crate::content_filter;
+use crate::fault::StreamErrorConfig;
+use crate::hooks::Hooks;
+use crate::profile::ProviderProfile;
+use crate::reply::{generate_tool_call_id, Annotation, BuiltinToolCall, Reply, ReplyChoice, ToolCall};
+
+/// Model reported in chunks unless fidelity mode echoes the requested one
+pub const DEFAULT_MODEL: &str = "gpt-4o-2024-08-06";
+/// `finish_reason` of a stream cut short by the content filter
+const CONTENT_FILTER: &str = "content_filter";
+/// Terminates an OpenAI stream in fidelity mode
+pub const DONE_FRAME: &str = "data: [DONE]\n\n";
+/// `system_fingerprint` of every chunk unless a profile overrides it
+const SYSTEM_FINGERPRINT: &str = "fp_d28bcae782";
 
 #[derive(Serialize)]
 pub struct Chunk {
@@ -14,9 +39,23 @@ pub struct Chunk {
     pub object: String,
     pub created: u64,
     pub model: String,
-    pub system_fingerprint: String,
+    pub system_fingerprint: Option<String>,
     pub choices: Vec<Choice>,
     pub usage: Option<Usage>,
+    /// Groq's request id and, on the last chunk, usage with timings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x_groq: Option<serde_json::Value>,
+    /// Upstream provider of an OpenRouter completion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Sources cited by `[n]` markers, as Perplexity sends them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub citations: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_filter_results: Option<serde_json::Value>,
+    /// Request parameters echoed back, see `CaptureConfig::echo_parameters`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sim_parameters: Option<serde_json::Value>,
 }
 
 #[derive(Serialize)]
@@ -25,11 +64,58 @@ pub struct Choice {
     pub delta: Delta,
     pub logprobs: Option<serde_json::Value>,
     pub finish_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_filter_results: Option<serde_json::Value>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub struct Delta {
-    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Vec<Annotation>>,
+}
+
+/// A delta of the stream body, with the index of the choice it belongs to
+/// and the finish reason it carries
+pub type StreamDelta = (u32, Delta, Option<String>);
+
+#[derive(Serialize)]
+pub struct ToolCallDelta {
+    pub index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionDelta>,
+    /// Output of a built-in tool call, under the key named by its type
+    #[serde(flatten)]
+    pub output: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Serialize)]
+pub struct FunctionDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+impl Delta {
+    /// Text billed as completion tokens: content and tool call names and
+    /// arguments
+    fn completion_text(&self) -> String {
+        let mut text = self.content.clone().unwrap_or_default();
+        for function in self.tool_calls.iter().flatten().filter_map(|call| call.function.as_ref()) {
+            text.push_str(function.name.as_deref().unwrap_or_default());
+            text.push_str(&function.arguments);
+        }
+        text
+    }
 }
 
 #[derive(Serialize)]
@@ -39,6 +125,11 @@ pub struct Usage {
     pub total_tokens: u32,
     pub prompt_tokens_details: PromptTokensDetails,
     pub completion_tokens_details: CompletionTokensDetails,
+    /// Anthropic prompt caching, with `ProviderProfile::cache_control`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_creation_input_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_read_input_tokens: Option<u32>,
 }
 
 #[derive(Serialize)]
@@ -65,58 +156,971 @@ pub fn generate_id() -> String {
     format!("{}{}", prefix, suffix)
 }
 
+/// Rough token count for `text`, using OpenAI's rule of thumb of about
+/// four characters per token
+pub fn estimate_tokens(text: &str) -> u32 {
+    (text.chars().count() as u32).div_ceil(4)
+}
+
+/// Split `input` every 10 grapheme clusters, so accented letters, emoji
+/// and their modifiers are never cut apart
 fn split_into_chunks(input: &str) -> Vec<String> {
     let chunk_size = 10; // Adjust chunk size as needed
-    input
-        .as_bytes()
-        .chunks(chunk_size)
-        .map(|chunk| String::from_utf8_lossy(chunk).to_string())
+    let graphemes: Vec<&str> = input.graphemes(true).collect();
+    graphemes.chunks(chunk_size).map(|chunk| chunk.concat()).collect()
+}
+
+/// Split `input` into words, each followed by the whitespace after it
+fn split_into_words(input: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for ch in input.chars() {
+        if !ch.is_whitespace() && word.ends_with(char::is_whitespace) {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push(ch);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Split `input` into sentences: a sentence ends with `.`, `!`, `?` or `…`
+/// (and any closing quotes or brackets) followed by whitespace, which stays
+/// with it, or with a line break
+fn split_into_sentences(input: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut sentence = String::new();
+    // Ending punctuation seen, and then whitespace
+    let (mut terminated, mut ended) = (false, false);
+    for ch in input.chars() {
+        if ended && !ch.is_whitespace() {
+            sentences.push(std::mem::take(&mut sentence));
+            (terminated, ended) = (false, false);
+        }
+        sentence.push(ch);
+        if matches!(ch, '.' | '!' | '?' | '…') {
+            terminated = true;
+        } else if ch == '\n' || (ch.is_whitespace() && terminated) {
+            ended = true;
+        } else if !ch.is_whitespace() && !matches!(ch, '"' | '\'' | ')' | ']' | '”' | '’' | '»') {
+            terminated = false;
+        }
+    }
+    if !sentence.is_empty() {
+        sentences.push(sentence);
+    }
+    sentences
+}
+
+/// Split `input` into token-sized pieces: words keep their leading
+/// whitespace and long words are cut into runs of at most four grapheme
+/// clusters
+fn split_into_tokens(input: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    for ch in input.chars() {
+        match words.last_mut() {
+            Some(word) if !(ch.is_whitespace() && word.chars().last().is_some_and(|c| !c.is_whitespace())) => {
+                word.push(ch)
+            }
+            _ => words.push(ch.to_string()),
+        }
+    }
+    words
+        .iter()
+        .flat_map(|word| {
+            let graphemes: Vec<&str> = word.graphemes(true).collect();
+            graphemes.chunks(4).map(|run| run.concat()).collect::<Vec<_>>()
+        })
         .collect()
 }
 
-async fn generate_chunks(tx: Sender<String>, input: &str) {
-    info!("Generating chunks for input");
-    let content_chunks = split_into_chunks(input);
+/// Token deltas as OpenAI streams them: mostly one token per chunk, with
+/// the occasional chunk carrying two or three
+fn split_into_token_groups(input: &str, rng: &mut StdRng) -> Vec<String> {
+    let tokens = split_into_tokens(input);
+    let mut groups = Vec::new();
+    let mut rest = tokens.as_slice();
+    while !rest.is_empty() {
+        let size = if rng.gen_bool(0.15) { rng.gen_range(2..=3) } else { 1 };
+        let (group, tail) = rest.split_at(size.min(rest.len()));
+        groups.push(group.concat());
+        rest = tail;
+    }
+    groups
+}
+
+/// Merge every `deltas` consecutive chunks into one
+fn coalesce(chunks: Vec<String>, deltas: usize) -> Vec<String> {
+    if deltas <= 1 {
+        return chunks;
+    }
+    chunks.chunks(deltas).map(|merged| merged.concat()).collect()
+}
 
-    for content in content_chunks.iter() {
-        let chunk = Chunk {
-            id: generate_id(),
+/// Run one step of the chunk pipeline over `chunks`
+fn transform_chunks(chunks: Vec<String>, transform: &ChunkTransform, rng: &mut StdRng) -> Vec<String> {
+    match transform {
+        ChunkTransform::Resize { min, max } => {
+            let min = (*min).max(1);
+            let max = (*max).max(min);
+            let content = chunks.concat();
+            let graphemes: Vec<&str> = content.graphemes(true).collect();
+            let mut resized = Vec::new();
+            let mut rest = graphemes.as_slice();
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(rng.gen_range(min..=max).min(rest.len()));
+                resized.push(chunk.concat());
+                rest = tail;
+            }
+            resized
+        }
+        ChunkTransform::Typo { probability, backspace } => {
+            let mut typed = Vec::with_capacity(chunks.len());
+            for chunk in chunks {
+                let graphemes: Vec<&str> = chunk.graphemes(true).collect();
+                let alphabetic = |grapheme: &str| grapheme.chars().next().is_some_and(char::is_alphabetic);
+                let swappable = graphemes
+                    .windows(2)
+                    .position(|pair| alphabetic(pair[0]) && alphabetic(pair[1]) && pair[0] != pair[1]);
+                match swappable.filter(|_| rng.gen_bool(probability.clamp(0.0, 1.0))) {
+                    Some(at) => {
+                        let mut typo = graphemes[..at].concat();
+                        typo.push_str(graphemes[at + 1]);
+                        typo.push_str(graphemes[at]);
+                        typed.push(typo);
+                        let correction = graphemes[at..].concat();
+                        typed.push(format!("{}{}", backspace.repeat(2), correction));
+                    }
+                    None => typed.push(chunk),
+                }
+            }
+            typed
+        }
+        ChunkTransform::Marker { text, every } => {
+            let every = (*every).max(1);
+            let mut marked = Vec::with_capacity(chunks.len() + chunks.len() / every);
+            for (index, chunk) in chunks.into_iter().enumerate() {
+                marked.push(chunk);
+                if (index + 1) % every == 0 {
+                    marked.push(text.clone());
+                }
+            }
+            marked
+        }
+    }
+}
+
+/// SSE event id of the `seq`-th content frame of a stream
+pub fn format_event_id(stream_id: &str, seq: usize) -> String {
+    format!("{}:{}", stream_id, seq)
+}
+
+/// Split a `Last-Event-ID` value back into stream id and frame sequence
+pub fn parse_event_id(value: &str) -> Option<(String, usize)> {
+    let (stream_id, seq) = value.trim().rsplit_once(':')?;
+    Some((stream_id.to_string(), seq.parse().ok()?))
+}
+
+/// How a single response stream is generated
+#[derive(Clone, Debug)]
+pub struct StreamOptions {
+    pub channel_capacity: usize,
+    pub latency: LatencyConfig,
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Abort the stream when the client has not accepted a frame for this long
+    pub stall_timeout: Option<Duration>,
+    /// Interval between SSE comments sent while waiting out a delay
+    pub keepalive_interval: Option<Duration>,
+    /// Emit `id:` lines built from this stream id
+    pub stream_id: Option<String>,
+    /// Skip frames up to and including this sequence (resumed streams)
+    pub resume_after: Option<usize>,
+    /// Seeds the random split of the content and the `resize` and `typo`
+    /// transforms, so a resumed stream cuts it exactly as the one it resumes
+    pub seed: u64,
+    /// Advertise this reconnection delay on the first frame
+    pub retry_ms: Option<u64>,
+    /// Reproduce OpenAI's chunk anatomy, see `ProviderProfile::fidelity`
+    pub fidelity: bool,
+    /// Id shared by every chunk of the completion in fidelity mode
+    pub completion_id: String,
+    pub model: String,
+    pub created: u64,
+    pub prompt_tokens: u32,
+    /// Prompt tokens read from the prompt cache; `Some` when prompt caching
+    /// is simulated, which also counts the prompt tokens outside fidelity
+    /// mode
+    pub cached_tokens: Option<u32>,
+    /// Prompt tokens written to the cache by `cache_control` breakpoints;
+    /// `Some` adds Anthropic's cache fields to usage
+    pub cache_creation_tokens: Option<u32>,
+    /// Add Azure content filter annotations to every choice
+    pub content_filter_results: bool,
+    /// Send the running usage with every chunk
+    pub continuous_usage: bool,
+    /// Finish reasons renamed on the wire
+    pub finish_reasons: HashMap<String, String>,
+    pub system_fingerprint: Option<String>,
+    /// Request id of the `x_groq` block, when the profile sends one
+    pub x_groq: Option<String>,
+    pub provider: Option<String>,
+    /// Sent with every chunk, see `ProviderProfile::citations`
+    pub citations: Option<Vec<String>>,
+    /// Cut the stream short as blocked in this category
+    pub content_filter: Option<String>,
+    /// Content chunks delivered before a content-filter stop
+    pub content_filter_after: usize,
+    /// Fail the stream partway with an `error` event
+    pub stream_error: Option<StreamErrorConfig>,
+    /// `x-request-id` of the request, for log lines
+    pub request_id: String,
+    /// Scripts rewriting every content delta (`on_chunk`)
+    pub hooks: Option<Arc<Hooks>>,
+    /// Sent with the usage chunk as `sim_parameters`
+    pub echo_parameters: Option<serde_json::Value>,
+    /// Where content is cut into chunks outside fidelity mode
+    pub chunking: Chunking,
+    /// Deltas of content and tool call arguments merged into one chunk
+    pub coalesce: usize,
+    /// Reshape the content chunks before they are sent
+    pub transforms: Vec<ChunkTransform>,
+}
+
+impl StreamOptions {
+    pub fn new(config: &StreamingConfig, channel_capacity: usize, latency: LatencyConfig) -> Self {
+        Self {
+            channel_capacity,
+            latency,
+            slow_consumer: config.slow_consumer,
+            stall_timeout: (config.stall_timeout_ms > 0).then(|| Duration::from_millis(config.stall_timeout_ms)),
+            keepalive_interval: (config.keepalive_interval_ms > 0)
+                .then(|| Duration::from_millis(config.keepalive_interval_ms)),
+            stream_id: None,
+            resume_after: None,
+            seed: rand::random(),
+            retry_ms: (config.retry_ms > 0).then_some(config.retry_ms),
+            fidelity: false,
+            completion_id: generate_id(),
+            model: DEFAULT_MODEL.to_string(),
+            created: chrono::Utc::now().timestamp() as u64,
+            prompt_tokens: 0,
+            cached_tokens: None,
+            cache_creation_tokens: None,
+            content_filter_results: false,
+            continuous_usage: false,
+            finish_reasons: HashMap::new(),
+            system_fingerprint: Some(SYSTEM_FINGERPRINT.to_string()),
+            x_groq: None,
+            provider: None,
+            citations: None,
+            content_filter: None,
+            content_filter_after: 0,
+            stream_error: None,
+            request_id: String::new(),
+            hooks: None,
+            echo_parameters: None,
+            chunking: config.chunking,
+            coalesce: 1,
+            transforms: config.transforms.clone(),
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: String) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn with_hooks(mut self, hooks: Arc<Hooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    pub fn with_echo_parameters(mut self, parameters: serde_json::Value) -> Self {
+        self.echo_parameters = Some(parameters);
+        self
+    }
+
+    /// Report `cached_tokens` of the prompt as served from the prompt
+    /// cache, out of the actual prompt tokens
+    pub fn with_cached_tokens(mut self, cached_tokens: u32) -> Self {
+        self.cached_tokens = Some(cached_tokens);
+        self
+    }
+
+    /// Name the upstream provider in every chunk, as OpenRouter does
+    pub fn with_provider(mut self, provider: String) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Send the sources of the reply with every chunk
+    pub fn with_citations(mut self, citations: Vec<String>) -> Self {
+        self.citations = Some(citations);
+        self
+    }
+
+    /// Report Anthropic's `cache_read_input_tokens` (the cached tokens) and
+    /// `cache_creation_input_tokens`
+    pub fn with_cache_creation_tokens(mut self, creation_tokens: u32) -> Self {
+        self.cache_creation_tokens = Some(creation_tokens);
+        self
+    }
+
+    /// Apply the wire-format quirks of a provider profile
+    pub fn with_profile(mut self, profile: &ProviderProfile) -> Self {
+        self.fidelity = profile.fidelity;
+        self.content_filter_results = profile.content_filter_results;
+        self.continuous_usage = profile.continuous_usage;
+        self.finish_reasons = profile.finish_reasons.clone();
+        if let Some(fingerprint) = &profile.system_fingerprint {
+            self.system_fingerprint = Some(fingerprint.clone()).filter(|fingerprint| !fingerprint.is_empty());
+        }
+        self.x_groq = profile.x_groq.then(|| format!("req_{}", uuid::Uuid::new_v4().simple()));
+        self
+    }
+
+    /// Send every `deltas` consecutive content or argument deltas as one
+    /// chunk
+    pub fn with_coalesce(mut self, deltas: usize) -> Self {
+        self.coalesce = deltas.max(1);
+        self
+    }
+
+    /// End the stream with `finish_reason: "content_filter"` after
+    /// `after_chunks` content chunks, reporting `category` as blocked
+    pub fn with_content_filter(mut self, category: String, after_chunks: usize) -> Self {
+        self.content_filter = Some(category);
+        self.content_filter_after = after_chunks;
+        self
+    }
+
+    /// Replace everything after the first `after_chunks` chunks with an
+    /// `error` event, without usage chunk or `[DONE]`
+    pub fn with_stream_error(mut self, error: StreamErrorConfig) -> Self {
+        self.stream_error = Some(error);
+        self
+    }
+
+    /// Identify the completion being streamed, for fidelity-mode chunks
+    pub fn with_completion(mut self, completion_id: String, model: Option<String>, prompt_tokens: u32) -> Self {
+        self.completion_id = completion_id;
+        if let Some(model) = model {
+            self.model = model;
+        }
+        self.prompt_tokens = prompt_tokens;
+        self
+    }
+
+    fn chunk(&self, index: u32, delta: Option<Delta>, finish_reason: Option<&str>, usage: Option<Usage>) -> Chunk {
+        let (id, created, model) = if self.fidelity {
+            (self.completion_id.clone(), self.created, self.model.clone())
+        } else {
+            (generate_id(), 1735278816, DEFAULT_MODEL.to_string())
+        };
+        Chunk {
+            id,
             object: "chat.completion.chunk".to_string(),
-            created: 1735278816,
-            model: "gpt-4o-2024-08-06".to_string(),
-            system_fingerprint: "fp_d28bcae782".to_string(),
-            choices: vec![Choice {
-                index: 0,
-                delta: Delta { content: content.clone() },
-                logprobs: None,
-                finish_reason: None,
-            }],
-            usage: None,
+            created,
+            model,
+            system_fingerprint: self.system_fingerprint.clone(),
+            choices: delta
+                .map(|delta| Choice {
+                    index,
+                    delta,
+                    logprobs: None,
+                    finish_reason: finish_reason
+                        .map(|reason| self.finish_reasons.get(reason).map_or(reason, String::as_str).to_string()),
+                    content_filter_results: self.content_filter_results.then(|| {
+                        let filtered = self.content_filter.as_deref().filter(|_| finish_reason == Some(CONTENT_FILTER));
+                        content_filter::annotations(filtered)
+                    }),
+                })
+                .into_iter()
+                .collect(),
+            usage,
+            x_groq: None,
+            provider: self.provider.clone(),
+            citations: self.citations.clone(),
+            prompt_filter_results: None,
+            sim_parameters: None,
+        }
+    }
+
+    /// The deltas making up the body of the stream, in order, with the
+    /// index of the choice each belongs to. Several choices are streamed
+    /// interleaved, one delta of each in turn.
+    pub fn deltas(&self, reply: &Reply) -> Vec<StreamDelta> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut per_choice: Vec<_> = reply
+            .choices
+            .iter()
+            .enumerate()
+            .map(|(index, choice)| self.choice_deltas(choice, &mut rng).into_iter().map(move |(delta, finish)| (index as u32, delta, finish)))
+            .collect();
+        let mut deltas = Vec::new();
+        loop {
+            let before = deltas.len();
+            deltas.extend(per_choice.iter_mut().filter_map(Iterator::next));
+            if deltas.len() == before {
+                return deltas;
+            }
+        }
+    }
+
+    /// The deltas streaming one choice
+    fn choice_deltas(&self, choice: &ReplyChoice, rng: &mut StdRng) -> Vec<(Delta, Option<String>)> {
+        let content = choice.message.content.as_deref().unwrap_or_default();
+        let mut contents = match self.chunking {
+            _ if self.fidelity => split_into_token_groups(content, rng),
+            Chunking::Chars => split_into_chunks(content),
+            Chunking::Words => split_into_words(content),
+            Chunking::Sentences => split_into_sentences(content),
+        };
+        for transform in &self.transforms {
+            contents = transform_chunks(contents, transform, rng);
+        }
+        contents = coalesce(contents, self.coalesce);
+        if self.content_filter.is_some() {
+            contents.truncate(self.content_filter_after);
+        }
+        if let Some(hooks) = &self.hooks {
+            contents = contents.into_iter().enumerate().map(|(index, chunk)| hooks.on_chunk(chunk, index)).collect();
+        }
+
+        let mut deltas = Vec::new();
+        if self.fidelity {
+            let content = choice.message.tool_calls.is_empty().then(String::new);
+            deltas.push((Delta { role: Some("assistant".to_string()), content, ..Delta::default() }, None));
+        }
+        // Built-in tools run before the answer, and take the first indexes
+        let builtin = &choice.message.builtin_tool_calls;
+        for (index, call) in builtin.iter().enumerate() {
+            deltas.extend(builtin_tool_call_deltas(index as u32, call).into_iter().map(|delta| (delta, None)));
+        }
+        deltas.extend(contents.into_iter().map(|content| (Delta { content: Some(content), ..Delta::default() }, None)));
+        if self.content_filter.is_some() {
+            deltas.push((Delta::default(), Some(CONTENT_FILTER.to_string())));
+            return deltas;
+        }
+        // Sent once the content they index into is complete
+        if !choice.message.annotations.is_empty() {
+            deltas.push((Delta { annotations: Some(choice.message.annotations.clone()), ..Delta::default() }, None));
+        }
+        for (index, call) in choice.message.tool_calls.iter().enumerate() {
+            deltas.extend(self.tool_call_deltas((builtin.len() + index) as u32, call, rng).into_iter().map(|delta| (delta, None)));
+        }
+        // Outside fidelity mode the final chunk is only sent for a finish
+        // reason other than a plain stop
+        if self.fidelity || choice.finish_reason.is_some() || !choice.message.tool_calls.is_empty() {
+            deltas.push((Delta::default(), Some(choice.finish_reason().to_string())));
+        }
+        deltas
+    }
+
+    /// The deltas streaming one tool call as OpenAI does: the first names
+    /// the call and carries its id, the following ones only append
+    /// fragments of the JSON arguments, cut anywhere, under the same index
+    fn tool_call_deltas(&self, index: u32, call: &ToolCall, rng: &mut StdRng) -> Vec<Delta> {
+        let opening = ToolCallDelta {
+            index,
+            id: Some(call.id.clone().unwrap_or_else(generate_tool_call_id)),
+            kind: Some(call.kind.clone()),
+            function: Some(FunctionDelta {
+                name: Some(call.function.name.clone()),
+                arguments: String::new(),
+            }),
+            output: None,
         };
+        let arguments = &call.function.arguments;
+        let fragments = if self.fidelity { split_into_token_groups(arguments, rng) } else { split_into_chunks(arguments) };
+        let fragments = coalesce(fragments, self.coalesce);
+        std::iter::once(opening)
+            .chain(fragments.into_iter().map(|arguments| ToolCallDelta {
+                index,
+                id: None,
+                kind: None,
+                function: Some(FunctionDelta { name: None, arguments }),
+                output: None,
+            }))
+            .map(|delta| Delta { tool_calls: Some(vec![delta]), ..Delta::default() })
+            .collect()
+    }
+
+    /// Trailing chunk with the token usage of the `completion` text streamed
+    fn usage_chunk(&self, completion: &str) -> Chunk {
+        let mut chunk = self.chunk(0, None, None, Some(self.usage(completion)));
+        chunk.sim_parameters = self.echo_parameters.clone();
+        chunk
+    }
+
+    /// Token usage once the `completion` text has been streamed
+    fn usage(&self, completion: &str) -> Usage {
+        // Outside fidelity mode usage is a fixed sample, except running
+        // usage, which has to grow with the text streamed so far, and the
+        // prompt cached tokens are reported out of
+        let counted = self.fidelity || self.continuous_usage;
+        let prompt_tokens = if counted || self.cached_tokens.is_some() { self.prompt_tokens } else { 182 };
+        let completion_tokens = if counted { estimate_tokens(completion) } else { 520 };
+        let cached_tokens = self.cached_tokens.unwrap_or_default().min(prompt_tokens);
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            prompt_tokens_details: PromptTokensDetails {
+                cached_tokens,
+                audio_tokens: 0,
+            },
+            completion_tokens_details: CompletionTokensDetails {
+                reasoning_tokens: 0,
+                audio_tokens: 0,
+                accepted_prediction_tokens: 0,
+                rejected_prediction_tokens: 0,
+            },
+            cache_creation_input_tokens: self
+                .cache_creation_tokens
+                .map(|creation_tokens| creation_tokens.min(prompt_tokens - cached_tokens)),
+            cache_read_input_tokens: self.cache_creation_tokens.map(|_| cached_tokens),
+        }
+    }
+
+    /// Milliseconds the stream of `deltas` is scheduled to wait before its
+    /// first frame and in all, from `latency` and the frames it will send
+    pub fn schedule(&self, deltas: &[StreamDelta]) -> (u64, u64) {
+        let frames = deltas.len();
+        let first = self.resume_after.map_or(0, |seq| seq + 1);
+        let delayed = match &self.stream_error {
+            Some(error) => error.after_chunks.min(frames).saturating_sub(first) + 1,
+            None => frames.saturating_sub(first),
+        };
+        match delayed {
+            0 => (0, 0),
+            delayed => (self.latency.ttft_ms, self.latency.ttft_ms + self.latency.chunk_delay_ms * (delayed as u64 - 1)),
+        }
+    }
+
+    /// Usage as in `x_groq`, with the seconds spent queued, reading the
+    /// prompt and generating the `chunks` of the completion
+    fn groq_usage(&self, completion: &str, chunks: usize) -> serde_json::Value {
+        let usage = self.usage(completion);
+        let queue_time = rand::thread_rng().gen_range(0.01..0.05);
+        let prompt_time = self.latency.ttft_ms as f64 / 1000.0;
+        let completion_time = (self.latency.chunk_delay_ms * chunks.saturating_sub(1) as u64) as f64 / 1000.0;
+        serde_json::json!({
+            "queue_time": queue_time,
+            "prompt_tokens": usage.prompt_tokens,
+            "prompt_time": prompt_time,
+            "completion_tokens": usage.completion_tokens,
+            "completion_time": completion_time,
+            "total_tokens": usage.total_tokens,
+            "total_time": prompt_time + completion_time,
+        })
+    }
+
+    /// Split and transform the content as the stream seeded with `seed` did
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Tag frames with event ids, continuing after `resume_after` if set
+    pub fn with_event_ids(mut self, stream_id: String, resume_after: Option<usize>) -> Self {
+        self.stream_id = Some(stream_id);
+        self.resume_after = resume_after;
+        self
+    }
+}
+
+/// The deltas streaming a built-in tool call: the call itself, then, when
+/// it has `outputs` (as `code_interpreter` does), a follow-up delta
+/// carrying them once the tool has run
+fn builtin_tool_call_deltas(index: u32, call: &BuiltinToolCall) -> Vec<Delta> {
+    let mut output = call.output.clone();
+    let outputs = output
+        .get_mut(&call.kind)
+        .and_then(|payload| payload.get_mut("outputs"))
+        .map(|outputs| std::mem::replace(outputs, serde_json::Value::Array(Vec::new())));
+    let opening = ToolCallDelta {
+        index,
+        id: Some(call.id.clone()),
+        kind: Some(call.kind.clone()),
+        function: None,
+        output: Some(output),
+    };
+    let follow_up = outputs.map(|outputs| ToolCallDelta {
+        index,
+        id: None,
+        kind: None,
+        function: None,
+        output: Some(serde_json::Map::from_iter([(call.kind.clone(), serde_json::json!({ "outputs": outputs }))])),
+    });
+    std::iter::once(opening)
+        .chain(follow_up)
+        .map(|delta| Delta { tool_calls: Some(vec![delta]), ..Delta::default() })
+        .collect()
+}
+
+/// Why frame delivery stopped early
+enum SendFailure {
+    /// The client stopped reading for longer than the stall timeout
+    Stalled,
+    /// The response was dropped, usually because the client disconnected
+    Closed,
+}
+
+async fn send_frame(tx: &Sender<String>, frame: String, stall_timeout: Option<Duration>) -> Result<(), SendFailure> {
+    match stall_timeout {
+        Some(timeout) => tx.send_timeout(frame, timeout).await.map_err(|e| match e {
+            SendTimeoutError::Timeout(_) => SendFailure::Stalled,
+            SendTimeoutError::Closed(_) => SendFailure::Closed,
+        }),
+        None => tx.send(frame).await.map_err(|_| SendFailure::Closed),
+    }
+}
+
+/// SSE comment line; clients ignore it but it keeps idle connections alive
+pub const KEEPALIVE_FRAME: &str = ": keep-alive\n\n";
+
+/// Wait `delay`, sending a keep-alive comment every `keepalive_interval` so
+/// proxies and client read timeouts don't give up during long pauses
+async fn pause(tx: &Sender<String>, delay: Duration, options: &StreamOptions) -> Result<(), SendFailure> {
+    let deadline = tokio::time::Instant::now() + delay;
+    if let Some(interval) = options.keepalive_interval {
+        while tokio::time::Instant::now() + interval < deadline {
+            tokio::time::sleep(interval).await;
+            send_frame(tx, KEEPALIVE_FRAME.to_string(), options.stall_timeout).await?;
+        }
+    }
+    tokio::time::sleep_until(deadline).await;
+    Ok(())
+}
+
+/// Returns true when every frame was handed to the client, false when the
+/// stream was aborted because the client stalled
+async fn generate_chunks(tx: Sender<String>, deltas: Vec<StreamDelta>, options: StreamOptions) -> bool {
+    info!("Generating chunks for input request_id={}", options.request_id);
+    let completion: String = deltas.iter().map(|(_, delta, _)| delta.completion_text()).collect();
+    let latency = options.latency;
+
+    let first = options.resume_after.map_or(0, |seq| seq + 1);
+    let total = deltas.len();
+
+    let failing_at = options.stream_error.as_ref().map(|error| error.after_chunks);
+    // Completion text up to the current chunk, for continuous usage
+    let mut streamed: String = deltas.iter().take(first).map(|(_, delta, _)| delta.completion_text()).collect();
+
+    for (index, (choice, delta, finish_reason)) in deltas.into_iter().enumerate().skip(first) {
+        let delay = if index == first { latency.ttft_ms } else { latency.chunk_delay_ms };
+        if failing_at.is_some_and(|at| index >= at) {
+            return send_stream_error(&tx, delay, &options).await;
+        }
+
+        let usage = options.continuous_usage.then(|| {
+            streamed.push_str(&delta.completion_text());
+            options.usage(&streamed)
+        });
+        let mut chunk = options.chunk(choice, Some(delta), finish_reason.as_deref(), usage);
+        if let Some(id) = &options.x_groq {
+            if index == 0 {
+                chunk.x_groq = Some(serde_json::json!({ "id": id }));
+            } else if index + 1 == total {
+                chunk.x_groq = Some(serde_json::json!({ "id": id, "usage": options.groq_usage(&completion, total) }));
+            }
+        }
+        if index == 0 && options.content_filter_results {
+            chunk.prompt_filter_results = Some(serde_json::json!([
+                { "prompt_index": 0, "content_filter_results": content_filter::annotations(None) }
+            ]));
+        }
 
         let chunk_str = serde_json::to_string(&chunk).unwrap();
-        let combined_chunk = format!("data: {}\n\n", chunk_str);
+        let mut combined_chunk = String::new();
+        if let Some(retry_ms) = options.retry_ms.filter(|_| index == first) {
+            combined_chunk.push_str(&format!("retry: {}\n", retry_ms));
+        }
+        if let Some(stream_id) = &options.stream_id {
+            combined_chunk.push_str(&format!("id: {}\n", format_event_id(stream_id, index)));
+        }
+        combined_chunk.push_str(&format!("data: {}\n\n", chunk_str));
 
-        if let Err(e) = tx.send(combined_chunk.clone()).await {
-            error!("Failed to send chunk: {}. Error: {}", combined_chunk, e);
-        } else {
-            debug!("Sent chunk: {}", combined_chunk);
+        let sent = async {
+            if delay > 0 {
+                pause(&tx, Duration::from_millis(delay), &options).await?;
+            }
+            send_frame(&tx, combined_chunk.clone(), options.stall_timeout).await
+        };
+
+        match sent.await {
+            Ok(()) => debug!("Sent chunk: {}", combined_chunk),
+            Err(SendFailure::Stalled) => {
+                warn!(
+                    "Aborting stream after chunk {}/{}: client has not read for {:?} request_id={}",
+                    index,
+                    total,
+                    options.stall_timeout.unwrap_or_default(),
+                    options.request_id
+                );
+                return false;
+            }
+            Err(SendFailure::Closed) => {
+                debug!(
+                    "Client disconnected after chunk {}/{}, stopping generation request_id={}",
+                    index, total, options.request_id
+                );
+                return true;
+            }
         }
     }
 
-    // Remove the final chunk sending from here
+    if failing_at.is_some() {
+        return send_stream_error(&tx, latency.chunk_delay_ms, &options).await;
+    }
+
+    let final_chunk = options.usage_chunk(&completion);
+    let mut trailer = vec![format!("data: {}\n\n", serde_json::to_string(&final_chunk).unwrap())];
+    if options.fidelity {
+        trailer.push(DONE_FRAME.to_string());
+    }
+    for frame in trailer {
+        debug!("Sending final frame: {}", frame);
+        match send_frame(&tx, frame, options.stall_timeout).await {
+            Ok(()) => {}
+            Err(SendFailure::Stalled) => {
+                warn!(
+                    "Aborting stream before its final frame: client has not read for {:?} request_id={}",
+                    options.stall_timeout.unwrap_or_default(),
+                    options.request_id
+                );
+                return false;
+            }
+            Err(SendFailure::Closed) => return true,
+        }
+    }
+    true
 }
 
-pub fn openai_simulator(input: &str) -> impl Stream<Item = String> {
+/// End the stream with the configured `error` event after `delay`.
+/// Returns false when the client stalled, like `generate_chunks`.
+async fn send_stream_error(tx: &Sender<String>, delay: u64, options: &StreamOptions) -> bool {
+    let Some(error) = &options.stream_error else {
+        return true;
+    };
+    info!("Failing stream with an error event request_id={}", options.request_id);
+    let frame = format!("data: {}\n\n", error.event());
+    let sent = async {
+        if delay > 0 {
+            pause(tx, Duration::from_millis(delay), options).await?;
+        }
+        send_frame(tx, frame, options.stall_timeout).await
+    };
+    match sent.await {
+        Err(SendFailure::Stalled) => {
+            warn!(
+                "Aborting stream before its error event: client has not read for {:?} request_id={}",
+                options.stall_timeout.unwrap_or_default(),
+                options.request_id
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Stream the SSE frames for `deltas`, from `StreamOptions::deltas`. When the client stalls past the
+/// configured timeout the stream ends with an error, which makes actix
+/// abort the connection instead of ending the response cleanly.
+pub fn openai_simulator(deltas: Vec<StreamDelta>, options: StreamOptions) -> impl Stream<Item = io::Result<String>> {
     //info!("Starting OpenAI simulator");
 
-    // Use async channel with capacity 10000
-    let (tx, rx) = channel(CONFIG.channel_capacity);
-    let input = input.to_string();
+    let capacity = match options.slow_consumer {
+        // Keep at most one frame in flight so generation tracks consumption
+        SlowConsumerPolicy::Pace => 1,
+        SlowConsumerPolicy::Buffer => options.channel_capacity,
+    };
+    let (tx, rx) = channel(capacity);
+    let (done_tx, done_rx) = oneshot::channel();
 
     tokio::spawn(async move {
-        generate_chunks(tx, &input).await;
+        let delivered = generate_chunks(tx, deltas, options).await;
+        let _ = done_tx.send(delivered);
     });
 
-    ReceiverStream::new(rx)
-}
\ No newline at end of file
+    let abort_marker = futures_util::stream::once(async move {
+        match done_rx.await {
+            Ok(false) => Some(Err(io::Error::new(io::ErrorKind::TimedOut, "client stalled, stream aborted"))),
+            _ => None,
+        }
+    })
+    .filter_map(futures_util::future::ready);
+
+    ReceiverStream::new(rx).map(Ok).chain(abort_marker)
+}
+
+/// Timing of one frame relative to the start of the response
+#[derive(Serialize, Clone, Debug)]
+pub struct ChunkTiming {
+    pub offset_ms: f64,
+    pub bytes: usize,
+}
+
+/// `frames` sent again, each at the offset in milliseconds from the start
+/// of the response it was first sent at
+pub fn replay(frames: Vec<(Bytes, f64)>) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let started = tokio::time::Instant::now();
+    futures_util::stream::iter(frames).then(move |(frame, offset_ms)| async move {
+        tokio::time::sleep_until(started + Duration::from_secs_f64(offset_ms / 1000.0)).await;
+        Ok(frame)
+    })
+}
+
+/// What a `TracedStream` observed once it ended or was dropped
+pub struct StreamTrace {
+    pub chunks: Vec<ChunkTiming>,
+    /// False when the client went away before the stream ended
+    pub completed: bool,
+    pub duration: Duration,
+    /// The recorded frames, when the stream was `recording_frames`
+    pub frames: Vec<Bytes>,
+}
+
+type FinishCallback = Box<dyn FnOnce(StreamTrace) + Send>;
+
+/// Wraps a response body stream, timestamps every frame handed to actix and
+/// reports the trace to `on_finish` when the stream ends or is dropped.
+/// SSE comment frames (keep-alives) are passed through but not recorded, so
+/// they don't count towards TTFT or chunk counts.
+pub struct TracedStream<S> {
+    inner: Pin<Box<S>>,
+    started: Instant,
+    chunks: Vec<ChunkTiming>,
+    frames: Option<Vec<Bytes>>,
+    completed: bool,
+    on_finish: Option<FinishCallback>,
+}
+
+impl<S> TracedStream<S> {
+    pub fn new(inner: S, on_finish: impl FnOnce(StreamTrace) + Send + 'static) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            started: Instant::now(),
+            chunks: Vec::new(),
+            frames: None,
+            completed: false,
+            on_finish: Some(Box::new(on_finish)),
+        }
+    }
+
+    /// Also keep the content of every recorded frame for the trace
+    pub fn recording_frames(mut self) -> Self {
+        self.frames = Some(Vec::new());
+        self
+    }
+
+    fn finish(&mut self) {
+        if let Some(on_finish) = self.on_finish.take() {
+            on_finish(StreamTrace {
+                chunks: std::mem::take(&mut self.chunks),
+                completed: self.completed,
+                duration: self.started.elapsed(),
+                frames: self.frames.take().unwrap_or_default(),
+            });
+        }
+    }
+}
+
+impl<S> Stream for TracedStream<S>
+where
+    S: Stream<Item = Result<Bytes, actix_web::Error>>,
+{
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.inner.as_mut().poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                if let Some(bytes) = item.as_ref().ok().filter(|bytes| !bytes.starts_with(b":")) {
+                    this.chunks.push(ChunkTiming {
+                        offset_ms: this.started.elapsed().as_secs_f64() * 1000.0,
+                        bytes: bytes.len(),
+                    });
+                    if let Some(frames) = &mut this.frames {
+                        frames.push(bytes.clone());
+                    }
+                }
+                Poll::Ready(Some(item))
+            }
+            Poll::Ready(None) => {
+                this.completed = true;
+                this.finish();
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S> Drop for TracedStream<S> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StreamOptions;
+    use crate::config_loader::{ChunkTransform, LatencyConfig, StreamingConfig};
+    use crate::reply::Reply;
+
+    fn options() -> StreamOptions {
+        StreamOptions::new(&StreamingConfig::default(), 16, LatencyConfig::default())
+    }
+
+    #[test]
+    fn continuous_usage_counts_the_text_streamed_so_far() {
+        let mut options = options();
+        options.continuous_usage = true;
+        options.prompt_tokens = 12;
+        let early = options.usage("Halo");
+        let later = options.usage("Halo, apa kabar hari ini?");
+        assert_eq!((early.prompt_tokens, early.completion_tokens), (12, 1));
+        assert_eq!((later.prompt_tokens, later.completion_tokens), (12, 7));
+        assert_eq!(later.total_tokens, 19);
+    }
+
+    #[test]
+    fn cached_tokens_are_reported_out_of_the_actual_prompt() {
+        let mut options = options().with_cached_tokens(1024);
+        options.prompt_tokens = 1500;
+        let usage = options.usage("Halo");
+        assert_eq!(usage.prompt_tokens, 1500);
+        assert_eq!(usage.prompt_tokens_details.cached_tokens, 1024);
+        assert_eq!(usage.completion_tokens, 520);
+    }
+
+    #[test]
+    fn the_same_seed_splits_the_content_the_same_way() {
+        let reply = Reply::text("Simulator ini memecah jawaban menjadi token seperti OpenAI, kadang dua atau tiga sekaligus.");
+        let contents = |seed: u64| {
+            let mut options = options().with_seed(seed);
+            options.fidelity = true;
+            options.deltas(&reply).into_iter().map(|(_, delta, _)| delta.completion_text()).collect::<Vec<_>>()
+        };
+        assert_eq!(contents(7), contents(7));
+        assert_eq!(contents(7).concat(), contents(8).concat());
+    }
+
+    #[test]
+    fn the_same_seed_applies_the_same_transforms() {
+        let reply = Reply::text("Simulator ini memecah jawaban menjadi potongan acak dengan sesekali salah ketik.");
+        let contents = |seed: u64| {
+            let mut options = options().with_seed(seed);
+            options.transforms = vec![
+                ChunkTransform::Resize { min: 1, max: 6 },
+                ChunkTransform::Typo { probability: 0.5, backspace: "\u{8}".to_string() },
+            ];
+            options.deltas(&reply).into_iter().map(|(_, delta, _)| delta.completion_text()).collect::<Vec<_>>()
+        };
+        assert_eq!(contents(7), contents(7));
+    }
+}
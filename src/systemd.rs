@@ -0,0 +1,49 @@
+use std::net::TcpListener;
+use log::{info, warn};
+use crate::error::CustomError;
+
+/// First descriptor systemd passes (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+/// The listening socket systemd passed on socket activation (`LISTEN_PID`
+/// naming this process and `LISTEN_FDS`), to serve on instead of binding
+/// `binding.host:binding.port`. Only the first of several sockets is
+/// served; it must be a TCP stream socket (`ListenStream=<port>` or
+/// `<host>:<port>`).
+#[cfg(unix)]
+pub fn listener() -> Result<Option<TcpListener>, CustomError> {
+    use std::os::unix::io::FromRawFd;
+
+    let for_us = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    let Some(fds) = std::env::var("LISTEN_FDS").ok().filter(|_| for_us) else {
+        return Ok(None);
+    };
+    let fds: u32 = fds
+        .parse()
+        .map_err(|_| CustomError::ConfigError(format!("LISTEN_FDS '{}' is not a number", fds)))?;
+    if fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!("systemd passed {} sockets, serving on the first only", fds);
+    }
+    // SAFETY: with LISTEN_PID naming this process, systemd hands it the
+    // descriptors from LISTEN_FDS_START on and nothing else owns them
+    let socket = unsafe { socket2::Socket::from_raw_fd(LISTEN_FDS_START) };
+    let not_tcp = || {
+        CustomError::ConfigError("the socket passed by systemd is not a TCP stream socket, use ListenStream=<port>".to_string())
+    };
+    if socket.r#type()? != socket2::Type::STREAM {
+        return Err(not_tcp());
+    }
+    let addr = socket.local_addr()?.as_socket().ok_or_else(not_tcp)?;
+    info!("Serving on {} passed by systemd, binding is ignored", addr);
+    Ok(Some(socket.into()))
+}
+
+/// Socket activation needs a Unix host
+#[cfg(not(unix))]
+pub fn listener() -> Result<Option<TcpListener>, CustomError> {
+    Ok(None)
+}
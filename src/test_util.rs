@@ -0,0 +1,134 @@
+//! Helpers for booting the simulator from integration tests.
+//!
+//! Enabled with the `test-util` feature:
+//!
+//! ```no_run
+//! # async fn run() {
+//! use rai_endpoint_simulator::test_util::TestSimulator;
+//!
+//! let sim = TestSimulator::start("tests/fixtures").await.unwrap();
+//! let endpoint = sim.endpoint("/v1/chat/completions");
+//! // ... drive the client under test against `endpoint` ...
+//! # drop(endpoint);
+//! // the server is stopped when `sim` goes out of scope
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::error::CustomError;
+use crate::simulator::{RunningSimulator, Simulator, SimulatorBuilder};
+
+/// How long `start` waits for the server to answer before giving up
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A simulator bound to an ephemeral port that shuts down on drop
+pub struct TestSimulator {
+    inner: Option<RunningSimulator>,
+    url: String,
+}
+
+impl TestSimulator {
+    /// Start a single-worker simulator with the default configuration,
+    /// serving the responses in `response_dir` (rather than `zresponse`
+    /// relative to the working directory)
+    pub async fn start(response_dir: impl AsRef<Path>) -> Result<Self, CustomError> {
+        let response_dir = response_dir.as_ref().to_string_lossy().into_owned();
+        Self::start_with(Simulator::builder().workers(1).response_dir(response_dir)).await
+    }
+
+    /// Start from a preconfigured builder. The bind address is always
+    /// overridden with an ephemeral loopback port.
+    pub async fn start_with(builder: SimulatorBuilder) -> Result<Self, CustomError> {
+        let running = builder.bind_random_port().spawn().await?;
+        let addr = running.addr();
+
+        if let Err(e) = wait_until_ready(addr).await {
+            running.stop().await;
+            return Err(e);
+        }
+
+        Ok(Self {
+            url: running.url(),
+            inner: Some(running),
+        })
+    }
+
+    /// Base URL, e.g. `http://127.0.0.1:38123`
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Absolute URL for a path on the simulator, e.g. `endpoint("/health")`
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.running().addr()
+    }
+
+    /// Gracefully stop the server and wait for it to exit
+    pub async fn shutdown(mut self) {
+        if let Some(running) = self.inner.take() {
+            running.stop().await;
+        }
+    }
+
+    fn running(&self) -> &RunningSimulator {
+        self.inner.as_ref().expect("simulator already shut down")
+    }
+}
+
+impl Drop for TestSimulator {
+    fn drop(&mut self) {
+        if let Some(running) = self.inner.take() {
+            // The stop command is sent eagerly; the returned future only
+            // waits for completion, which a synchronous drop cannot do.
+            drop(running.handle().stop(false));
+        }
+    }
+}
+
+/// Poll the server until it answers an HTTP request or `READY_TIMEOUT`
+/// elapses
+async fn wait_until_ready(addr: SocketAddr) -> Result<(), CustomError> {
+    let deadline = tokio::time::Instant::now() + READY_TIMEOUT;
+
+    loop {
+        match probe(addr).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => debug!("Simulator at {} not ready yet", addr),
+            Err(e) => debug!("Readiness probe to {} failed: {}", addr, e),
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            return Err(CustomError::BindError(format!(
+                "simulator at {} not ready after {:?}",
+                addr, READY_TIMEOUT
+            )));
+        }
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+}
+
+/// Whether a worker answers `GET /readyz`. Any status counts: the probe
+/// only needs the server to be serving, and the route may be disabled
+/// (`endpoints.health`) or report a database still connecting.
+async fn probe(addr: SocketAddr) -> std::io::Result<bool> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!(
+        "GET /readyz HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(response.starts_with(b"HTTP/1.1 "))
+}
@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::error::{ApiError, CustomError};
+
+/// Tier applied to keys no other tier lists
+pub const DEFAULT_TIER: &str = "default";
+
+/// A class of API keys with its own concurrency pool and rate limit, like
+/// the usage tiers providers put organizations in
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct TierConfig {
+    /// Masked key ids in this tier, as reported by `/admin/stats`, e.g.
+    /// `sk-...a1b2`
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Requests of the tier's keys served at once; the others wait. Unset
+    /// means only `semaphore_limit` applies.
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    /// Requests each key of the tier may send per minute before getting
+    /// `429 rate_limit_error`
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+}
+
+/// Requests a key has left, refilled continuously at `requests_per_minute`
+#[derive(Clone)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+struct Tier {
+    semaphore: Option<Arc<Semaphore>>,
+    concurrency: Option<usize>,
+    requests_per_minute: Option<u32>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+/// Concurrency and rate of one tier, for `/admin/stats`
+#[derive(Serialize)]
+pub struct TierStatus {
+    pub keys: usize,
+    pub in_use: Option<usize>,
+    pub concurrency: Option<usize>,
+    pub requests_per_minute: Option<u32>,
+}
+
+pub struct Tiers {
+    tiers: HashMap<String, Tier>,
+    /// Tier name of every listed key
+    by_key: HashMap<String, String>,
+}
+
+impl Tiers {
+    pub fn new(config: &HashMap<String, TierConfig>) -> Self {
+        let tiers = config
+            .iter()
+            .map(|(name, tier)| {
+                let tier = Tier {
+                    semaphore: tier.concurrency.map(|limit| Arc::new(Semaphore::new(limit))),
+                    concurrency: tier.concurrency,
+                    requests_per_minute: tier.requests_per_minute,
+                    buckets: Mutex::new(HashMap::new()),
+                };
+                (name.clone(), tier)
+            })
+            .collect();
+        let by_key = config
+            .iter()
+            .flat_map(|(name, tier)| tier.keys.iter().map(move |key| (key.clone(), name.clone())))
+            .collect();
+        Self { tiers, by_key }
+    }
+
+    /// Keep the per-minute allowances `previous`, the tiers before a reload,
+    /// had left, for the tiers still configured
+    pub fn carry_over(&self, previous: &Tiers) {
+        for (name, tier) in &self.tiers {
+            if let Some(before) = previous.tiers.get(name) {
+                *tier.buckets.lock().unwrap() = before.buckets.lock().unwrap().clone();
+            }
+        }
+    }
+
+    /// The tier `api_key` belongs to, if any
+    pub fn tier_of(&self, api_key: &str) -> Option<&str> {
+        match self.by_key.get(api_key) {
+            Some(name) => Some(name),
+            None => self.tiers.contains_key(DEFAULT_TIER).then_some(DEFAULT_TIER),
+        }
+    }
+
+    fn tier(&self, api_key: &str) -> Option<&Tier> {
+        self.tier_of(api_key).and_then(|name| self.tiers.get(name))
+    }
+
+    /// Wait for a permit of the tier of `api_key`; `None` when the tier has
+    /// no concurrency limit
+    pub async fn acquire(&self, api_key: &str) -> Result<Option<OwnedSemaphorePermit>, CustomError> {
+        let Some(semaphore) = self.tier(api_key).and_then(|tier| tier.semaphore.clone()) else {
+            return Ok(None);
+        };
+        semaphore.acquire_owned().await.map(Some).map_err(|_| CustomError::FetchError)
+    }
+
+    /// Take one request from the per-minute allowance of `api_key`, or
+    /// reject it the way OpenAI does, with its `x-ratelimit-*` headers
+    pub fn check_rate(&self, api_key: &str) -> Result<(), ApiError> {
+        let Some((tier, limit)) = self.tier(api_key).and_then(|tier| Some((tier, tier.requests_per_minute?))) else {
+            return Ok(());
+        };
+        let per_second = f64::from(limit) / 60.0;
+        let now = Instant::now();
+        let mut buckets = tier.buckets.lock().unwrap();
+        let bucket = buckets.entry(api_key.to_string()).or_insert_with(|| Bucket {
+            tokens: f64::from(limit),
+            refilled_at: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(f64::from(limit));
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let retry_after = if per_second > 0.0 {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / per_second)
+        } else {
+            Duration::from_secs(60)
+        };
+        let retry_after_ms = retry_after.as_millis().max(1);
+        let message = format!(
+            "Rate limit reached for {} on requests per min (RPM): Limit {}, Used {}, Requested 1. Please try again in {}ms.",
+            api_key, limit, limit, retry_after_ms
+        );
+        Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, "requests", message)
+            .with_code("rate_limit_exceeded")
+            .with_header("retry-after", retry_after_ms.div_ceil(1000).to_string())
+            .with_header("retry-after-ms", retry_after_ms.to_string())
+            .with_header("x-ratelimit-limit-requests", limit.to_string())
+            .with_header("x-ratelimit-remaining-requests", "0".to_string())
+            .with_header("x-ratelimit-reset-requests", format!("{}ms", retry_after_ms)))
+    }
+
+    pub fn status(&self) -> BTreeMap<String, TierStatus> {
+        self.tiers
+            .iter()
+            .map(|(name, tier)| {
+                let status = TierStatus {
+                    keys: self.by_key.values().filter(|tier| *tier == name).count(),
+                    in_use: tier
+                        .semaphore
+                        .as_ref()
+                        .zip(tier.concurrency)
+                        .map(|(semaphore, limit)| limit.saturating_sub(semaphore.available_permits())),
+                    concurrency: tier.concurrency,
+                    requests_per_minute: tier.requests_per_minute,
+                };
+                (name.clone(), status)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::bench::Percentiles;
+use crate::cli::Args;
+use crate::config_loader::Config;
+use crate::error::CustomError;
+use crate::response::{load_corpus, CorpusEntry};
+use crate::stream::estimate_tokens;
+
+/// Upper bounds, in tokens, of the answer length histogram
+const HISTOGRAM_BOUNDS: [u32; 5] = [64, 256, 1024, 4096, 16384];
+
+/// Load the corpus the config serves from, print every entry with a
+/// problem and the corpus statistics; fails when any entry has a problem.
+/// `--max-tokens` (4096) sets the answer length above which an entry is
+/// oversized.
+pub async fn run(args: &Args) -> Result<(), CustomError> {
+    let config = Config::read(args.profile())?;
+    let max_tokens: u32 = args.parse_or("max-tokens", 4096)?;
+    let entries = load_corpus(&config).await?;
+    println!("Validating {} entries from the {:?} source\n", entries.len(), config.source);
+
+    let issues = issues(&entries, max_tokens);
+    for (origin, issue) in &issues {
+        println!("{}: {}", origin, issue);
+    }
+    if !issues.is_empty() {
+        println!();
+    }
+    print_statistics(&entries);
+
+    let flagged = issues.iter().map(|(origin, _)| origin).collect::<HashSet<_>>().len();
+    if flagged > 0 {
+        return Err(CustomError::ConfigError(format!(
+            "{} issues in {} of {} entries",
+            issues.len(),
+            flagged,
+            entries.len()
+        )));
+    }
+    println!("\nNo issues found");
+    Ok(())
+}
+
+/// Problems of every entry, by origin: unreadable content, an empty
+/// answer, an answer over `max_tokens`, and a question (or, for entries
+/// without one, an answer) already seen in an earlier entry
+fn issues(entries: &[CorpusEntry], max_tokens: u32) -> Vec<(&str, String)> {
+    let mut issues = Vec::new();
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for entry in entries {
+        let origin = entry.origin.as_str();
+        if let Some(problem) = &entry.problem {
+            issues.push((origin, problem.clone()));
+        }
+        let response = &entry.response;
+        if response.jawaban.trim().is_empty() {
+            issues.push((origin, "empty jawaban".to_string()));
+        }
+        let tokens = estimate_tokens(&response.jawaban);
+        if tokens > max_tokens {
+            issues.push((origin, format!("oversized jawaban: ~{} tokens, above {}", tokens, max_tokens)));
+        }
+        let (field, text) = match response.pertanyaan.trim() {
+            "" => ("jawaban", response.jawaban.as_str()),
+            pertanyaan => ("pertanyaan", pertanyaan),
+        };
+        let normalized = text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+        if normalized.is_empty() {
+            continue;
+        }
+        match seen.get(&normalized) {
+            Some(first) => issues.push((origin, format!("duplicate {} of {}", field, first))),
+            None => {
+                seen.insert(normalized, origin);
+            }
+        }
+    }
+    issues
+}
+
+fn print_statistics(entries: &[CorpusEntry]) {
+    let tokens: Vec<u32> = entries.iter().map(|entry| estimate_tokens(&entry.response.jawaban)).collect();
+    let total: u64 = tokens.iter().map(|&tokens| u64::from(tokens)).sum();
+    println!("Entries: {}", entries.len());
+    if entries.is_empty() {
+        return;
+    }
+    let percentiles = Percentiles::of(tokens.iter().map(|&tokens| f64::from(tokens)).collect());
+    println!(
+        "Jawaban tokens (estimated): min {}, mean {:.0}, p50 {}, p90 {}, p99 {}, max {}",
+        tokens.iter().min().copied().unwrap_or_default(),
+        total as f64 / tokens.len() as f64,
+        percentiles.p50,
+        percentiles.p90,
+        percentiles.p99,
+        percentiles.max
+    );
+    let mut histogram: BTreeMap<usize, usize> = BTreeMap::new();
+    for &tokens in &tokens {
+        let bucket = HISTOGRAM_BOUNDS.iter().position(|&bound| tokens <= bound).unwrap_or(HISTOGRAM_BOUNDS.len());
+        *histogram.entry(bucket).or_insert(0) += 1;
+    }
+    for (bucket, count) in histogram {
+        let label = match HISTOGRAM_BOUNDS.get(bucket) {
+            Some(bound) => format!("<= {}", bound),
+            None => format!("> {}", HISTOGRAM_BOUNDS[HISTOGRAM_BOUNDS.len() - 1]),
+        };
+        println!("  {:>9} tokens: {}", label, count);
+    }
+    let mut languages: BTreeMap<&str, usize> = BTreeMap::new();
+    for entry in entries {
+        let language = entry.response.language.as_str();
+        *languages.entry(if language.is_empty() { "-" } else { language }).or_insert(0) += 1;
+    }
+    let languages: Vec<String> = languages.iter().map(|(language, count)| format!("{} {}", language, count)).collect();
+    println!("Languages: {}", languages.join(", "));
+}
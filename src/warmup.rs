@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::http::StatusCode;
+use rand::Rng;
+use serde::Deserialize;
+use crate::error::ApiError;
+
+/// Cold start of an inference server: for a while after startup latency is
+/// elevated and some requests are turned away with 503
+#[derive(Deserialize, Clone, Debug)]
+pub struct WarmupConfig {
+    /// Length of the warmup window; 0 disables warmup
+    #[serde(default)]
+    pub duration_ms: u64,
+    /// Factor applied to the latency of requests served while warming up
+    #[serde(default = "default_latency_multiplier")]
+    pub latency_multiplier: f64,
+    /// Share of requests answered with 503 while warming up, from 0.0 to 1.0
+    #[serde(default)]
+    pub unavailable_ratio: f64,
+    /// Give every provider profile its own window, starting with the first
+    /// request that uses it, as if switching profiles loaded another model
+    #[serde(default)]
+    pub per_profile: bool,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self {
+            duration_ms: 0,
+            latency_multiplier: default_latency_multiplier(),
+            unavailable_ratio: 0.0,
+            per_profile: false,
+        }
+    }
+}
+
+fn default_latency_multiplier() -> f64 {
+    3.0
+}
+
+pub struct Warmup {
+    config: WarmupConfig,
+    started: Instant,
+    /// First request per profile name, with `per_profile`
+    profiles: Mutex<HashMap<String, Instant>>,
+}
+
+impl Warmup {
+    pub fn new(config: WarmupConfig) -> Self {
+        Self {
+            config,
+            started: Instant::now(),
+            profiles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep warming up from when `previous`, the warmup before a reload,
+    /// started, so a reload is no cold start
+    pub fn carry_over(&mut self, previous: &Warmup) {
+        self.started = previous.started;
+        *self.profiles.lock().unwrap() = previous.profiles.lock().unwrap().clone();
+    }
+
+    /// Time left in the warmup window of `profile`, if still warming up
+    fn remaining(&self, profile: Option<&str>) -> Option<Duration> {
+        if self.config.duration_ms == 0 {
+            return None;
+        }
+        let started = match profile.filter(|_| self.config.per_profile) {
+            Some(profile) => *self
+                .profiles
+                .lock()
+                .unwrap()
+                .entry(profile.to_string())
+                .or_insert_with(Instant::now),
+            None => self.started,
+        };
+        Duration::from_millis(self.config.duration_ms).checked_sub(started.elapsed())
+    }
+
+    /// Latency factor for a request using `profile`, or the 503 it gets
+    /// while the server is warming up
+    pub fn admit(&self, profile: Option<&str>) -> Result<f64, ApiError> {
+        let Some(remaining) = self.remaining(profile) else {
+            return Ok(1.0);
+        };
+        if rand::thread_rng().gen_bool(self.config.unavailable_ratio.clamp(0.0, 1.0)) {
+            let error = ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_error",
+                "The model is still loading, please try again shortly.",
+            );
+            let retry_after = remaining.as_millis().div_ceil(1000).max(1);
+            return Err(error.with_header("retry-after", retry_after.to_string()));
+        }
+        Ok(self.config.latency_multiplier.max(1.0))
+    }
+}
@@ -0,0 +1,159 @@
+//! Response generation by a WebAssembly module, for simulation logic that
+//! does not belong in the crate.
+//!
+//! The module exports its `memory` and `alloc(len: i32) -> i32`, which
+//! returns where the host may write `len` bytes, plus either or both hooks:
+//!
+//! - `select_response(ptr: i32, len: i32) -> i64` gets the request body
+//!   and returns the reply, as a `{"choices": [...]}` object or plain text.
+//!   An empty result leaves the choice to the configured source.
+//! - `post_process(ptr: i32, len: i32) -> i64` gets the content of every
+//!   choice about to be served and returns its replacement.
+//!
+//! Results are returned as `(ptr << 32) | len`. Every call runs in a fresh
+//! instance, so modules cannot keep state between requests.
+
+use serde::Deserialize;
+use crate::error::CustomError;
+use crate::reply::Reply;
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct WasmConfig {
+    /// Path of the module (`.wasm`, or `.wat` text)
+    #[serde(default)]
+    pub module: Option<String>,
+    /// Instructions a single call may execute before it is aborted
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+}
+
+fn default_fuel() -> u64 {
+    100_000_000
+}
+
+pub use imp::WasmPlugin;
+
+impl WasmPlugin {
+    /// `None` when no module is configured
+    pub fn from_config(config: &WasmConfig) -> Result<Option<Self>, CustomError> {
+        config.module.as_deref().map(|path| Self::load(path, config.fuel)).transpose()
+    }
+
+    /// Reply chosen by the module for `body`, if it makes a choice
+    pub fn select_response(&self, body: &[u8]) -> Result<Option<Reply>, CustomError> {
+        let Some(output) = self.call("select_response", body)? else {
+            return Ok(None);
+        };
+        if output.is_empty() {
+            return Ok(None);
+        }
+        let text = String::from_utf8_lossy(&output);
+        Ok(Some(serde_json::from_str(&text).unwrap_or_else(|_| Reply::text(text))))
+    }
+
+    /// `reply` with the content of every choice passed through the module
+    pub fn post_process(&self, mut reply: Reply) -> Result<Reply, CustomError> {
+        for content in reply.choices.iter_mut().filter_map(|choice| choice.message.content.as_mut()) {
+            match self.call("post_process", content.as_bytes())? {
+                Some(output) => *content = String::from_utf8_lossy(&output).into_owned(),
+                None => break,
+            }
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod imp {
+    use log::info;
+    use wasmtime::{Config, Engine, Instance, Linker, Module, Store};
+    use crate::error::{ApiError, CustomError};
+
+    pub struct WasmPlugin {
+        engine: Engine,
+        module: Module,
+        linker: Linker<()>,
+        fuel: u64,
+    }
+
+    impl WasmPlugin {
+        pub(super) fn load(path: &str, fuel: u64) -> Result<Self, CustomError> {
+            let load_error = |e: wasmtime::Error| CustomError::ConfigError(format!("cannot load WASM module {}: {:#}", path, e));
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config).map_err(load_error)?;
+            let module = Module::from_file(&engine, path).map_err(load_error)?;
+            let exports: Vec<_> = module.exports().map(|export| export.name().to_string()).collect();
+            for required in ["memory", "alloc"] {
+                if !exports.iter().any(|name| name == required) {
+                    return Err(CustomError::ConfigError(format!(
+                        "WASM module {} does not export '{}'", path, required
+                    )));
+                }
+            }
+            info!("Loaded WASM plugin {} exporting {:?}", path, exports);
+            Ok(Self {
+                linker: Linker::new(&engine),
+                engine,
+                module,
+                fuel,
+            })
+        }
+
+        /// Run export `hook` on `input` in a fresh instance. `None` when the
+        /// module does not export the hook.
+        pub(super) fn call(&self, hook: &str, input: &[u8]) -> Result<Option<Vec<u8>>, CustomError> {
+            if self.module.get_export(hook).is_none() {
+                return Ok(None);
+            }
+            let failed = |e: wasmtime::Error| {
+                CustomError::from(ApiError::new(
+                    actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    format!("WASM plugin failed in {}: {:#}", hook, e),
+                ))
+            };
+            let mut store = Store::new(&self.engine, ());
+            store.set_fuel(self.fuel).map_err(failed)?;
+            let instance: Instance = self.linker.instantiate(&mut store, &self.module).map_err(failed)?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| failed(wasmtime::Error::msg("no exported memory")))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc").map_err(failed)?;
+            let run = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook).map_err(failed)?;
+
+            let len = i32::try_from(input.len()).map_err(|e| failed(e.into()))?;
+            let ptr = alloc.call(&mut store, len).map_err(failed)?;
+            memory.write(&mut store, ptr as u32 as usize, input).map_err(|e| failed(e.into()))?;
+            let packed = run.call(&mut store, (ptr, len)).map_err(failed)? as u64;
+
+            let (start, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+            let output = memory
+                .data(&store)
+                .get(start..start + len)
+                .ok_or_else(|| failed(wasmtime::Error::msg("result out of memory bounds")))?;
+            Ok(Some(output.to_vec()))
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+mod imp {
+    use crate::error::CustomError;
+
+    /// Stand-in when the crate is built without the `wasm` feature: no
+    /// module can be loaded
+    pub enum WasmPlugin {}
+
+    impl WasmPlugin {
+        pub(super) fn load(path: &str, _fuel: u64) -> Result<Self, CustomError> {
+            Err(CustomError::ConfigError(format!(
+                "cannot load WASM module {}: built without the `wasm` feature", path
+            )))
+        }
+
+        pub(super) fn call(&self, _hook: &str, _input: &[u8]) -> Result<Option<Vec<u8>>, CustomError> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::sync::Arc;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use crate::health::SinkHealth;
+use crate::outbound;
+
+/// Header carrying the signature of a notification sent to a target with a
+/// `secret`
+pub const SIGNATURE_HEADER: &str = "x-sim-signature";
+
+/// JSON notifications POSTed to external URLs when something happens in
+/// the simulator
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebhooksConfig {
+    #[serde(default)]
+    pub targets: Vec<WebhookTarget>,
+    /// Give up on a delivery after this long
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            timeout_ms: default_timeout_ms(),
+        }
+    }
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebhookTarget {
+    /// `https://` endpoint, verified with the built-in web roots, or a
+    /// plain `http://` one
+    #[serde(deserialize_with = "http_url")]
+    pub url: http::Uri,
+    /// Sign notifications with HMAC-SHA256 under this secret
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Events sent to this target; every event when empty
+    #[serde(default)]
+    pub events: Vec<Event>,
+}
+
+fn http_url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<http::Uri, D::Error> {
+    let url = String::deserialize(deserializer)?;
+    let uri: http::Uri = url.parse().map_err(serde::de::Error::custom)?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) {
+        return Err(serde::de::Error::custom(format!("webhook url must be an http:// or https:// endpoint, got '{}'", url)));
+    }
+    Ok(uri)
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Permit occupancy reached `limits.saturation_warn_ratio`
+    #[serde(rename = "saturation.breached")]
+    SaturationBreached,
+    /// Permit occupancy fell back below the threshold
+    #[serde(rename = "saturation.recovered")]
+    SaturationRecovered,
+    /// An API key used up its daily or monthly token quota
+    #[serde(rename = "quota.exhausted")]
+    QuotaExhausted,
+    /// A circuit breaker stopped sending queries to its backend
+    #[serde(rename = "circuit.opened")]
+    CircuitOpened,
+    /// A circuit breaker's backend answered again
+    #[serde(rename = "circuit.closed")]
+    CircuitClosed,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SaturationBreached => "saturation.breached",
+            Self::SaturationRecovered => "saturation.recovered",
+            Self::QuotaExhausted => "quota.exhausted",
+            Self::CircuitOpened => "circuit.opened",
+            Self::CircuitClosed => "circuit.closed",
+        }
+    }
+}
+
+/// Sends events to the configured targets without holding up the caller
+pub struct Webhooks {
+    targets: Vec<WebhookTarget>,
+    timeout: Duration,
+    client: outbound::Client,
+    health: Arc<SinkHealth>,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self::new(&WebhooksConfig::default())
+    }
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhooksConfig) -> Self {
+        Self {
+            targets: config.targets.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            client: outbound::client(),
+            health: Arc::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.targets.is_empty()
+    }
+
+    pub fn health(&self) -> &SinkHealth {
+        &self.health
+    }
+
+    /// POST `{"id", "object": "event", "type", "created_at", "data"}` to
+    /// every target subscribed to `event`. Deliveries run in the background
+    /// and failures are only logged.
+    pub fn send(&self, event: Event, data: Value) {
+        let targets: Vec<&WebhookTarget> = self
+            .targets
+            .iter()
+            .filter(|target| target.events.is_empty() || target.events.contains(&event))
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+        // Events raised outside the runtime (e.g. at shutdown) are dropped
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let payload = json!({
+            "id": format!("evt_{}", uuid::Uuid::new_v4().simple()),
+            "object": "event",
+            "type": event.name(),
+            "created_at": chrono::Utc::now().timestamp(),
+            "data": data,
+        })
+        .to_string();
+        for target in targets {
+            let delivery = deliver(self.client.clone(), target.clone(), event, payload.clone(), self.timeout);
+            let health = self.health.clone();
+            runtime.spawn(async move {
+                match delivery.await {
+                    Ok(()) => health.record_success(),
+                    Err(e) => health.record_failure(e),
+                }
+            });
+        }
+    }
+}
+
+async fn deliver(
+    client: outbound::Client,
+    target: WebhookTarget,
+    event: Event,
+    payload: String,
+    timeout: Duration,
+) -> Result<(), String> {
+    let mut headers = Vec::new();
+    if let Some(secret) = &target.secret {
+        headers.push((SIGNATURE_HEADER, sign(secret, chrono::Utc::now().timestamp(), &payload)));
+    }
+    match outbound::post_json(&client, &target.url, headers, payload, timeout).await {
+        Ok(status) => {
+            debug!("event=webhook type={} url={} status={}", event.name(), target.url, status);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("event=webhook type={} url={} error=\"{}\"", event.name(), target.url, e);
+            Err(format!("{}: {}", target.url, e))
+        }
+    }
+}
+
+/// `t=<unix>,v1=<hex>` where `<hex>` is the HMAC-SHA256 of
+/// `<unix>.<payload>` under `secret`
+fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    let signature: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("t={},v1={}", timestamp, signature)
+}
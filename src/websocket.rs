@@ -0,0 +1,96 @@
+use actix_web::web::Bytes;
+use actix_ws::{CloseCode, CloseReason, Closed, Message, MessageStream, Session};
+use futures_util::{Stream, StreamExt};
+use log::debug;
+use crate::error::{ApiError, CustomError};
+
+/// Wait for the chat completion request: the first text (or binary)
+/// message on the socket. `None` when the client left before sending one.
+pub async fn read_request(session: &mut Session, messages: &mut MessageStream) -> Option<Bytes> {
+    while let Some(message) = messages.next().await {
+        match message {
+            Ok(Message::Text(text)) => return Some(text.into_bytes()),
+            Ok(Message::Binary(bytes)) => return Some(bytes),
+            Ok(Message::Ping(bytes)) => session.pong(&bytes).await.ok()?,
+            Ok(Message::Close(_)) | Err(_) => return None,
+            Ok(_) => {}
+        }
+    }
+    None
+}
+
+/// Send `error` as one text message in the same body the HTTP route
+/// returns, then close the socket
+pub async fn reject(session: Session, error: CustomError) {
+    let (status, body) = match &error {
+        CustomError::Api(error) => (error.status, error.body()),
+        _ => {
+            let status = actix_web::http::StatusCode::INTERNAL_SERVER_ERROR;
+            (status, ApiError::new(status, "server_error", error.to_string()).body())
+        }
+    };
+    let code = if status.is_client_error() { CloseCode::Policy } else { CloseCode::Error };
+    let mut sender = session.clone();
+    let _ = sender.text(body.to_string()).await;
+    let _ = session
+        .close(Some(CloseReason {
+            code,
+            description: Some(status.to_string()),
+        }))
+        .await;
+}
+
+/// Relay the SSE frames of a completion stream over the socket: every
+/// `data:` payload becomes a text message, keep-alive comments become pings
+/// and `id:`/`retry:` lines are dropped. The socket is closed normally once
+/// the stream ends, or with an error when it was aborted. Dropping the
+/// stream when the client closes first stops generation as a disconnect
+/// does for SSE.
+pub async fn relay<S>(mut session: Session, mut messages: MessageStream, stream: S)
+where
+    S: Stream<Item = Result<Bytes, actix_web::Error>>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let close = loop {
+        tokio::select! {
+            frame = stream.next() => match frame {
+                Some(Ok(frame)) => {
+                    if relay_frame(&mut session, &frame).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Err(e)) => {
+                    break CloseReason {
+                        code: CloseCode::Error,
+                        description: Some(e.to_string()),
+                    };
+                }
+                None => break CloseCode::Normal.into(),
+            },
+            message = messages.next() => match message {
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    debug!("WebSocket client closed before the stream ended");
+                    return;
+                }
+                Some(Ok(_)) => {}
+            },
+        }
+    };
+    let _ = session.close(Some(close)).await;
+}
+
+async fn relay_frame(session: &mut Session, frame: &[u8]) -> Result<(), Closed> {
+    let frame = String::from_utf8_lossy(frame);
+    if frame.starts_with(':') {
+        return session.ping(b"").await;
+    }
+    for data in frame.lines().filter_map(|line| line.strip_prefix("data: ")) {
+        session.text(data.to_string()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+use actix_web::web::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use rai_endpoint_simulator::test_util::TestSimulator;
+use rai_endpoint_simulator::{Config, Simulator};
+use serde_json::{json, Value};
+
+const REPLY: &str = "The simulator served this fixture.";
+
+/// A response folder holding a single reply, removed on drop
+struct Fixtures(PathBuf);
+
+impl Fixtures {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("rai-sim-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("reply.md"), REPLY).unwrap();
+        Self(dir)
+    }
+}
+
+impl Drop for Fixtures {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// POST a chat completion, returning the content type and body
+async fn chat(sim: &TestSimulator, stream: bool) -> (String, String) {
+    let client: Client<HttpConnector, Full<Bytes>> = Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+    let body = json!({
+        "model": "gpt-4o",
+        "stream": stream,
+        "messages": [{"role": "user", "content": "Hello"}],
+    });
+    let request = http::Request::post(sim.endpoint("/v1/chat/completions"))
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap();
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let content_type = response.headers()[http::header::CONTENT_TYPE].to_str().unwrap().to_string();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (content_type, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn serves_a_completion_from_the_fixture_dir() {
+    let fixtures = Fixtures::new();
+    let sim = TestSimulator::start(&fixtures.0).await.unwrap();
+
+    let (content_type, body) = chat(&sim, false).await;
+    assert!(content_type.starts_with("application/json"), "{}", content_type);
+    let completion: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(completion["object"], "chat.completion");
+    assert_eq!(completion["choices"][0]["message"]["content"], REPLY);
+
+    sim.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streams_a_completion_from_the_fixture_dir() {
+    let fixtures = Fixtures::new();
+    let sim = TestSimulator::start(&fixtures.0).await.unwrap();
+
+    let (content_type, body) = chat(&sim, true).await;
+    assert!(content_type.starts_with("text/event-stream"), "{}", content_type);
+    let content: String = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(str::trim)
+        .filter(|data| *data != "[DONE]")
+        .map(|data| serde_json::from_str::<Value>(data).unwrap())
+        .inspect(|chunk| assert_eq!(chunk["object"], "chat.completion.chunk"))
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str().map(str::to_string))
+        .collect();
+    assert_eq!(content, REPLY);
+
+    sim.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn starts_with_the_health_routes_disabled() {
+    let fixtures = Fixtures::new();
+    let mut config = Config::default();
+    config.endpoints.health = false;
+    let builder = Simulator::builder().config(config).workers(1).response_dir(fixtures.0.to_string_lossy());
+    let sim = TestSimulator::start_with(builder).await.unwrap();
+
+    let (_, body) = chat(&sim, false).await;
+    let completion: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(completion["choices"][0]["message"]["content"], REPLY);
+
+    sim.shutdown().await;
+}